@@ -0,0 +1,137 @@
+#!/usr/bin/env python3
+"""Stand-in enrollment endpoint for testing `greenion-server enroll` / `renew`.
+
+Signs CSRs with the root CA created by script.sh (rootCA.crt / rootCA.key in the
+current folder). Needs openssl in the PATH.
+
+    ./enrollment-ca.py --token 0123456789 --port 5004
+    greenion-server enroll --machine-id 1234 --token 0123456789
+
+with `enrollment_url = "http://127.0.0.1:5004/api_auth/v1/enrollment"`.
+"""
+
+import argparse
+import base64
+import json
+import os
+import subprocess
+import tempfile
+from http.server import BaseHTTPRequestHandler, HTTPServer
+
+PREFIX = "/api_auth/v1/enrollment"
+
+EXTENSIONS = """basicConstraints=CA:FALSE
+keyUsage=critical,digitalSignature,keyEncipherment
+extendedKeyUsage=serverAuth
+"""
+
+
+def openssl(*args, stdin=None):
+    return subprocess.run(
+        ["openssl", *args], input=stdin, capture_output=True, check=True
+    ).stdout
+
+
+def subject_cn(kind, pem_file):
+    subject = openssl(kind, "-in", pem_file, "-noout", "-subject", "-nameopt", "multiline")
+    for line in subject.decode().splitlines():
+        key, _, value = line.partition("=")
+        if key.strip() == "commonName":
+            return value.strip()
+    return None
+
+
+class EnrollmentHandler(BaseHTTPRequestHandler):
+    tokens = set()
+    ca_cert = "rootCA.crt"
+    ca_key = "rootCA.key"
+    days = 365
+
+    def do_POST(self):
+        length = int(self.headers.get("Content-Length", 0))
+        try:
+            request = json.loads(self.rfile.read(length))
+        except ValueError:
+            return self.reply(400, {"error": "invalid JSON"})
+
+        with tempfile.TemporaryDirectory() as tmp:
+            csr_file = os.path.join(tmp, "req.csr")
+            with open(csr_file, "w") as f:
+                f.write(request.get("csr", ""))
+
+            if self.path == PREFIX + "/enroll":
+                token = request.get("token")
+                if token not in self.tokens:
+                    return self.reply(403, {"error": "unknown or already used token"})
+                self.tokens.discard(token)
+            elif self.path == PREFIX + "/renew":
+                error = self.check_renewal(tmp, csr_file, request)
+                if error:
+                    return self.reply(403, {"error": error})
+            else:
+                return self.reply(404, {"error": "not found"})
+
+            try:
+                certificate = self.sign(tmp, csr_file)
+            except subprocess.CalledProcessError as e:
+                return self.reply(400, {"error": e.stderr.decode()})
+            self.reply(200, {"certificate": certificate})
+
+    def check_renewal(self, tmp, csr_file, request):
+        cert_file = os.path.join(tmp, "current.pem")
+        pubkey_file = os.path.join(tmp, "current.pub")
+        signature_file = os.path.join(tmp, "signature")
+        with open(cert_file, "w") as f:
+            f.write(request.get("certificate", ""))
+        with open(signature_file, "wb") as f:
+            f.write(base64.b64decode(request.get("signature", "")))
+
+        try:
+            openssl("verify", "-CAfile", self.ca_cert, cert_file)
+            with open(pubkey_file, "wb") as f:
+                f.write(openssl("x509", "-in", cert_file, "-noout", "-pubkey"))
+            openssl(
+                "dgst", "-sha256", "-verify", pubkey_file,
+                "-signature", signature_file, csr_file,
+            )
+            same_machine = subject_cn("req", csr_file) == subject_cn("x509", cert_file)
+        except subprocess.CalledProcessError as e:
+            return e.stderr.decode() or "verification failed"
+
+        if not same_machine:
+            return "CSR machine id doesn't match the current certificate"
+        return None
+
+    def sign(self, tmp, csr_file):
+        ext_file = os.path.join(tmp, "ext")
+        with open(ext_file, "w") as f:
+            f.write(EXTENSIONS)
+        return openssl(
+            "x509", "-req", "-in", csr_file,
+            "-CA", self.ca_cert, "-CAkey", self.ca_key, "-CAcreateserial",
+            "-days", str(self.days), "-copy_extensions", "copy", "-extfile", ext_file,
+        ).decode()
+
+    def reply(self, status, body):
+        payload = json.dumps(body).encode()
+        self.send_response(status)
+        self.send_header("Content-Type", "application/json")
+        self.send_header("Content-Length", str(len(payload)))
+        self.end_headers()
+        self.wfile.write(payload)
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.splitlines()[0])
+    parser.add_argument("--token", action="append", default=[], help="accepted one-time token, can be repeated")
+    parser.add_argument("--port", type=int, default=5004)
+    parser.add_argument("--days", type=int, default=365, help="validity of issued certificates")
+    args = parser.parse_args()
+
+    EnrollmentHandler.tokens = set(args.token)
+    EnrollmentHandler.days = args.days
+    HTTPServer(("127.0.0.1", args.port), EnrollmentHandler).serve_forever()
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,627 @@
+use anyhow::{anyhow, bail};
+use jsonwebtoken::{jwk::JwkSet, DecodingKey};
+use jwks::{Jwk, Jwks};
+use log::{debug, error, info, warn};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime},
+};
+
+use super::jwt::get_jwks;
+
+/// Where the keys that validate connection tokens come from.
+#[derive(Debug, Clone)]
+pub enum JwksSource {
+    /// JWKS fetched over HTTP on every request
+    Url(String),
+    /// JWKS stored in a local file
+    File(String),
+    /// PEM public keys (RSA, EC or Ed25519), each one identified by its file name without
+    /// extension as `kid`
+    PemFiles(Vec<String>),
+    /// JWKS found through the OpenID Connect discovery document of an issuer
+    Issuer(String),
+}
+
+/// Settings of an agent telling where its keys come from, the same for the server and the
+/// client.
+#[derive(Debug, Clone, Copy)]
+pub struct JwksSettings<'a> {
+    pub issuer: Option<&'a str>,
+    pub jwks_url: Option<&'a str>,
+    pub jwks_file: Option<&'a str>,
+    pub jwt_public_key_files: &'a [String],
+    pub discovery_refresh_secs: u64,
+}
+
+impl JwksSettings<'_> {
+    /// Local keys take precedence : `jwks_file` first, then `jwt_public_key_files`, then the
+    /// keys of `issuer` and finally `jwks_url`, falling back to `DEFAULT_JWKS_URL`.
+    pub fn source(&self) -> JwksSource {
+        if let Some(file) = self.jwks_file {
+            JwksSource::File(file.to_owned())
+        } else if !self.jwt_public_key_files.is_empty() {
+            JwksSource::PemFiles(self.jwt_public_key_files.to_vec())
+        } else if let Some(issuer) = self.issuer {
+            JwksSource::Issuer(issuer.to_owned())
+        } else if let Some(jwks_url) = self.jwks_url {
+            JwksSource::Url(jwks_url.to_owned())
+        } else {
+            warn!(
+                "None of issuer, jwks_url, jwks_file or jwt_public_key_files is set, using default jwks_url {}",
+                DEFAULT_JWKS_URL
+            );
+            JwksSource::Url(DEFAULT_JWKS_URL.to_string())
+        }
+    }
+}
+
+const DEFAULT_JWKS_URL: &str = "http://greenion.local:5004/.well-known/jwks.json";
+
+/// Provider of the keys configured by `settings`, fetched within `timeout`.
+pub fn jwks_provider(settings: JwksSettings, timeout: Duration) -> JwksProvider {
+    JwksProvider::new(
+        settings.source(),
+        settings.issuer.map(str::to_owned),
+        Duration::from_secs(settings.discovery_refresh_secs),
+        timeout,
+    )
+}
+
+/// Provides the current JWKS. Local sources are read once and read again whenever one of
+/// their files changes, so keys can be rotated without restarting the agents.
+/// The discovery document of an issuer is cached for `discovery_refresh`.
+pub struct JwksProvider {
+    source: JwksSource,
+    issuer: Option<String>,
+    discovery_refresh: Duration,
+    timeout: Duration,
+    cached: Mutex<Option<CachedJwks>>,
+    discovery: Mutex<Option<CachedDiscovery>>,
+}
+
+struct CachedJwks {
+    jwks: Jwks,
+    modified: Vec<Option<SystemTime>>,
+}
+
+struct CachedDiscovery {
+    jwks_uri: String,
+    fetched_at: Instant,
+}
+
+#[derive(Deserialize)]
+struct OpenIdConfiguration {
+    issuer: String,
+    jwks_uri: String,
+}
+
+impl JwksProvider {
+    pub fn new(
+        source: JwksSource,
+        issuer: Option<String>,
+        discovery_refresh: Duration,
+        timeout: Duration,
+    ) -> Self {
+        Self {
+            source,
+            issuer,
+            discovery_refresh,
+            timeout,
+            cached: Mutex::new(None),
+            discovery: Mutex::new(None),
+        }
+    }
+
+    pub fn source(&self) -> &JwksSource {
+        &self.source
+    }
+
+    /// Issuer that tokens must carry in their `iss` claim, if any.
+    pub fn issuer(&self) -> Option<&str> {
+        self.issuer.as_deref()
+    }
+
+    pub async fn get(&self) -> anyhow::Result<Jwks> {
+        let files = match &self.source {
+            JwksSource::Url(url) => return get_jwks(url, self.timeout).await,
+            JwksSource::Issuer(issuer) => {
+                let jwks_uri = self.discover_jwks_uri(issuer).await?;
+                return get_jwks(&jwks_uri, self.timeout).await;
+            }
+            JwksSource::File(file) => std::slice::from_ref(file),
+            JwksSource::PemFiles(files) => files.as_slice(),
+        };
+
+        let modified = files
+            .iter()
+            .map(|f| fs::metadata(f).and_then(|m| m.modified()).ok())
+            .collect::<Vec<_>>();
+
+        let mut cached = self.cached.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(c) = cached.as_ref() {
+            if c.modified == modified {
+                return Ok(c.jwks.clone());
+            }
+        }
+
+        let loaded = match &self.source {
+            JwksSource::File(file) => load_jwks_file(file),
+            JwksSource::PemFiles(files) => load_pem_public_keys(files),
+            JwksSource::Url(_) | JwksSource::Issuer(_) => unreachable!(),
+        };
+        match loaded {
+            Ok(jwks) => {
+                if cached.is_some() {
+                    info!("Reloaded token validation keys from {}", files.join(", "));
+                }
+                *cached = Some(CachedJwks {
+                    jwks: jwks.clone(),
+                    modified,
+                });
+                Ok(jwks)
+            }
+            Err(e) => match cached.as_ref() {
+                Some(c) => {
+                    error!(
+                        "Could not reload token validation keys, keeping the current ones : {}",
+                        e
+                    );
+                    Ok(c.jwks.clone())
+                }
+                None => Err(e),
+            },
+        }
+    }
+}
+
+impl JwksProvider {
+    /// Returns the `jwks_uri` of the discovery document of `issuer`, fetching the document
+    /// again once the cached one is older than `discovery_refresh`. If it can't be fetched,
+    /// the previous `jwks_uri` is kept.
+    async fn discover_jwks_uri(&self, issuer: &str) -> anyhow::Result<String> {
+        let previous = {
+            let discovery = self.discovery.lock().unwrap_or_else(|e| e.into_inner());
+            match discovery.as_ref() {
+                Some(d) if d.fetched_at.elapsed() < self.discovery_refresh => {
+                    return Ok(d.jwks_uri.clone())
+                }
+                Some(d) => Some(d.jwks_uri.clone()),
+                None => None,
+            }
+        };
+
+        match fetch_openid_configuration(issuer, self.timeout).await {
+            Ok(jwks_uri) => {
+                if previous.as_deref() != Some(jwks_uri.as_str()) {
+                    info!("Issuer {} publishes its keys at {}", issuer, jwks_uri);
+                }
+                *self.discovery.lock().unwrap_or_else(|e| e.into_inner()) = Some(CachedDiscovery {
+                    jwks_uri: jwks_uri.clone(),
+                    fetched_at: Instant::now(),
+                });
+                Ok(jwks_uri)
+            }
+            Err(e) => match previous {
+                Some(jwks_uri) => {
+                    warn!(
+                        "Could not refresh the discovery document of {}, keeping jwks_uri {} : {}",
+                        issuer, jwks_uri, e
+                    );
+                    Ok(jwks_uri)
+                }
+                None => Err(e),
+            },
+        }
+    }
+}
+
+/// Fetches `{issuer}/.well-known/openid-configuration` and returns its `jwks_uri`, after
+/// checking that the document belongs to `issuer`.
+pub async fn fetch_openid_configuration(issuer: &str, timeout: Duration) -> anyhow::Result<String> {
+    let url = format!(
+        "{}/.well-known/openid-configuration",
+        issuer.trim_end_matches('/')
+    );
+
+    let client = reqwest::Client::builder().timeout(timeout).build()?;
+    let res = match client.get(&url).send().await {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Could not fetch OpenID configuration from {} : {}", url, e);
+            bail!("Could not reach issuer {} : {}", issuer, e);
+        }
+    };
+    if !res.status().is_success() {
+        error!(
+            "Could not fetch OpenID configuration from {} : received status code {}",
+            url,
+            res.status()
+        );
+        bail!(
+            "Issuer {} has no OpenID configuration at {} ({})",
+            issuer,
+            url,
+            res.status()
+        );
+    }
+
+    let configuration = match res.json::<OpenIdConfiguration>().await {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Could not decode OpenID configuration at {} : {}", url, e);
+            bail!("OpenID configuration at {} is invalid : {}", url, e);
+        }
+    };
+
+    if configuration.issuer != issuer {
+        error!(
+            "OpenID configuration at {} belongs to issuer {}, not to the configured issuer {}",
+            url, configuration.issuer, issuer
+        );
+        bail!(
+            "Configured issuer {} doesn't match the issuer {} of its OpenID configuration",
+            issuer,
+            configuration.issuer
+        );
+    }
+
+    debug!(
+        "Fetched OpenID configuration of {} : jwks_uri is {}",
+        issuer, configuration.jwks_uri
+    );
+    Ok(configuration.jwks_uri)
+}
+
+/// Loads a JWKS (`{"keys": [...]}`) from a local file.
+pub fn load_jwks_file(filename: &str) -> anyhow::Result<Jwks> {
+    let contents = match fs::read_to_string(filename) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Could not read JWKS file {} : {}", filename, e);
+            bail!("Could not read JWKS file {}", filename);
+        }
+    };
+    let jwk_set = match serde_json::from_str::<JwkSet>(&contents) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Could not parse JWKS file {} : {}", filename, e);
+            bail!("JWKS file {} is not a valid JWKS : {}", filename, e);
+        }
+    };
+
+    let mut keys = HashMap::new();
+    for jwk in jwk_set.keys.iter() {
+        let Some(kid) = &jwk.common.key_id else {
+            bail!("A key of JWKS file {} has no kid", filename);
+        };
+        match DecodingKey::from_jwk(jwk) {
+            Ok(decoding_key) => {
+                keys.insert(kid.clone(), Jwk { decoding_key });
+            }
+            Err(e) => bail!("Key {} of JWKS file {} is invalid : {}", kid, filename, e),
+        }
+    }
+
+    debug!("Loaded {} key(s) from JWKS file {}", keys.len(), filename);
+    Ok(Jwks { keys })
+}
+
+/// Loads PEM public keys. The `kid` of each key is its file name without extension.
+pub fn load_pem_public_keys(filenames: &[String]) -> anyhow::Result<Jwks> {
+    let mut keys = HashMap::new();
+    for filename in filenames {
+        let contents = match fs::read(filename) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Could not read public key file {} : {}", filename, e);
+                bail!("Could not read public key file {}", filename);
+            }
+        };
+        let Some(kid) = Path::new(filename).file_stem().and_then(|s| s.to_str()) else {
+            bail!(
+                "Can't use the name of public key file {} as a kid",
+                filename
+            );
+        };
+
+        let decoding_key = DecodingKey::from_rsa_pem(&contents)
+            .or_else(|_| DecodingKey::from_ec_pem(&contents))
+            .or_else(|_| DecodingKey::from_ed_pem(&contents))
+            .map_err(|e| {
+                error!("Could not parse public key file {} : {}", filename, e);
+                anyhow!(
+                    "Public key file {} doesn't contain a PEM RSA, EC or Ed25519 public key",
+                    filename
+                )
+            })?;
+        keys.insert(kid.to_owned(), Jwk { decoding_key });
+    }
+
+    if keys.is_empty() {
+        bail!("No public key to validate tokens with");
+    }
+    debug!("Loaded {} PEM public key(s)", keys.len());
+    Ok(Jwks { keys })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use std::{fs::File, io::Write, path::PathBuf, sync::Arc};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        task::JoinHandle,
+    };
+
+    /// Writes `contents` to `path` and sets its modification time to `modified` seconds after
+    /// the epoch, so that a reload is noticed whatever the timestamp granularity.
+    fn write_file(path: &Path, contents: &str, modified: u64) {
+        let mut file = File::create(path).unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+        file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(modified))
+            .unwrap();
+    }
+
+    /// JWKS holding a new P-256 key for each of `kids`.
+    fn jwks_json(kids: &[&str]) -> String {
+        let keys = kids
+            .iter()
+            .map(|kid| {
+                let key_pair = rcgen::KeyPair::generate().unwrap();
+                let point = key_pair.public_key_raw();
+                serde_json::json!({
+                    "kty": "EC",
+                    "crv": "P-256",
+                    "kid": kid,
+                    "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+                    "y": URL_SAFE_NO_PAD.encode(&point[33..]),
+                })
+            })
+            .collect::<Vec<_>>();
+        serde_json::json!({ "keys": keys }).to_string()
+    }
+
+    fn pem_public_key() -> String {
+        rcgen::KeyPair::generate().unwrap().public_key_pem()
+    }
+
+    /// Tells whether `jwk` validates a token signed by `key_pair`.
+    fn validates(jwk: &Jwk, key_pair: &rcgen::KeyPair) -> bool {
+        let encoding_key =
+            jsonwebtoken::EncodingKey::from_ec_pem(key_pair.serialize_pem().as_bytes()).unwrap();
+        let token = jsonwebtoken::encode(
+            &jsonwebtoken::Header::new(jsonwebtoken::Algorithm::ES256),
+            &serde_json::json!({ "exp": 4_000_000_000u64 }),
+            &encoding_key,
+        )
+        .unwrap();
+        jsonwebtoken::decode::<serde_json::Value>(
+            &token,
+            &jwk.decoding_key,
+            &jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::ES256),
+        )
+        .is_ok()
+    }
+
+    fn provider(source: JwksSource) -> JwksProvider {
+        JwksProvider::new(
+            source,
+            None,
+            Duration::from_secs(3600),
+            Duration::from_secs(2),
+        )
+    }
+
+    fn kids(jwks: &Jwks) -> Vec<String> {
+        let mut kids = jwks.keys.keys().cloned().collect::<Vec<_>>();
+        kids.sort();
+        kids
+    }
+
+    fn path_string(path: PathBuf) -> String {
+        path.to_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn pem_kid_is_the_file_name_without_extension() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let files = ["signing-2024.pem", "backup.key.pub", "noext"]
+            .iter()
+            .map(|name| {
+                let path = dir.path().join(name);
+                write_file(&path, &pem_public_key(), 1);
+                path_string(path)
+            })
+            .collect::<Vec<_>>();
+
+        let jwks = load_pem_public_keys(&files).unwrap();
+        assert_eq!(kids(&jwks), ["backup.key", "noext", "signing-2024"]);
+    }
+
+    #[test]
+    fn jwks_file_keys_need_a_kid() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("jwks.json");
+        write_file(&path, &jwks_json(&["a", "b"]), 1);
+        assert_eq!(
+            kids(&load_jwks_file(&path_string(path.clone())).unwrap()),
+            ["a", "b"]
+        );
+
+        let mut jwks = serde_json::from_str::<serde_json::Value>(&jwks_json(&["a"])).unwrap();
+        jwks["keys"][0].as_object_mut().unwrap().remove("kid");
+        write_file(&path, &jwks.to_string(), 2);
+        assert!(load_jwks_file(&path_string(path)).is_err());
+    }
+
+    #[tokio::test]
+    async fn jwks_file_reload_picks_up_changes() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("jwks.json");
+        write_file(&path, &jwks_json(&["old"]), 1);
+        let provider = provider(JwksSource::File(path_string(path.clone())));
+        assert_eq!(kids(&provider.get().await.unwrap()), ["old"]);
+
+        write_file(&path, &jwks_json(&["new", "next"]), 2);
+        assert_eq!(kids(&provider.get().await.unwrap()), ["new", "next"]);
+    }
+
+    #[tokio::test]
+    async fn pem_reload_picks_up_changes() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("signing.pem");
+        let old = rcgen::KeyPair::generate().unwrap();
+        write_file(&path, &old.public_key_pem(), 1);
+        let provider = provider(JwksSource::PemFiles(vec![path_string(path.clone())]));
+        assert!(validates(
+            &provider.get().await.unwrap().keys["signing"],
+            &old
+        ));
+
+        let new = rcgen::KeyPair::generate().unwrap();
+        write_file(&path, &new.public_key_pem(), 2);
+        let jwks = provider.get().await.unwrap();
+        assert_eq!(kids(&jwks), ["signing"]);
+        assert!(validates(&jwks.keys["signing"], &new));
+        assert!(!validates(&jwks.keys["signing"], &old));
+    }
+
+    #[tokio::test]
+    async fn failed_reload_keeps_the_current_keys() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("jwks.json");
+        write_file(&path, &jwks_json(&["current"]), 1);
+        let provider = provider(JwksSource::File(path_string(path.clone())));
+        assert_eq!(kids(&provider.get().await.unwrap()), ["current"]);
+
+        write_file(&path, "{ not json", 2);
+        assert_eq!(kids(&provider.get().await.unwrap()), ["current"]);
+
+        fs::remove_file(&path).unwrap();
+        assert_eq!(kids(&provider.get().await.unwrap()), ["current"]);
+
+        write_file(&path, &jwks_json(&["fixed"]), 3);
+        assert_eq!(kids(&provider.get().await.unwrap()), ["fixed"]);
+    }
+
+    #[tokio::test]
+    async fn failed_first_load_is_an_error() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("signing.pem");
+        write_file(&path, "not a key", 1);
+        let provider = provider(JwksSource::PemFiles(vec![path_string(path)]));
+        assert!(provider.get().await.is_err());
+    }
+
+    /// Serves a discovery document whose `jwks_uri` is the current value of `jwks_uri` and
+    /// whose issuer is `issuer`, or the URL of the server itself. Returns that URL.
+    async fn serve_discovery(
+        issuer: Option<&str>,
+        jwks_uri: Arc<Mutex<String>>,
+    ) -> (String, JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let issuer = issuer.unwrap_or(&url).to_owned();
+        let server = tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                }
+                assert!(request.starts_with(b"GET /.well-known/openid-configuration "));
+                let body = serde_json::json!({
+                    "issuer": issuer,
+                    "jwks_uri": *jwks_uri.lock().unwrap(),
+                })
+                .to_string();
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+                stream.shutdown().await.unwrap();
+            }
+        });
+        (url, server)
+    }
+
+    fn issuer_provider(issuer: &str, discovery_refresh: Duration) -> JwksProvider {
+        JwksProvider::new(
+            JwksSource::Issuer(issuer.to_owned()),
+            Some(issuer.to_owned()),
+            discovery_refresh,
+            Duration::from_secs(2),
+        )
+    }
+
+    #[tokio::test]
+    async fn discovery_returns_the_jwks_uri_of_the_issuer() {
+        let jwks_uri = Arc::new(Mutex::new("http://keys.test/jwks.json".to_owned()));
+        let (issuer, _server) = serve_discovery(None, jwks_uri).await;
+        assert_eq!(
+            fetch_openid_configuration(&issuer, Duration::from_secs(2))
+                .await
+                .unwrap(),
+            "http://keys.test/jwks.json"
+        );
+    }
+
+    #[tokio::test]
+    async fn discovery_refuses_another_issuer() {
+        let jwks_uri = Arc::new(Mutex::new("http://keys.test/jwks.json".to_owned()));
+        let (issuer, _server) = serve_discovery(Some("https://other.test"), jwks_uri).await;
+        assert!(fetch_openid_configuration(&issuer, Duration::from_secs(2))
+            .await
+            .is_err());
+
+        let provider = issuer_provider(&issuer, Duration::from_secs(3600));
+        assert!(provider.discover_jwks_uri(&issuer).await.is_err());
+        assert!(provider.get().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn discovered_jwks_uri_is_refreshed() {
+        let jwks_uri = Arc::new(Mutex::new("http://keys.test/old.json".to_owned()));
+        let (issuer, server) = serve_discovery(None, jwks_uri.clone()).await;
+        let provider = issuer_provider(&issuer, Duration::from_millis(500));
+        assert_eq!(
+            provider.discover_jwks_uri(&issuer).await.unwrap(),
+            "http://keys.test/old.json"
+        );
+
+        // cached until discovery_refresh has elapsed
+        *jwks_uri.lock().unwrap() = "http://keys.test/new.json".to_owned();
+        assert_eq!(
+            provider.discover_jwks_uri(&issuer).await.unwrap(),
+            "http://keys.test/old.json"
+        );
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert_eq!(
+            provider.discover_jwks_uri(&issuer).await.unwrap(),
+            "http://keys.test/new.json"
+        );
+
+        // the issuer going away keeps the last jwks_uri
+        server.abort();
+        let _ = server.await;
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert_eq!(
+            provider.discover_jwks_uri(&issuer).await.unwrap(),
+            "http://keys.test/new.json"
+        );
+    }
+}
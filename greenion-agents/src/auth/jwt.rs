@@ -1,11 +1,16 @@
 use log::error;
-use std::time::Duration;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
 
 use anyhow::{anyhow, bail};
-use jsonwebtoken::{decode, decode_header, Validation};
+use jsonwebtoken::{decode, decode_header, Algorithm, Validation};
 use jwks::Jwks;
 use serde::{Deserialize, Serialize};
 
+use crate::metrics::metrics;
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Claims {
     pub exp: usize,
@@ -19,10 +24,14 @@ pub struct Claims {
     pub machine_ip: String,
     #[serde(rename(deserialize = "machineExternalPort"))]
     pub machine_port: u16,
+    /// Every other claim of the token (groups, roles...)
+    #[serde(flatten)]
+    pub extra: HashMap<String, serde_json::Value>,
 }
 
 pub async fn get_jwks(jwks_url: &str, timeout: Duration) -> anyhow::Result<jwks::Jwks> {
-    match tokio::time::timeout(timeout, Jwks::from_jwks_url(jwks_url)).await {
+    let fetch_started = Instant::now();
+    let res = match tokio::time::timeout(timeout, Jwks::from_jwks_url(jwks_url)).await {
         Err(e) => {
             error!("Timed out while retrieving JWKS from {} : {}", jwks_url, e);
             Err(anyhow!("Timed out while retrieving JWKS from {}", jwks_url))
@@ -34,10 +43,22 @@ pub async fn get_jwks(jwks_url: &str, timeout: Duration) -> anyhow::Result<jwks:
                 Err(anyhow!("Could not retrieve JWKS from {}", jwks_url))
             }
         },
+    };
+    metrics()
+        .jwks_fetch_duration
+        .observe(fetch_started.elapsed());
+    if res.is_err() {
+        metrics().jwks_fetch_failures.inc();
     }
+    res
 }
 
-pub fn parse_and_validate_jwt(jwt: &str, jwks: &Jwks) -> anyhow::Result<Claims> {
+/// Validates `jwt` with `jwks`. When `issuer` is given, the `iss` claim must be present and equal to it.
+pub fn parse_and_validate_jwt(
+    jwt: &str,
+    jwks: &Jwks,
+    issuer: Option<&str>,
+) -> anyhow::Result<Claims> {
     let header = match decode_header(jwt) {
         Ok(v) => v,
         Err(e) => {
@@ -56,8 +77,29 @@ pub fn parse_and_validate_jwt(jwt: &str, jwks: &Jwks) -> anyhow::Result<Claims>
         bail!("Connection token is invalid.");
     };
 
-    let mut validation = Validation::new(jsonwebtoken::Algorithm::RS256);
+    // Asymmetric algorithms only : the key type must match the algorithm, which jsonwebtoken checks
+    let algorithm = match header.alg {
+        Algorithm::RS256
+        | Algorithm::RS384
+        | Algorithm::RS512
+        | Algorithm::PS256
+        | Algorithm::PS384
+        | Algorithm::PS512
+        | Algorithm::ES256
+        | Algorithm::ES384
+        | Algorithm::EdDSA => header.alg,
+        alg => {
+            error!("Unsupported JWT algorithm {:?}", alg);
+            bail!("Connection token is invalid.");
+        }
+    };
+
+    let mut validation = Validation::new(algorithm);
     validation.validate_aud = false;
+    if let Some(iss) = issuer {
+        validation.set_issuer(&[iss]);
+        validation.set_required_spec_claims(&["exp", "iss"]);
+    }
 
     match decode::<Claims>(jwt, &jwk.decoding_key, &validation) {
         Ok(decoded_token) => Ok(decoded_token.claims),
@@ -66,6 +108,17 @@ pub fn parse_and_validate_jwt(jwt: &str, jwks: &Jwks) -> anyhow::Result<Claims>
                 error!("JWT token expired");
                 bail!("Connection token is expired.");
             }
+            jsonwebtoken::errors::ErrorKind::InvalidIssuer => {
+                error!(
+                    "JWT was not issued by the configured issuer {}",
+                    issuer.unwrap_or_default()
+                );
+                bail!("Connection token was issued by an unknown issuer.");
+            }
+            jsonwebtoken::errors::ErrorKind::MissingRequiredClaim(claim) => {
+                error!("JWT has no {} claim", claim);
+                bail!("Connection token is invalid.");
+            }
             e => {
                 error!("Error when extracting claims from connection JWT : {:?}", e);
                 bail!("Connection token is invalid.");
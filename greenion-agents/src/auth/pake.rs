@@ -0,0 +1,358 @@
+use anyhow::{anyhow, bail};
+use curve25519_dalek::{
+    constants::RISTRETTO_BASEPOINT_POINT,
+    ristretto::{CompressedRistretto, RistrettoPoint},
+    scalar::Scalar,
+    traits::Identity,
+};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256, Sha512};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsStream;
+
+use super::random_bytes;
+
+// SPAKE2 (RFC 9382) over ristretto255, the client being A and the server B. RFC 9382 only
+// defines M and N for NIST and Edwards curves : like the RFC ones, ours are hashed from fixed
+// labels (with the ristretto255 hash-to-group of RFC 9496), so nobody knows their discrete
+// logarithm. ristretto255 has a prime order, so no cofactor has to be cleared.
+// The key schedule is the one of RFC 9382 section 4 with SHA-256, HKDF-SHA256 and
+// HMAC-SHA256, checked against the test vectors of its appendix B. The TLS channel binding is
+// its associated data.
+// The `spake2` crate isn't used : it follows the draft that predates RFC 9382, without key
+// confirmation nor associated data.
+const M_LABEL: &[u8] = b"greenion pairing SPAKE2 M";
+const N_LABEL: &[u8] = b"greenion pairing SPAKE2 N";
+const CLIENT_IDENTITY: &[u8] = b"greenion-agent-client";
+const SERVER_IDENTITY: &[u8] = b"greenion-agent-server";
+// RFC 5705 lets exporter labels starting with "EXPERIMENTAL" be used without registration
+const CHANNEL_BINDING_LABEL: &[u8] = b"EXPERIMENTAL greenion pairing";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PakeSide {
+    Client,
+    Server,
+}
+
+/// One side of a pairing handshake. Both sides derive the same key only if they used the same
+/// pairing code, and an eavesdropper or an active attacker learns nothing that would let them
+/// test more than one code per handshake.
+pub struct Spake2 {
+    side: PakeSide,
+    password: Scalar,
+    secret: Scalar,
+    message: [u8; 32],
+}
+
+/// Keys confirming a pairing handshake, and the transcript they authenticate.
+pub struct PairingKey {
+    client_confirmation_key: [u8; 16],
+    server_confirmation_key: [u8; 16],
+    transcript: Vec<u8>,
+}
+
+impl Spake2 {
+    pub fn start(side: PakeSide, code: &str) -> anyhow::Result<Self> {
+        let password = password_scalar(code);
+        let mut random = [0u8; 64];
+        random_bytes(&mut random)?;
+        let secret = Scalar::from_bytes_mod_order_wide(&random);
+
+        let blinding = match side {
+            PakeSide::Client => RistrettoPoint::hash_from_bytes::<Sha512>(M_LABEL),
+            PakeSide::Server => RistrettoPoint::hash_from_bytes::<Sha512>(N_LABEL),
+        };
+        let message = (RISTRETTO_BASEPOINT_POINT * secret + blinding * password)
+            .compress()
+            .to_bytes();
+
+        Ok(Self {
+            side,
+            password,
+            secret,
+            message,
+        })
+    }
+
+    /// Message to send to the other side.
+    pub fn message(&self) -> &[u8] {
+        &self.message
+    }
+
+    /// Derives the shared key from the message of the other side. `channel_binding` ties the
+    /// key to the TLS session, so that a pairing relayed by a man in the middle fails.
+    pub fn finish(self, peer_message: &[u8], channel_binding: &[u8]) -> anyhow::Result<PairingKey> {
+        let peer = CompressedRistretto::from_slice(peer_message)
+            .ok()
+            .and_then(|p| p.decompress())
+            .ok_or_else(|| anyhow!("Invalid pairing message"))?;
+
+        let peer_blinding = match self.side {
+            PakeSide::Client => RistrettoPoint::hash_from_bytes::<Sha512>(N_LABEL),
+            PakeSide::Server => RistrettoPoint::hash_from_bytes::<Sha512>(M_LABEL),
+        };
+        let shared = (peer - peer_blinding * self.password) * self.secret;
+        if shared == RistrettoPoint::identity() {
+            bail!("Invalid pairing message");
+        }
+
+        let (client_message, server_message) = match self.side {
+            PakeSide::Client => (&self.message[..], peer_message),
+            PakeSide::Server => (peer_message, &self.message[..]),
+        };
+        Ok(PairingKey::derive(
+            [
+                CLIENT_IDENTITY,
+                SERVER_IDENTITY,
+                client_message,
+                server_message,
+                shared.compress().as_bytes(),
+                self.password.as_bytes(),
+            ],
+            channel_binding,
+        ))
+    }
+}
+
+impl PairingKey {
+    /// RFC 9382 key schedule from the parts of the transcript (A, B, pA, pB, K and w, already
+    /// encoded) and the associated data.
+    fn derive(parts: [&[u8]; 6], associated_data: &[u8]) -> Self {
+        let mut transcript = Vec::new();
+        for part in parts {
+            transcript.extend_from_slice(&(part.len() as u64).to_le_bytes());
+            transcript.extend_from_slice(part);
+        }
+        // Ke, the first half, would be the shared secret : pairing only needs the confirmation
+        let hash = Sha256::digest(&transcript);
+        let confirmation_keys = hkdf_sha256(&hash[16..], &[b"ConfirmationKeys", associated_data]);
+        Self {
+            client_confirmation_key: confirmation_keys[..16].try_into().unwrap(),
+            server_confirmation_key: confirmation_keys[16..].try_into().unwrap(),
+            transcript,
+        }
+    }
+
+    /// Proof that `side` derived this key, to send to the other side.
+    pub fn confirmation(&self, side: PakeSide) -> Vec<u8> {
+        self.confirmation_mac(side).finalize().into_bytes().to_vec()
+    }
+
+    /// Checks, in constant time, the proof sent by `side`.
+    pub fn verify_confirmation(&self, side: PakeSide, confirmation: &[u8]) -> bool {
+        self.confirmation_mac(side)
+            .verify_slice(confirmation)
+            .is_ok()
+    }
+
+    fn confirmation_mac(&self, side: PakeSide) -> Hmac<Sha256> {
+        let key = match side {
+            PakeSide::Client => &self.client_confirmation_key,
+            PakeSide::Server => &self.server_confirmation_key,
+        };
+        let mut mac =
+            <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts keys of any size");
+        mac.update(&self.transcript);
+        mac
+    }
+}
+
+/// HKDF-SHA256 (RFC 5869) without salt, returning 32 bytes of output keying material for the
+/// concatenation of `info`.
+fn hkdf_sha256(ikm: &[u8], info: &[&[u8]]) -> [u8; 32] {
+    let mut extract =
+        <Hmac<Sha256> as Mac>::new_from_slice(&[0u8; 32]).expect("HMAC accepts keys of any size");
+    extract.update(ikm);
+    let prk = extract.finalize().into_bytes();
+
+    let mut expand =
+        <Hmac<Sha256> as Mac>::new_from_slice(&prk).expect("HMAC accepts keys of any size");
+    for part in info {
+        expand.update(part);
+    }
+    expand.update(&[1]);
+    expand.finalize().into_bytes().into()
+}
+
+/// Pairing codes are shown grouped (`1234-5678`) : separators and spaces are ignored.
+fn password_scalar(code: &str) -> Scalar {
+    let normalized = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_ascii_uppercase();
+    Scalar::hash_from_bytes::<Sha512>(normalized.as_bytes())
+}
+
+/// Value both ends of a TLS session agree on (RFC 5705 exporter), and that differs when a
+/// man in the middle terminates TLS on each side.
+pub fn channel_binding(stream: &TlsStream<TcpStream>) -> anyhow::Result<[u8; 32]> {
+    let mut binding = [0u8; 32];
+    let res = match stream {
+        TlsStream::Client(s) => {
+            s.get_ref()
+                .1
+                .export_keying_material(&mut binding, CHANNEL_BINDING_LABEL, None)
+        }
+        TlsStream::Server(s) => {
+            s.get_ref()
+                .1
+                .export_keying_material(&mut binding, CHANNEL_BINDING_LABEL, None)
+        }
+    };
+    match res {
+        Ok(_) => Ok(binding),
+        Err(e) => bail!("Could not bind pairing to the TLS session : {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BINDING: &[u8] = b"tls session";
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// Runs both sides of a handshake, returning the keys they derived.
+    fn handshake(
+        client_code: &str,
+        server_code: &str,
+        server_binding: &[u8],
+    ) -> (PairingKey, PairingKey) {
+        let client = Spake2::start(PakeSide::Client, client_code).unwrap();
+        let server = Spake2::start(PakeSide::Server, server_code).unwrap();
+        let client_message = client.message().to_vec();
+        let server_message = server.message().to_vec();
+        (
+            client.finish(&server_message, BINDING).unwrap(),
+            server.finish(&client_message, server_binding).unwrap(),
+        )
+    }
+
+    fn confirmed(client: &PairingKey, server: &PairingKey) -> bool {
+        server.verify_confirmation(PakeSide::Client, &client.confirmation(PakeSide::Client))
+            && client.verify_confirmation(PakeSide::Server, &server.confirmation(PakeSide::Server))
+    }
+
+    #[test]
+    fn same_code() {
+        let (client, server) = handshake("1234-5678", "1234-5678", BINDING);
+        assert_eq!(
+            client.confirmation(PakeSide::Client),
+            server.confirmation(PakeSide::Client)
+        );
+        assert!(confirmed(&client, &server));
+    }
+
+    #[test]
+    fn codes_ignore_separators_and_case() {
+        let (client, server) = handshake("ab12 34-cd", "AB1234CD", BINDING);
+        assert!(confirmed(&client, &server));
+    }
+
+    #[test]
+    fn other_code() {
+        let (client, server) = handshake("1234-5678", "1234-5679", BINDING);
+        assert_ne!(
+            client.confirmation(PakeSide::Client),
+            server.confirmation(PakeSide::Client)
+        );
+        assert!(!confirmed(&client, &server));
+    }
+
+    #[test]
+    fn other_tls_session() {
+        let (client, server) = handshake("1234-5678", "1234-5678", b"relayed tls session");
+        assert!(!confirmed(&client, &server));
+    }
+
+    #[test]
+    fn confirmations_depend_on_the_side() {
+        let (client, server) = handshake("1234-5678", "1234-5678", BINDING);
+        // a server echoing the client confirmation doesn't prove it knows the code
+        assert!(
+            !client.verify_confirmation(PakeSide::Server, &client.confirmation(PakeSide::Client))
+        );
+        assert!(
+            !server.verify_confirmation(PakeSide::Client, &server.confirmation(PakeSide::Server))
+        );
+    }
+
+    #[test]
+    fn messages_are_random() {
+        let first = Spake2::start(PakeSide::Client, "1234-5678").unwrap();
+        let second = Spake2::start(PakeSide::Client, "1234-5678").unwrap();
+        assert_ne!(first.message(), second.message());
+    }
+
+    #[test]
+    fn invalid_messages() {
+        let start = || Spake2::start(PakeSide::Server, "1234-5678").unwrap();
+        assert!(start().finish(&[0u8; 31], BINDING).is_err());
+        assert!(start().finish(&[0xffu8; 32], BINDING).is_err());
+        // a message whose blinding cancels out leaves a shared point known to the sender
+        let cancelling = (RistrettoPoint::hash_from_bytes::<Sha512>(M_LABEL)
+            * password_scalar("1234-5678"))
+        .compress()
+        .to_bytes();
+        assert!(start().finish(&cancelling, BINDING).is_err());
+    }
+
+    #[test]
+    fn hkdf_rfc5869_vector() {
+        // test case 3 : no salt nor info, first 32 bytes of the output
+        assert_eq!(
+            hkdf_sha256(&[0x0b; 22], &[]).to_vec(),
+            hex("8da4e775a563c18f715f802a063c5a31b8a11f5c5ee1879ec3454e5f3c738d2d")
+        );
+    }
+
+    #[test]
+    fn key_schedule_rfc9382_vector() {
+        // first test vector of RFC 9382 appendix B (SPAKE2-P256-SHA256-HKDF-HMAC) : the key
+        // schedule only sees encoded group elements, so the P-256 ones check it as well
+        let pa = hex("04a56fa807caaa53a4d28dbb9853b9815c61a411118a6fe516a8798434751470f9010153ac33d0d5f2047ffdb1a3e42c9b4e6be662766e1eeb4116988ede5f912c");
+        let pb = hex("0406557e482bd03097ad0cbaa5df82115460d951e3451962f1eaf4367a420676d09857ccbc522686c83d1852abfa8ed6e4a1155cf8f1543ceca528afb591a1e0b7");
+        let k = hex("0412af7e89717850671913e6b469ace67bd90a4df8ce45c2af19010175e37eed69f75897996d539356e2fa6a406d528501f907e04d97515fbe83db277b715d3325");
+        let w = hex("2ee57912099d31560b3a44b1184b9b4866e904c49d12ac5042c97dca461b1a5f");
+        let key = PairingKey::derive([b"server", b"client", &pa, &pb, &k, &w], b"");
+
+        assert_eq!(
+            key.client_confirmation_key.to_vec(),
+            hex("00c12546835755c86d8c0db7851ae86f")
+        );
+        assert_eq!(
+            key.server_confirmation_key.to_vec(),
+            hex("a9fa3406c3b781b93d804485430ca27a")
+        );
+        assert_eq!(
+            key.confirmation(PakeSide::Client),
+            hex("58ad4aa88e0b60d5061eb6b5dd93e80d9c4f00d127c65b3b35b1b5281fee38f0")
+        );
+        assert_eq!(
+            key.confirmation(PakeSide::Server),
+            hex("d3e2e547f1ae04f2dbdbf0fc4b79f8ecff2dff314b5d32fe9fcef2fb26dc459b")
+        );
+    }
+
+    #[test]
+    fn blinding_points() {
+        // changing M or N breaks pairing with agents of other versions
+        let m = RistrettoPoint::hash_from_bytes::<Sha512>(M_LABEL).compress();
+        let n = RistrettoPoint::hash_from_bytes::<Sha512>(N_LABEL).compress();
+        assert_eq!(
+            m.as_bytes().to_vec(),
+            hex("bef66d629e37fb9fc7d2d69185d1605b7a9a13835f1160419995d5cfaa24e701")
+        );
+        assert_eq!(
+            n.as_bytes().to_vec(),
+            hex("145b71251ff5f2ffe704c41b4a3bab4040c7e936602bd0281deae28667f4fb38")
+        );
+    }
+}
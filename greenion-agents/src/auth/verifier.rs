@@ -0,0 +1,594 @@
+use std::{
+    fs::File,
+    io::Read,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, bail};
+use log::{debug, error, info};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{
+        verify_tls12_signature, verify_tls13_signature, CryptoProvider, WebPkiSupportedAlgorithms,
+    },
+    pki_types::{CertificateDer, CertificateRevocationListDer, ServerName, TrustAnchor, UnixTime},
+    CertificateError, DigitallySignedStruct, OtherError, SignatureScheme,
+};
+use sha2::{Digest, Sha256};
+use webpki::{
+    anchor_from_trusted_cert, CertRevocationList, EndEntityCert, KeyUsage, OwnedCertRevocationList,
+    RevocationCheckDepth, RevocationOptionsBuilder, UnknownStatusPolicy,
+};
+
+use super::x509::{check_certificate_key_usage, check_certificate_machine_id, parse_x509};
+
+/// Verifies the certificate presented by a server agent during the TLS handshake.
+///
+/// The chain must lead to one of the configured CAs, every certificate of the chain must be
+/// within its validity period, the end-entity certificate must be usable for server
+/// authentication and must carry the expected machine id (in a SAN URI or its CN).
+/// When a revocation list is configured, revoked certificates are refused as well.
+///
+/// The server name sent by rustls (the machine IP) is ignored : server agents are
+/// identified by their machine id, not by their address.
+#[derive(Debug)]
+pub struct MachineCertVerifier {
+    trust_anchors: Vec<TrustAnchor<'static>>,
+    // Subject of each trust anchor, in the same order, used to tell which CA validated a server
+    ca_names: Vec<String>,
+    machine_id: String,
+    crls: Vec<CertRevocationList<'static>>,
+    supported_algs: WebPkiSupportedAlgorithms,
+}
+
+impl MachineCertVerifier {
+    pub fn new(
+        ca_certs: &[CertificateDer<'static>],
+        machine_id: &str,
+        revocation_list_file: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        let mut trust_anchors = vec![];
+        let mut ca_names = vec![];
+        for ca_cert in ca_certs {
+            let anchor = match anchor_from_trusted_cert(ca_cert) {
+                Ok(v) => v.to_owned(),
+                Err(e) => {
+                    error!("Failed to use CA certificate as a trust anchor : {}", e);
+                    bail!("Failed to create local CA root store");
+                }
+            };
+            let name = match parse_x509(ca_cert) {
+                Ok(v) => format!("{} (serial {})", v.subject(), v.raw_serial_as_string()),
+                Err(_) => bail!("Failed to create local CA root store"),
+            };
+            trust_anchors.push(anchor);
+            ca_names.push(name);
+        }
+
+        if trust_anchors.is_empty() {
+            error!("No CA certificate to validate servers with");
+            bail!("No trusted CA certificate");
+        }
+
+        let crls = match revocation_list_file {
+            Some(filename) => load_crls(filename)?,
+            None => vec![],
+        };
+
+        let provider = CryptoProvider::get_default()
+            .cloned()
+            .unwrap_or_else(|| Arc::new(rustls::crypto::aws_lc_rs::default_provider()));
+
+        Ok(Self {
+            trust_anchors,
+            ca_names,
+            machine_id: machine_id.to_owned(),
+            crls,
+            supported_algs: provider.signature_verification_algorithms,
+        })
+    }
+}
+
+impl ServerCertVerifier for MachineCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let cert = EndEntityCert::try_from(end_entity).map_err(|e| {
+            error!("Could not parse server certificate : {}", e);
+            rustls::Error::InvalidCertificate(CertificateError::BadEncoding)
+        })?;
+
+        let crl_refs = self.crls.iter().collect::<Vec<_>>();
+        let revocation = if crl_refs.is_empty() {
+            None
+        } else {
+            RevocationOptionsBuilder::new(&crl_refs)
+                .ok()
+                .map(|builder| {
+                    builder
+                        .with_depth(RevocationCheckDepth::Chain)
+                        .with_status_policy(UnknownStatusPolicy::Allow)
+                        .build()
+                })
+        };
+
+        let anchor_name = match cert.verify_for_usage(
+            self.supported_algs.all,
+            &self.trust_anchors,
+            intermediates,
+            now,
+            KeyUsage::server_auth(),
+            revocation,
+            None,
+        ) {
+            Ok(path) => self
+                .trust_anchors
+                .iter()
+                .position(|anchor| {
+                    anchor.subject_public_key_info == path.anchor().subject_public_key_info
+                })
+                .map(|i| self.ca_names[i].as_str())
+                .unwrap_or("unknown CA"),
+            Err(e) => {
+                error!("Validation failed for server certificate chain : {}", e);
+                return Err(pki_error(e));
+            }
+        };
+        info!(
+            "Server certificate chain was validated by CA '{}'",
+            anchor_name
+        );
+
+        let parsed = parse_x509(end_entity)
+            .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+
+        if let Err(e) = check_certificate_key_usage(&parsed) {
+            error!("Server certificate can't be used by a server : {}", e);
+            return Err(rustls::Error::InvalidCertificate(
+                CertificateError::InvalidPurpose,
+            ));
+        }
+
+        if let Err(e) = check_certificate_machine_id(&parsed, &self.machine_id) {
+            error!(
+                "Server certificate doesn't belong to the expected machine : {}",
+                e
+            );
+            return Err(rustls::Error::InvalidCertificate(
+                CertificateError::NotValidForName,
+            ));
+        }
+
+        info!(
+            "Server certificate is valid for machine id {}",
+            self.machine_id
+        );
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.supported_algs)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.supported_algs)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.supported_algs.supported_schemes()
+    }
+}
+
+/// Verifies a paired server agent by the public key of its certificate, whose SHA-256 was
+/// recorded when pairing with it. No CA is involved.
+///
+/// While pairing, no key is pinned yet : any certificate is accepted and its key is recorded,
+/// the pairing handshake being what authenticates the server.
+#[derive(Debug)]
+pub struct PinnedKeyVerifier {
+    pinned_key_sha256: Option<Vec<u8>>,
+    seen_key_sha256: Mutex<Option<Vec<u8>>>,
+    supported_algs: WebPkiSupportedAlgorithms,
+}
+
+impl PinnedKeyVerifier {
+    pub fn pinned(key_sha256: Vec<u8>) -> Self {
+        Self::new(Some(key_sha256))
+    }
+
+    pub fn recording() -> Self {
+        Self::new(None)
+    }
+
+    fn new(pinned_key_sha256: Option<Vec<u8>>) -> Self {
+        let provider = CryptoProvider::get_default()
+            .cloned()
+            .unwrap_or_else(|| Arc::new(rustls::crypto::aws_lc_rs::default_provider()));
+        Self {
+            pinned_key_sha256,
+            seen_key_sha256: Mutex::new(None),
+            supported_algs: provider.signature_verification_algorithms,
+        }
+    }
+
+    /// SHA-256 of the public key of the last certificate presented by the server.
+    pub fn seen_key_sha256(&self) -> Option<Vec<u8>> {
+        self.seen_key_sha256
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+}
+
+impl ServerCertVerifier for PinnedKeyVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let parsed = parse_x509(end_entity)
+            .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+        let key_sha256 = Sha256::digest(parsed.public_key().raw).to_vec();
+        *self
+            .seen_key_sha256
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = Some(key_sha256.clone());
+
+        match &self.pinned_key_sha256 {
+            None => Ok(ServerCertVerified::assertion()),
+            Some(pinned) if *pinned == key_sha256 => {
+                info!("Server presented the key recorded when pairing with it");
+                Ok(ServerCertVerified::assertion())
+            }
+            Some(_) => {
+                error!(
+                    "Server certificate key doesn't match the key recorded when pairing with it"
+                );
+                Err(rustls::Error::InvalidCertificate(
+                    CertificateError::ApplicationVerificationFailure,
+                ))
+            }
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.supported_algs)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.supported_algs)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.supported_algs.supported_schemes()
+    }
+}
+
+/// Loads a revocation list file, either PEM (one or more `X509 CRL` blocks) or a single DER CRL.
+pub fn load_crls(filename: &str) -> anyhow::Result<Vec<CertRevocationList<'static>>> {
+    let mut f = match File::open(filename) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Could not open revocation list file {} : {}", filename, e);
+            return Err(anyhow!("Could not open revocation list file {}", filename));
+        }
+    };
+
+    let mut file_contents = vec![];
+    f.read_to_end(&mut file_contents)?;
+
+    let ders = if file_contents.starts_with(b"-----") {
+        match rustls_pemfile::crls(&mut file_contents.as_slice())
+            .collect::<Result<Vec<CertificateRevocationListDer>, _>>()
+        {
+            Ok(v) => v,
+            Err(e) => {
+                error!(
+                    "Could not deserialize revocation list pemfile at {} : {}",
+                    filename, e
+                );
+                return Err(anyhow!("Could not read revocation list file {}", filename));
+            }
+        }
+    } else {
+        vec![CertificateRevocationListDer::from(file_contents)]
+    };
+
+    let mut crls = vec![];
+    for der in ders {
+        match OwnedCertRevocationList::from_der(&der) {
+            Ok(crl) => crls.push(CertRevocationList::from(crl)),
+            Err(e) => {
+                error!("Could not parse revocation list in {} : {}", filename, e);
+                return Err(anyhow!("Could not parse revocation list file {}", filename));
+            }
+        }
+    }
+
+    debug!(
+        "Loaded {} revocation list(s) from {} successfully",
+        crls.len(),
+        filename
+    );
+    Ok(crls)
+}
+
+fn pki_error(error: webpki::Error) -> rustls::Error {
+    use webpki::Error::*;
+    let cert_error = match error {
+        BadDer | BadDerTime | TrailingData(_) => CertificateError::BadEncoding,
+        CertExpired => CertificateError::Expired,
+        CertNotValidYet => CertificateError::NotValidYet,
+        CertRevoked => CertificateError::Revoked,
+        UnknownIssuer => CertificateError::UnknownIssuer,
+        InvalidSignatureForPublicKey
+        | UnsupportedSignatureAlgorithm
+        | UnsupportedSignatureAlgorithmForPublicKey => CertificateError::BadSignature,
+        RequiredEkuNotFound | CaUsedAsEndEntity | EndEntityUsedAsCa => {
+            CertificateError::InvalidPurpose
+        }
+        e => CertificateError::Other(OtherError(Arc::new(e))),
+    };
+    rustls::Error::InvalidCertificate(cert_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use rcgen::{
+        date_time_ymd, BasicConstraints, Certificate, CertificateParams,
+        CertificateRevocationListParams, DistinguishedName, DnType, ExtendedKeyUsagePurpose, IsCa,
+        KeyIdMethod, KeyPair, KeyUsagePurpose, RevokedCertParams, SanType, SerialNumber,
+    };
+
+    use super::*;
+    use crate::auth::x509::MACHINE_ID_URI_PREFIX;
+
+    const MACHINE_ID: &str = "1234";
+
+    fn distinguished_name(cn: &str) -> DistinguishedName {
+        let mut name = DistinguishedName::new();
+        name.push(DnType::CommonName, cn);
+        name
+    }
+
+    fn ca(cn: &str) -> (Certificate, KeyPair) {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::default();
+        params.distinguished_name = distinguished_name(cn);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+        (params.self_signed(&key).unwrap(), key)
+    }
+
+    fn server_params(machine_id: &str) -> CertificateParams {
+        let mut params = CertificateParams::default();
+        params.distinguished_name = distinguished_name("server");
+        params.subject_alt_names = vec![SanType::URI(
+            format!("{}{}", MACHINE_ID_URI_PREFIX, machine_id)
+                .try_into()
+                .unwrap(),
+        )];
+        params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        params.serial_number = Some(SerialNumber::from(2));
+        params
+    }
+
+    fn issue(params: CertificateParams, (ca, ca_key): &(Certificate, KeyPair)) -> Certificate {
+        let key = KeyPair::generate().unwrap();
+        params.signed_by(&key, ca, ca_key).unwrap()
+    }
+
+    fn verify(
+        verifier: &impl ServerCertVerifier,
+        cert: &Certificate,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        verifier.verify_server_cert(
+            cert.der(),
+            &[],
+            &ServerName::try_from("127.0.0.1").unwrap(),
+            &[],
+            UnixTime::now(),
+        )
+    }
+
+    fn machine_verifier(ca: &(Certificate, KeyPair)) -> MachineCertVerifier {
+        MachineCertVerifier::new(&[ca.0.der().clone()], MACHINE_ID, None).unwrap()
+    }
+
+    fn is_refused(
+        result: Result<ServerCertVerified, rustls::Error>,
+        expected: CertificateError,
+    ) -> bool {
+        matches!(result, Err(rustls::Error::InvalidCertificate(e)) if e == expected)
+    }
+
+    #[test]
+    fn accepts_machine_id_in_san_uri() {
+        let ca = ca("CA");
+        let cert = issue(server_params(MACHINE_ID), &ca);
+        assert!(verify(&machine_verifier(&ca), &cert).is_ok());
+    }
+
+    #[test]
+    fn accepts_machine_id_in_cn() {
+        let ca = ca("CA");
+        let mut params = server_params(MACHINE_ID);
+        params.subject_alt_names = vec![];
+        params.distinguished_name = distinguished_name(MACHINE_ID);
+        let cert = issue(params, &ca);
+        assert!(verify(&machine_verifier(&ca), &cert).is_ok());
+    }
+
+    #[test]
+    fn san_uri_takes_precedence_over_cn() {
+        let ca = ca("CA");
+        let mut params = server_params("5678");
+        params.distinguished_name = distinguished_name(MACHINE_ID);
+        let cert = issue(params, &ca);
+        assert!(is_refused(
+            verify(&machine_verifier(&ca), &cert),
+            CertificateError::NotValidForName
+        ));
+    }
+
+    #[test]
+    fn refuses_other_machine() {
+        let ca = ca("CA");
+        let cert = issue(server_params("5678"), &ca);
+        assert!(is_refused(
+            verify(&machine_verifier(&ca), &cert),
+            CertificateError::NotValidForName
+        ));
+    }
+
+    #[test]
+    fn refuses_unknown_ca() {
+        let cert = issue(server_params(MACHINE_ID), &ca("Other CA"));
+        assert!(is_refused(
+            verify(&machine_verifier(&ca("CA")), &cert),
+            CertificateError::UnknownIssuer
+        ));
+    }
+
+    #[test]
+    fn refuses_expired_certificate() {
+        let ca = ca("CA");
+        let mut params = server_params(MACHINE_ID);
+        params.not_before = date_time_ymd(2000, 1, 1);
+        params.not_after = date_time_ymd(2001, 1, 1);
+        let cert = issue(params, &ca);
+        assert!(is_refused(
+            verify(&machine_verifier(&ca), &cert),
+            CertificateError::Expired
+        ));
+    }
+
+    #[test]
+    fn refuses_client_only_certificate() {
+        let ca = ca("CA");
+        let mut params = server_params(MACHINE_ID);
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        let cert = issue(params, &ca);
+        assert!(is_refused(
+            verify(&machine_verifier(&ca), &cert),
+            CertificateError::InvalidPurpose
+        ));
+    }
+
+    #[test]
+    fn refuses_key_usage_without_signature() {
+        let ca = ca("CA");
+        let mut params = server_params(MACHINE_ID);
+        params.key_usages = vec![KeyUsagePurpose::DataEncipherment];
+        let cert = issue(params, &ca);
+        assert!(is_refused(
+            verify(&machine_verifier(&ca), &cert),
+            CertificateError::InvalidPurpose
+        ));
+    }
+
+    #[test]
+    fn refuses_revoked_certificate() {
+        let ca = ca("CA");
+        let cert = issue(server_params(MACHINE_ID), &ca);
+        let crl_verifier = |revoked_serial: u64| {
+            let crl = CertificateRevocationListParams {
+                this_update: date_time_ymd(2020, 1, 1),
+                next_update: date_time_ymd(4000, 1, 1),
+                crl_number: SerialNumber::from(1),
+                issuing_distribution_point: None,
+                revoked_certs: vec![RevokedCertParams {
+                    serial_number: SerialNumber::from(revoked_serial),
+                    revocation_time: date_time_ymd(2020, 1, 1),
+                    reason_code: None,
+                    invalidity_date: None,
+                }],
+                key_identifier_method: KeyIdMethod::Sha256,
+            }
+            .signed_by(&ca.0, &ca.1)
+            .unwrap();
+            let file = tempfile::NamedTempFile::new().unwrap();
+            std::fs::write(file.path(), crl.pem().unwrap()).unwrap();
+            MachineCertVerifier::new(&[ca.0.der().clone()], MACHINE_ID, file.path().to_str())
+                .unwrap()
+        };
+
+        assert!(verify(&crl_verifier(3), &cert).is_ok());
+        assert!(is_refused(
+            verify(&crl_verifier(2), &cert),
+            CertificateError::Revoked
+        ));
+    }
+
+    #[test]
+    fn accepts_any_trusted_ca() {
+        let (first, second) = (ca("First CA"), ca("Second CA"));
+        let verifier = MachineCertVerifier::new(
+            &[first.0.der().clone(), second.0.der().clone()],
+            MACHINE_ID,
+            None,
+        )
+        .unwrap();
+        for ca in [&first, &second] {
+            assert!(verify(&verifier, &issue(server_params(MACHINE_ID), ca)).is_ok());
+        }
+        let cert = issue(server_params(MACHINE_ID), &ca("Third CA"));
+        assert!(is_refused(
+            verify(&verifier, &cert),
+            CertificateError::UnknownIssuer
+        ));
+    }
+
+    #[test]
+    fn needs_a_ca() {
+        assert!(MachineCertVerifier::new(&[], MACHINE_ID, None).is_err());
+    }
+
+    #[test]
+    fn pinned_key() {
+        let ca = ca("CA");
+        let cert = issue(server_params(MACHINE_ID), &ca);
+        let recording = PinnedKeyVerifier::recording();
+        assert!(recording.seen_key_sha256().is_none());
+        assert!(verify(&recording, &cert).is_ok());
+        let key_sha256 = recording.seen_key_sha256().unwrap();
+
+        let pinned = PinnedKeyVerifier::pinned(key_sha256);
+        assert!(verify(&pinned, &cert).is_ok());
+        // same subject and issuer, another key
+        let other = issue(server_params(MACHINE_ID), &ca);
+        assert!(is_refused(
+            verify(&pinned, &other),
+            CertificateError::ApplicationVerificationFailure
+        ));
+    }
+}
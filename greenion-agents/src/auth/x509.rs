@@ -1,22 +1,69 @@
 use anyhow::{anyhow, bail, Result};
-use log::error;
-use x509_parser::{certificate::X509Certificate, prelude::FromDer};
-
-pub fn validate_x509_machine_id(
-    server_cert: &X509Certificate,
-    ca_cert: &X509Certificate,
-    machine_id: &str,
-) -> Result<()> {
-    let ca_pubkey = ca_cert.public_key();
-    match server_cert.verify_signature(Some(ca_pubkey)) {
-        Ok(()) => {}
+use log::{debug, error};
+use x509_parser::{certificate::X509Certificate, extensions::GeneralName, prelude::FromDer};
+
+/// Prefix of the SAN URI carrying the machine id of a server agent, e.g. `greenion://machine/1234`
+pub const MACHINE_ID_URI_PREFIX: &str = "greenion://machine/";
+
+pub fn parse_x509(bytes: &[u8]) -> anyhow::Result<X509Certificate<'_>> {
+    match X509Certificate::from_der(bytes) {
+        Ok(v) => Ok(v.1),
+        Err(e) => {
+            error!("Could not parse provided X509 : {}", e);
+            bail!("Could not parse x509 from provided bytes");
+        }
+    }
+}
+
+pub fn extract_id_from_certificate(certificate: &X509Certificate) -> Result<String> {
+    let Some(cn) = certificate.subject().iter_common_name().next() else {
+        error!("No CN in provided certificate");
+        bail!("No CN in certificate");
+    };
+
+    match cn.as_str() {
+        Ok(v) => Ok(v.to_owned()),
+        Err(e) => {
+            error!("Could not convert CN to string : {}", e);
+            bail!("CN could not be converted to string");
+        }
+    }
+}
+
+pub fn extract_id_from_san_uri(certificate: &X509Certificate) -> Option<String> {
+    let san = match certificate.subject_alternative_name() {
+        Ok(Some(v)) => v,
+        Ok(None) => return None,
         Err(e) => {
-            error!("Validation failed for server certificate : {}", e);
-            bail!("Server certificate signature is invalid");
+            error!(
+                "Could not parse certificate subject alternative names : {}",
+                e
+            );
+            return None;
         }
     };
 
-    let id = match extract_id_from_certificate(server_cert) {
+    san.value.general_names.iter().find_map(|name| match name {
+        GeneralName::URI(uri) => uri
+            .strip_prefix(MACHINE_ID_URI_PREFIX)
+            .map(|id| id.trim_end_matches('/').to_owned()),
+        _ => None,
+    })
+}
+
+/// Extracts the machine id of a server agent certificate. The SAN URI takes precedence over the CN.
+pub fn extract_machine_id(certificate: &X509Certificate) -> Result<String> {
+    match extract_id_from_san_uri(certificate) {
+        Some(v) => {
+            debug!("Found machine id {} in certificate SAN URI", v);
+            Ok(v)
+        }
+        None => extract_id_from_certificate(certificate),
+    }
+}
+
+pub fn check_certificate_machine_id(certificate: &X509Certificate, machine_id: &str) -> Result<()> {
+    let id = match extract_machine_id(certificate) {
         Ok(s) => s,
         Err(e) => {
             error!("Could not extract id from '{}'", e);
@@ -35,27 +82,29 @@ pub fn validate_x509_machine_id(
     }
 }
 
-pub fn parse_x509(bytes: &[u8]) -> anyhow::Result<X509Certificate> {
-    match X509Certificate::from_der(bytes) {
-        Ok(v) => Ok(v.1),
+/// Checks that the certificate key usage (if any) allows it to authenticate a TLS server.
+pub fn check_certificate_key_usage(certificate: &X509Certificate) -> Result<()> {
+    match certificate.basic_constraints() {
+        Ok(Some(bc)) if bc.value.ca => bail!("Certificate is a CA certificate"),
+        Ok(_) => {}
         Err(e) => {
-            error!("Could not parse provided X509 : {}", e);
-            bail!("Could not parse x509 from provided bytes");
+            error!("Could not parse certificate basic constraints : {}", e);
+            bail!("Invalid basic constraints extension");
         }
     }
-}
-
-pub fn extract_id_from_certificate(certificate: &X509Certificate) -> Result<String> {
-    let Some(cn) = certificate.subject().iter_common_name().next() else {
-        error!("No CN in provided certificate");
-        bail!("No CN in certificate");
-    };
 
-    match cn.as_str() {
-        Ok(v) => Ok(v.to_owned()),
+    match certificate.key_usage() {
+        Ok(Some(ku)) => {
+            if ku.value.digital_signature() || ku.value.key_encipherment() {
+                Ok(())
+            } else {
+                bail!("Key usage allows neither digitalSignature nor keyEncipherment")
+            }
+        }
+        Ok(None) => Ok(()),
         Err(e) => {
-            error!("Could not convert CN to string : {}", e);
-            bail!("CN could not be converted to string");
+            error!("Could not parse certificate key usage : {}", e);
+            bail!("Invalid key usage extension");
         }
     }
 }
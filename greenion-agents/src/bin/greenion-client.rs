@@ -1,27 +1,38 @@
+use clap::Parser;
 use greenion_agents::{
-    auth::{
-        jwt::{get_jwks, parse_and_validate_jwt},
-        load_certs,
-    },
+    auth::{jwt::parse_and_validate_jwt, verifier::MachineCertVerifier},
     client::{
         errors::{exit_with_greenion_client_final_error_popup, GreenionClientFinalError},
         main_connect::main_connect,
+        pairing::{find_paired_server, pair},
         utils::{
-            check_is_certificate_cacert, get_client_config_file_path, setup_client_agent_log_file,
+            device_name, get_client_config_file_path, load_trusted_ca_certs,
+            setup_client_agent_log_file,
         },
+        ClientCredential, ConnectionTarget,
     },
     close_session,
-    conf::{client_args::get_jwt, client_config::build_client_config},
+    conf::{
+        client_args::{get_jwt, ClientArgs, ClientCommand},
+        client_config::{build_client_config, ClientAuthConfig},
+    },
+    metrics::serve_metrics,
     setup_fern, CloseSessionArgs,
 };
 use log::{debug, error, info, warn};
-use std::{env, time::Duration};
+use std::{
+    io::{self, Write},
+    sync::Arc,
+    time::Duration,
+};
 use tokio::time::sleep;
 
 const PAUSE_BETWEEN_RETRIES: Duration = Duration::from_millis(2000);
+const DEFAULT_SERVER_PORT: u16 = 9447;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let args = ClientArgs::parse();
     let log_file = setup_client_agent_log_file().unwrap_or_default();
 
     let _ = setup_fern(log_file.as_path());
@@ -35,11 +46,91 @@ async fn main() -> anyhow::Result<()> {
     let agent_config = build_client_config(config_file_path.as_path()).unwrap_or_default();
     info!("Read config at {}", config_file_path.display());
 
+    if agent_config.metrics_config.enabled {
+        let listen_address = agent_config.metrics_config.listen_address;
+        tokio::spawn(async move {
+            if let Err(e) = serve_metrics(listen_address).await {
+                error!("Metrics endpoint is unavailable : {}", e);
+            }
+        });
+    }
+
     let agent_auth_config = &agent_config.client_auth_config;
     let agent_network_config = &agent_config.client_network_config;
 
-    let args: Vec<String> = env::args().collect();
-    let jwt_string = match get_jwt(&args) {
+    let timeout = Duration::from_secs(agent_network_config.timeout_secs as u64);
+
+    let (target, csa) = match args.command {
+        Some(ClientCommand::Pair {
+            address,
+            code,
+            name,
+        }) => {
+            pair_command(&address, code, name, timeout, agent_auth_config).await;
+            return Ok(());
+        }
+        Some(ClientCommand::Connect { machine_id }) => {
+            match find_paired_server(&agent_auth_config.paired_servers_file, &machine_id)
+                .and_then(|server| server.connection_target())
+            {
+                Ok(target) => (target, None),
+                Err(e) => GreenionClientFinalError::exit_complete(
+                    "Failed to initialize greenion client agent",
+                    &e.to_string(),
+                ),
+            }
+        }
+        None => {
+            let (target, csa) = open_uri(args.uri.as_deref(), timeout, agent_auth_config).await;
+            (target, Some(csa))
+        }
+    };
+
+    let mut last_result = Ok(());
+    for i in 0..=agent_network_config.max_retries {
+        if i > 0 {
+            let trials_left = agent_network_config.max_retries - i + 1;
+            let msg = format!(
+                "An error occured when connecting to server !\nRetrying {} more time{}...",
+                trials_left,
+                if trials_left > 1 { "s" } else { "" }
+            );
+            let _ = notifica::notify("Greenion Agent Client", msg.as_str());
+            info!("Retry #{i} starting...");
+        }
+        let connect_res = main_connect(&target, &timeout, &agent_config).await;
+        let error = match connect_res {
+            Ok(()) => break,
+            Err(e) => e,
+        };
+        warn!("Failed to connect to the server: {error}.");
+        if i == agent_network_config.max_retries {
+            last_result = Err(error);
+        } else {
+            sleep(PAUSE_BETWEEN_RETRIES).await;
+        }
+    }
+
+    if let Some(csa) = csa {
+        let _ = close_session(csa).await;
+    }
+    match last_result {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            error!("Ran out of retries, giving up now.");
+            exit_with_greenion_client_final_error_popup(e);
+        }
+    }
+}
+
+/// Validates the connection token of a greenion-open:// link and returns the server it
+/// points to, with what's needed to close its session in the web application.
+async fn open_uri(
+    uri: Option<&str>,
+    timeout: Duration,
+    agent_auth_config: &ClientAuthConfig,
+) -> (ConnectionTarget, CloseSessionArgs) {
+    let jwt_string = match get_jwt(uri) {
         Ok(v) => v,
         Err(e) => GreenionClientFinalError::exit_complete(
             "Failed to initialize greenion client agent",
@@ -47,16 +138,15 @@ async fn main() -> anyhow::Result<()> {
         ),
     };
 
-    let timeout = Duration::from_secs(agent_network_config.timeout_secs as u64);
-
-    let jwks = match get_jwks(agent_auth_config.jwks_url.as_str(), timeout).await {
+    let jwks_provider = agent_auth_config.jwks_provider(timeout);
+    let jwks = match jwks_provider.get().await {
         Ok(v) => v,
         Err(e) => GreenionClientFinalError::exit_complete(
             "Failed to initialize greenion client agent",
             &e.to_string(),
         ),
     };
-    let jwt = match parse_and_validate_jwt(&jwt_string, &jwks) {
+    let jwt = match parse_and_validate_jwt(&jwt_string, &jwks, jwks_provider.issuer()) {
         Ok(v) => v,
         Err(e) => {
             let inner = format!("{}. Please refresh the web application to get a new one", e);
@@ -73,7 +163,7 @@ async fn main() -> anyhow::Result<()> {
         session_id: jwt.session_id,
     };
 
-    let ca_certs = match load_certs(&agent_auth_config.ca_cert_file) {
+    let ca_certs = match load_trusted_ca_certs(agent_auth_config) {
         Ok(v) => v,
         Err(e) => {
             let _ = close_session(csa).await;
@@ -84,54 +174,87 @@ async fn main() -> anyhow::Result<()> {
         }
     };
 
-    let ca_cert = match ca_certs.first() {
-        Some(v) => v,
-        None => {
+    let verifier = match MachineCertVerifier::new(
+        &ca_certs,
+        &jwt.machine_id,
+        agent_auth_config.revocation_list_file.as_deref(),
+    ) {
+        Ok(v) => v,
+        Err(e) => {
             let _ = close_session(csa).await;
             GreenionClientFinalError::exit_complete(
                 "Failed to initialize greenion client agent",
-                "Could not get first CA certificate",
+                &e.to_string(),
             );
         }
     };
 
-    if !check_is_certificate_cacert(ca_cert) {
-        let _ = close_session(csa).await;
-        GreenionClientFinalError::exit_complete("Failed to initialize greenion client agent","Certificate common name is incorrect, you probably have the wrong file for the ca_cert_file config field" )
-    }
+    let target = ConnectionTarget {
+        server_ip: jwt.machine_ip.to_owned(),
+        server_port: jwt.machine_port,
+        verifier: Arc::new(verifier),
+        credential: ClientCredential::Jwt {
+            token: jwt_string,
+            jwks,
+        },
+    };
+    (target, csa)
+}
 
-    let mut last_result = Ok(());
-    for i in 0..=agent_network_config.max_retries {
-        if i > 0 {
-            let trials_left = agent_network_config.max_retries - i + 1;
-            let msg = format!(
-                "An error occured when connecting to server !\nRetrying {} more time{}...",
-                trials_left,
-                if trials_left > 1 { "s" } else { "" }
-            );
-            let _ = notifica::notify("Greenion Agent Client", msg.as_str());
-            info!("Retry #{i} starting...");
-        }
-        let connect_res =
-            main_connect(&jwt, &timeout, &jwt_string, &jwks, ca_cert, &agent_config).await;
-        let error = match connect_res {
-            Ok(()) => break,
-            Err(e) => e,
-        };
-        warn!("Failed to connect to the server: {error}.");
-        if i == agent_network_config.max_retries {
-            last_result = Err(error);
-        } else {
-            sleep(PAUSE_BETWEEN_RETRIES).await;
+async fn pair_command(
+    address: &str,
+    code: Option<String>,
+    name: Option<String>,
+    timeout: Duration,
+    agent_auth_config: &ClientAuthConfig,
+) {
+    let (server_ip, server_port) = match address.rsplit_once(':') {
+        Some((ip, port)) => match port.parse::<u16>() {
+            Ok(port) => (ip, port),
+            Err(_) => GreenionClientFinalError::exit_complete(
+                "Failed to pair with the server",
+                &format!("Invalid port in address {}", address),
+            ),
+        },
+        None => (address, DEFAULT_SERVER_PORT),
+    };
+
+    let code = match code {
+        Some(v) => v,
+        None => {
+            print!("Pairing code shown by the server : ");
+            let _ = io::stdout().flush();
+            let mut code = String::new();
+            if let Err(e) = io::stdin().read_line(&mut code) {
+                GreenionClientFinalError::exit_complete(
+                    "Failed to pair with the server",
+                    &format!("Could not read the pairing code : {}", e),
+                );
+            }
+            code
         }
-    }
+    };
+    let name = name.unwrap_or_else(device_name);
 
-    let _ = close_session(csa).await;
-    match last_result {
-        Ok(()) => Ok(()),
-        Err(e) => {
-            error!("Ran out of retries, giving up now.");
-            exit_with_greenion_client_final_error_popup(e);
+    match pair(
+        server_ip,
+        server_port,
+        code.trim(),
+        &name,
+        timeout,
+        &agent_auth_config.paired_servers_file,
+    )
+    .await
+    {
+        Ok(server) => {
+            println!(
+                "Paired with machine {} as '{}'. Connect to it with : greenion-client connect {}",
+                server.machine_id, name, server.machine_id
+            );
         }
+        Err(e) => exit_with_greenion_client_final_error_popup(GreenionClientFinalError::new(
+            "Failed to pair with the server",
+            e,
+        )),
     }
 }
@@ -1,32 +1,242 @@
-use greenion_agents::auth::x509::{extract_id_from_certificate, parse_x509};
-use greenion_agents::auth::{load_certs, load_private_key};
-use greenion_agents::setup_fern;
+use anyhow::{bail, Context};
+use clap::Parser;
+use greenion_agents::conf::server_args::{CtlCommand, ServerArgs, ServerCommand};
+use greenion_agents::metrics::{metrics, serve_metrics};
+use greenion_agents::standalone_server::audit::{verify_audit_log, AuditLog};
+use greenion_agents::standalone_server::cert_resolver::ReloadableCertResolver;
+use greenion_agents::standalone_server::control::{
+    control_request, ControlRequest, ControlResponse, ControlServer, HealthStatus,
+};
+#[cfg(feature = "enrollment")]
+use greenion_agents::standalone_server::enrollment::{read_enrollment_token, Enroller};
+use greenion_agents::standalone_server::pairing::PairingStore;
+use greenion_agents::standalone_server::policy::PolicyProvider;
 use greenion_agents::standalone_server::process_client_connection::process_client_connection;
+use greenion_agents::standalone_server::rate_limit::RateLimiter;
+use greenion_agents::standalone_server::sessions::SessionManager;
+use greenion_agents::standalone_server::shutdown::{shut_down, ShutdownSignals};
+use greenion_agents::standalone_server::source_acl::SourceAcl;
+use greenion_agents::standalone_server::systemd::{
+    activated_listener, sessions_status, SystemdNotifier,
+};
 use greenion_agents::standalone_server::utils::{
     get_server_config_file_path, setup_server_agent_log_folder, setup_server_panic_hook,
 };
+use greenion_agents::standalone_server::warm_pool::WarmPool;
+use greenion_agents::standalone_server::{Authenticator, SanzuLauncher, SanzuServers};
+use greenion_agents::{setup_fern, setup_fern_with};
 use log::{error, info, warn};
-use std::io::{self};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::sync::Semaphore;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
-use greenion_agents::conf::server_config::{build_server_config, ServerConfig};
+use greenion_agents::conf::server_config::{
+    build_server_config, ServerAuthConfig, ServerConfig, ServerNetworkConfig,
+};
 use tokio::net::TcpListener;
+use tokio::task::JoinSet;
 use tokio_rustls::{rustls, TlsAcceptor};
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let _ = match setup_server_agent_log_folder() {
-        Ok(log_folder) => setup_fern(log_folder.join("greenion-agent-server.log").as_path()),
+/// Control socket bound by the supervisor, see `privsep`
+#[cfg(unix)]
+type InheritedControl = tokio::net::UnixListener;
+#[cfg(not(unix))]
+type InheritedControl = std::convert::Infallible;
+
+/// Prints what the running agent answered to `greenion-server ctl`.
+fn print_control_response(response: ControlResponse) -> anyhow::Result<()> {
+    match response {
+        ControlResponse::Sessions { active, queued } => {
+            println!("{} running session(s)", active.len());
+            for s in active {
+                println!(
+                    "{}\t{}\t{}\tport {}\tfor {}\t{} bytes from client\t{} bytes to client",
+                    s.id,
+                    s.user_id,
+                    s.client_addr,
+                    s.sanzu_port,
+                    humantime::format_duration(Duration::from_secs(s.duration_secs)),
+                    s.bytes_from_client,
+                    s.bytes_to_client
+                );
+            }
+            println!("{} queued connection(s)", queued.len());
+            for q in queued {
+                println!(
+                    "#{}\t{}\t{}\twaiting for {}",
+                    q.position,
+                    q.user_id,
+                    q.client_addr,
+                    humantime::format_duration(Duration::from_secs(q.waiting_secs))
+                );
+            }
+        }
+        ControlResponse::Kicked { session_id } => println!("Session {} is ending", session_id),
+        ControlResponse::Draining { active_sessions } => println!(
+            "Draining : no new session starts, {} running session(s) go on",
+            active_sessions
+        ),
+        ControlResponse::Resumed => println!("New sessions start again"),
+        ControlResponse::Certificate(c) => {
+            println!("Machine id  : {}", c.machine_id);
+            println!("Subject     : {}", c.subject);
+            println!("Issuer      : {}", c.issuer);
+            println!("Serial      : {}", c.serial);
+            println!("Valid from  : {}", c.not_before);
+            println!("Valid until : {}", c.not_after);
+            println!("SHA-256     : {}", c.sha256_fingerprint);
+        }
+        ControlResponse::Health(h) => {
+            println!("Status      : {:?}", h.status);
+            println!("Version     : {}", h.version);
+            println!(
+                "Uptime      : {}",
+                humantime::format_duration(Duration::from_secs(h.uptime_secs))
+            );
+            println!("Sessions    : {} / {}", h.active_sessions, h.max_sessions);
+            println!("Queued      : {}", h.queued_connections);
+            println!(
+                "Certificate : expires in {}",
+                humantime::format_duration(Duration::from_secs(
+                    h.certificate_expires_in_secs.max(0) as u64
+                ))
+            );
+            println!(
+                "Refused     : {} banned source(s), {} rate limited and {} refused connection(s)",
+                h.banned_sources, h.rate_limited_connections, h.refused_connections
+            );
+            if h.status != HealthStatus::Ok {
+                bail!("The agent is not healthy : {:?}", h.status);
+            }
+        }
+        ControlResponse::Error { message } => bail!("{}", message),
+    }
+    Ok(())
+}
+
+/// Listening socket passed by systemd, or bound from the network config.
+fn bind_listener(network_config: &ServerNetworkConfig) -> anyhow::Result<std::net::TcpListener> {
+    let listener = match activated_listener()? {
+        Some(listener) => listener,
+        None => {
+            let listening_on = format!(
+                "{}:{}",
+                &network_config.server_listening_ip, &network_config.server_port
+            );
+            std::net::TcpListener::bind(listening_on)?
+        }
+    };
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+fn reload_interval(auth_config: &ServerAuthConfig) -> Option<Duration> {
+    match auth_config.cert_reload_interval_secs {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = ServerArgs::parse();
+    #[cfg(target_os = "linux")]
+    if let Some(ServerCommand::PrivsepNetwork {
+        listener_fd,
+        supervisor_fd,
+        config_fd,
+        log_fd,
+        control_fd,
+    }) = args.command
+    {
+        return run_network_process(listener_fd, supervisor_fd, config_fd, log_fd, control_fd);
+    }
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run(args))
+}
+
+/// Network process of the agent when privilege separation is enabled, see `privsep`. It is
+/// confined before the runtime starts any thread.
+#[cfg(target_os = "linux")]
+fn run_network_process(
+    listener_fd: i32,
+    supervisor_fd: i32,
+    config_fd: Option<i32>,
+    log_fd: Option<i32>,
+    control_fd: Option<i32>,
+) -> anyhow::Result<()> {
+    use greenion_agents::standalone_server::{privsep, sandbox};
+
+    let started_at = Instant::now();
+    let log_file = log_fd.map(privsep::inherited_fd).transpose()?;
+    let _ = setup_fern_with(log_file.map(std::fs::File::from));
+    setup_server_panic_hook();
+    privsep::check_unprivileged()?;
+
+    let listener = std::net::TcpListener::from(privsep::inherited_fd(listener_fd)?);
+    let supervisor = std::os::unix::net::UnixStream::from(privsep::inherited_fd(supervisor_fd)?);
+    let control = control_fd
+        .map(privsep::inherited_fd)
+        .transpose()?
+        .map(std::os::unix::net::UnixListener::from);
+    let agent_config = privsep::read_config(config_fd.map(privsep::inherited_fd).transpose()?)?;
+    if agent_config.privsep_config.sandbox {
+        sandbox::confine(&agent_config)?;
+    } else {
+        warn!("The network process is not sandboxed");
+    }
+    info!("Network process started");
+
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(async {
+            let client = Arc::new(privsep::SupervisorClient::new(supervisor)?);
+            let cert_resolver = Arc::new(ReloadableCertResolver::from_supervisor(Arc::clone(
+                &client,
+            ))?);
+            info!(
+                "Serving the certificate of the supervisor for machine '{}'",
+                cert_resolver.machine_id()
+            );
+            tokio::spawn(
+                Arc::clone(&cert_resolver).watch(reload_interval(&agent_config.server_auth_config)),
+            );
+            let control = match control {
+                Some(listener) => {
+                    listener.set_nonblocking(true)?;
+                    Some(tokio::net::UnixListener::from_std(listener)?)
+                }
+                None => None,
+            };
+            serve(
+                agent_config,
+                started_at,
+                cert_resolver,
+                listener,
+                SanzuLauncher::Supervisor(client),
+                control,
+                // the supervisor only signs TLS 1.3 handshakes
+                &[&rustls::version::TLS13],
+            )
+            .await
+        })
+}
+
+async fn run(args: ServerArgs) -> anyhow::Result<()> {
+    let log_file = match setup_server_agent_log_folder() {
+        Ok(log_folder) => log_folder.join("greenion-agent-server.log"),
         Err(e) => {
             println!("An error occurred while creating the logs folder: {}", e);
-            setup_fern(PathBuf::default().as_path())
+            PathBuf::default()
         }
     };
+    let _ = setup_fern(log_file.as_path());
 
     setup_server_panic_hook();
 
+    let started_at = Instant::now();
     info!("Starting greenion-agent-server");
     let config_file_path = get_server_config_file_path().unwrap_or_default();
     let agent_config = build_server_config(config_file_path.as_path()).unwrap_or_else(|_| {
@@ -36,57 +246,403 @@ async fn main() -> anyhow::Result<()> {
     });
     let agent_auth_config = agent_config.server_auth_config.clone();
     let agent_network_config = agent_config.server_network_config.clone();
+    let enrollment_config = agent_config.enrollment_config.clone();
+    let pairing_config = agent_config.pairing_config.clone();
+    let audit_config = agent_config.audit_config.clone();
+
+    #[cfg(feature = "enrollment")]
+    let enroller = Enroller::new(&enrollment_config, &agent_auth_config);
+
+    match args.command {
+        #[cfg(feature = "enrollment")]
+        Some(ServerCommand::Enroll {
+            machine_id,
+            token,
+            token_file,
+        }) => {
+            let Some(machine_id) = machine_id.or(enrollment_config.machine_id.clone()) else {
+                bail!("No machine id to enroll : use --machine-id or set enrollment_config.machine_id");
+            };
+            let token_file = token_file.unwrap_or(PathBuf::from(&enrollment_config.token_file));
+            let token = read_enrollment_token(token, Some(token_file.as_path()))?;
+            return enroller.enroll(&machine_id, &token).await;
+        }
+        #[cfg(feature = "enrollment")]
+        Some(ServerCommand::Renew) => return enroller.renew().await,
+        #[cfg(not(feature = "enrollment"))]
+        Some(ServerCommand::Enroll { .. } | ServerCommand::Renew) => {
+            bail!("This agent was built without enrollment support");
+        }
+        Some(ServerCommand::Pair) => {
+            if !pairing_config.enabled {
+                bail!("Pairing is disabled : set pairing_config.enabled to true");
+            }
+            #[cfg(target_os = "linux")]
+            greenion_agents::standalone_server::privsep::prepare_state(&agent_config)?;
+            let validity = Duration::from_secs(pairing_config.code_validity_secs);
+            let code = PairingStore::new(&pairing_config).create_code(validity)?;
+            #[cfg(target_os = "linux")]
+            greenion_agents::standalone_server::privsep::hand_over(
+                &agent_config.privsep_config,
+                Path::new(&pairing_config.pairing_code_file),
+            )?;
+            println!(
+                "Pairing code : {} (valid for {})",
+                code,
+                humantime::format_duration(validity)
+            );
+            println!("On the client device, run : greenion-client pair <address of this machine> and enter this code");
+            return Ok(());
+        }
+        Some(ServerCommand::Devices) => {
+            for device in PairingStore::new(&pairing_config).devices()? {
+                println!(
+                    "{}\t{}\tpaired {}",
+                    device.id,
+                    device.name,
+                    humantime::format_rfc3339_seconds(
+                        UNIX_EPOCH + Duration::from_secs(device.paired_at)
+                    )
+                );
+            }
+            return Ok(());
+        }
+        Some(ServerCommand::Unpair { device }) => {
+            #[cfg(target_os = "linux")]
+            greenion_agents::standalone_server::privsep::prepare_state(&agent_config)?;
+            match PairingStore::new(&pairing_config).remove_device(&device)? {
+                0 => bail!("No paired device with id or name {}", device),
+                n => println!("Removed {} paired device(s)", n),
+            }
+            #[cfg(target_os = "linux")]
+            greenion_agents::standalone_server::privsep::hand_over(
+                &agent_config.privsep_config,
+                Path::new(&pairing_config.devices_file),
+            )?;
+            return Ok(());
+        }
+        Some(ServerCommand::Ctl {
+            command,
+            socket,
+            json,
+        }) => {
+            let request = match command {
+                CtlCommand::Sessions => ControlRequest::Sessions,
+                CtlCommand::Kick { session_id, reason } => {
+                    ControlRequest::Kick { session_id, reason }
+                }
+                CtlCommand::Drain => ControlRequest::Drain,
+                CtlCommand::Resume => ControlRequest::Resume,
+                CtlCommand::Cert => ControlRequest::Certificate,
+                CtlCommand::Health => ControlRequest::Health,
+            };
+            let socket = socket.unwrap_or(agent_config.control_config.socket.clone());
+            let response = control_request(&socket, &request).await?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&response)?);
+                match response {
+                    ControlResponse::Error { message } => bail!("{}", message),
+                    ControlResponse::Health(h) if h.status != HealthStatus::Ok => {
+                        bail!("The agent is not healthy : {:?}", h.status)
+                    }
+                    _ => return Ok(()),
+                }
+            }
+            return print_control_response(response);
+        }
+        Some(ServerCommand::VerifyAudit { file }) => {
+            let file = file.unwrap_or(PathBuf::from(&audit_config.audit_file));
+            match verify_audit_log(&file, Path::new(&audit_config.key_file)) {
+                Ok(records) => println!("{} : {} record(s), chain intact", file.display(), records),
+                Err(e) => bail!("{} failed verification : {}", file.display(), e),
+            }
+            return Ok(());
+        }
+        Some(ServerCommand::PrivsepNetwork { .. }) => {
+            bail!("The network process is only started by the agent, on Linux")
+        }
+        None => {}
+    }
 
-    let certs = load_certs(&agent_auth_config.cert_file).unwrap_or_else(|_| {
-        panic!(
-            "Failed to load certificate at {}",
+    #[cfg(not(feature = "enrollment"))]
+    if enrollment_config.enabled {
+        error!("Enrollment is enabled but this agent was built without enrollment support");
+        bail!("This agent was built without enrollment support");
+    }
+    #[cfg(feature = "enrollment")]
+    if enrollment_config.enabled && !Path::new(&agent_auth_config.cert_file).exists() {
+        info!(
+            "No certificate at {}, enrolling this machine",
             &agent_auth_config.cert_file
-        )
-    });
-    let key = load_private_key(&agent_auth_config.private_key_file).unwrap_or_else(|_| {
-        panic!(
-            "Failed to load private key at {} ",
-            &agent_auth_config.private_key_file
-        )
-    });
+        );
+        let Some(machine_id) = &enrollment_config.machine_id else {
+            error!("Enrollment is enabled but enrollment_config.machine_id is not set");
+            bail!("Enrollment is enabled but enrollment_config.machine_id is not set");
+        };
+        let token_file = Path::new(&enrollment_config.token_file);
+        let token = match read_enrollment_token(None, Some(token_file)) {
+            Ok(token) => token,
+            Err(e) => {
+                error!("Failed to read enrollment token : {}", e);
+                return Err(e);
+            }
+        };
+        if let Err(e) = enroller.enroll(machine_id, &token).await {
+            error!("Failed to enroll machine {} : {}", machine_id, e);
+            return Err(e);
+        }
+        // the token is single use
+        if let Err(e) = std::fs::remove_file(token_file) {
+            warn!(
+                "Could not remove used enrollment token file {} : {}",
+                token_file.display(),
+                e
+            );
+        }
+    }
+
+    let cert_resolver = match ReloadableCertResolver::new(
+        &agent_auth_config.cert_file,
+        &agent_auth_config.private_key_file,
+        agent_auth_config.private_key_options(),
+    ) {
+        Ok(v) => Arc::new(v),
+        Err(e) => {
+            error!(
+                "Failed to load certificate at {} and private key at {} : {}",
+                &agent_auth_config.cert_file, &agent_auth_config.private_key_file, e
+            );
+            bail!(
+                "Failed to load certificate at {} and private key at {} : {}",
+                &agent_auth_config.cert_file,
+                &agent_auth_config.private_key_file,
+                e
+            );
+        }
+    };
     info!(
         "Loaded certificate ({}) and private key ({})",
         &agent_auth_config.cert_file, &agent_auth_config.private_key_file
     );
-    let cert = parse_x509(
-        certs
-            .first()
-            .expect("Failed to get first certificate while extracting machine ID"),
+    tokio::spawn(Arc::clone(&cert_resolver).watch(reload_interval(&agent_auth_config)));
+
+    #[cfg(feature = "enrollment")]
+    if enrollment_config.enabled {
+        tokio::spawn(enroller.watch_expiry(
+            Arc::clone(&cert_resolver),
+            Duration::from_secs(enrollment_config.renew_before_days * 24 * 60 * 60),
+            Duration::from_secs(enrollment_config.renewal_check_interval_secs),
+        ));
+    }
+
+    let listener = bind_listener(&agent_network_config)?;
+    if agent_config.privsep_config.enabled {
+        #[cfg(target_os = "linux")]
+        return greenion_agents::standalone_server::privsep::supervise(
+            &agent_config,
+            &config_file_path,
+            &log_file,
+            cert_resolver,
+            listener,
+        )
+        .await;
+        #[cfg(not(target_os = "linux"))]
+        warn!("Privilege separation is only available on Linux, running as a single process");
+    }
+    serve(
+        agent_config,
+        started_at,
+        cert_resolver,
+        listener,
+        SanzuLauncher::Local,
+        None,
+        rustls::DEFAULT_VERSIONS,
     )
-    .expect("Could not parse server x509 certificate");
+    .await
+}
 
-    let machine_id =
-        extract_id_from_certificate(&cert).expect("Could not extract id from server certificate");
+/// Accepts connections until SIGTERM or SIGINT, then shuts the agent down.
+async fn serve(
+    agent_config: ServerConfig,
+    started_at: Instant,
+    cert_resolver: Arc<ReloadableCertResolver>,
+    listener: std::net::TcpListener,
+    launcher: SanzuLauncher,
+    inherited_control: Option<InheritedControl>,
+    tls_versions: &[&'static rustls::SupportedProtocolVersion],
+) -> anyhow::Result<()> {
+    let agent_auth_config = agent_config.server_auth_config.clone();
+    let agent_network_config = agent_config.server_network_config.clone();
+    let pairing_config = agent_config.pairing_config.clone();
+    let audit_config = agent_config.audit_config.clone();
+    let machine_id = cert_resolver.machine_id().to_owned();
+    let served_cert = Arc::clone(&cert_resolver);
 
-    let config = rustls::ServerConfig::builder()
+    let config = rustls::ServerConfig::builder_with_protocol_versions(tls_versions)
         .with_no_client_auth()
-        .with_single_cert(certs, key)
-        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        .with_cert_resolver(cert_resolver);
     let acceptor = TlsAcceptor::from(Arc::new(config));
 
-    let listening_on = format!(
-        "{}:{}",
-        &agent_network_config.server_listening_ip, &agent_network_config.server_port
+    let jwks_provider = Arc::new(agent_auth_config.jwks_provider(Duration::from_secs(3)));
+    info!(
+        "Validating connection tokens with keys from {:?}",
+        jwks_provider.source()
     );
+
+    let policy = agent_auth_config.policy_file.as_ref().map(|policy_file| {
+        let policy = PolicyProvider::new(policy_file)
+            .unwrap_or_else(|e| panic!("Failed to load access policy at {} : {}", policy_file, e));
+        info!("Enforcing access policy {}", policy_file);
+        Arc::new(policy)
+    });
+
+    let pairing = pairing_config.enabled.then(|| {
+        info!(
+            "Pairing is enabled, paired devices are stored in {}",
+            &pairing_config.devices_file
+        );
+        PairingStore::new(&pairing_config)
+    });
+
+    let audit = if !audit_config.enabled {
+        AuditLog::disabled()
+    } else {
+        match &launcher {
+            // the supervisor holds the key of the audit log, and writes it
+            #[cfg(target_os = "linux")]
+            SanzuLauncher::Supervisor(client) => {
+                let client = Arc::clone(client);
+                AuditLog::written_by(move |entry| client.audit(entry))?
+            }
+            SanzuLauncher::Local => {
+                let audit = AuditLog::open(
+                    Path::new(&audit_config.audit_file),
+                    Path::new(&audit_config.key_file),
+                )
+                .with_context(|| {
+                    format!("Failed to open audit log at {}", &audit_config.audit_file)
+                })?;
+                info!("Writing audit log to {}", &audit_config.audit_file);
+                audit
+            }
+        }
+    };
+    let flushed_audit = audit.clone();
+
+    let source_acl = Arc::new(SourceAcl::new(&agent_network_config));
+    let rate_limiter = Arc::new(RateLimiter::new(&agent_config.rate_limit_config));
+    if agent_config.rate_limit_config.enabled {
+        tokio::spawn(Arc::clone(&rate_limiter).prune_periodically());
+    }
+
+    let authenticator = Authenticator {
+        local_machine_id: machine_id,
+        jwks_provider,
+        policy,
+        pairing,
+        audit,
+        rate_limiter: Arc::clone(&rate_limiter),
+        source_claim: agent_network_config.source_ip_claim.clone(),
+        timeout: Duration::from_secs(3),
+    };
+
+    let notifier = SystemdNotifier::from_env();
+    let listener = TcpListener::from_std(listener)?;
     info!(
         "Startup done. Listening for new connections on {} ",
-        listening_on
+        listener.local_addr()?
+    );
+    let sanzu_config = &agent_config.sanzu_server_launch_config;
+    let sessions = Arc::new(SessionManager::new(
+        agent_config.session_config.max_sessions,
+        sanzu_config.sanzu_server_port,
+        !sanzu_config.sanzu_server_external_startup,
+    ));
+    info!(
+        "Serving up to {} session(s)",
+        agent_config.session_config.max_sessions
     );
-    let listener = TcpListener::bind(listening_on).await?;
-    // limits the number of connected clients to 1
-    let sem_connected_clients = Arc::new(Semaphore::new(1));
+    let warm_pool = WarmPool::new(
+        &agent_config.warm_pool_config,
+        sanzu_config,
+        agent_config.session_config.max_sessions,
+        launcher.clone(),
+        Arc::clone(&sessions),
+    )
+    .map(Arc::new);
+    if let Some(pool) = &warm_pool {
+        tokio::spawn(Arc::clone(pool).run());
+    }
+
+    if agent_config.control_config.enabled {
+        let control = Arc::new(ControlServer {
+            sessions: Arc::clone(&sessions),
+            cert_resolver: served_cert,
+            rate_limiter: Arc::clone(&rate_limiter),
+            source_acl: Arc::clone(&source_acl),
+            started_at,
+        });
+        let socket = agent_config.control_config.socket.clone();
+        tokio::spawn(async move {
+            #[cfg(unix)]
+            let served = match inherited_control {
+                Some(listener) => control.serve_on(listener).await,
+                None => control.serve(&socket).await,
+            };
+            #[cfg(not(unix))]
+            let served = {
+                let _ = inherited_control;
+                control.serve(&socket).await
+            };
+            if let Err(e) = served {
+                error!("Control socket is unavailable : {}", e);
+            }
+        });
+    }
+
+    if agent_config.metrics_config.enabled {
+        let listen_address = agent_config.metrics_config.listen_address;
+        tokio::spawn(async move {
+            if let Err(e) = serve_metrics(listen_address).await {
+                error!("Metrics endpoint is unavailable : {}", e);
+            }
+        });
+    }
 
+    let mut signals = ShutdownSignals::new();
+    let mut connections = JoinSet::new();
+    notifier.ready(&sessions_status(&sessions));
+    let mut keep_alive = notifier.keep_alive_interval().map(tokio::time::interval);
     loop {
         let local_config = agent_config.clone();
-        let local_machine_id = machine_id.clone();
         let acceptor = acceptor.clone();
+        let authenticator = authenticator.clone();
+        let sanzu_servers = SanzuServers {
+            launcher: launcher.clone(),
+            warm_pool: warm_pool.clone(),
+        };
 
-        let (stream, peer_addr) = match listener.accept().await {
+        let keep_alive_tick = async {
+            match keep_alive.as_mut() {
+                Some(i) => i.tick().await,
+                None => std::future::pending().await,
+            }
+        };
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            signal = signals.recv() => {
+                info!("Received {}, no longer accepting connections", signal);
+                break;
+            }
+            // a stuck accept loop stops the pings, and the watchdog restarts the agent
+            _ = keep_alive_tick => {
+                notifier.keep_alive(&sessions_status(&sessions));
+                continue;
+            }
+            // forgets the connections that ended
+            Some(_) = connections.join_next() => continue,
+        };
+        let (stream, peer_addr) = match accepted {
             Ok((s, pa)) => (s, pa),
             Err(e) => {
                 error!("Could not accept connection : {}", e);
@@ -94,7 +650,11 @@ async fn main() -> anyhow::Result<()> {
             }
         };
 
+        if !source_acl.allows(peer_addr.ip()) || !rate_limiter.allow_connection(peer_addr.ip()) {
+            continue;
+        }
         info!("Got a connection from {}", peer_addr);
+        metrics().connections.inc();
 
         match stream.set_nodelay(true) {
             Ok(_) => {}
@@ -103,15 +663,16 @@ async fn main() -> anyhow::Result<()> {
             }
         };
 
-        let sem_connected_clients = Arc::clone(&sem_connected_clients);
-        tokio::spawn(async move {
+        let sessions = Arc::clone(&sessions);
+        connections.spawn(async move {
             if let Err(err) = process_client_connection(
                 stream,
                 peer_addr,
                 acceptor,
-                local_machine_id.as_str(),
+                authenticator,
                 local_config,
-                sem_connected_clients,
+                sessions,
+                sanzu_servers,
             )
             .await
             {
@@ -119,4 +680,20 @@ async fn main() -> anyhow::Result<()> {
             }
         });
     }
+
+    drop(listener);
+    notifier.stopping(&format!(
+        "Shutting down, {} running session(s)",
+        sessions.sessions().len()
+    ));
+    shut_down(
+        &sessions,
+        &mut connections,
+        &mut signals,
+        &notifier,
+        Duration::from_secs(agent_config.session_config.shutdown_drain_secs),
+    )
+    .await;
+    tokio::task::spawn_blocking(move || flushed_audit.flush()).await?;
+    Ok(())
 }
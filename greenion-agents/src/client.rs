@@ -1,4 +1,6 @@
-use std::time::Duration;
+use jwks::Jwks;
+use rustls::client::danger::ServerCertVerifier;
+use std::{sync::Arc, time::Duration};
 
 use tokio::net::{TcpListener, TcpStream};
 use tokio_rustls::TlsStream;
@@ -8,19 +10,42 @@ pub mod dialer;
 pub mod errors;
 pub mod forwarder;
 pub mod main_connect;
+pub mod pairing;
 pub mod sanzu_client_starter;
 pub mod server_status_handler;
 pub mod utils;
 
+/// How the client proves to the server agent that it may connect.
+#[derive(Clone)]
+pub enum ClientCredential {
+    /// Connection token delivered by the web application
+    Jwt { token: String, jwks: Jwks },
+    /// Credential received when pairing with the server
+    Device { device_id: String, secret: Vec<u8> },
+}
+
+/// Server agent to connect to, and how to recognize it.
+#[derive(Clone)]
+pub struct ConnectionTarget {
+    pub server_ip: String,
+    pub server_port: u16,
+    pub verifier: Arc<dyn ServerCertVerifier>,
+    pub credential: ClientCredential,
+}
+
 pub struct SanzuClientStarter {
     pub sanzu_client_exe_path: String,
     pub sanzu_client_config_path: String,
     pub sanzu_client_log_file: String,
     pub port: u16,
+    /// Arguments telling the sanzu client which codec the server picked
+    pub codec_args: Vec<String>,
 }
 
 pub struct ClientForwarder {
     pub outbound_tls_stream: TlsStream<TcpStream>,
     pub sanzu_listener: TcpListener,
     pub initial_timeout: Option<Duration>,
+    /// The stream from the server is made of frames, with notices along the sanzu stream
+    pub session_notices: bool,
 }
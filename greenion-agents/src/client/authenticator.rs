@@ -5,28 +5,28 @@ use std::time::Duration;
 
 use log::{debug, error, info};
 use prost::Message;
-use rustls::pki_types::CertificateDer;
 use tokio::net::TcpStream;
 use tokio_rustls::TlsStream;
 
 use crate::auth::jwt::parse_and_validate_jwt;
-use crate::auth::x509::parse_x509;
-use crate::auth::x509::validate_x509_machine_id;
 use crate::proto::common::check_version_matches;
 use crate::proto::common::recv_msg_async;
 use crate::proto::common::send_msg_async;
 use crate::proto::messages;
 
-use super::errors::GreenionClientIntermediateError;
+use super::{errors::GreenionClientIntermediateError, ClientCredential};
 
 pub struct Authenticator {
     pub stream: TlsStream<TcpStream>,
     pub timeout: Duration,
-    pub jwt: String,
+    pub credential: ClientCredential,
     pub client_version: String,
-    pub jwks: jwks::Jwks,
-    pub server_cert: CertificateDer<'static>,
-    pub ca_cert: CertificateDer<'static>,
+    pub issuer: Option<String>,
+    /// Codecs the sanzu client decodes, preferred one and display, for the server to pick
+    /// the codec and match the display
+    pub codecs: Vec<String>,
+    pub preferred_codec: Option<String>,
+    pub display: Option<messages::Display>,
 }
 
 pub trait Authenticate {
@@ -40,55 +40,21 @@ impl Authenticate for Authenticator {
         self,
     ) -> anyhow::Result<TlsStream<TcpStream>, GreenionClientIntermediateError> {
         info!("Authenticating to the server...");
-        debug!("Parsing server certificate");
-        let server_cert = match parse_x509(&self.server_cert) {
-            Ok(v) => {
-                debug!("Parsed server certificate");
-                v
-            }
-            Err(e) => {
-                error!("Could not parse server certificate : {}", e);
-                return Err(GreenionClientIntermediateError::new(
-                    "Could not parse server certificate".into(),
-                ));
-            }
-        };
-
-        debug!("Parsing CA certificate");
-        let ca_cert = match parse_x509(&self.ca_cert) {
-            Ok(v) => {
-                debug!("Parsed CA certificate");
-                v
-            }
-            Err(e) => {
-                error!("Could not parse CA certificate : {}", e);
+        if let ClientCredential::Jwt { token, jwks } = &self.credential {
+            debug!("Checking that the JWT is still valid");
+            let claims = match parse_and_validate_jwt(token, jwks, self.issuer.as_deref()) {
+                Ok(c) => c,
+                Err(e) => {
+                    let msg = format!("{} Please refresh the web application to get a new one.", e);
+                    return Err(GreenionClientIntermediateError::new(msg));
+                }
+            };
+            if claims.machine_id.is_empty() {
+                error!("Extracted server machine id is empty");
                 return Err(GreenionClientIntermediateError::new(
-                    "Could not parse CA certificate".into(),
+                    "Server machine id is empty".into(),
                 ));
             }
-        };
-
-        debug!("Parsing JWT to extract server machine id");
-        let claims = match parse_and_validate_jwt(&self.jwt, &self.jwks) {
-            Ok(c) => c,
-            Err(e) => {
-                let msg = format!("{} Please refresh the web application to get a new one.", e);
-                return Err(GreenionClientIntermediateError::new(msg));
-            }
-        };
-        debug!("Extracting machine id from jwt claims");
-        let machine_id = &claims.machine_id;
-        if machine_id.is_empty() {
-            error!("Extracted server machine id is empty");
-            return Err(GreenionClientIntermediateError::new(
-                "Server machine id is empty".into(),
-            ));
-        }
-
-        debug!("Validating server certificate");
-        if let Err(e) = validate_x509_machine_id(&server_cert, &ca_cert, machine_id) {
-            error!("Failed to authenticate server : {}", e);
-            return Err(GreenionClientIntermediateError::new(e.to_string()));
         }
 
         info!("Server is legit. Proceding to greenion handshake");
@@ -117,9 +83,23 @@ impl Authenticate for Authenticator {
             )));
         }
 
-        let ch = messages::ClientHello {
+        let offer = messages::ClientHello {
             version: self.client_version.to_owned(),
-            jwt: self.jwt.to_owned(),
+            codecs: self.codecs.clone(),
+            preferred_codec: self.preferred_codec.clone().unwrap_or_default(),
+            display: self.display,
+            ..Default::default()
+        };
+        let ch = match &self.credential {
+            ClientCredential::Jwt { token, .. } => messages::ClientHello {
+                jwt: token.to_owned(),
+                ..offer
+            },
+            ClientCredential::Device { device_id, secret } => messages::ClientHello {
+                device_id: device_id.to_owned(),
+                device_secret: secret.to_owned(),
+                ..offer
+            },
         };
         match send_msg_async(&mut stream, ch, Some(self.timeout)).await {
             Ok(_) => {}
@@ -157,6 +137,25 @@ impl Authenticate for Authenticator {
                     "Server refused our authentication request".into(),
                 ));
             }
+            messages::AuthResult::PolicyDenied => {
+                let reason = match sar.deny_reason() {
+                    messages::PolicyDenyReason::UserNotAllowed => {
+                        "You are not allowed to connect to this machine"
+                    }
+                    messages::PolicyDenyReason::OutsideAllowedHours => {
+                        "Connections to this machine are not allowed at this time"
+                    }
+                    messages::PolicyDenyReason::SourceNotAllowed => {
+                        "Connections to this machine are not allowed from your network"
+                    }
+                    messages::PolicyDenyReason::NotDenied => "Access denied by the server policy",
+                };
+                error!(
+                    "Server access policy refused the connection : {:?}",
+                    sar.deny_reason()
+                );
+                return Err(GreenionClientIntermediateError::new(reason.into()));
+            }
             messages::AuthResult::AuthOk => {
                 info!("Authentication successful");
             }
@@ -1,7 +1,8 @@
 #![allow(async_fn_in_trait)]
 use log::error;
 use log::{debug, info};
-use rustls::pki_types::{CertificateDer, ServerName};
+use rustls::client::danger::ServerCertVerifier;
+use rustls::pki_types::ServerName;
 use std::{sync::Arc, time::Duration};
 use tokio::net::TcpStream;
 use tokio::time::timeout;
@@ -11,40 +12,21 @@ use tokio_rustls::TlsStream;
 use super::errors::GreenionClientIntermediateError;
 
 pub trait Dialer {
-    async fn dial(
-        self,
-    ) -> anyhow::Result<
-        (TlsStream<TcpStream>, CertificateDer<'static>),
-        GreenionClientIntermediateError,
-    >;
+    async fn dial(self) -> anyhow::Result<TlsStream<TcpStream>, GreenionClientIntermediateError>;
 }
 
 pub struct StandaloneDialer {
     pub server_ip: String,
     pub server_port: u16,
     pub timeout: Duration,
-    pub cert: CertificateDer<'static>,
+    pub verifier: Arc<dyn ServerCertVerifier>,
 }
 
 impl Dialer for StandaloneDialer {
-    async fn dial(
-        self,
-    ) -> anyhow::Result<
-        (TlsStream<TcpStream>, CertificateDer<'static>),
-        GreenionClientIntermediateError,
-    > {
-        let mut root_cert_store = rustls::RootCertStore::empty();
-        debug!("Built client root cert store");
-
-        let Ok(_) = root_cert_store.add(self.cert.clone()) else {
-            error!("Failed to add CA certificate to client root cert store");
-            return Err(GreenionClientIntermediateError::new(
-                "Failed to create local CA root store".into(),
-            ));
-        };
-        debug!("Added ca cert to root_cert_store");
+    async fn dial(self) -> anyhow::Result<TlsStream<TcpStream>, GreenionClientIntermediateError> {
         let tls_config = rustls::ClientConfig::builder()
-            .with_root_certificates(root_cert_store)
+            .dangerous()
+            .with_custom_certificate_verifier(self.verifier)
             .with_no_client_auth();
         let connector = TlsConnector::from(Arc::new(tls_config));
 
@@ -113,30 +95,21 @@ impl Dialer for StandaloneDialer {
                     "Could not establish TLS stream with {} : {}",
                     address_string, e
                 );
+                if let Some(rustls::Error::InvalidCertificate(cert_error)) = e
+                    .get_ref()
+                    .and_then(|inner| inner.downcast_ref::<rustls::Error>())
+                {
+                    return Err(GreenionClientIntermediateError::new(format!(
+                        "The server certificate was refused : {:?}",
+                        cert_error
+                    )));
+                }
                 return Err(GreenionClientIntermediateError::new(
                     "Could not establish a TLS stream with the server".into(),
                 ));
             }
         };
 
-        let Some(certificate) = tls_stream.get_ref().1.peer_certificates() else {
-            error!("Server did not provide any TLS certificate");
-            return Err(GreenionClientIntermediateError::new(
-                "Server did not provide any TLS certificate".into(),
-            ));
-        };
-
-        let cert = match certificate.first() {
-            Some(crt) => crt,
-            None => {
-                error!("Could not get server's first certificate");
-                return Err(GreenionClientIntermediateError::new(
-                    "Could not get server's first certificate".into(),
-                ));
-            }
-        }
-        .to_owned();
-
-        Ok((TlsStream::Client(tls_stream), cert))
+        Ok(TlsStream::Client(tls_stream))
     }
 }
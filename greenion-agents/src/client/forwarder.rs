@@ -1,7 +1,23 @@
-use log::{error, info, warn};
-use tokio::time::timeout;
+use log::{debug, error, info, warn};
+use prost::Message;
+use std::{
+    io,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
 
-use crate::client::errors::GreenionClientIntermediateError;
+use crate::{
+    client::errors::GreenionClientIntermediateError,
+    metrics::{metrics, CountingStream},
+    proto::{
+        common::{read_session_frame, SESSION_DATA_FRAME, SESSION_NOTICE_FRAME},
+        messages::SessionNotice,
+    },
+};
 
 use super::ClientForwarder;
 
@@ -37,8 +53,21 @@ impl ClientForwarder {
             warn!("Could not disable buffering on sanzu stream");
         }
 
-        let res =
-            tokio::io::copy_bidirectional(&mut self.outbound_tls_stream, &mut sanzu_stream).await;
+        let metrics = metrics();
+        let mut server_stream = CountingStream {
+            inner: &mut self.outbound_tls_stream,
+            read: &[&metrics.downstream_bytes],
+            written: &[&metrics.upstream_bytes],
+        };
+        let started_at = Instant::now();
+        metrics.active_sessions.inc();
+        let res = if self.session_notices {
+            forward_framed(&mut server_stream, &mut sanzu_stream).await
+        } else {
+            tokio::io::copy_bidirectional(&mut server_stream, &mut sanzu_stream).await
+        };
+        metrics.active_sessions.dec();
+        metrics.session_duration.observe(started_at.elapsed());
 
         match res {
             Ok((v1, v2)) => {
@@ -52,3 +81,51 @@ impl ClientForwarder {
         Ok(())
     }
 }
+
+/// Forwards the sanzu stream as is to the server, and the data frames of the server to the
+/// sanzu client, showing the notices in between.
+async fn forward_framed<S: AsyncRead + AsyncWrite + Unpin>(
+    server_stream: S,
+    sanzu_stream: &mut TcpStream,
+) -> io::Result<(u64, u64)> {
+    let (mut server_read, mut server_write) = tokio::io::split(server_stream);
+    let (mut sanzu_read, mut sanzu_write) = sanzu_stream.split();
+
+    let upstream = async {
+        let written = tokio::io::copy(&mut sanzu_read, &mut server_write).await?;
+        server_write.shutdown().await?;
+        Ok::<_, io::Error>(written)
+    };
+    let downstream = async {
+        let mut payload = Vec::new();
+        let mut written = 0;
+        while let Some(kind) = read_session_frame(&mut server_read, &mut payload).await? {
+            match kind {
+                SESSION_DATA_FRAME => {
+                    sanzu_write.write_all(&payload).await?;
+                    written += payload.len() as u64;
+                }
+                SESSION_NOTICE_FRAME => match SessionNotice::decode(payload.as_slice()) {
+                    Ok(notice) => show_notice(&notice),
+                    Err(e) => warn!("Could not decode a notice of the server : {}", e),
+                },
+                kind => debug!("Ignoring a session frame of unknown kind {}", kind),
+            }
+        }
+        sanzu_write.shutdown().await?;
+        Ok(written)
+    };
+    tokio::try_join!(upstream, downstream)
+}
+
+fn show_notice(notice: &SessionNotice) {
+    if notice.shutdown_deadline == 0 {
+        return;
+    }
+    let msg = format!(
+        "The server is shutting down : your session ends in {} at the latest.",
+        humantime::format_duration(Duration::from_secs(notice.shutdown_in_secs))
+    );
+    warn!("{}", msg);
+    let _ = notifica::notify("Greenion Agent Client", &msg);
+}
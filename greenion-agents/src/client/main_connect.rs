@@ -1,31 +1,31 @@
-use std::{process::ExitStatus, time::Duration};
+use std::{
+    process::ExitStatus,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use jwks::Jwks;
 use log::{debug, error, info};
-use rustls::pki_types::CertificateDer;
 use tokio::{net::TcpListener, task::JoinHandle};
 
 use crate::{
-    auth::jwt::Claims,
     client::{
         authenticator::{Authenticate, Authenticator},
         dialer::{Dialer, StandaloneDialer},
         errors::GreenionClientIntermediateError,
-        ClientForwarder, SanzuClientStarter,
+        utils::local_display,
+        ClientForwarder, ConnectionTarget, SanzuClientStarter,
     },
     conf::client_config::ClientConfig,
+    metrics::metrics,
 };
 
 use super::{errors::GreenionClientFinalError, server_status_handler::ServerStatusHandler};
 
-static CLIENT_VERSION: &str = "v0.0.1";
+pub static CLIENT_VERSION: &str = "v0.0.2";
 
 pub async fn main_connect(
-    jwt: &Claims,
+    target: &ConnectionTarget,
     timeout: &Duration,
-    jwt_string: &str,
-    jwks: &Jwks,
-    cert: &CertificateDer<'static>,
     agent_config: &ClientConfig,
 ) -> Result<(), GreenionClientFinalError> {
     let timeout = timeout.to_owned();
@@ -33,19 +33,22 @@ pub async fn main_connect(
     let agent_sanzu_client_launch_config = agent_config.sanzu_client_launch_config.to_owned();
 
     let standalone_dialer = StandaloneDialer {
-        server_ip: jwt.machine_ip.to_owned(),
-        server_port: jwt.machine_port,
+        server_ip: target.server_ip.to_owned(),
+        server_port: target.server_port,
         timeout,
-        cert: cert.clone(),
+        verifier: Arc::clone(&target.verifier),
     };
 
+    let dial_started = Instant::now();
     let res_dial = standalone_dialer.dial().await;
-    let (stream, certificate) = match res_dial {
+    let stream = match res_dial {
         Ok(v) => {
             info!("Dialing worked");
+            metrics().connections.inc();
             v
         }
         Err(e) => {
+            metrics().handshakes.inc("dial_failed");
             return Err(GreenionClientFinalError::new("Failed to dial server", e));
         }
     };
@@ -53,22 +56,29 @@ pub async fn main_connect(
     let authenticator = Authenticator {
         stream,
         timeout,
-        jwt: jwt_string.to_owned(),
+        credential: target.credential.clone(),
         client_version: CLIENT_VERSION.to_string(),
-        jwks: jwks.to_owned(),
-        server_cert: certificate.clone(),
-        ca_cert: cert.to_owned(),
+        issuer: agent_config.client_auth_config.issuer.clone(),
+        codecs: agent_sanzu_client_launch_config.codecs(),
+        preferred_codec: agent_sanzu_client_launch_config
+            .preferred_codec
+            .clone()
+            .filter(|family| agent_sanzu_client_launch_config.decoder(family).is_some()),
+        display: local_display(&agent_sanzu_client_launch_config),
     };
 
     let res_authenticator = authenticator.authenticate().await;
+    metrics().handshake_duration.observe(dial_started.elapsed());
 
     let outbound_tls_stream = match res_authenticator {
         Ok(v) => {
             info!("Authentication worked");
+            metrics().handshakes.inc("ok");
             v
         }
         Err(e) => {
             error!("Could not authenticate : {}", e);
+            metrics().handshakes.inc("auth_failed");
             return Err(GreenionClientFinalError::new(
                 "Failed to authenticate to server",
                 e,
@@ -82,7 +92,7 @@ pub async fn main_connect(
     }
     .handle()
     .await;
-    let outbound_tls_stream = match res_sshandler {
+    let (outbound_tls_stream, server_status) = match res_sshandler {
         Ok(v) => {
             info!("Server status was OK");
             v
@@ -95,6 +105,23 @@ pub async fn main_connect(
         }
     };
 
+    let codec = server_status.codec;
+    let codec_args = if codec.is_empty() {
+        debug!("The server did not tell its codec");
+        Vec::new()
+    } else {
+        // the server only picks families we advertised, so with a decoder
+        let decoder = agent_sanzu_client_launch_config
+            .decoder(&codec)
+            .unwrap_or_else(|| codec.clone());
+        info!("The server streams with codec {} ({})", codec, decoder);
+        agent_sanzu_client_launch_config
+            .sanzu_client_codec_args
+            .iter()
+            .map(|arg| arg.replace("{codec}", &decoder))
+            .collect()
+    };
+
     let local_binding_addr = format!("127.0.0.1:{}", agent_network_config.listening_port);
 
     let wait_duration: Duration = if agent_sanzu_client_launch_config.sanzu_client_external_startup
@@ -151,6 +178,7 @@ pub async fn main_connect(
                     .clone(),
                 sanzu_client_log_file: agent_sanzu_client_launch_config.sanzu_log_file.clone(),
                 port: agent_network_config.listening_port,
+                codec_args,
             };
             scw.run().await
         }))
@@ -160,6 +188,7 @@ pub async fn main_connect(
         let cf = ClientForwarder {
             outbound_tls_stream,
             sanzu_listener: sanzu_stream_binder,
+            session_notices: server_status.session_notices,
             initial_timeout: {
                 if agent_sanzu_client_launch_config.sanzu_client_external_startup {
                     None
@@ -182,6 +211,7 @@ pub async fn main_connect(
                             match sz_cli_res {
                                 Ok(_) => Ok(()) ,
                                 Err(intermediate_error) => {
+                                    metrics().sanzu_start_failures.inc();
                                     Err(GreenionClientFinalError::new("Error with core remote desktop", intermediate_error))
                                 }
                             }
@@ -0,0 +1,221 @@
+use anyhow::{bail, Context};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use log::{error, info};
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    io::{Cursor, ErrorKind},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{
+    auth::{
+        pake::{channel_binding, PakeSide, Spake2},
+        verifier::PinnedKeyVerifier,
+        write_file_atomically,
+    },
+    proto::{
+        common::{check_version_matches, recv_msg_async, send_msg_async},
+        messages::{
+            ClientHello, ClientPairingConfirmation, PairingResult, PairingStatus, ServerHello,
+            ServerPairing,
+        },
+    },
+};
+
+use super::{
+    dialer::{Dialer, StandaloneDialer},
+    errors::GreenionClientIntermediateError,
+    main_connect::CLIENT_VERSION,
+    ClientCredential, ConnectionTarget,
+};
+
+/// A server agent this device paired with.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PairedServer {
+    pub machine_id: String,
+    pub address: String,
+    pub port: u16,
+    /// SHA-256 of the public key of the server certificate, base64 encoded
+    pub server_key_sha256: String,
+    pub device_id: String,
+    /// base64 encoded
+    device_secret: String,
+}
+
+impl PairedServer {
+    pub fn connection_target(&self) -> anyhow::Result<ConnectionTarget> {
+        let key_sha256 = STANDARD
+            .decode(&self.server_key_sha256)
+            .context("Invalid server key fingerprint")?;
+        let secret = STANDARD
+            .decode(&self.device_secret)
+            .context("Invalid device secret")?;
+        Ok(ConnectionTarget {
+            server_ip: self.address.clone(),
+            server_port: self.port,
+            verifier: Arc::new(PinnedKeyVerifier::pinned(key_sha256)),
+            credential: ClientCredential::Device {
+                device_id: self.device_id.clone(),
+                secret,
+            },
+        })
+    }
+}
+
+pub fn load_paired_servers(filename: &str) -> anyhow::Result<Vec<PairedServer>> {
+    match fs::read(filename) {
+        Ok(v) => serde_json::from_slice(&v)
+            .with_context(|| format!("Paired servers file {} is invalid", filename)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => {
+            error!("Could not read paired servers file {} : {}", filename, e);
+            bail!("Could not read paired servers file {}", filename);
+        }
+    }
+}
+
+pub fn find_paired_server(filename: &str, machine_id: &str) -> anyhow::Result<PairedServer> {
+    match load_paired_servers(filename)?
+        .into_iter()
+        .find(|s| s.machine_id == machine_id)
+    {
+        Some(v) => Ok(v),
+        None => bail!(
+            "This device is not paired with machine {}. Pair it first with `greenion-client pair`",
+            machine_id
+        ),
+    }
+}
+
+/// Records `server`, replacing a previous pairing with the same machine.
+fn save_paired_server(filename: &str, server: &PairedServer) -> anyhow::Result<()> {
+    let mut servers = load_paired_servers(filename)?;
+    servers.retain(|s| s.machine_id != server.machine_id);
+    servers.push(server.clone());
+    write_file_atomically(
+        Path::new(filename),
+        &serde_json::to_vec_pretty(&servers)?,
+        true,
+    )
+}
+
+/// Pairs this device with the server agent at `server_ip:server_port`, using the one-time
+/// code it displayed, and stores the credential it issues in `paired_servers_file`.
+pub async fn pair(
+    server_ip: &str,
+    server_port: u16,
+    code: &str,
+    device_name: &str,
+    timeout: Duration,
+    paired_servers_file: &str,
+) -> anyhow::Result<PairedServer, GreenionClientIntermediateError> {
+    let verifier = Arc::new(PinnedKeyVerifier::recording());
+    let mut stream = StandaloneDialer {
+        server_ip: server_ip.to_owned(),
+        server_port,
+        timeout,
+        verifier: Arc::clone(&verifier) as _,
+    }
+    .dial()
+    .await?;
+
+    let fail = |msg: &str| GreenionClientIntermediateError::new(msg.to_owned());
+
+    let sh = recv_msg_async(&mut stream, Some(timeout))
+        .await
+        .ok()
+        .and_then(|msg| ServerHello::decode(&mut Cursor::new(msg)).ok())
+        .ok_or_else(|| fail("Failed to read server hello"))?;
+    if !check_version_matches(CLIENT_VERSION, &sh.version) {
+        return Err(fail(&format!(
+            "Server version {} doesn't match client version {}",
+            sh.version, CLIENT_VERSION
+        )));
+    }
+
+    let spake = Spake2::start(PakeSide::Client, code).map_err(|e| fail(&e.to_string()))?;
+    let binding = channel_binding(&stream).map_err(|e| fail(&e.to_string()))?;
+    let ch = ClientHello {
+        version: CLIENT_VERSION.to_owned(),
+        pairing_message: spake.message().to_vec(),
+        device_name: device_name.to_owned(),
+        ..Default::default()
+    };
+    send_msg_async(&mut stream, ch, Some(timeout))
+        .await
+        .map_err(|_| fail("Could not send pairing request"))?;
+
+    let server_pairing = recv_msg_async(&mut stream, Some(timeout))
+        .await
+        .ok()
+        .and_then(|msg| ServerPairing::decode(&mut Cursor::new(msg)).ok())
+        .ok_or_else(|| fail("Could not receive the pairing answer of the server"))?;
+    match server_pairing.status() {
+        PairingStatus::PairingContinue => {}
+        PairingStatus::PairingUnavailable => {
+            return Err(fail(
+                "The server is not waiting for a pairing. Run `greenion-server pair` on it to get a new code",
+            ))
+        }
+        _ => return Err(fail("The server refused to pair")),
+    }
+
+    let wrong_code = "Wrong pairing code, or the connection was intercepted. Run `greenion-server pair` on the server to get a new code";
+    let key = spake
+        .finish(&server_pairing.pairing_message, &binding)
+        .map_err(|e| fail(&e.to_string()))?;
+    if !key.verify_confirmation(PakeSide::Server, &server_pairing.confirmation) {
+        error!("Server pairing confirmation doesn't match");
+        // the server learns the attempt failed from our missing confirmation
+        let _ = send_msg_async(
+            &mut stream,
+            ClientPairingConfirmation::default(),
+            Some(timeout),
+        )
+        .await;
+        return Err(fail(wrong_code));
+    }
+    let confirmation = ClientPairingConfirmation {
+        confirmation: key.confirmation(PakeSide::Client),
+    };
+    send_msg_async(&mut stream, confirmation, Some(timeout))
+        .await
+        .map_err(|_| fail("Could not send pairing confirmation"))?;
+
+    let result = recv_msg_async(&mut stream, Some(timeout))
+        .await
+        .ok()
+        .and_then(|msg| PairingResult::decode(&mut Cursor::new(msg)).ok())
+        .ok_or_else(|| fail("Could not receive the pairing result"))?;
+    if result.status() != PairingStatus::Paired {
+        return Err(fail(wrong_code));
+    }
+
+    let Some(server_key_sha256) = verifier.seen_key_sha256() else {
+        return Err(fail("Server presented no certificate"));
+    };
+    let paired = PairedServer {
+        machine_id: result.machine_id,
+        address: server_ip.to_owned(),
+        port: server_port,
+        server_key_sha256: STANDARD.encode(server_key_sha256),
+        device_id: result.device_id,
+        device_secret: STANDARD.encode(result.device_secret),
+    };
+    if let Err(e) = save_paired_server(paired_servers_file, &paired) {
+        error!("Could not store pairing : {}", e);
+        return Err(fail(&format!(
+            "Could not store the pairing in {}",
+            paired_servers_file
+        )));
+    }
+    info!(
+        "Paired with machine {} at {}:{}",
+        paired.machine_id, server_ip, server_port
+    );
+    Ok(paired)
+}
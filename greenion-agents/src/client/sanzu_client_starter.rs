@@ -55,7 +55,8 @@ impl SanzuClientStarter {
             .arg(self.sanzu_client_config_path.as_str())
             .arg("127.0.0.1")
             .arg(self.port.to_string())
-            .arg("-w");
+            .arg("-w")
+            .args(&self.codec_args);
 
         match sanzu_client_log_file {
             Ok(logfile) => {
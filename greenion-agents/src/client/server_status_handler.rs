@@ -5,23 +5,101 @@ use tokio::net::TcpStream;
 use tokio_rustls::TlsStream;
 
 use crate::{
-    client::errors::GreenionClientIntermediateError,
+    client::{errors::GreenionClientIntermediateError, utils::ask_confirmation_dialog},
     proto::{
-        common::recv_msg_async,
-        messages::{ServerStartProxy, StartProxyStatus},
+        common::{recv_msg_async, send_msg_async},
+        messages::{ClientTakeoverAnswer, ServerStartProxy, StartProxyStatus},
     },
 };
 
+/// Queue updates that may be missed before giving up on the server
+const MISSED_QUEUE_UPDATES: u32 = 3;
+/// Interval between queue updates of servers that don't tell it
+const DEFAULT_QUEUE_UPDATE_INTERVAL: Duration = Duration::from_secs(16);
+
 pub struct ServerStatusHandler {
     pub stream: TlsStream<TcpStream>,
     pub timeout: Duration,
 }
 
 impl ServerStatusHandler {
+    /// Stream to forward once the server started the session, and the StartProxy status
+    /// telling the codec of its sanzu server and whether the stream is framed.
     pub async fn handle(
         mut self,
-    ) -> anyhow::Result<TlsStream<TcpStream>, GreenionClientIntermediateError> {
-        let server_status = match recv_msg_async(&mut self.stream, Some(self.timeout)).await {
+    ) -> anyhow::Result<(TlsStream<TcpStream>, ServerStartProxy), GreenionClientIntermediateError>
+    {
+        let mut server_status = self.recv_status(Some(self.timeout)).await?;
+        if server_status.result() == StartProxyStatus::TakeoverConfirmation {
+            let takeover = tokio::task::spawn_blocking(|| {
+                ask_confirmation_dialog(
+                    "You already have a session running on this server, from another device. \
+                    Do you want to end it and continue here ?",
+                )
+            })
+            .await
+            .unwrap_or(false);
+            info!("Answering the server's takeover question : {}", takeover);
+            if let Err(e) =
+                send_msg_async(&mut self.stream, ClientTakeoverAnswer { takeover }, None).await
+            {
+                error!("Could not answer the server's takeover question : {}", e);
+                return Err(GreenionClientIntermediateError::new(
+                    "Could not answer the server's takeover question.".into(),
+                ));
+            }
+            if !takeover {
+                return Err(GreenionClientIntermediateError::new(
+                    "You already have a session running on this server.".into(),
+                ));
+            }
+            server_status = self.recv_status(Some(self.timeout)).await?;
+        }
+
+        let mut last_position = None;
+        while server_status.result() == StartProxyStatus::Queued {
+            if last_position != Some(server_status.queue_position) {
+                let msg = queue_message(&server_status);
+                info!("{}", msg);
+                let _ = notifica::notify("Greenion Agent Client", &msg);
+                last_position = Some(server_status.queue_position);
+            }
+            // the server bounds the wait, tells when the session starts and reminds the
+            // position regularly : a server that stays silent is gone
+            let update_interval = match server_status.update_interval_secs {
+                0 => DEFAULT_QUEUE_UPDATE_INTERVAL,
+                secs => Duration::from_secs(secs),
+            };
+            let max_wait = update_interval * MISSED_QUEUE_UPDATES;
+            server_status = match tokio::time::timeout(max_wait, self.recv_status(None)).await {
+                Ok(status) => status?,
+                Err(_) => {
+                    error!(
+                        "The server sent no queue update within {}",
+                        humantime::format_duration(max_wait)
+                    );
+                    return Err(GreenionClientIntermediateError::new(
+                        "Lost contact with the server while waiting in its queue.".into(),
+                    ));
+                }
+            };
+        }
+        if last_position.is_some() && server_status.result() == StartProxyStatus::ServerBusy {
+            error!("Gave up waiting in the server's queue");
+            return Err(GreenionClientIntermediateError::new(
+                "The server you're trying to connect to is still busy. You waited too long in its queue.".into(),
+            ));
+        }
+
+        let server_status = Self::check_status(server_status)?;
+        Ok((self.stream, server_status))
+    }
+
+    async fn recv_status(
+        &mut self,
+        timeout: Option<Duration>,
+    ) -> Result<ServerStartProxy, GreenionClientIntermediateError> {
+        let server_status = match recv_msg_async(&mut self.stream, timeout).await {
             Ok(msg) => msg,
             Err(e) => {
                 error!("Could not get server's status : {}", e);
@@ -41,10 +119,27 @@ impl ServerStatusHandler {
             }
         };
 
+        Ok(server_status)
+    }
+
+    fn check_status(
+        server_status: ServerStartProxy,
+    ) -> Result<ServerStartProxy, GreenionClientIntermediateError> {
         match server_status.result() {
             StartProxyStatus::StartProxy => {
                 info!("Server is available for streaming, proceding");
             }
+            StartProxyStatus::NoCommonCodec => {
+                let server_codecs = server_status.server_codecs.join(", ");
+                error!(
+                    "The sanzu client decodes none of the codecs of the server : {}",
+                    server_codecs
+                );
+                return Err(GreenionClientIntermediateError::new(format!(
+                    "The server only streams with {}, none of which this computer decodes. Check sanzu_client_codecs in the client configuration.",
+                    server_codecs
+                )));
+            }
             StartProxyStatus::InternalServerError => {
                 // not reachable for now as it's never sent
                 error!("An internal server error occured. Please check the server logs.");
@@ -60,12 +155,53 @@ impl ServerStatusHandler {
                 "The server you're trying to connect to is busy. Someone else is already connected".into()));
             }
             StartProxyStatus::SanzuStartError => {
-                error!("Failed to start the sanzu server. Please check its logs");
+                if server_status.error.is_empty() {
+                    error!("Failed to start the sanzu server. Please check its logs");
+                    return Err(GreenionClientIntermediateError::new(
+                        "Failed to start the sanzu server. Please check its logs".into(),
+                    ));
+                }
+                error!("Failed to start the sanzu server : {}", server_status.error);
+                return Err(GreenionClientIntermediateError::new(format!(
+                    "Failed to start the sanzu server : {}",
+                    server_status.error
+                )));
+            }
+            StartProxyStatus::ServerShuttingDown => {
+                error!("The server you're trying to connect to is shutting down.");
+                return Err(GreenionClientIntermediateError::new(
+                    "The server you're trying to connect to is shutting down. Please try again in a few minutes.".into(),
+                ));
+            }
+            StartProxyStatus::SessionAlreadyActive => {
+                error!("You already have a session running on this server, from another device.");
                 return Err(GreenionClientIntermediateError::new(
-                    "Failed to start the sanzu server. Please check its logs".into(),
+                    "You already have a session running on this server, from another device. End it before connecting again.".into(),
+                ));
+            }
+            StartProxyStatus::Queued => {
+                // handled by handle
+                unreachable!()
+            }
+            StartProxyStatus::TakeoverConfirmation => {
+                // already answered once
+                error!("Server asked to confirm a takeover twice");
+                return Err(GreenionClientIntermediateError::new(
+                    "Server asked to confirm a takeover twice.".into(),
                 ));
             }
         }
-        Ok(self.stream)
+        Ok(server_status)
     }
 }
+
+fn queue_message(server_status: &ServerStartProxy) -> String {
+    let wait = match server_status.estimated_wait_secs {
+        0 => "unknown".to_owned(),
+        secs => humantime::format_duration(Duration::from_secs(secs)).to_string(),
+    };
+    format!(
+        "The server is busy : you are number {} in line, estimated wait {}. Your session starts as soon as a place frees up.",
+        server_status.queue_position, wait
+    )
+}
@@ -2,10 +2,15 @@ use anyhow::anyhow;
 use anyhow::Context;
 use std::{env, fs::create_dir_all, path::PathBuf};
 
-use log::error;
+use log::{error, info, warn};
 use native_dialog::MessageDialog;
+use rustls::pki_types::CertificateDer;
 
-use crate::auth::x509::parse_x509;
+use crate::{
+    auth::{load_certs, load_certs_from_dir, x509::parse_x509},
+    conf::client_config::{ClientAuthConfig, SanzuClientLaunchConfig},
+    proto::messages::Display,
+};
 
 pub fn get_client_config_file_path() -> anyhow::Result<PathBuf> {
     if let Ok(v) = env::var("GREENION_CLIENT_CONFIG_FILE") {
@@ -55,9 +60,17 @@ pub fn print_message_dialog(msg: &str) {
         .show_alert();
 }
 
-pub fn check_is_certificate_cacert(ca_cert: &[u8]) -> bool {
-    const EXPECTED_CN: &str = "GREENION-CA";
+/// Asks a yes/no question. Returns false when the dialog can't be shown.
+pub fn ask_confirmation_dialog(msg: &str) -> bool {
+    MessageDialog::new()
+        .set_title("Greenion Agent Client")
+        .set_text(msg)
+        .set_type(native_dialog::MessageType::Warning)
+        .show_confirm()
+        .unwrap_or(false)
+}
 
+pub fn check_is_certificate_cacert(ca_cert: &[u8], accepted_ca_names: &[String]) -> bool {
     let ca_cert = match parse_x509(ca_cert) {
         Ok(v) => v,
         Err(_) => {
@@ -65,20 +78,117 @@ pub fn check_is_certificate_cacert(ca_cert: &[u8]) -> bool {
         }
     };
 
-    let common_name = ca_cert
+    let Some(common_name) = ca_cert
         .subject()
         .iter_common_name()
         .next()
-        .unwrap()
-        .as_str()
-        .unwrap();
+        .and_then(|cn| cn.as_str().ok())
+    else {
+        error!("CA certificate has no Subject.CN");
+        return false;
+    };
 
-    if common_name != EXPECTED_CN {
+    if !accepted_ca_names.iter().any(|name| name == common_name) {
         error!(
-            "Expected certificate Subject.CN to start with '{EXPECTED_CN}' but got '{common_name}'"
+            "Expected certificate Subject.CN to be one of {:?} but got '{common_name}'",
+            accepted_ca_names
         );
         return false;
     }
 
     true
 }
+
+/// Loads the CA certificates from `ca_cert_file` (which may be a bundle) and `ca_cert_dir`,
+/// keeping only the ones whose CN is accepted by the configuration.
+pub fn load_trusted_ca_certs(
+    auth_config: &ClientAuthConfig,
+) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let mut candidates = match load_certs(&auth_config.ca_cert_file) {
+        Ok(v) => v,
+        Err(e) => {
+            if auth_config.ca_cert_dir.is_none() {
+                return Err(e);
+            }
+            warn!("Could not load CA certificate bundle : {}", e);
+            vec![]
+        }
+    };
+    if let Some(ca_cert_dir) = &auth_config.ca_cert_dir {
+        candidates.append(&mut load_certs_from_dir(ca_cert_dir)?);
+    }
+
+    let ca_certs = candidates
+        .into_iter()
+        .filter(|cert| check_is_certificate_cacert(cert, &auth_config.accepted_ca_names))
+        .collect::<Vec<_>>();
+
+    if ca_certs.is_empty() {
+        return Err(anyhow!("Certificate common name is incorrect, you probably have the wrong file for the ca_cert_file config field"));
+    }
+    info!("Loaded {} trusted CA certificate(s)", ca_certs.len());
+    Ok(ca_certs)
+}
+
+/// Name of this device, used as its name on the servers it pairs with.
+pub fn device_name() -> String {
+    if let Ok(v) = env::var("COMPUTERNAME").or_else(|_| env::var("HOSTNAME")) {
+        return v;
+    }
+    std::fs::read_to_string("/etc/hostname")
+        .map(|v| v.trim().to_owned())
+        .ok()
+        .filter(|v| !v.is_empty())
+        .unwrap_or_else(|| "greenion-client".to_owned())
+}
+
+/// Display advertised to the server : the one of the configuration, else the primary display
+/// found on Windows.
+pub fn local_display(config: &SanzuClientLaunchConfig) -> Option<Display> {
+    if let (Some(width), Some(height)) = (config.display_width, config.display_height) {
+        return Some(Display {
+            width,
+            height,
+            scale: config.display_scale.unwrap_or(1.0),
+        });
+    }
+    primary_display()
+}
+
+#[cfg(target_os = "windows")]
+fn primary_display() -> Option<Display> {
+    use windows::Win32::Graphics::Gdi::{EnumDisplaySettingsW, DEVMODEW, ENUM_CURRENT_SETTINGS};
+    use windows::Win32::UI::HiDpi::GetDpiForSystem;
+    use windows::Win32::UI::WindowsAndMessaging::{GetSystemMetrics, SM_CXSCREEN};
+    use windows_strings::PCWSTR;
+
+    let mut mode = DEVMODEW {
+        dmSize: std::mem::size_of::<DEVMODEW>() as u16,
+        ..Default::default()
+    };
+    // SAFETY: mode is a DEVMODEW of the size it announces
+    if !unsafe { EnumDisplaySettingsW(PCWSTR::null(), ENUM_CURRENT_SETTINGS, &mut mode) }.as_bool()
+    {
+        warn!("Could not find the resolution of the display");
+        return None;
+    }
+    // processes that aren't DPI aware see a scaled down screen, and a 96 DPI system
+    // SAFETY: both only read system settings
+    let logical_width = unsafe { GetSystemMetrics(SM_CXSCREEN) };
+    let dpi = unsafe { GetDpiForSystem() };
+    let scale = if logical_width > 0 && mode.dmPelsWidth > logical_width as u32 {
+        mode.dmPelsWidth as f32 / logical_width as f32
+    } else {
+        dpi as f32 / 96.0
+    };
+    Some(Display {
+        width: mode.dmPelsWidth,
+        height: mode.dmPelsHeight,
+        scale,
+    })
+}
+
+#[cfg(not(target_os = "windows"))]
+fn primary_display() -> Option<Display> {
+    None
+}
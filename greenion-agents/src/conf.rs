@@ -1,3 +1,5 @@
 pub mod client_args;
 pub mod client_config;
+pub mod codecs;
+pub mod server_args;
 pub mod server_config;
@@ -1,9 +1,42 @@
 use anyhow::anyhow;
+use clap::{Parser, Subcommand};
 use log::error;
 use url::Url;
 
-pub fn get_jwt(args: &[String]) -> anyhow::Result<String> {
-    let uri_string = match args.get(1) {
+#[derive(Parser, Debug)]
+#[command(
+    about = "Greenion client agent. Opens the connection of a greenion-open:// link when no command is given.",
+    args_conflicts_with_subcommands = true
+)]
+pub struct ClientArgs {
+    /// greenion-open:// link given by the web application
+    pub uri: Option<String>,
+    #[command(subcommand)]
+    pub command: Option<ClientCommand>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ClientCommand {
+    /// Pair this device with a server agent, using the one-time code shown by `greenion-server pair`
+    Pair {
+        /// Address of the server agent, as host or host:port
+        address: String,
+        /// Pairing code. Asked for when not given
+        #[arg(long)]
+        code: Option<String>,
+        /// Name of this device on the server. Defaults to the host name
+        #[arg(long)]
+        name: Option<String>,
+    },
+    /// Connect to a server agent this device is paired with
+    Connect {
+        /// Machine id of the server
+        machine_id: String,
+    },
+}
+
+pub fn get_jwt(uri: Option<&str>) -> anyhow::Result<String> {
+    let uri_string = match uri {
         Some(v) => v,
         None => {
             error!("Error when retrieving JWT : Not enough arguments");
@@ -2,13 +2,18 @@ use anyhow::anyhow;
 use log::error;
 use serde::Deserialize;
 use std::{
+    collections::BTreeMap,
     fs::File,
     io::Read,
+    net::SocketAddr,
     path::{Path, PathBuf},
+    time::Duration,
 };
 use toml;
 
+use crate::auth::jwks_provider::{jwks_provider, JwksProvider, JwksSettings};
 use crate::client::utils::get_client_log_folder;
+use crate::conf::codecs::{default_decoder, DEFAULT_CODEC};
 
 #[derive(Debug, Deserialize, Clone, Default)]
 pub struct ClientConfig {
@@ -18,14 +23,32 @@ pub struct ClientConfig {
     pub client_network_config: ClientNetworkConfig,
     #[serde(default)]
     pub sanzu_client_launch_config: SanzuClientLaunchConfig,
+    #[serde(default)]
+    pub metrics_config: ClientMetricsConfig,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct ClientAuthConfig {
     #[serde(default = "default_ca_cert_file")]
     pub ca_cert_file: String,
-    #[serde(default = "default_jwks_url")]
-    pub jwks_url: String,
+    #[serde(default)]
+    pub ca_cert_dir: Option<String>,
+    #[serde(default = "default_accepted_ca_names")]
+    pub accepted_ca_names: Vec<String>,
+    #[serde(default)]
+    pub revocation_list_file: Option<String>,
+    #[serde(default)]
+    pub issuer: Option<String>,
+    #[serde(default = "default_discovery_refresh_secs")]
+    pub discovery_refresh_secs: u64,
+    #[serde(default)]
+    pub jwks_url: Option<String>,
+    #[serde(default)]
+    pub jwks_file: Option<String>,
+    #[serde(default)]
+    pub jwt_public_key_files: Vec<String>,
+    #[serde(default = "default_paired_servers_file")]
+    pub paired_servers_file: String,
     #[serde(default = "default_webapp_url")]
     pub webapp_url: String,
 }
@@ -37,6 +60,22 @@ impl Default for ClientAuthConfig {
     }
 }
 
+impl ClientAuthConfig {
+    pub fn jwks_settings(&self) -> JwksSettings<'_> {
+        JwksSettings {
+            issuer: self.issuer.as_deref(),
+            jwks_url: self.jwks_url.as_deref(),
+            jwks_file: self.jwks_file.as_deref(),
+            jwt_public_key_files: &self.jwt_public_key_files,
+            discovery_refresh_secs: self.discovery_refresh_secs,
+        }
+    }
+
+    pub fn jwks_provider(&self, timeout: Duration) -> JwksProvider {
+        jwks_provider(self.jwks_settings(), timeout)
+    }
+}
+
 fn default_ca_cert_file() -> String {
     if cfg!(target_os = "windows") {
         "C:\\Program Files (x86)\\GreenionClient\\Agent\\rootCA.crt".to_string()
@@ -44,8 +83,19 @@ fn default_ca_cert_file() -> String {
         "/etc/greenion-client/certs/rootCA.crt".to_string()
     }
 }
-fn default_jwks_url() -> String {
-    "http://greenion.local:5004/.well-known/jwks.json".to_string()
+fn default_accepted_ca_names() -> Vec<String> {
+    vec!["GREENION-CA".to_string()]
+}
+fn default_discovery_refresh_secs() -> u64 {
+    60 * 60
+}
+fn default_paired_servers_file() -> String {
+    dirs::config_dir()
+        .unwrap_or_default()
+        .join("GreenionClient")
+        .join("paired_servers.json")
+        .display()
+        .to_string()
 }
 fn default_webapp_url() -> String {
     "http://greenion.local:5001/".to_string()
@@ -88,6 +138,25 @@ pub struct SanzuClientLaunchConfig {
     pub sanzu_client_config_path: String,
     #[serde(default = "default_sanzu_log_file")]
     pub sanzu_log_file: String,
+    /// Codec families the sanzu client decodes, advertised to the server
+    #[serde(default = "default_sanzu_client_codecs")]
+    pub sanzu_client_codecs: Vec<String>,
+    #[serde(default)]
+    pub preferred_codec: Option<String>,
+    /// FFmpeg decoder of each codec family, instead of the default one
+    #[serde(default)]
+    pub sanzu_client_decoders: BTreeMap<String, String>,
+    /// Arguments added to the sanzu client command line, `{codec}` being replaced by the
+    /// decoder of the codec family the server picked
+    #[serde(default)]
+    pub sanzu_client_codec_args: Vec<String>,
+    /// Display advertised to the server, instead of the one found on Windows
+    #[serde(default)]
+    pub display_width: Option<u32>,
+    #[serde(default)]
+    pub display_height: Option<u32>,
+    #[serde(default)]
+    pub display_scale: Option<f32>,
 }
 
 impl Default for SanzuClientLaunchConfig {
@@ -97,6 +166,25 @@ impl Default for SanzuClientLaunchConfig {
     }
 }
 
+impl SanzuClientLaunchConfig {
+    /// Codec families the sanzu client decodes, leaving out those without a decoder.
+    pub fn codecs(&self) -> Vec<String> {
+        self.sanzu_client_codecs
+            .iter()
+            .filter(|family| self.decoder(family).is_some())
+            .cloned()
+            .collect()
+    }
+
+    /// FFmpeg decoder the sanzu client uses for the codec `family`.
+    pub fn decoder(&self, family: &str) -> Option<String> {
+        self.sanzu_client_decoders
+            .get(family)
+            .cloned()
+            .or_else(|| default_decoder(family).map(str::to_string))
+    }
+}
+
 fn default_sanzu_client_external_startup() -> bool {
     false
 }
@@ -120,6 +208,10 @@ fn default_sanzu_client_config_path() -> String {
     }
 }
 
+fn default_sanzu_client_codecs() -> Vec<String> {
+    vec![DEFAULT_CODEC.to_string()]
+}
+
 fn default_sanzu_log_file() -> String {
     get_client_log_folder()
         .unwrap_or(PathBuf::from("/var/log/"))
@@ -128,6 +220,26 @@ fn default_sanzu_log_file() -> String {
         .to_string()
 }
 
+#[derive(Debug, Deserialize, Clone)]
+pub struct ClientMetricsConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Metrics are served at `http://<listen_address>/metrics`
+    #[serde(default = "default_client_metrics_address")]
+    pub listen_address: SocketAddr,
+}
+
+impl Default for ClientMetricsConfig {
+    fn default() -> Self {
+        let c = String::new();
+        toml::from_str::<ClientMetricsConfig>(&c).unwrap()
+    }
+}
+
+fn default_client_metrics_address() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 9465))
+}
+
 pub fn build_client_config(config_file: &Path) -> anyhow::Result<ClientConfig> {
     let mut content = String::new();
     match File::open(config_file) {
@@ -0,0 +1,27 @@
+//! Codec families negotiated between the agents, and the FFmpeg encoders and decoders used
+//! for them unless the configuration says otherwise.
+
+/// Family, FFmpeg encoder of the sanzu server, FFmpeg decoder of the sanzu client
+const CODEC_FAMILIES: [(&str, &str, &str); 4] = [
+    ("h264", "libx264", "h264"),
+    ("hevc", "libx265", "hevc"),
+    ("av1", "libsvtav1", "libdav1d"),
+    ("vp9", "libvpx-vp9", "vp9"),
+];
+
+/// Family every agent knows, used when nothing else is configured
+pub const DEFAULT_CODEC: &str = "h264";
+
+pub fn default_encoder(family: &str) -> Option<&'static str> {
+    CODEC_FAMILIES
+        .iter()
+        .find(|(f, _, _)| *f == family)
+        .map(|(_, encoder, _)| *encoder)
+}
+
+pub fn default_decoder(family: &str) -> Option<&'static str> {
+    CODEC_FAMILIES
+        .iter()
+        .find(|(f, _, _)| *f == family)
+        .map(|(_, _, decoder)| *decoder)
+}
@@ -0,0 +1,90 @@
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+#[derive(Parser, Debug)]
+#[command(about = "Greenion server agent. Runs the agent when no command is given.")]
+pub struct ServerArgs {
+    #[command(subcommand)]
+    pub command: Option<ServerCommand>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ServerCommand {
+    /// Generate a key pair and have the certificate of this machine issued by the enrollment endpoint
+    Enroll {
+        /// Machine id to enroll. Defaults to `machine_id` from the enrollment config
+        #[arg(long)]
+        machine_id: Option<String>,
+        /// One-time enrollment token
+        #[arg(long, conflicts_with = "token_file")]
+        token: Option<String>,
+        /// File containing the one-time enrollment token. Defaults to `token_file` from the enrollment config
+        #[arg(long)]
+        token_file: Option<PathBuf>,
+    },
+    /// Renew the certificate of this machine now
+    Renew,
+    /// Generate a one-time code to pair a client device with this machine
+    Pair,
+    /// List the devices paired with this machine
+    Devices,
+    /// Remove a paired device
+    Unpair {
+        /// Name or id of the device
+        device: String,
+    },
+    /// Manage the agent running on this machine
+    Ctl {
+        #[command(subcommand)]
+        command: CtlCommand,
+        /// Control socket (named pipe on Windows). Defaults to `socket` from the control config
+        #[arg(long)]
+        socket: Option<String>,
+        /// Print the raw JSON response
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check that the audit log wasn't truncated or edited
+    VerifyAudit {
+        /// Audit log to check. Defaults to `audit_file` from the audit config
+        #[arg(long)]
+        file: Option<PathBuf>,
+    },
+    /// Network process started by the agent when privilege separation is enabled, with the
+    /// descriptors it passes on
+    #[command(hide = true)]
+    PrivsepNetwork {
+        #[arg(long)]
+        listener_fd: i32,
+        #[arg(long)]
+        supervisor_fd: i32,
+        #[arg(long)]
+        config_fd: Option<i32>,
+        #[arg(long)]
+        log_fd: Option<i32>,
+        #[arg(long)]
+        control_fd: Option<i32>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CtlCommand {
+    /// List running sessions and queued connections
+    Sessions,
+    /// End a running session
+    Kick {
+        /// Id of the session, as listed by `sessions`
+        session_id: u64,
+        /// Reason recorded in the logs and the audit log
+        #[arg(long, default_value = "no reason given")]
+        reason: String,
+    },
+    /// Stop starting new sessions, running ones go on
+    Drain,
+    /// Start new sessions again after a drain
+    Resume,
+    /// Show the certificate the agent serves and its machine id
+    Cert,
+    /// Report the health of the agent. Fails unless it is ok
+    Health,
+}
@@ -1,9 +1,15 @@
 use anyhow::anyhow;
 use clap::Parser;
+use ipnet::IpNet;
 use serde::Deserialize;
-use std::{fs::File, io::Read, path::Path};
+use std::{collections::BTreeMap, fs::File, io::Read, net::SocketAddr, path::Path, time::Duration};
 use toml;
 
+use crate::auth::{
+    jwks_provider::{jwks_provider, JwksProvider, JwksSettings},
+    PrivateKeyOptions,
+};
+use crate::conf::codecs::{default_encoder, DEFAULT_CODEC};
 use crate::standalone_server::utils::get_server_log_folder;
 
 #[derive(Debug, Deserialize, Clone, Default)]
@@ -14,6 +20,24 @@ pub struct ServerConfig {
     pub server_network_config: ServerNetworkConfig,
     #[serde(default)]
     pub sanzu_server_launch_config: SanzuServerLaunchConfig,
+    #[serde(default)]
+    pub enrollment_config: EnrollmentConfig,
+    #[serde(default)]
+    pub pairing_config: PairingConfig,
+    #[serde(default)]
+    pub audit_config: AuditConfig,
+    #[serde(default)]
+    pub rate_limit_config: RateLimitConfig,
+    #[serde(default)]
+    pub session_config: SessionConfig,
+    #[serde(default)]
+    pub control_config: ControlConfig,
+    #[serde(default)]
+    pub metrics_config: MetricsConfig,
+    #[serde(default)]
+    pub privsep_config: PrivsepConfig,
+    #[serde(default)]
+    pub warm_pool_config: WarmPoolConfig,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -22,8 +46,27 @@ pub struct ServerAuthConfig {
     pub cert_file: String,
     #[serde(default = "default_private_key_file")]
     pub private_key_file: String,
-    #[serde(default = "default_jwks_url")]
-    pub jwks_url: String,
+    #[serde(default = "default_cert_reload_interval_secs")]
+    pub cert_reload_interval_secs: u64,
+    #[serde(default = "default_private_key_passphrase_env")]
+    pub private_key_passphrase_env: String,
+    #[serde(default)]
+    pub private_key_passphrase_file: Option<String>,
+    /// Unset, unencrypted keys are loaded with a warning
+    #[serde(default)]
+    pub allow_unencrypted_private_key: Option<bool>,
+    #[serde(default)]
+    pub issuer: Option<String>,
+    #[serde(default = "default_discovery_refresh_secs")]
+    pub discovery_refresh_secs: u64,
+    #[serde(default)]
+    pub jwks_url: Option<String>,
+    #[serde(default)]
+    pub jwks_file: Option<String>,
+    #[serde(default)]
+    pub jwt_public_key_files: Vec<String>,
+    #[serde(default)]
+    pub policy_file: Option<String>,
     #[serde(default = "default_webapp_url")]
     pub webapp_url: String,
 }
@@ -35,6 +78,30 @@ impl Default for ServerAuthConfig {
     }
 }
 
+impl ServerAuthConfig {
+    pub fn jwks_settings(&self) -> JwksSettings<'_> {
+        JwksSettings {
+            issuer: self.issuer.as_deref(),
+            jwks_url: self.jwks_url.as_deref(),
+            jwks_file: self.jwks_file.as_deref(),
+            jwt_public_key_files: &self.jwt_public_key_files,
+            discovery_refresh_secs: self.discovery_refresh_secs,
+        }
+    }
+
+    pub fn jwks_provider(&self, timeout: Duration) -> JwksProvider {
+        jwks_provider(self.jwks_settings(), timeout)
+    }
+
+    pub fn private_key_options(&self) -> PrivateKeyOptions {
+        PrivateKeyOptions::new(
+            Some(self.private_key_passphrase_env.clone()).filter(|v| !v.is_empty()),
+            self.private_key_passphrase_file.clone(),
+            self.allow_unencrypted_private_key,
+        )
+    }
+}
+
 fn default_cert_file() -> String {
     if cfg!(target_os = "windows") {
         "C:\\Program Files (x86)\\GreenionServer\\Key\\cert.pem".to_string()
@@ -49,8 +116,14 @@ fn default_private_key_file() -> String {
         "/etc/greenion-server/certs/key.pem".to_string()
     }
 }
-fn default_jwks_url() -> String {
-    "http://greenion.local:5004/.well-known/jwks.json".to_string()
+fn default_cert_reload_interval_secs() -> u64 {
+    30
+}
+fn default_private_key_passphrase_env() -> String {
+    "GREENION_KEY_PASSPHRASE".to_string()
+}
+fn default_discovery_refresh_secs() -> u64 {
+    60 * 60
 }
 fn default_webapp_url() -> String {
     "http://greenion.local:5001/".to_string()
@@ -66,6 +139,12 @@ pub struct ServerNetworkConfig {
     pub timeout_secs: u16,
     #[serde(default = "default_handshake_timeout_secs")]
     pub handshake_timeout_secs: u16,
+    #[serde(default)]
+    pub allowed_source_cidrs: Vec<IpNet>,
+    #[serde(default)]
+    pub denied_source_cidrs: Vec<IpNet>,
+    #[serde(default)]
+    pub source_ip_claim: Option<String>,
 }
 
 impl Default for ServerNetworkConfig {
@@ -100,10 +179,34 @@ pub struct SanzuServerLaunchConfig {
     pub sanzu_server_config_path: String,
     #[serde(default = "default_sanzu_log_file")]
     pub sanzu_log_file: String,
+    /// FFmpeg encoder of the h264 family
     #[serde(default = "default_sanzu_server_codec")]
     pub sanzu_server_codec: String,
+    /// Codec families the sanzu servers may use, preferred first. Empty for h264 only
+    #[serde(default)]
+    pub sanzu_server_codecs: Vec<String>,
+    /// FFmpeg encoder of each codec family, instead of the default one
+    #[serde(default)]
+    #[arg(skip)]
+    pub sanzu_server_encoders: BTreeMap<String, String>,
+    #[serde(default = "default_sanzu_server_args")]
+    pub sanzu_server_args: Vec<String>,
     #[serde(default = "default_sanzu_server_startup_timeout")]
     pub sanzu_server_startup_timeout: u64,
+    /// Text the sanzu server writes on its standard error once it accepts connections
+    #[serde(default)]
+    pub sanzu_server_ready_marker: Option<String>,
+    /// Environment variables of the sanzu server, their values are templates like its
+    /// arguments
+    #[serde(default)]
+    #[arg(skip)]
+    pub sanzu_server_env: BTreeMap<String, String>,
+    /// Template rendered for each sanzu server into `sanzu_server_generated_config_dir`,
+    /// `{config}` then names the generated file
+    #[serde(default)]
+    pub sanzu_server_config_template: Option<String>,
+    #[serde(default = "default_sanzu_server_generated_config_dir")]
+    pub sanzu_server_generated_config_dir: String,
 }
 
 impl Default for SanzuServerLaunchConfig {
@@ -113,6 +216,49 @@ impl Default for SanzuServerLaunchConfig {
     }
 }
 
+impl SanzuServerLaunchConfig {
+    /// Codec families the sanzu servers may use, preferred first, leaving out those without
+    /// an encoder. Never empty.
+    pub fn codecs(&self) -> Vec<String> {
+        let codecs = self
+            .sanzu_server_codecs
+            .iter()
+            .filter(|family| self.encoder(family).is_some())
+            .cloned()
+            .collect::<Vec<_>>();
+        if codecs.is_empty() {
+            vec![DEFAULT_CODEC.to_string()]
+        } else {
+            codecs
+        }
+    }
+
+    /// FFmpeg encoders of the codec families the sanzu servers may use.
+    pub fn encoders(&self) -> Vec<String> {
+        self.codecs()
+            .iter()
+            .filter_map(|family| self.encoder(family))
+            .collect()
+    }
+
+    /// FFmpeg encoder of the preferred codec family.
+    pub fn preferred_encoder(&self) -> String {
+        self.encoder(&self.codecs()[0])
+            .unwrap_or_else(|| self.sanzu_server_codec.clone())
+    }
+
+    /// FFmpeg encoder the sanzu servers use for the codec `family`.
+    pub fn encoder(&self, family: &str) -> Option<String> {
+        if let Some(encoder) = self.sanzu_server_encoders.get(family) {
+            return Some(encoder.clone());
+        }
+        if family == DEFAULT_CODEC {
+            return Some(self.sanzu_server_codec.clone());
+        }
+        default_encoder(family).map(str::to_string)
+    }
+}
+
 fn default_sanzu_server_port() -> u16 {
     1122
 }
@@ -142,9 +288,369 @@ fn default_sanzu_log_file() -> String {
 fn default_sanzu_server_codec() -> String {
     "libx264".to_string()
 }
+fn default_sanzu_server_args() -> Vec<String> {
+    [
+        "-x",
+        "-f",
+        "{config}",
+        "-e",
+        "{codec}",
+        "-a",
+        "127.0.0.1",
+        "-p",
+        "{port}",
+    ]
+    .iter()
+    .map(|a| a.to_string())
+    .collect()
+}
 fn default_sanzu_server_startup_timeout() -> u64 {
     5
 }
+fn default_sanzu_server_generated_config_dir() -> String {
+    if cfg!(target_os = "windows") {
+        std::env::temp_dir()
+            .join("greenion-sanzu")
+            .to_string_lossy()
+            .to_string()
+    } else {
+        "/run/greenion-server/sanzu".to_string()
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct EnrollmentConfig {
+    #[serde(default = "default_enrollment_enabled")]
+    pub enabled: bool,
+    #[serde(default = "default_enrollment_url")]
+    pub enrollment_url: String,
+    #[serde(default)]
+    pub machine_id: Option<String>,
+    #[serde(default = "default_enrollment_token_file")]
+    pub token_file: String,
+    #[serde(default = "default_renew_before_days")]
+    pub renew_before_days: u64,
+    #[serde(default = "default_renewal_check_interval_secs")]
+    pub renewal_check_interval_secs: u64,
+    #[serde(default = "default_enrollment_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+impl Default for EnrollmentConfig {
+    fn default() -> Self {
+        let c = String::new();
+        toml::from_str::<EnrollmentConfig>(&c).unwrap()
+    }
+}
+
+fn default_enrollment_enabled() -> bool {
+    false
+}
+fn default_enrollment_url() -> String {
+    "http://greenion.local:5004/api_auth/v1/enrollment".to_string()
+}
+fn default_enrollment_token_file() -> String {
+    if cfg!(target_os = "windows") {
+        "C:\\Program Files (x86)\\GreenionServer\\Key\\enrollment_token".to_string()
+    } else {
+        "/etc/greenion-server/certs/enrollment_token".to_string()
+    }
+}
+fn default_renew_before_days() -> u64 {
+    30
+}
+fn default_renewal_check_interval_secs() -> u64 {
+    6 * 60 * 60
+}
+fn default_enrollment_timeout_secs() -> u64 {
+    10
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct PairingConfig {
+    #[serde(default = "default_pairing_enabled")]
+    pub enabled: bool,
+    #[serde(default = "default_devices_file")]
+    pub devices_file: String,
+    #[serde(default = "default_pairing_code_file")]
+    pub pairing_code_file: String,
+    #[serde(default = "default_pairing_code_validity_secs")]
+    pub code_validity_secs: u64,
+}
+
+impl Default for PairingConfig {
+    fn default() -> Self {
+        let c = String::new();
+        toml::from_str::<PairingConfig>(&c).unwrap()
+    }
+}
+
+fn default_pairing_enabled() -> bool {
+    false
+}
+fn default_devices_file() -> String {
+    if cfg!(target_os = "windows") {
+        "C:\\Program Files (x86)\\GreenionServer\\Pairing\\paired_devices.json".to_string()
+    } else {
+        "/var/lib/greenion-server/paired_devices.json".to_string()
+    }
+}
+fn default_pairing_code_file() -> String {
+    if cfg!(target_os = "windows") {
+        "C:\\Program Files (x86)\\GreenionServer\\Pairing\\pairing_code.json".to_string()
+    } else {
+        "/var/lib/greenion-server/pairing_code.json".to_string()
+    }
+}
+fn default_pairing_code_validity_secs() -> u64 {
+    5 * 60
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct AuditConfig {
+    #[serde(default = "default_audit_enabled")]
+    pub enabled: bool,
+    #[serde(default = "default_audit_file")]
+    pub audit_file: String,
+    /// Key of the HMAC chaining the records, only readable by root and out of the folder of
+    /// the audit log
+    #[serde(default = "default_audit_key_file")]
+    pub key_file: String,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        let c = String::new();
+        toml::from_str::<AuditConfig>(&c).unwrap()
+    }
+}
+
+fn default_audit_enabled() -> bool {
+    true
+}
+fn default_audit_file() -> String {
+    get_server_log_folder()
+        .join("audit.jsonl")
+        .to_string_lossy()
+        .to_string()
+}
+fn default_audit_key_file() -> String {
+    if cfg!(target_os = "windows") {
+        "C:\\Program Files (x86)\\GreenionServer\\Key\\audit.key".to_string()
+    } else {
+        "/etc/greenion-server/audit.key".to_string()
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ControlConfig {
+    #[serde(default = "default_control_enabled")]
+    pub enabled: bool,
+    /// Unix socket on Linux, named pipe on Windows
+    #[serde(default = "default_control_socket")]
+    pub socket: String,
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        let c = String::new();
+        toml::from_str::<ControlConfig>(&c).unwrap()
+    }
+}
+
+fn default_control_enabled() -> bool {
+    true
+}
+fn default_control_socket() -> String {
+    if cfg!(target_os = "windows") {
+        r"\\.\pipe\greenion-server-control".to_string()
+    } else {
+        "/run/greenion-server/control.sock".to_string()
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct MetricsConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Metrics are served at `http://<listen_address>/metrics`
+    #[serde(default = "default_server_metrics_address")]
+    pub listen_address: SocketAddr,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        let c = String::new();
+        toml::from_str::<MetricsConfig>(&c).unwrap()
+    }
+}
+
+fn default_server_metrics_address() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 9464))
+}
+
+/// Privilege separation, Linux only : a supervisor keeps the privileges to sign with the
+/// private key and start sanzu servers, everything facing the network runs as `user`.
+#[derive(Debug, Deserialize, Clone)]
+pub struct PrivsepConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Unprivileged user running the network process, its primary group is used too
+    #[serde(default = "default_privsep_user")]
+    pub user: String,
+    /// Confine the network process with landlock and seccomp
+    #[serde(default = "default_privsep_sandbox")]
+    pub sandbox: bool,
+    /// Folder given to `user`, the pairing files must be in it
+    #[serde(default = "default_privsep_state_dir")]
+    pub state_dir: String,
+}
+
+impl Default for PrivsepConfig {
+    fn default() -> Self {
+        let c = String::new();
+        toml::from_str::<PrivsepConfig>(&c).unwrap()
+    }
+}
+
+fn default_privsep_user() -> String {
+    "greenion-server".to_string()
+}
+fn default_privsep_sandbox() -> bool {
+    true
+}
+fn default_privsep_state_dir() -> String {
+    "/var/lib/greenion-server".to_string()
+}
+
+/// Sanzu servers started ahead of the sessions, each handed to the next session that starts.
+#[derive(Debug, Deserialize, Clone)]
+pub struct WarmPoolConfig {
+    /// Sanzu servers kept started and ready, 0 disables the pool
+    #[serde(default)]
+    pub size: usize,
+    /// A sanzu server unused for this long is replaced with a fresh one, 0 keeps it
+    #[serde(default = "default_warm_pool_idle_recycle_secs")]
+    pub idle_recycle_secs: u64,
+}
+
+impl Default for WarmPoolConfig {
+    fn default() -> Self {
+        let c = String::new();
+        toml::from_str::<WarmPoolConfig>(&c).unwrap()
+    }
+}
+
+fn default_warm_pool_idle_recycle_secs() -> u64 {
+    3600
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct RateLimitConfig {
+    #[serde(default = "default_rate_limit_enabled")]
+    pub enabled: bool,
+    #[serde(default = "default_max_connections_per_minute")]
+    pub max_connections_per_minute: u32,
+    #[serde(default = "default_max_failed_auth")]
+    pub max_failed_auth: u32,
+    #[serde(default = "default_failed_auth_window_secs")]
+    pub failed_auth_window_secs: u64,
+    #[serde(default = "default_ban_duration_secs")]
+    pub ban_duration_secs: u64,
+    #[serde(default = "default_max_ban_duration_secs")]
+    pub max_ban_duration_secs: u64,
+    #[serde(default)]
+    pub allow_list: Vec<IpNet>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        let c = String::new();
+        toml::from_str::<RateLimitConfig>(&c).unwrap()
+    }
+}
+
+fn default_rate_limit_enabled() -> bool {
+    true
+}
+fn default_max_connections_per_minute() -> u32 {
+    30
+}
+fn default_max_failed_auth() -> u32 {
+    5
+}
+fn default_failed_auth_window_secs() -> u64 {
+    5 * 60
+}
+fn default_ban_duration_secs() -> u64 {
+    10 * 60
+}
+fn default_max_ban_duration_secs() -> u64 {
+    24 * 60 * 60
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SessionConfig {
+    #[serde(default = "default_max_sessions")]
+    pub max_sessions: usize,
+    #[serde(default = "default_takeover_policy")]
+    pub takeover_policy: TakeoverPolicy,
+    #[serde(default = "default_takeover_question_timeout_secs")]
+    pub takeover_question_timeout_secs: u64,
+    /// Keep connections waiting when every slot is in use, instead of refusing them
+    #[serde(default)]
+    pub queue_enabled: bool,
+    #[serde(default = "default_max_queue_size")]
+    pub max_queue_size: usize,
+    #[serde(default = "default_max_queue_wait_secs")]
+    pub max_queue_wait_secs: u64,
+    #[serde(default = "default_queue_update_interval_secs")]
+    pub queue_update_interval_secs: u64,
+    /// How long running sessions may go on once the agent is asked to stop
+    #[serde(default = "default_shutdown_drain_secs")]
+    pub shutdown_drain_secs: u64,
+}
+
+/// What to do when a user connects while they already have a session on this server.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TakeoverPolicy {
+    /// End the current session and start the new one
+    Takeover,
+    /// Refuse the new connection
+    Deny,
+    /// Ask the new client whether to take over the current session
+    Ask,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        let c = String::new();
+        toml::from_str::<SessionConfig>(&c).unwrap()
+    }
+}
+
+fn default_max_sessions() -> usize {
+    1
+}
+fn default_takeover_policy() -> TakeoverPolicy {
+    TakeoverPolicy::Takeover
+}
+fn default_takeover_question_timeout_secs() -> u64 {
+    60
+}
+fn default_max_queue_size() -> usize {
+    10
+}
+fn default_max_queue_wait_secs() -> u64 {
+    1800
+}
+fn default_queue_update_interval_secs() -> u64 {
+    15
+}
+fn default_shutdown_drain_secs() -> u64 {
+    60
+}
 
 pub fn default_windows_wakeup_exe_path() -> String {
     if cfg!(target_os = "windows") {
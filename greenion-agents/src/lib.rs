@@ -1,5 +1,6 @@
 use std::{
     collections::HashMap,
+    fs::File,
     path::Path,
     time::{Duration, SystemTime},
 };
@@ -12,12 +13,20 @@ use reqwest::{Client, StatusCode};
 pub mod auth;
 pub mod client;
 pub mod conf;
+pub mod metrics;
 pub mod proto;
 pub mod standalone_server;
 
 pub fn setup_fern(logfile: &Path) -> anyhow::Result<()> {
-    let fern_logfile = fern::log_file(logfile);
+    let logfile = fern::log_file(logfile).map_err(|e| {
+        println!("Could not create log file {} : {}", logfile.display(), e);
+        println!("Logging only to stdout");
+    });
+    setup_fern_with(logfile.ok())
+}
 
+/// Logs to stdout, and to `logfile` when there is one.
+pub fn setup_fern_with(logfile: Option<File>) -> anyhow::Result<()> {
     let mut fern_builder = fern::Dispatch::new()
         .format(|out, message, record| {
             if record.level() == Level::Error {
@@ -43,12 +52,8 @@ pub fn setup_fern(logfile: &Path) -> anyhow::Result<()> {
         .level(log::LevelFilter::Debug)
         .chain(std::io::stdout());
 
-    match fern_logfile {
-        Ok(flogfile) => fern_builder = fern_builder.chain(flogfile),
-        Err(e) => {
-            println!("Could not create log file {} : {}", logfile.display(), e);
-            println!("Logging only to stdout");
-        }
+    if let Some(logfile) = logfile {
+        fern_builder = fern_builder.chain(logfile);
     }
     if let Err(e) = fern_builder.apply() {
         error!("Could not setup logging : {}", e);
@@ -85,6 +90,9 @@ pub async fn close_session(close_session_args: CloseSessionArgs) -> anyhow::Resu
 
     info!("Closing session id {} at url {}", session_id, url);
     let res = _client.put(url).json(&map).bearer_auth(jwt).send().await;
+    if !res.as_ref().is_ok_and(|v| v.status() == StatusCode::OK) {
+        metrics::metrics().close_session_failures.inc();
+    }
 
     match res {
         Ok(v) => {
@@ -0,0 +1,425 @@
+use log::{debug, error, info};
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        LazyLock, Mutex,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::{TcpListener, TcpStream},
+};
+
+const LATENCY_BUCKETS: &[f64] = &[0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+const SESSION_BUCKETS: &[f64] = &[
+    60.0, 300.0, 900.0, 1800.0, 3600.0, 7200.0, 14400.0, 28800.0, 86400.0,
+];
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, v: u64) {
+        self.0.fetch_add(v, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Counter split by the value of one label.
+#[derive(Debug, Default)]
+pub struct LabeledCounter(Mutex<BTreeMap<&'static str, u64>>);
+
+impl LabeledCounter {
+    pub fn inc(&self, label: &'static str) {
+        *self
+            .0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(label)
+            .or_default() += 1;
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn set(&self, v: i64) {
+        self.0.store(v, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug)]
+pub struct Histogram {
+    buckets: &'static [f64],
+    counts: Vec<AtomicU64>,
+    /// Sum of the observed values, in microunits
+    sum: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Self {
+        Self {
+            buckets,
+            counts: buckets.iter().map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let v = duration.as_secs_f64();
+        for (bound, count) in self.buckets.iter().zip(&self.counts) {
+            if v <= *bound {
+                count.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.sum
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Metrics of the running agent, exposed by `serve_metrics` in the Prometheus text format.
+/// They are always kept up to date : exposing them is what the configuration enables.
+#[derive(Debug)]
+pub struct Metrics {
+    /// Server : TCP connections accepted. Client : connections established to the server
+    pub connections: Counter,
+    /// Server : connections dropped before any TLS work, by reason
+    pub refused_connections: LabeledCounter,
+    /// Handshakes by outcome, `ok` or why they failed
+    pub handshakes: LabeledCounter,
+    /// Server : bans of source addresses that failed authentication too often
+    pub source_bans: Counter,
+    /// Server : source addresses currently banned
+    pub banned_sources: Gauge,
+    /// From the TCP connection to the authentication result
+    pub handshake_duration: Histogram,
+    pub jwks_fetch_duration: Histogram,
+    pub jwks_fetch_failures: Counter,
+    pub active_sessions: Gauge,
+    /// Bytes forwarded from the client to the server
+    pub upstream_bytes: AtomicU64,
+    /// Bytes forwarded from the server to the client
+    pub downstream_bytes: AtomicU64,
+    pub sanzu_start_failures: Counter,
+    /// From starting a sanzu server to it accepting connections
+    pub sanzu_startup_duration: Histogram,
+    /// Sanzu servers that failed on their own while their session ran, with a non-zero exit
+    /// status or killed by a signal
+    pub sanzu_crashes: Counter,
+    pub close_session_failures: Counter,
+    pub session_duration: Histogram,
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(|| Metrics {
+    connections: Counter::default(),
+    refused_connections: LabeledCounter::default(),
+    handshakes: LabeledCounter::default(),
+    source_bans: Counter::default(),
+    banned_sources: Gauge::default(),
+    handshake_duration: Histogram::new(LATENCY_BUCKETS),
+    jwks_fetch_duration: Histogram::new(LATENCY_BUCKETS),
+    jwks_fetch_failures: Counter::default(),
+    active_sessions: Gauge::default(),
+    upstream_bytes: AtomicU64::new(0),
+    downstream_bytes: AtomicU64::new(0),
+    sanzu_start_failures: Counter::default(),
+    sanzu_startup_duration: Histogram::new(LATENCY_BUCKETS),
+    sanzu_crashes: Counter::default(),
+    close_session_failures: Counter::default(),
+    session_duration: Histogram::new(SESSION_BUCKETS),
+});
+
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn write_counter(out: &mut String, name: &str, help: &str, value: u64) {
+    write_header(out, name, "counter", help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn write_labeled_counter(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    label: &str,
+    counter: &LabeledCounter,
+) {
+    write_header(out, name, "counter", help);
+    for (value, count) in counter.0.lock().unwrap_or_else(|e| e.into_inner()).iter() {
+        let _ = writeln!(out, "{}{{{}=\"{}\"}} {}", name, label, value, count);
+    }
+}
+
+fn write_gauge(out: &mut String, name: &str, help: &str, gauge: &Gauge) {
+    write_header(out, name, "gauge", help);
+    let _ = writeln!(out, "{} {}", name, gauge.0.load(Ordering::Relaxed));
+}
+
+fn write_histogram(out: &mut String, name: &str, help: &str, histogram: &Histogram) {
+    write_header(out, name, "histogram", help);
+    for (bound, count) in histogram.buckets.iter().zip(&histogram.counts) {
+        let _ = writeln!(
+            out,
+            "{}_bucket{{le=\"{}\"}} {}",
+            name,
+            bound,
+            count.load(Ordering::Relaxed)
+        );
+    }
+    let count = histogram.count.load(Ordering::Relaxed);
+    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+    let _ = writeln!(
+        out,
+        "{}_sum {}",
+        name,
+        histogram.sum.load(Ordering::Relaxed) as f64 / 1e6
+    );
+    let _ = writeln!(out, "{}_count {}", name, count);
+}
+
+impl Metrics {
+    /// Metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        write_counter(
+            &mut out,
+            "greenion_connections_total",
+            "Connections accepted by the server agent, or established by the client agent",
+            self.connections.get(),
+        );
+
+        write_labeled_counter(
+            &mut out,
+            "greenion_connections_refused_total",
+            "Connections dropped by the server agent before any TLS work, by reason",
+            "reason",
+            &self.refused_connections,
+        );
+        write_labeled_counter(
+            &mut out,
+            "greenion_handshakes_total",
+            "Handshakes by outcome",
+            "outcome",
+            &self.handshakes,
+        );
+        write_counter(
+            &mut out,
+            "greenion_source_bans_total",
+            "Bans of source addresses that failed authentication too often",
+            self.source_bans.get(),
+        );
+        write_gauge(
+            &mut out,
+            "greenion_banned_sources",
+            "Source addresses currently banned",
+            &self.banned_sources,
+        );
+        write_histogram(
+            &mut out,
+            "greenion_handshake_duration_seconds",
+            "Time from the TCP connection to the authentication result",
+            &self.handshake_duration,
+        );
+        write_histogram(
+            &mut out,
+            "greenion_jwks_fetch_duration_seconds",
+            "Time to fetch the token validation keys",
+            &self.jwks_fetch_duration,
+        );
+        write_counter(
+            &mut out,
+            "greenion_jwks_fetch_failures_total",
+            "Failed fetches of the token validation keys",
+            self.jwks_fetch_failures.get(),
+        );
+
+        write_gauge(
+            &mut out,
+            "greenion_active_sessions",
+            "Sessions being forwarded",
+            &self.active_sessions,
+        );
+
+        write_header(
+            &mut out,
+            "greenion_forwarded_bytes_total",
+            "counter",
+            "Bytes forwarded, upstream from the client to the server or downstream",
+        );
+        let _ = writeln!(
+            out,
+            "greenion_forwarded_bytes_total{{direction=\"upstream\"}} {}",
+            self.upstream_bytes.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "greenion_forwarded_bytes_total{{direction=\"downstream\"}} {}",
+            self.downstream_bytes.load(Ordering::Relaxed)
+        );
+
+        write_counter(
+            &mut out,
+            "greenion_sanzu_start_failures_total",
+            "Sanzu servers or clients that could not be started or reached",
+            self.sanzu_start_failures.get(),
+        );
+        write_histogram(
+            &mut out,
+            "greenion_sanzu_startup_duration_seconds",
+            "From starting a sanzu server to it accepting connections",
+            &self.sanzu_startup_duration,
+        );
+        write_counter(
+            &mut out,
+            "greenion_sanzu_crashes_total",
+            "Sanzu servers that exited on their own while their session ran",
+            self.sanzu_crashes.get(),
+        );
+        write_counter(
+            &mut out,
+            "greenion_close_session_failures_total",
+            "Sessions that could not be closed in the web application",
+            self.close_session_failures.get(),
+        );
+        write_histogram(
+            &mut out,
+            "greenion_session_duration_seconds",
+            "Duration of the sessions that ended",
+            &self.session_duration,
+        );
+        out
+    }
+}
+
+/// Stream counting the bytes read from and written to it, into every counter given.
+pub struct CountingStream<'a, S> {
+    pub inner: &'a mut S,
+    pub read: &'a [&'a AtomicU64],
+    pub written: &'a [&'a AtomicU64],
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CountingStream<'_, S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let res = Pin::new(&mut *self.inner).poll_read(cx, buf);
+        let read = (buf.filled().len() - before) as u64;
+        for counter in self.read {
+            counter.fetch_add(read, Ordering::Relaxed);
+        }
+        res
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CountingStream<'_, S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut *self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = res {
+            for counter in self.written {
+                counter.fetch_add(written as u64, Ordering::Relaxed);
+            }
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_shutdown(cx)
+    }
+}
+
+async fn answer_scrape(mut stream: TcpStream) -> io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        if request.len() > MAX_REQUEST_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request too large",
+            ));
+        }
+        match stream.read(&mut buf).await? {
+            0 => return Ok(()),
+            n => request.extend_from_slice(&buf[..n]),
+        }
+    }
+
+    let response = if request.starts_with(b"GET /metrics ") {
+        let body = metrics().render();
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_owned()
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Serves the metrics at `http://<listen_address>/metrics`.
+pub async fn serve_metrics(listen_address: SocketAddr) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(listen_address).await?;
+    info!("Serving metrics on http://{}/metrics", listen_address);
+    loop {
+        let (stream, peer_addr) = match listener.accept().await {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Could not accept metrics connection : {}", e);
+                continue;
+            }
+        };
+        tokio::spawn(async move {
+            match tokio::time::timeout(REQUEST_TIMEOUT, answer_scrape(stream)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => debug!("Metrics request of {} failed : {}", peer_addr, e),
+                Err(_) => debug!("Metrics request of {} timed out", peer_addr),
+            }
+        });
+    }
+}
@@ -4,13 +4,83 @@ use anyhow::{anyhow, Error, Result};
 use byteorder::{ByteOrder, LittleEndian};
 use log::error;
 use prost::Message;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio_rustls::TlsStream;
 
 const MAX_PACKET_SIZE: u32 = 10 * 1024 * 1024;
 
+/// First client version that knows the start statuses of the takeover question, the queue,
+/// the shutdown and the codec negotiation. Older ones decode them as StartProxy.
+pub const SESSION_STATUSES_VERSION: &str = "v0.0.2";
+/// First client version that reads the frames of the session stream.
+pub const SESSION_NOTICES_VERSION: &str = "v0.0.2";
+/// Frame of the session stream carrying sanzu data.
+pub const SESSION_DATA_FRAME: u8 = 0;
+/// Frame of the session stream carrying a `SessionNotice`.
+pub const SESSION_NOTICE_FRAME: u8 = 1;
+
+/// Agents of the same major and minor version understand each other : later patch versions
+/// only add messages and fields, sent to the clients that tell they know them.
 pub fn check_version_matches(client_ver: &str, server_ver: &str) -> bool {
-    client_ver == server_ver
+    match (parse_version(client_ver), parse_version(server_ver)) {
+        (Some(client), Some(server)) => client[..2] == server[..2],
+        _ => client_ver == server_ver,
+    }
+}
+
+/// Whether `version` is `min` or later. Versions that don't parse are older than any other.
+pub fn version_at_least(version: &str, min: &str) -> bool {
+    match (parse_version(version), parse_version(min)) {
+        (Some(version), Some(min)) => version >= min,
+        _ => false,
+    }
+}
+
+/// `v<major>.<minor>.<patch>`
+fn parse_version(version: &str) -> Option<[u32; 3]> {
+    let mut parts = version.strip_prefix('v')?.split('.');
+    let mut parsed = [0; 3];
+    for part in parsed.iter_mut() {
+        *part = parts.next()?.parse().ok()?;
+    }
+    parts.next().is_none().then_some(parsed)
+}
+
+/// Writes a frame of the session stream : its kind, the length of its payload and the payload.
+pub async fn write_session_frame<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    kind: u8,
+    payload: &[u8],
+) -> std::io::Result<()> {
+    let mut header = [kind, 0, 0, 0, 0];
+    LittleEndian::write_u32(&mut header[1..], payload.len() as u32);
+    stream.write_all(&header).await?;
+    stream.write_all(payload).await
+}
+
+/// Reads a frame of the session stream into `payload`, and returns its kind. None when the
+/// stream ends between two frames.
+pub async fn read_session_frame<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    payload: &mut Vec<u8>,
+) -> std::io::Result<Option<u8>> {
+    let mut header = [0u8; 5];
+    match stream.read_exact(&mut header[..1]).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    stream.read_exact(&mut header[1..]).await?;
+    let len = LittleEndian::read_u32(&header[1..]);
+    if len > MAX_PACKET_SIZE {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("session frame of {} bytes, over the size limit", len),
+        ));
+    }
+    payload.resize(len as usize, 0);
+    stream.read_exact(payload).await?;
+    Ok(Some(header[0]))
 }
 
 pub async fn send_msg_async<T>(
@@ -69,3 +139,73 @@ pub async fn recv_msg_async(
 
     Ok(data_buffer)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions_match_on_major_and_minor() {
+        assert!(check_version_matches("v0.0.2", "v0.0.1"));
+        assert!(check_version_matches("v0.0.1", "v0.0.2"));
+        assert!(!check_version_matches("v0.1.0", "v0.0.1"));
+        assert!(!check_version_matches("v1.0.0", "v0.0.1"));
+        // versions that don't parse must be equal
+        assert!(check_version_matches("dev", "dev"));
+        assert!(!check_version_matches("dev", "v0.0.1"));
+    }
+
+    #[test]
+    fn versions_at_least() {
+        assert!(version_at_least("v0.0.2", "v0.0.2"));
+        assert!(version_at_least("v0.0.10", "v0.0.2"));
+        assert!(version_at_least("v0.1.0", "v0.0.2"));
+        assert!(!version_at_least("v0.0.1", "v0.0.2"));
+        assert!(!version_at_least("", "v0.0.2"));
+        assert!(!version_at_least("v0.0", "v0.0.2"));
+        assert!(!version_at_least("v0.0.2.1", "v0.0.2"));
+        assert!(!version_at_least("0.0.2", "v0.0.2"));
+    }
+
+    #[tokio::test]
+    async fn session_frames() {
+        let mut stream = vec![];
+        write_session_frame(&mut stream, SESSION_DATA_FRAME, b"sanzu data")
+            .await
+            .unwrap();
+        write_session_frame(&mut stream, SESSION_NOTICE_FRAME, b"")
+            .await
+            .unwrap();
+        assert_eq!(&stream[..5], &[SESSION_DATA_FRAME, 10, 0, 0, 0]);
+
+        let mut reader = stream.as_slice();
+        let mut payload = vec![];
+        let kind = read_session_frame(&mut reader, &mut payload).await.unwrap();
+        assert_eq!(
+            (kind, payload.as_slice()),
+            (Some(SESSION_DATA_FRAME), &b"sanzu data"[..])
+        );
+        let kind = read_session_frame(&mut reader, &mut payload).await.unwrap();
+        assert_eq!((kind, payload.len()), (Some(SESSION_NOTICE_FRAME), 0));
+        assert_eq!(
+            read_session_frame(&mut reader, &mut payload).await.unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn truncated_and_oversized_session_frames() {
+        let mut payload = vec![];
+        let mut truncated = &[SESSION_DATA_FRAME, 10, 0, 0, 0, b'a'][..];
+        assert!(read_session_frame(&mut truncated, &mut payload)
+            .await
+            .is_err());
+
+        let mut header = [SESSION_DATA_FRAME, 0, 0, 0, 0];
+        LittleEndian::write_u32(&mut header[1..], MAX_PACKET_SIZE + 1);
+        let e = read_session_frame(&mut &header[..], &mut payload)
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
@@ -1,19 +1,70 @@
+pub mod audit;
 pub mod authenticator;
-use std::{net::SocketAddr, time::Duration};
-use tokio::net::TcpStream;
+pub mod cert_resolver;
+pub mod control;
+#[cfg(feature = "enrollment")]
+pub mod enrollment;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::{net::TcpStream, sync::watch};
 use tokio_rustls::TlsStream;
 pub mod forwarder;
+pub mod pairing;
+pub mod policy;
+#[cfg(target_os = "linux")]
+pub mod privsep;
 pub mod process_client_connection;
+pub mod rate_limit;
+#[cfg(target_os = "linux")]
+pub mod sandbox;
+pub mod sanzu_launch;
+pub mod sanzu_readiness;
+pub mod sessions;
+pub mod shutdown;
+pub mod source_acl;
+pub mod systemd;
 pub mod utils;
+pub mod warm_pool;
 #[cfg(target_os = "windows")]
 use windows::Win32::Foundation::HANDLE;
 
+use crate::{
+    auth::jwks_provider::JwksProvider,
+    conf::server_config::SanzuServerLaunchConfig,
+    metrics::metrics,
+    standalone_server::{
+        audit::AuditLog,
+        pairing::PairingStore,
+        policy::PolicyProvider,
+        rate_limit::RateLimiter,
+        sanzu_launch::{GeneratedConfig, SanzuLaunch, SanzuLaunchVars},
+    },
+};
+
+/// A stopped sanzu server gets SIGKILL after 3 seconds, it is gone well before this
+const SANZU_STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub struct SanzuServerWrapper {
     pub sanzu_server_path: String,
-    pub sanzu_server_config: String,
-    pub sanzu_server_codec: String,
+    pub sanzu_server_args: Vec<String>,
+    pub sanzu_server_env: Vec<(String, String)>,
     pub sanzu_log_file: Option<String>,
-    pub sanzu_wait_time: Duration,
+    pub sanzu_server_port: u16,
+    /// How long the sanzu server has to listen on its port
+    pub sanzu_startup_timeout: Duration,
+    /// Also wait for the sanzu server to write this on its standard error
+    pub sanzu_ready_marker: Option<String>,
+    /// Removed once the sanzu server exited
+    sanzu_generated_config: Option<GeneratedConfig>,
     #[cfg(target_os = "windows")]
     pub wakeup_exe_path: String,
     #[cfg(target_os = "windows")]
@@ -25,27 +76,271 @@ pub struct SanzuServerWrapper {
 impl SanzuServerWrapper {
     pub fn new(
         sanzu_server_path: &str,
-        sanzu_server_config: &str,
-        sanzu_server_codec: &str,
+        sanzu_server_args: Vec<String>,
         sanzu_log_file: Option<String>,
-        sanzu_wait_time: Duration,
+        sanzu_server_port: u16,
+        sanzu_startup_timeout: Duration,
     ) -> Self {
         Self {
             sanzu_server_path: sanzu_server_path.to_string(),
-            sanzu_server_config: sanzu_server_config.to_string(),
-            sanzu_server_codec: sanzu_server_codec.to_string(),
+            sanzu_server_args,
+            sanzu_server_env: Vec::new(),
             #[cfg(target_os = "windows")]
             wakeup_exe_path: crate::conf::server_config::default_windows_wakeup_exe_path(),
             sanzu_log_file,
-            sanzu_wait_time,
+            sanzu_server_port,
+            sanzu_startup_timeout,
+            sanzu_ready_marker: None,
+            sanzu_generated_config: None,
             handles: None,
         }
     }
+
+    /// Sanzu server of a session, launched with the templates of `config` rendered with
+    /// `vars`.
+    pub fn from_config(
+        config: &SanzuServerLaunchConfig,
+        vars: &SanzuLaunchVars,
+    ) -> anyhow::Result<Self> {
+        let launch = SanzuLaunch::render(config, vars)?;
+        let mut sss = Self::new(
+            &launch.path,
+            launch.args,
+            Some(config.sanzu_log_file.clone()),
+            vars.port,
+            Duration::from_secs(config.sanzu_server_startup_timeout),
+        );
+        sss.sanzu_server_env = launch.env;
+        sss.sanzu_ready_marker = config.sanzu_server_ready_marker.clone();
+        sss.sanzu_generated_config = launch.generated_config;
+        Ok(sss)
+    }
+}
+
+/// How a sanzu server started by the agent ended.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SanzuExit {
+    /// Exit status as reported by the system
+    pub status: String,
+    /// The agent stopped it, it didn't exit on its own
+    pub stopped: bool,
+    /// It exited with a non-zero status or was killed by a signal. False when the status
+    /// is unknown
+    pub failed: bool,
+    /// Last lines the sanzu server wrote on its standard error
+    pub stderr_tail: Vec<String>,
+}
+
+impl SanzuExit {
+    /// It failed on its own, while the agent didn't stop it.
+    pub fn crashed(&self) -> bool {
+        self.failed && !self.stopped
+    }
+}
+
+impl fmt::Display for SanzuExit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.status)?;
+        if !self.stderr_tail.is_empty() {
+            write!(f, ", last errors : {}", self.stderr_tail.join(" | "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Sanzu server started for a session. It is stopped, with the processes it started, when
+/// this is dropped.
+pub struct SanzuProcess {
+    exit: watch::Receiver<Option<SanzuExit>>,
+    stop: SanzuStop,
+}
+
+enum SanzuStop {
+    Local(Arc<AtomicBool>),
+    #[cfg(target_os = "linux")]
+    Supervisor {
+        client: Arc<privsep::SupervisorClient>,
+        port: u16,
+    },
+}
+
+impl SanzuProcess {
+    /// How the sanzu server ended, if it did.
+    pub fn exit(&self) -> Option<SanzuExit> {
+        self.exit.borrow().clone()
+    }
+
+    /// Resolves once the sanzu server exited, or once nothing watches it anymore.
+    pub async fn exited(&self) -> SanzuExit {
+        let mut exit = self.exit.clone();
+        let exited = exit
+            .wait_for(Option::is_some)
+            .await
+            .map(|exit| exit.clone().unwrap_or_default());
+        exited.unwrap_or_else(|_| SanzuExit {
+            status: "exited, status unknown".to_string(),
+            ..Default::default()
+        })
+    }
+
+    /// How the sanzu server ended, if it exits within `grace`.
+    pub async fn exited_within(&self, grace: Duration) -> Option<SanzuExit> {
+        tokio::time::timeout(grace, self.exited()).await.ok()
+    }
+
+    /// Stops the sanzu server, resolving once its port is free again. None if it did not
+    /// exit in time.
+    pub async fn stop(self) -> Option<SanzuExit> {
+        self.request_stop();
+        let exit = self.exited_within(SANZU_STOP_TIMEOUT).await;
+        if exit.is_none() {
+            warn!(
+                "The sanzu server did not exit within {:?} of being stopped",
+                SANZU_STOP_TIMEOUT
+            );
+        }
+        exit
+    }
+
+    fn request_stop(&self) {
+        if self.exit.borrow().is_some() {
+            return;
+        }
+        match &self.stop {
+            SanzuStop::Local(stop) => stop.store(true, Ordering::Relaxed),
+            #[cfg(target_os = "linux")]
+            SanzuStop::Supervisor { client, port } => client.stop_sanzu(*port),
+        }
+    }
+}
+
+impl Drop for SanzuProcess {
+    fn drop(&mut self) {
+        self.request_stop();
+    }
+}
+
+/// Starts the sanzu server of a session.
+#[derive(Clone)]
+pub enum SanzuLauncher {
+    /// This process starts the sanzu servers
+    Local,
+    /// The privileged supervisor starts them for the network process
+    #[cfg(target_os = "linux")]
+    Supervisor(Arc<privsep::SupervisorClient>),
+}
+
+impl SanzuLauncher {
+    /// Starts a sanzu server for the session described by `vars`. Servers started here are
+    /// also killed once `stop_all` is set.
+    pub async fn launch(
+        &self,
+        config: &SanzuServerLaunchConfig,
+        vars: &SanzuLaunchVars,
+        stop_all: Arc<AtomicBool>,
+    ) -> anyhow::Result<SanzuProcess> {
+        let port = vars.port;
+        let (exit_tx, exit) = watch::channel(None);
+        let started_at = Instant::now();
+        let process = match self {
+            Self::Local => {
+                let mut sss = SanzuServerWrapper::from_config(config, vars)?;
+                let sss = tokio::task::spawn_blocking(move || sss.start().map(|()| sss)).await??;
+
+                let stop = Arc::new(AtomicBool::new(false));
+                let stop_session = Arc::clone(&stop);
+                tokio::task::spawn_blocking(move || {
+                    let exit = sss.wait(|| {
+                        stop_all.load(Ordering::Relaxed) || stop_session.load(Ordering::Relaxed)
+                    });
+                    let _ = exit_tx.send(Some(exit));
+                });
+                SanzuProcess {
+                    exit,
+                    stop: SanzuStop::Local(stop),
+                }
+            }
+            #[cfg(target_os = "linux")]
+            Self::Supervisor(client) => {
+                let starting = Arc::clone(client);
+                let vars = vars.clone();
+                if let Err(e) =
+                    tokio::task::spawn_blocking(move || starting.start_sanzu(&vars)).await?
+                {
+                    error!("The supervisor could not start the sanzu server : {}", e);
+                    return Err(e);
+                }
+
+                let waiting = Arc::clone(client);
+                tokio::task::spawn_blocking(move || match waiting.wait_sanzu(port) {
+                    Ok(exit) => {
+                        let _ = exit_tx.send(Some(exit));
+                    }
+                    Err(e) => {
+                        error!("Could not follow the sanzu server on port {} : {}", port, e)
+                    }
+                });
+                SanzuProcess {
+                    exit,
+                    stop: SanzuStop::Supervisor {
+                        client: Arc::clone(client),
+                        port,
+                    },
+                }
+            }
+        };
+        let startup = started_at.elapsed();
+        metrics().sanzu_startup_duration.observe(startup);
+        debug!(
+            "Sanzu server for '{}' accepts connections on port {}, after {} ms",
+            vars.user_id,
+            port,
+            startup.as_millis()
+        );
+        Ok(process)
+    }
+}
+
+/// Where sessions get their sanzu server from.
+#[derive(Clone)]
+pub struct SanzuServers {
+    pub launcher: SanzuLauncher,
+    pub warm_pool: Option<Arc<warm_pool::WarmPool>>,
+}
+
+impl SanzuServers {
+    /// Sanzu server of the session holding `slot` : the one of the warm pool on its port, or
+    /// a new one launched with `vars`.
+    pub async fn start_for(
+        &self,
+        slot: &sessions::SessionSlot,
+        config: &SanzuServerLaunchConfig,
+        vars: &SanzuLaunchVars,
+        stop_all: Arc<AtomicBool>,
+    ) -> anyhow::Result<SanzuProcess> {
+        if let (Some(pool), true) = (&self.warm_pool, slot.warm) {
+            if let Some(process) = pool.take(slot.sanzu_port, &vars.codec).await {
+                info!(
+                    "Session {} uses the sanzu server of the warm pool on port {}",
+                    slot.id, slot.sanzu_port
+                );
+                return Ok(process);
+            }
+        }
+        self.launcher.launch(config, vars, stop_all).await
+    }
 }
 
+#[derive(Clone)]
 pub struct Authenticator {
     pub local_machine_id: String,
-    pub jwks_url: String,
+    pub jwks_provider: Arc<JwksProvider>,
+    pub policy: Option<Arc<PolicyProvider>>,
+    pub pairing: Option<PairingStore>,
+    pub audit: AuditLog,
+    pub rate_limiter: Arc<RateLimiter>,
+    /// Claim holding the addresses a token may be used from
+    pub source_claim: Option<String>,
     pub timeout: Duration,
 }
 
@@ -55,6 +350,9 @@ pub struct StandaloneServerForwarder {
     pub client_addr: SocketAddr,
     pub client_id: String,
     pub timeout: Duration,
+    /// Set for clients that read the frames of the session stream : they are sent a notice
+    /// when the server is shutting down
+    pub shutdown_deadline: Option<watch::Receiver<Option<SystemTime>>>,
 }
 
 #[cfg(target_os = "linux")]
@@ -70,6 +368,9 @@ pub use sanzu_server_starter_windows as sanzu_server_starter;
 #[cfg(target_os = "linux")]
 pub struct SanzuServerLinuxHandles {
     pub sanzu_server_process_child: std::process::Child,
+    /// Last lines of the standard error, kept by the thread copying it to the log file
+    pub sanzu_server_stderr_tail: Arc<std::sync::Mutex<std::collections::VecDeque<String>>>,
+    pub sanzu_server_stderr_copier: std::thread::JoinHandle<()>,
 }
 
 #[cfg(target_os = "windows")]
@@ -0,0 +1,573 @@
+use anyhow::{anyhow, bail, Context};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, SecondsFormat, Utc};
+use hmac::{Hmac, Mac};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+
+use crate::auth::{random_bytes, write_file_atomically};
+
+/// How long `flush` waits for the records before it to be written
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What an audit record is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditEvent {
+    TlsAccepted,
+    TlsFailed,
+    AuthSucceeded,
+    AuthFailed,
+    DevicePaired,
+    PairingFailed,
+    /// Authenticated, but no session could be started
+    SessionRefused,
+    SessionStarted,
+    SessionEnded,
+}
+
+/// Fields of an audit record, set depending on the event.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AuditDetails {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// `jwt` or `device`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+    /// Name of a paired device, given by the device when pairing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<u32>,
+    /// SHA-256 of the connection token, base64 encoded. The token itself is never logged
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ended_at: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_secs: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bytes_from_client: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bytes_to_client: Option<u64>,
+}
+
+/// Event waiting to be written to the audit log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub time: String,
+    pub event: AuditEvent,
+    #[serde(flatten)]
+    pub details: AuditDetails,
+}
+
+/// One line of the audit log. `hash` is the HMAC-SHA256, with the key of the audit log, of
+/// the record serialized with an empty `hash`, and `prev_hash` the hash of the previous
+/// record (empty for the first one), so that editing, removing or reordering records breaks
+/// the chain, and that only the holder of the key can rebuild it.
+#[derive(Debug, Serialize, Deserialize)]
+struct AuditRecord {
+    seq: u64,
+    time: String,
+    event: AuditEvent,
+    #[serde(flatten)]
+    details: AuditDetails,
+    prev_hash: String,
+    #[serde(default)]
+    hash: String,
+}
+
+/// Last record written, kept next to the log so that removing records at its end is
+/// detected too.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+struct AuditHead {
+    seq: u64,
+    hash: String,
+}
+
+/// Secret key of the HMAC chaining the records.
+struct AuditKey(Vec<u8>);
+
+impl AuditKey {
+    /// Reads the key in `key_file`, base64 encoded.
+    fn read(key_file: &Path) -> anyhow::Result<Self> {
+        let content = fs::read_to_string(key_file)
+            .with_context(|| format!("Could not read audit key {}", key_file.display()))?;
+        let key = STANDARD
+            .decode(content.trim())
+            .with_context(|| format!("Audit key {} is invalid", key_file.display()))?;
+        if key.len() < 32 {
+            bail!("Audit key {} is too short", key_file.display());
+        }
+        Ok(Self(key))
+    }
+
+    /// Reads the key in `key_file`, or creates it when there is none yet.
+    fn read_or_create(key_file: &Path) -> anyhow::Result<Self> {
+        if key_file.exists() {
+            return Self::read(key_file);
+        }
+        let mut key = vec![0u8; 32];
+        random_bytes(&mut key)?;
+        write_file_atomically(key_file, STANDARD.encode(&key).as_bytes(), true)?;
+        info!("Created audit key {}", key_file.display());
+        Ok(Self(key))
+    }
+
+    fn mac(&self, content: &[u8]) -> String {
+        let mut mac =
+            <Hmac<Sha256> as Mac>::new_from_slice(&self.0).expect("HMAC accepts keys of any size");
+        mac.update(content);
+        STANDARD.encode(mac.finalize().into_bytes())
+    }
+}
+
+/// Whether `key_file` is out of the folder of the log at `path`, where whoever can write the
+/// log could read it.
+fn is_outside_log_folder(key_file: &Path, path: &Path) -> bool {
+    let folder = |p: &Path| {
+        let parent = p.parent().unwrap_or(Path::new(""));
+        fs::canonicalize(parent).unwrap_or_else(|_| parent.to_owned())
+    };
+    folder(key_file) != folder(path)
+}
+
+impl AuditRecord {
+    fn compute_hash(&self, key: &AuditKey) -> anyhow::Result<String> {
+        let unhashed = AuditRecord {
+            seq: self.seq,
+            time: self.time.clone(),
+            event: self.event,
+            details: self.details.clone(),
+            prev_hash: self.prev_hash.clone(),
+            hash: String::new(),
+        };
+        Ok(key.mac(&serde_json::to_vec(&unhashed)?))
+    }
+}
+
+pub fn token_sha256(token: &str) -> String {
+    STANDARD.encode(Sha256::digest(token.as_bytes()))
+}
+
+pub fn audit_time(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+fn head_path(path: &Path) -> PathBuf {
+    path.with_extension("head")
+}
+
+fn read_head(path: &Path) -> anyhow::Result<Option<AuditHead>> {
+    let head_path = head_path(path);
+    match fs::read(&head_path) {
+        Ok(v) => Ok(Some(serde_json::from_slice(&v).with_context(|| {
+            format!("Audit head file {} is invalid", head_path.display())
+        })?)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(anyhow!(
+            "Could not read audit head file {} : {}",
+            head_path.display(),
+            e
+        )),
+    }
+}
+
+/// Append-only audit log of connections, as JSON lines. Records are written by a thread of
+/// their own, in order. Cloning it is cheap, and every clone writes to the same file.
+#[derive(Clone)]
+pub struct AuditLog {
+    sender: Option<mpsc::Sender<AuditMessage>>,
+}
+
+enum AuditMessage {
+    Record(Box<AuditEntry>),
+    /// Answered once the records sent before are written
+    Flush(mpsc::Sender<()>),
+}
+
+struct AuditWriter {
+    path: PathBuf,
+    key: AuditKey,
+    file: File,
+    head: Option<AuditHead>,
+}
+
+impl AuditLog {
+    pub fn disabled() -> Self {
+        Self { sender: None }
+    }
+
+    /// Opens the audit log at `path`, creating it if needed, with the key in `key_file`,
+    /// created if needed too. Refuses a log that doesn't pass verification, so that a
+    /// tampered log isn't silently extended, and a key the writers of the log could read.
+    pub fn open(path: &Path, key_file: &Path) -> anyhow::Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if !is_outside_log_folder(key_file, path) {
+            bail!(
+                "Audit key {} must not be in the folder of the audit log",
+                key_file.display()
+            );
+        }
+        let key = AuditKey::read_or_create(key_file)?;
+        let head = match verify(path, &key) {
+            Ok(head) => head,
+            Err(e) => bail!(
+                "Audit log {} failed verification, move it aside to start a new one : {}",
+                path.display(),
+                e
+            ),
+        };
+
+        let mut options = OpenOptions::new();
+        options.append(true).create(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let file = options
+            .open(path)
+            .with_context(|| format!("Could not open audit log {}", path.display()))?;
+
+        let mut writer = AuditWriter {
+            path: path.to_owned(),
+            key,
+            file,
+            head,
+        };
+        Self::written_by(move |entry| {
+            if let Err(e) = writer.append(entry) {
+                error!(
+                    "Could not write to audit log {} : {}",
+                    writer.path.display(),
+                    e
+                );
+            }
+        })
+    }
+
+    /// Audit log whose records are handed to `write`, on a thread of their own.
+    pub fn written_by(mut write: impl FnMut(AuditEntry) + Send + 'static) -> anyhow::Result<Self> {
+        let (sender, receiver) = mpsc::channel();
+        thread::Builder::new()
+            .name("audit-writer".to_owned())
+            .spawn(move || {
+                for message in receiver {
+                    match message {
+                        AuditMessage::Record(entry) => write(*entry),
+                        AuditMessage::Flush(done) => {
+                            let _ = done.send(());
+                        }
+                    }
+                }
+            })
+            .context("Could not start the audit log writer")?;
+        Ok(Self {
+            sender: Some(sender),
+        })
+    }
+
+    /// Appends a record. Failures are logged, they never interrupt the connection.
+    pub fn record(&self, event: AuditEvent, details: AuditDetails) {
+        self.write(AuditEntry {
+            time: audit_time(Utc::now()),
+            event,
+            details,
+        });
+    }
+
+    /// Appends a record of an event that already happened.
+    pub fn write(&self, entry: AuditEntry) {
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(AuditMessage::Record(Box::new(entry)));
+        }
+    }
+
+    /// Waits for the records appended so far to be written, for a few seconds at most.
+    pub fn flush(&self) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        let (done, written) = mpsc::channel();
+        if sender.send(AuditMessage::Flush(done)).is_ok()
+            && written.recv_timeout(FLUSH_TIMEOUT).is_err()
+        {
+            warn!("Some audit records may not have been written");
+        }
+    }
+}
+
+impl AuditWriter {
+    fn append(&mut self, entry: AuditEntry) -> anyhow::Result<()> {
+        let mut record = AuditRecord {
+            seq: self.head.as_ref().map(|h| h.seq + 1).unwrap_or(0),
+            time: entry.time,
+            event: entry.event,
+            details: entry.details,
+            prev_hash: self
+                .head
+                .as_ref()
+                .map(|h| h.hash.clone())
+                .unwrap_or_default(),
+            hash: String::new(),
+        };
+        record.hash = record.compute_hash(&self.key)?;
+
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.file.sync_data()?;
+
+        let head = AuditHead {
+            seq: record.seq,
+            hash: record.hash,
+        };
+        write_file_atomically(&head_path(&self.path), &serde_json::to_vec(&head)?, true)?;
+        self.head = Some(head);
+        Ok(())
+    }
+}
+
+/// Checks the hash chain of the audit log at `path` with the key in `key_file`, and returns
+/// its number of records. Fails on the first record that was edited, removed or reordered.
+pub fn verify_audit_log(path: &Path, key_file: &Path) -> anyhow::Result<u64> {
+    let key = AuditKey::read(key_file)?;
+    Ok(verify(path, &key)?.map(|h| h.seq + 1).unwrap_or(0))
+}
+
+fn verify(path: &Path, key: &AuditKey) -> anyhow::Result<Option<AuditHead>> {
+    let file = match File::open(path) {
+        Ok(v) => Some(v),
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => bail!("Could not read audit log {} : {}", path.display(), e),
+    };
+
+    let mut last: Option<AuditHead> = None;
+    for (i, line) in file
+        .map(|f| BufReader::new(f).lines())
+        .into_iter()
+        .flatten()
+        .enumerate()
+    {
+        let line_number = i + 1;
+        let line = line.with_context(|| format!("Could not read line {}", line_number))?;
+        let value = serde_json::from_str::<serde_json::Value>(&line)
+            .with_context(|| format!("Line {} is not valid JSON", line_number))?;
+        let record = serde_json::from_value::<AuditRecord>(value.clone())
+            .with_context(|| format!("Line {} is not an audit record", line_number))?;
+
+        let expected_seq = last.as_ref().map(|h| h.seq + 1).unwrap_or(0);
+        let expected_prev_hash = last.as_ref().map(|h| h.hash.as_str()).unwrap_or("");
+        if record.seq != expected_seq || record.prev_hash != expected_prev_hash {
+            bail!(
+                "Line {} holds record {} where record {} was expected : records were removed or reordered",
+                line_number,
+                record.seq,
+                expected_seq
+            );
+        }
+        // fields added to a record don't change its hash, but do change its serialization
+        if record.compute_hash(key)? != record.hash || serde_json::to_value(&record)? != value {
+            bail!("Record {} (line {}) was modified", record.seq, line_number);
+        }
+        last = Some(AuditHead {
+            seq: record.seq,
+            hash: record.hash,
+        });
+    }
+
+    match (read_head(path)?, &last) {
+        (None, None) => {}
+        (Some(head), Some(last)) if head == *last => {}
+        (Some(head), Some(last)) if head.seq > last.seq => bail!(
+            "The log ends at record {} but record {} was written : records were removed from its end",
+            last.seq,
+            head.seq
+        ),
+        (Some(head), None) => bail!(
+            "The log is empty but record {} was written : it was truncated",
+            head.seq
+        ),
+        (None, Some(_)) => bail!(
+            "Head file {} is missing",
+            head_path(path).display()
+        ),
+        (Some(head), Some(last)) => bail!(
+            "The log ends at record {} which doesn't match the head file (record {})",
+            last.seq,
+            head.seq
+        ),
+    }
+    Ok(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    /// Paths of a log and of its key, in separate folders.
+    fn paths(dir: &TempDir) -> (PathBuf, PathBuf) {
+        (
+            dir.path().join("log").join("audit.log"),
+            dir.path().join("key").join("audit.key"),
+        )
+    }
+
+    fn write_records(path: &Path, key_file: &Path, users: &[&str]) {
+        let log = AuditLog::open(path, key_file).unwrap();
+        for user in users {
+            log.record(
+                AuditEvent::AuthSucceeded,
+                AuditDetails {
+                    user_id: Some(user.to_string()),
+                    ..Default::default()
+                },
+            );
+        }
+        log.flush();
+    }
+
+    fn lines(path: &Path) -> Vec<String> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(str::to_owned)
+            .collect()
+    }
+
+    fn rewrite(path: &Path, lines: &[String]) {
+        fs::write(
+            path,
+            lines.iter().map(|l| format!("{}\n", l)).collect::<String>(),
+        )
+        .unwrap();
+    }
+
+    fn verification_error(path: &Path, key_file: &Path) -> String {
+        verify_audit_log(path, key_file).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn chain_continues_across_openings() {
+        let dir = TempDir::new().unwrap();
+        let (path, key_file) = paths(&dir);
+        write_records(&path, &key_file, &["alice", "bob"]);
+        write_records(&path, &key_file, &["carol"]);
+        assert_eq!(verify_audit_log(&path, &key_file).unwrap(), 3);
+        assert!(lines(&path)[2].contains("carol"));
+    }
+
+    #[test]
+    fn empty_log() {
+        let dir = TempDir::new().unwrap();
+        let (path, key_file) = paths(&dir);
+        write_records(&path, &key_file, &[]);
+        assert_eq!(verify_audit_log(&path, &key_file).unwrap(), 0);
+    }
+
+    #[test]
+    fn modified_record() {
+        let dir = TempDir::new().unwrap();
+        let (path, key_file) = paths(&dir);
+        write_records(&path, &key_file, &["alice", "bob", "carol"]);
+        let mut edited = lines(&path);
+        edited[1] = edited[1].replace("bob", "eve");
+        rewrite(&path, &edited);
+        assert!(verification_error(&path, &key_file).contains("Record 1 (line 2) was modified"));
+    }
+
+    #[test]
+    fn added_field() {
+        let dir = TempDir::new().unwrap();
+        let (path, key_file) = paths(&dir);
+        write_records(&path, &key_file, &["alice"]);
+        let mut edited = lines(&path);
+        edited[0] = edited[0].replacen('{', "{\"extra\":1,", 1);
+        rewrite(&path, &edited);
+        assert!(verification_error(&path, &key_file).contains("was modified"));
+    }
+
+    #[test]
+    fn removed_record() {
+        let dir = TempDir::new().unwrap();
+        let (path, key_file) = paths(&dir);
+        write_records(&path, &key_file, &["alice", "bob", "carol"]);
+        let mut edited = lines(&path);
+        edited.remove(1);
+        rewrite(&path, &edited);
+        assert!(verification_error(&path, &key_file).contains("removed or reordered"));
+    }
+
+    #[test]
+    fn reordered_records() {
+        let dir = TempDir::new().unwrap();
+        let (path, key_file) = paths(&dir);
+        write_records(&path, &key_file, &["alice", "bob"]);
+        let mut edited = lines(&path);
+        edited.swap(0, 1);
+        rewrite(&path, &edited);
+        assert!(verification_error(&path, &key_file).contains("removed or reordered"));
+    }
+
+    #[test]
+    fn truncated_log() {
+        let dir = TempDir::new().unwrap();
+        let (path, key_file) = paths(&dir);
+        write_records(&path, &key_file, &["alice", "bob"]);
+        let mut edited = lines(&path);
+        edited.pop();
+        rewrite(&path, &edited);
+        assert!(verification_error(&path, &key_file).contains("removed from its end"));
+        rewrite(&path, &[]);
+        assert!(verification_error(&path, &key_file).contains("truncated"));
+    }
+
+    #[test]
+    fn rebuilt_chain_needs_the_key() {
+        let dir = TempDir::new().unwrap();
+        let (path, key_file) = paths(&dir);
+        write_records(&path, &key_file, &["alice"]);
+        let other_key = dir.path().join("other").join("audit.key");
+        AuditKey::read_or_create(&other_key).unwrap();
+        assert!(verification_error(&path, &other_key).contains("was modified"));
+    }
+
+    #[test]
+    fn tampered_log_is_not_extended() {
+        let dir = TempDir::new().unwrap();
+        let (path, key_file) = paths(&dir);
+        write_records(&path, &key_file, &["alice", "bob"]);
+        let mut edited = lines(&path);
+        edited.pop();
+        rewrite(&path, &edited);
+        assert!(AuditLog::open(&path, &key_file).is_err());
+    }
+
+    #[test]
+    fn key_outside_the_log_folder() {
+        let dir = TempDir::new().unwrap();
+        let (path, _) = paths(&dir);
+        let key_file = path.with_file_name("audit.key");
+        assert!(AuditLog::open(&path, &key_file).is_err());
+    }
+}
@@ -1,29 +1,99 @@
 use anyhow::anyhow;
 use log::{debug, error, info};
 use prost::Message;
-use std::{io::Cursor, net::SocketAddr};
+use std::{fmt, io::Cursor, net::SocketAddr, time::Duration};
 use tokio::net::TcpStream;
 use tokio_rustls::TlsStream;
 
 use crate::{
-    auth::jwt::{get_jwks, parse_and_validate_jwt, Claims},
+    auth::jwt::{parse_and_validate_jwt, Claims},
+    metrics::metrics,
     proto::{
         common::{recv_msg_async, send_msg_async},
-        messages::{self, AuthResult},
+        messages::{self, AuthResult, PairingStatus, PolicyDenyReason},
     },
 };
 
-use super::Authenticator;
+use super::{
+    audit::{token_sha256, AuditDetails, AuditEvent},
+    pairing::pair_device,
+    policy::PolicyDecision,
+    sanzu_launch::ClientOffer,
+    source_acl::source_matches_claim,
+    Authenticator,
+};
 
+// clients before v0.0.2 only accept this exact version : the server tells the additions it
+// uses from the version of each client instead
 static SERVER_VERSION: &str = "v0.0.1";
 
+/// Error of `authenticate` when the client proved who it is, but the access policy or the
+/// source address claim of its token refuses it. Not a failure counting toward a ban.
+#[derive(Debug)]
+pub struct AccessRefused {
+    pub client_addr: SocketAddr,
+    pub reason: PolicyDenyReason,
+}
+
+impl fmt::Display for AccessRefused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Authentication failed : access policy denies {} ({:?})",
+            self.client_addr, self.reason
+        )
+    }
+}
+
+impl std::error::Error for AccessRefused {}
+
+/// Error of `authenticate` when the client presented a bad credential : an invalid or expired
+/// token, a token for another machine, an unknown device or a wrong pairing code. The only
+/// failures counting toward a ban.
+#[derive(Debug)]
+pub struct CredentialRejected(pub String);
+
+impl fmt::Display for CredentialRejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for CredentialRejected {}
+
+/// Error of `authenticate` when the keys that validate tokens can't be obtained, e.g. while the
+/// auth service is down. It says nothing about the client.
+#[derive(Debug)]
+pub struct KeysUnavailable;
+
+impl fmt::Display for KeysUnavailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Could not fetch jwks")
+    }
+}
+
+impl std::error::Error for KeysUnavailable {}
+
+/// Whether an error of `authenticate` counts toward a ban of the client's address. Timeouts,
+/// I/O errors and unavailable keys don't : they happen to legitimate users as well.
+pub fn counts_toward_ban(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<CredentialRejected>().is_some()
+}
+
 impl Authenticator {
-    // First return String : id, second return String : jwt
+    // First return String : id, second return String : jwt (empty for paired devices), then
+    // the maximum session duration set by the access policy, last return : the codecs and
+    // display the client asked for.
+    // Returns None when the client only came to pair with this server.
     pub async fn authenticate(
         &mut self,
         outbound_stream: &mut TlsStream<TcpStream>,
         client_addr: SocketAddr,
-    ) -> anyhow::Result<(String, String, Claims)> {
+    ) -> anyhow::Result<Option<(String, String, Claims, Option<Duration>, ClientOffer)>> {
+        let mut details = AuditDetails {
+            source: Some(client_addr.to_string()),
+            ..Default::default()
+        };
         let sh = messages::ServerHello {
             version: SERVER_VERSION.to_owned(),
         };
@@ -31,6 +101,7 @@ impl Authenticator {
             Ok(v) => v,
             Err(e) => {
                 error!("Could not send server hello to {} : {}", client_addr, e);
+                self.audit_failure(details, "protocol_error", "could not send server hello");
                 return Err(anyhow!("Could not send server hello"));
             }
         };
@@ -43,6 +114,7 @@ impl Authenticator {
                     "Could not receive client hello sent by {} : {}",
                     client_addr, e
                 );
+                self.audit_failure(details, "protocol_error", "could not receive client hello");
                 return Err(anyhow!("Could not receive client hello"));
             }
         };
@@ -54,41 +126,105 @@ impl Authenticator {
                     "Could not decode client hello sent by {} : {}",
                     client_addr, e
                 );
+                self.audit_failure(details, "protocol_error", "could not decode client hello");
                 return Err(anyhow!("Could not decode client hello"));
             }
         };
         debug!("Decoded client hello sent by {} successfully", client_addr);
-        let jwks = match get_jwks(self.jwks_url.as_str(), self.timeout).await {
-            Ok(v) => v,
-            Err(e) => {
-                error!(
-                    "Could not fetch jwks from {} : {}",
-                    self.jwks_url.as_str(),
-                    e
-                );
-                return Err(anyhow!("Could not fetch jwks"));
+        if !ch.pairing_message.is_empty() {
+            let Some(pairing) = &self.pairing else {
+                let unavailable = messages::ServerPairing {
+                    status: PairingStatus::PairingUnavailable.into(),
+                    ..Default::default()
+                };
+                details.reason = Some("pairing is disabled".to_owned());
+                metrics().handshakes.inc("pairing_disabled");
+                self.audit.record(AuditEvent::PairingFailed, details);
+                send_msg_async(outbound_stream, unavailable, Some(self.timeout)).await?;
+                return Err(anyhow!(
+                    "{} tried to pair but pairing is disabled",
+                    client_addr
+                ));
+            };
+            match pair_device(
+                pairing,
+                outbound_stream,
+                client_addr,
+                &ch,
+                &self.local_machine_id,
+                self.timeout,
+            )
+            .await
+            {
+                Ok(device) => {
+                    details.method = Some("device".to_owned());
+                    details.user_id = Some(device_user_id(&device.id));
+                    details.device_name = Some(device.name);
+                    self.audit.record(AuditEvent::DevicePaired, details);
+                    metrics().handshakes.inc("paired");
+                    return Ok(None);
+                }
+                Err(e) => {
+                    details.reason = Some(e.to_string());
+                    self.audit.record(AuditEvent::PairingFailed, details);
+                    metrics().handshakes.inc("pairing_failed");
+                    return Err(e);
+                }
             }
-        };
+        }
 
-        let claims = match parse_and_validate_jwt(ch.jwt.as_ref(), &jwks) {
-            Ok(v) => v,
-            Err(e) => {
-                error!(
-                    "Could not parse and validate JWT sent by {} : {}",
-                    client_addr, e
-                );
-                return Err(anyhow!("Could not parse and validate JWT"));
+        let claims = if ch.device_id.is_empty() {
+            details.method = Some("jwt".to_owned());
+            details.token_sha256 = Some(token_sha256(&ch.jwt));
+            match self.validate_jwt(&ch.jwt, client_addr).await {
+                Ok(v) => v,
+                Err(e) => {
+                    let outcome = match e.downcast_ref::<KeysUnavailable>() {
+                        Some(_) => "keys_unavailable",
+                        None => "invalid_token",
+                    };
+                    self.audit_failure(details, outcome, &e.to_string());
+                    return Err(e);
+                }
+            }
+        } else {
+            details.method = Some("device".to_owned());
+            match self.authenticate_device(&ch, client_addr) {
+                Some((claims, device_name)) => {
+                    details.device_name = Some(device_name);
+                    claims
+                }
+                None => {
+                    self.audit_failure(details, "unknown_device", "unknown device credential");
+                    let sar = messages::ServerAuthResult {
+                        result: AuthResult::AuthFailed as i32,
+                        ..Default::default()
+                    };
+                    if let Err(e) = send_msg_async(outbound_stream, sar, Some(self.timeout)).await {
+                        error!(
+                            "Could not send auth failed message to {} : {}",
+                            client_addr, e
+                        );
+                    }
+                    return Err(CredentialRejected(format!(
+                        "Authentication failed : {} presented an unknown device credential",
+                        client_addr
+                    ))
+                    .into());
+                }
             }
         };
-        debug!(
-            "Parsed and validated client JWT sent by {} successfully",
-            client_addr
-        );
+
+        details.user_id = Some(claims.user_id.clone());
+        if details.method.as_deref() == Some("jwt") {
+            details.session_id = Some(claims.session_id);
+        }
 
         let id = claims.machine_id.clone();
         if id.is_empty() {
             error!("Client jwt machine id sent by {} is empty", client_addr);
-            return Err(anyhow!("Empty target machine id"));
+            self.audit_failure(details, "invalid_token", "empty target machine id");
+            return Err(CredentialRejected("Empty target machine id".to_owned()).into());
         }
 
         let mut sar = messages::ServerAuthResult::default();
@@ -97,6 +233,11 @@ impl Authenticator {
                 "Error when authenticating {}: server is machine '{}' and client can only connect to '{}' ",
                 client_addr, self.local_machine_id, id
             );
+            self.audit_failure(
+                details,
+                "wrong_machine",
+                &format!("token is for machine '{}', not this one", id),
+            );
             sar.result = AuthResult::AuthFailed as i32;
             match send_msg_async(outbound_stream, sar, Some(self.timeout)).await {
                 Ok(()) => {}
@@ -108,24 +249,264 @@ impl Authenticator {
                     return Err(anyhow!("Could not send auth failed message"));
                 }
             };
-            Err(anyhow!(
+            Err(CredentialRejected(format!(
                 "Authentication failed : {} tried to connect to {} but we are {}",
-                client_addr,
-                id,
-                self.local_machine_id
+                client_addr, id, self.local_machine_id
             ))
+            .into())
         } else {
+            // a token may be bound to the addresses it can be used from
+            let source_claim = self
+                .source_claim
+                .as_deref()
+                .filter(|_| details.method.as_deref() == Some("jwt"))
+                .filter(|claim| !source_matches_claim(&claims, claim, client_addr.ip()));
+            let decision = match source_claim {
+                Some(claim) => {
+                    error!(
+                        "{} is not an address allowed by the '{}' claim of the token of user '{}'",
+                        client_addr, claim, claims.user_id
+                    );
+                    Some(PolicyDecision::Denied(PolicyDenyReason::SourceNotAllowed))
+                }
+                None => self
+                    .policy
+                    .as_ref()
+                    .map(|p| p.current().evaluate(&claims, client_addr.ip())),
+            };
+            let max_session_duration = match decision {
+                None => None,
+                Some(PolicyDecision::Allowed {
+                    max_session_duration,
+                }) => max_session_duration,
+                Some(PolicyDecision::Denied(reason)) => {
+                    match source_claim {
+                        Some(claim) => self.audit_failure(
+                            details,
+                            "source_not_allowed",
+                            &format!("source address not allowed by the '{}' claim", claim),
+                        ),
+                        None => {
+                            error!(
+                                "Access policy denies user '{}' connecting from {} : {:?}",
+                                claims.user_id, client_addr, reason
+                            );
+                            self.audit_failure(
+                                details,
+                                "policy_denied",
+                                &format!("access policy : {:?}", reason),
+                            );
+                        }
+                    }
+                    sar.result = AuthResult::PolicyDenied as i32;
+                    sar.deny_reason = reason as i32;
+                    match send_msg_async(outbound_stream, sar, Some(self.timeout)).await {
+                        Ok(()) => {}
+                        Err(e) => {
+                            error!(
+                                "Could not send policy denied message to {} : {}",
+                                client_addr, e
+                            );
+                            return Err(anyhow!("Could not send policy denied message"));
+                        }
+                    };
+                    return Err(AccessRefused {
+                        client_addr,
+                        reason,
+                    }
+                    .into());
+                }
+            };
+
             sar.result = AuthResult::AuthOk as i32;
             match send_msg_async(outbound_stream, sar, Some(self.timeout)).await {
                 Ok(()) => {
                     info!("Sent auth ok message to {} successfully", client_addr);
-                    Ok((id.to_string(), ch.jwt.to_owned(), claims))
+                    self.audit.record(AuditEvent::AuthSucceeded, details);
+                    metrics().handshakes.inc("ok");
+                    Ok(Some((
+                        id.to_string(),
+                        ch.jwt.to_owned(),
+                        claims,
+                        max_session_duration,
+                        ClientOffer::from_hello(&ch),
+                    )))
                 }
                 Err(e) => {
                     error!("Could not send auth result ok to {} : {}", client_addr, e);
+                    metrics().handshakes.inc("protocol_error");
                     Err(anyhow!("Could not send auth ok message"))
                 }
             }
         }
     }
 }
+
+impl Authenticator {
+    /// Records a failed authentication, `outcome` being its label in the handshake metrics.
+    fn audit_failure(&self, mut details: AuditDetails, outcome: &'static str, reason: &str) {
+        details.reason = Some(reason.to_owned());
+        self.audit.record(AuditEvent::AuthFailed, details);
+        metrics().handshakes.inc(outcome);
+    }
+
+    async fn validate_jwt(&self, jwt: &str, client_addr: SocketAddr) -> anyhow::Result<Claims> {
+        let jwks = match self.jwks_provider.get().await {
+            Ok(v) => v,
+            Err(e) => {
+                error!(
+                    "Could not get token validation keys from {:?} : {}",
+                    self.jwks_provider.source(),
+                    e
+                );
+                return Err(KeysUnavailable.into());
+            }
+        };
+
+        let claims = match parse_and_validate_jwt(jwt, &jwks, self.jwks_provider.issuer()) {
+            Ok(v) => v,
+            Err(e) => {
+                error!(
+                    "Could not parse and validate JWT sent by {} : {}",
+                    client_addr, e
+                );
+                return Err(CredentialRejected(format!(
+                    "Could not parse and validate JWT : {}",
+                    e
+                ))
+                .into());
+            }
+        };
+        debug!(
+            "Parsed and validated client JWT sent by {} successfully",
+            client_addr
+        );
+        Ok(claims)
+    }
+
+    /// Claims of a paired device, and its name. Its id stands for the user, prefixed with
+    /// `device:` : names are chosen by the devices and may collide.
+    fn authenticate_device(
+        &self,
+        ch: &messages::ClientHello,
+        client_addr: SocketAddr,
+    ) -> Option<(Claims, String)> {
+        let Some(pairing) = &self.pairing else {
+            error!(
+                "{} sent a device credential but pairing is disabled",
+                client_addr
+            );
+            return None;
+        };
+        let device = match pairing.authenticate_device(&ch.device_id, &ch.device_secret) {
+            Ok(Some(v)) => v,
+            Ok(None) => {
+                error!(
+                    "Unknown device credential {} sent by {}",
+                    ch.device_id, client_addr
+                );
+                return None;
+            }
+            Err(e) => {
+                error!(
+                    "Could not check device credential of {} : {}",
+                    client_addr, e
+                );
+                return None;
+            }
+        };
+        info!(
+            "{} authenticated as paired device '{}' (id {})",
+            client_addr, device.name, device.id
+        );
+
+        let claims = Claims {
+            exp: 0,
+            user_id: device_user_id(&device.id),
+            session_id: 0,
+            machine_id: self.local_machine_id.clone(),
+            machine_ip: String::new(),
+            machine_port: 0,
+            extra: Default::default(),
+        };
+        Some((claims, device.name))
+    }
+}
+
+/// User of a paired device in sessions, access policies and the audit log.
+fn device_user_id(device_id: &str) -> String {
+    format!("device:{}", device_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        auth::jwks_provider::{JwksProvider, JwksSource},
+        standalone_server::{audit::AuditLog, rate_limit::RateLimiter},
+    };
+
+    fn authenticator(source: JwksSource) -> Authenticator {
+        Authenticator {
+            local_machine_id: "1234".to_owned(),
+            jwks_provider: Arc::new(JwksProvider::new(
+                source,
+                None,
+                Duration::from_secs(3600),
+                Duration::from_secs(2),
+            )),
+            policy: None,
+            pairing: None,
+            audit: AuditLog::disabled(),
+            rate_limiter: Arc::new(RateLimiter::new(&Default::default())),
+            source_claim: None,
+            timeout: Duration::from_secs(2),
+        }
+    }
+
+    /// Fails authentication `attempts` times with `authenticator` as the connection handler
+    /// does, and tells whether the source ended up banned.
+    async fn banned_after(authenticator: &Authenticator, attempts: usize) -> bool {
+        let limiter = RateLimiter::new(&toml::from_str("max_failed_auth = 3").unwrap());
+        let client_addr = "192.0.2.1:40000".parse().unwrap();
+        for _ in 0..attempts {
+            let e = authenticator
+                .validate_jwt("not.a.token", client_addr)
+                .await
+                .unwrap_err();
+            if counts_toward_ban(&e) {
+                limiter.record_failure(client_addr.ip());
+            }
+        }
+        !limiter.allow_connection(client_addr.ip())
+    }
+
+    #[tokio::test]
+    async fn unavailable_keys_dont_ban() {
+        // nothing listens on the discard port
+        let authenticator = authenticator(JwksSource::Url("http://127.0.0.1:9/jwks".to_owned()));
+        let e = authenticator
+            .validate_jwt("not.a.token", "192.0.2.1:40000".parse().unwrap())
+            .await
+            .unwrap_err();
+        assert!(e.downcast_ref::<KeysUnavailable>().is_some());
+        assert!(!banned_after(&authenticator, 5).await);
+    }
+
+    #[tokio::test]
+    async fn invalid_tokens_ban() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let key_file = dir.path().join("key.pem");
+        std::fs::write(
+            &key_file,
+            rcgen::KeyPair::generate().unwrap().public_key_pem(),
+        )
+        .unwrap();
+        let authenticator = authenticator(JwksSource::PemFiles(vec![key_file
+            .to_string_lossy()
+            .into_owned()]));
+        assert!(banned_after(&authenticator, 3).await);
+    }
+}
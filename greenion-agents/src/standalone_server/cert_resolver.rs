@@ -0,0 +1,291 @@
+use anyhow::{anyhow, bail};
+use log::{debug, error, info, warn};
+use rustls::{
+    crypto::CryptoProvider,
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    InconsistentKeys,
+};
+use std::{
+    fs,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+#[cfg(target_os = "linux")]
+use super::privsep::SupervisorClient;
+use crate::auth::{
+    load_cert_and_key,
+    x509::{extract_machine_id, parse_x509},
+    PrivateKeyOptions,
+};
+
+/// How often a certificate served by the supervisor is checked when no interval is set
+#[cfg(target_os = "linux")]
+const SUPERVISOR_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Where the certificate and the private key come from.
+#[derive(Debug)]
+enum CertSource {
+    Files {
+        cert_file: String,
+        private_key_file: String,
+        key_options: PrivateKeyOptions,
+    },
+    /// The privileged supervisor holds the key and signs for us
+    #[cfg(target_os = "linux")]
+    Supervisor(Arc<SupervisorClient>),
+}
+
+impl CertSource {
+    fn load(&self) -> anyhow::Result<(CertifiedKey, String)> {
+        match self {
+            Self::Files {
+                cert_file,
+                private_key_file,
+                key_options,
+            } => load_certified_key(cert_file, private_key_file, key_options),
+            #[cfg(target_os = "linux")]
+            Self::Supervisor(client) => {
+                let certified_key = client.certified_key()?;
+                let machine_id = match certified_key.cert.first() {
+                    Some(v) => extract_machine_id(&parse_x509(v)?)?,
+                    None => bail!("The supervisor sent an empty certificate chain"),
+                };
+                Ok((certified_key, machine_id))
+            }
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Self::Files {
+                cert_file,
+                private_key_file,
+                ..
+            } => format!(
+                "certificate ({}) and private key ({})",
+                cert_file, private_key_file
+            ),
+            #[cfg(target_os = "linux")]
+            Self::Supervisor(_) => "certificate from the supervisor".to_owned(),
+        }
+    }
+}
+
+/// Serves the server agent certificate to new TLS handshakes and lets it be swapped at runtime.
+///
+/// Sessions that are already established keep the certificate they were accepted with.
+#[derive(Debug)]
+pub struct ReloadableCertResolver {
+    source: CertSource,
+    machine_id: String,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ReloadableCertResolver {
+    /// Loads the certificate and key for the first time. The machine id found in this
+    /// certificate is the one every reloaded certificate must carry.
+    pub fn new(
+        cert_file: &str,
+        private_key_file: &str,
+        key_options: PrivateKeyOptions,
+    ) -> anyhow::Result<Self> {
+        Self::from_source(CertSource::Files {
+            cert_file: cert_file.to_owned(),
+            private_key_file: private_key_file.to_owned(),
+            key_options,
+        })
+    }
+
+    /// Serves the certificate of the privileged supervisor, which keeps the private key and
+    /// signs the handshakes.
+    #[cfg(target_os = "linux")]
+    pub fn from_supervisor(client: Arc<SupervisorClient>) -> anyhow::Result<Self> {
+        Self::from_source(CertSource::Supervisor(client))
+    }
+
+    fn from_source(source: CertSource) -> anyhow::Result<Self> {
+        let (certified_key, machine_id) = source.load()?;
+        Ok(Self {
+            source,
+            machine_id,
+            current: RwLock::new(Arc::new(certified_key)),
+        })
+    }
+
+    pub fn machine_id(&self) -> &str {
+        &self.machine_id
+    }
+
+    pub fn current(&self) -> Arc<CertifiedKey> {
+        Arc::clone(&self.current.read().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    /// Reloads the certificate and key from disk. The running certificate is only replaced
+    /// when the new pair is valid and belongs to the same machine.
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let (certified_key, machine_id) = self.source.load()?;
+
+        if machine_id != self.machine_id {
+            error!(
+                "Refusing to reload {} : it was issued to machine '{}' but we are '{}'",
+                self.source.describe(),
+                machine_id,
+                self.machine_id
+            );
+            bail!("New certificate machine id doesn't match the running machine id");
+        }
+
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(certified_key);
+        info!("Reloaded {}", self.source.describe());
+        Ok(())
+    }
+
+    /// Reloads the certificate whenever the certificate or key file changes on disk
+    /// (checked every `interval`), and on SIGHUP on unix systems. A certificate served by
+    /// the supervisor is checked every `interval` instead, or every minute.
+    pub async fn watch(self: Arc<Self>, interval: Option<Duration>) {
+        #[cfg(target_os = "linux")]
+        if let CertSource::Supervisor(client) = &self.source {
+            let mut generation = client.generation();
+            loop {
+                tokio::time::sleep(interval.unwrap_or(SUPERVISOR_CHECK_INTERVAL)).await;
+                let client = Arc::clone(client);
+                match tokio::task::spawn_blocking(move || client.current_generation()).await {
+                    Ok(Ok(latest)) if latest == generation => continue,
+                    Ok(Ok(latest)) => generation = latest,
+                    Ok(Err(e)) => {
+                        error!("Could not check the certificate of the supervisor : {}", e);
+                        continue;
+                    }
+                    Err(_) => continue,
+                }
+                debug!("The supervisor serves a new certificate");
+                let resolver = Arc::clone(&self);
+                if let Ok(Err(e)) = tokio::task::spawn_blocking(move || resolver.reload()).await {
+                    error!(
+                        "Could not reload certificate, keeping the current one : {}",
+                        e
+                    );
+                }
+            }
+        }
+
+        #[cfg(unix)]
+        let mut sighup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        {
+            Ok(v) => Some(v),
+            Err(e) => {
+                warn!(
+                    "Could not listen for SIGHUP, certificates won't reload on it : {}",
+                    e
+                );
+                None
+            }
+        };
+
+        let mut last_modified = self.files_modified_at();
+
+        loop {
+            #[cfg(unix)]
+            let hangup = async {
+                match sighup.as_mut() {
+                    Some(s) => s.recv().await,
+                    None => std::future::pending().await,
+                }
+            };
+            #[cfg(not(unix))]
+            let hangup = std::future::pending::<Option<()>>();
+
+            let tick = async {
+                match interval {
+                    Some(i) => tokio::time::sleep(i).await,
+                    None => std::future::pending().await,
+                }
+            };
+
+            tokio::select! {
+                _ = hangup => {
+                    info!("Received SIGHUP, reloading certificate");
+                }
+                _ = tick => {
+                    let modified = self.files_modified_at();
+                    if modified == last_modified {
+                        continue;
+                    }
+                    debug!("Certificate or private key file changed on disk");
+                }
+            }
+
+            last_modified = self.files_modified_at();
+            if let Err(e) = self.reload() {
+                error!(
+                    "Could not reload certificate, keeping the current one : {}",
+                    e
+                );
+            }
+        }
+    }
+
+    fn files_modified_at(&self) -> (Option<SystemTime>, Option<SystemTime>) {
+        let modified = |f: &str| fs::metadata(f).and_then(|m| m.modified()).ok();
+        match &self.source {
+            CertSource::Files {
+                cert_file,
+                private_key_file,
+                ..
+            } => (modified(cert_file), modified(private_key_file)),
+            #[cfg(target_os = "linux")]
+            CertSource::Supervisor(_) => (None, None),
+        }
+    }
+}
+
+impl ResolvesServerCert for ReloadableCertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current())
+    }
+}
+
+fn load_certified_key(
+    cert_file: &str,
+    private_key_file: &str,
+    key_options: &PrivateKeyOptions,
+) -> anyhow::Result<(CertifiedKey, String)> {
+    let (certs, key) = load_cert_and_key(cert_file, private_key_file, key_options)?;
+
+    let machine_id = {
+        let cert = match certs.first() {
+            Some(v) => parse_x509(v)?,
+            None => bail!("Certificate file {} is empty", cert_file),
+        };
+        extract_machine_id(&cert)?
+    };
+
+    let provider = CryptoProvider::get_default()
+        .cloned()
+        .unwrap_or_else(|| Arc::new(rustls::crypto::aws_lc_rs::default_provider()));
+    let signing_key = match provider.key_provider.load_private_key(key) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Unsupported private key in {} : {}", private_key_file, e);
+            return Err(anyhow!("Unsupported private key in {}", private_key_file));
+        }
+    };
+
+    let certified_key = CertifiedKey::new(certs, signing_key);
+    match certified_key.keys_match() {
+        // Same as rustls : don't treat unknown consistency as an error
+        Ok(()) | Err(rustls::Error::InconsistentKeys(InconsistentKeys::Unknown)) => {}
+        Err(e) => {
+            error!(
+                "Private key {} doesn't match certificate {} : {}",
+                private_key_file, cert_file, e
+            );
+            bail!("Private key doesn't match certificate");
+        }
+    }
+
+    Ok((certified_key, machine_id))
+}
@@ -0,0 +1,491 @@
+use anyhow::{anyhow, bail, Context};
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+
+use crate::{
+    auth::x509::parse_x509,
+    standalone_server::{
+        audit::audit_time,
+        cert_resolver::ReloadableCertResolver,
+        rate_limit::RateLimiter,
+        sessions::{SessionManager, SessionTermination},
+        source_acl::SourceAcl,
+    },
+};
+
+/// Requests are single lines of JSON, and so are responses
+const MAX_REQUEST_SIZE: u64 = 64 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Pause before listening again after a control pipe failed to accept a client
+#[cfg(windows)]
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// What `greenion-server ctl` asks the running agent.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ControlRequest {
+    /// Running sessions and queued connections
+    Sessions,
+    /// End a running session
+    Kick {
+        session_id: u64,
+        reason: String,
+    },
+    /// Stop starting new sessions, running ones go on
+    Drain,
+    /// Start new sessions again after a drain
+    Resume,
+    /// Certificate the agent serves
+    Certificate,
+    Health,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum ControlResponse {
+    Sessions {
+        active: Vec<SessionInfo>,
+        queued: Vec<QueuedInfo>,
+    },
+    Kicked {
+        session_id: u64,
+    },
+    Draining {
+        active_sessions: usize,
+    },
+    Resumed,
+    Certificate(CertificateInfo),
+    Health(HealthReport),
+    Error {
+        message: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub id: u64,
+    pub user_id: String,
+    pub client_addr: String,
+    /// `sessionId` of the connection token, None for paired devices
+    pub web_session_id: Option<u32>,
+    pub sanzu_port: u16,
+    pub started_at: String,
+    pub duration_secs: u64,
+    pub bytes_from_client: u64,
+    pub bytes_to_client: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueuedInfo {
+    pub position: usize,
+    pub user_id: String,
+    pub client_addr: String,
+    pub queued_at: String,
+    pub waiting_secs: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CertificateInfo {
+    pub machine_id: String,
+    pub subject: String,
+    pub issuer: String,
+    pub serial: String,
+    pub not_before: String,
+    pub not_after: String,
+    /// Negative once expired
+    pub expires_in_secs: i64,
+    pub sha256_fingerprint: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthStatus {
+    Ok,
+    /// The agent stops once the running sessions are closed
+    ShuttingDown,
+    /// No new session starts
+    Draining,
+    /// Clients refuse the certificate the agent serves
+    CertificateExpired,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthReport {
+    pub status: HealthStatus,
+    pub version: String,
+    pub uptime_secs: u64,
+    pub active_sessions: usize,
+    pub max_sessions: usize,
+    pub queued_connections: usize,
+    pub certificate_expires_in_secs: i64,
+    pub banned_sources: usize,
+    /// Connections dropped by the rate limiter
+    pub rate_limited_connections: u64,
+    /// Connections dropped because of their source address
+    pub refused_connections: u64,
+}
+
+fn system_time(time: SystemTime) -> String {
+    audit_time(DateTime::<Utc>::from(time))
+}
+
+fn elapsed_secs(time: SystemTime) -> u64 {
+    time.elapsed().unwrap_or_default().as_secs()
+}
+
+/// Answers `greenion-server ctl` on a local socket. Only the user running the agent can
+/// reach it.
+pub struct ControlServer {
+    pub sessions: Arc<SessionManager>,
+    pub cert_resolver: Arc<ReloadableCertResolver>,
+    pub rate_limiter: Arc<RateLimiter>,
+    pub source_acl: Arc<SourceAcl>,
+    pub started_at: Instant,
+}
+
+impl ControlServer {
+    pub fn handle(&self, request: ControlRequest) -> ControlResponse {
+        match request {
+            ControlRequest::Sessions => ControlResponse::Sessions {
+                active: self
+                    .sessions
+                    .sessions()
+                    .into_iter()
+                    .map(|s| SessionInfo {
+                        id: s.id,
+                        user_id: s.user_id,
+                        client_addr: s.client_addr.to_string(),
+                        web_session_id: s.web_session_id,
+                        sanzu_port: s.sanzu_port,
+                        started_at: system_time(s.started_at),
+                        duration_secs: elapsed_secs(s.started_at),
+                        bytes_from_client: s.traffic.from_client.load(Ordering::Relaxed),
+                        bytes_to_client: s.traffic.to_client.load(Ordering::Relaxed),
+                    })
+                    .collect(),
+                queued: self
+                    .sessions
+                    .queued()
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, q)| QueuedInfo {
+                        position: i + 1,
+                        user_id: q.user_id,
+                        client_addr: q.client_addr.to_string(),
+                        queued_at: system_time(q.queued_at),
+                        waiting_secs: elapsed_secs(q.queued_at),
+                    })
+                    .collect(),
+            },
+            ControlRequest::Kick { session_id, reason } => {
+                let termination = SessionTermination {
+                    reason: format!("kicked by an administrator : {}", reason),
+                    keep_web_session: false,
+                };
+                if self.sessions.terminate(session_id, termination) {
+                    info!("Session {} kicked : {}", session_id, reason);
+                    ControlResponse::Kicked { session_id }
+                } else {
+                    ControlResponse::Error {
+                        message: format!("No running session {}", session_id),
+                    }
+                }
+            }
+            ControlRequest::Drain => {
+                info!("Draining : no new session starts until resumed");
+                self.sessions.set_draining(true);
+                ControlResponse::Draining {
+                    active_sessions: self.sessions.sessions().len(),
+                }
+            }
+            ControlRequest::Resume if self.sessions.is_shutting_down() => ControlResponse::Error {
+                message: "The agent is shutting down".to_owned(),
+            },
+            ControlRequest::Resume => {
+                info!("Resuming : new sessions start again");
+                self.sessions.set_draining(false);
+                ControlResponse::Resumed
+            }
+            ControlRequest::Certificate => match self.certificate() {
+                Ok(v) => ControlResponse::Certificate(v),
+                Err(e) => ControlResponse::Error {
+                    message: format!("Could not read the loaded certificate : {}", e),
+                },
+            },
+            ControlRequest::Health => ControlResponse::Health(self.health()),
+        }
+    }
+
+    fn certificate(&self) -> anyhow::Result<CertificateInfo> {
+        let certified_key = self.cert_resolver.current();
+        let Some(der) = certified_key.cert.first() else {
+            bail!("No certificate loaded");
+        };
+        let cert = parse_x509(der)?;
+        let validity = cert.validity();
+        let timestamp = |t: i64| {
+            DateTime::<Utc>::from_timestamp(t, 0)
+                .map(audit_time)
+                .unwrap_or_default()
+        };
+        Ok(CertificateInfo {
+            machine_id: self.cert_resolver.machine_id().to_owned(),
+            subject: cert.subject().to_string(),
+            issuer: cert.issuer().to_string(),
+            serial: cert.raw_serial_as_string(),
+            not_before: timestamp(validity.not_before.timestamp()),
+            not_after: timestamp(validity.not_after.timestamp()),
+            expires_in_secs: validity.not_after.timestamp() - Utc::now().timestamp(),
+            sha256_fingerprint: Sha256::digest(der)
+                .iter()
+                .map(|b| format!("{:02X}", b))
+                .collect::<Vec<_>>()
+                .join(":"),
+        })
+    }
+
+    fn health(&self) -> HealthReport {
+        let certificate_expires_in_secs = match self.certificate() {
+            Ok(v) => v.expires_in_secs,
+            Err(e) => {
+                error!("Could not read the loaded certificate : {}", e);
+                0
+            }
+        };
+        let status = if certificate_expires_in_secs <= 0 {
+            HealthStatus::CertificateExpired
+        } else if self.sessions.is_shutting_down() {
+            HealthStatus::ShuttingDown
+        } else if self.sessions.is_draining() {
+            HealthStatus::Draining
+        } else {
+            HealthStatus::Ok
+        };
+        let rate_limit = self.rate_limiter.stats();
+        HealthReport {
+            status,
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            uptime_secs: self.started_at.elapsed().as_secs(),
+            active_sessions: self.sessions.sessions().len(),
+            max_sessions: self.sessions.max_sessions(),
+            queued_connections: self.sessions.queued().len(),
+            certificate_expires_in_secs,
+            banned_sources: rate_limit.active_bans,
+            rate_limited_connections: rate_limit.rejected_connections,
+            refused_connections: self.source_acl.refused_connections(),
+        }
+    }
+
+    /// Answers one request on `stream`.
+    async fn serve_connection<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: S,
+    ) -> anyhow::Result<()> {
+        let mut stream = BufReader::new(stream);
+        let mut line = String::new();
+        tokio::time::timeout(
+            REQUEST_TIMEOUT,
+            (&mut stream).take(MAX_REQUEST_SIZE).read_line(&mut line),
+        )
+        .await
+        .map_err(|_| anyhow!("No request received"))??;
+
+        let response = match serde_json::from_str::<ControlRequest>(&line) {
+            Ok(request) => self.handle(request),
+            Err(e) => {
+                warn!("Invalid control request : {}", e);
+                ControlResponse::Error {
+                    message: format!("Invalid request : {}", e),
+                }
+            }
+        };
+        let mut response = serde_json::to_vec(&response)?;
+        response.push(b'\n');
+        stream.get_mut().write_all(&response).await?;
+        stream.get_mut().shutdown().await?;
+        Ok(())
+    }
+
+    /// Listens on the Unix socket at `path`, readable and writable by its owner only.
+    #[cfg(unix)]
+    pub async fn serve(self: Arc<Self>, path: &str) -> anyhow::Result<()> {
+        let listener = bind_control_socket(path).await?;
+        self.serve_on(listener).await
+    }
+
+    /// Answers on a control socket bound by `bind_control_socket`, possibly by another
+    /// process.
+    #[cfg(unix)]
+    pub async fn serve_on(
+        self: Arc<Self>,
+        listener: tokio::net::UnixListener,
+    ) -> anyhow::Result<()> {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    error!("Could not accept control connection : {}", e);
+                    continue;
+                }
+            };
+            let server = Arc::clone(&self);
+            tokio::spawn(async move {
+                if let Err(e) = server.serve_connection(stream).await {
+                    warn!("Control connection failed : {}", e);
+                }
+            });
+        }
+    }
+
+    /// Listens on the named pipe `path`, which remote clients can't open.
+    #[cfg(windows)]
+    pub async fn serve(self: Arc<Self>, path: &str) -> anyhow::Result<()> {
+        use tokio::net::windows::named_pipe::ServerOptions;
+
+        let new_instance = || {
+            ServerOptions::new()
+                .reject_remote_clients(true)
+                .create(path)
+                .with_context(|| format!("Could not create control pipe {}", path))
+        };
+        let mut pipe = ServerOptions::new()
+            .first_pipe_instance(true)
+            .reject_remote_clients(true)
+            .create(path)
+            .with_context(|| format!("Could not create control pipe {}", path))?;
+        info!("Control pipe listening on {}", path);
+
+        loop {
+            if let Err(e) = pipe.connect().await {
+                error!("Could not accept control connection : {}", e);
+                // the failed instance can't be connected again, and retrying at once would
+                // spin if the error persists
+                drop(pipe);
+                tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                pipe = new_instance()?;
+                continue;
+            }
+            let connected = pipe;
+            pipe = new_instance()?;
+            let server = Arc::clone(&self);
+            tokio::spawn(async move {
+                if let Err(e) = server.serve_connection(connected).await {
+                    warn!("Control connection failed : {}", e);
+                }
+            });
+        }
+    }
+}
+
+/// Binds the Unix socket at `path`, readable and writable by its owner only.
+///
+/// The socket is bound inside a private directory and moved to `path` once restricted, so it
+/// is never reachable by other users, whatever the umask.
+#[cfg(unix)]
+pub async fn bind_control_socket(path: &str) -> anyhow::Result<tokio::net::UnixListener> {
+    use std::{
+        fs,
+        io::ErrorKind,
+        os::unix::fs::{DirBuilderExt, PermissionsExt},
+        path::Path,
+    };
+    use tokio::net::{UnixListener, UnixStream};
+
+    let path = Path::new(path);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if UnixStream::connect(path).await.is_ok() {
+        bail!(
+            "Another agent already answers on control socket {}",
+            path.display()
+        );
+    }
+    let Some(file_name) = path.file_name() else {
+        bail!("{} is not a file path", path.display());
+    };
+    let mut private_name = std::ffi::OsString::from(".");
+    private_name.push(file_name);
+    private_name.push(format!(".{}", std::process::id()));
+    let private_dir = path.with_file_name(private_name);
+    match fs::remove_dir_all(&private_dir) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => bail!(
+            "Could not remove stale directory {} : {}",
+            private_dir.display(),
+            e
+        ),
+    }
+    fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&private_dir)
+        .with_context(|| format!("Could not create {}", private_dir.display()))?;
+
+    let private_path = private_dir.join(file_name);
+    let bound = UnixListener::bind(&private_path)
+        .with_context(|| format!("Could not bind control socket {}", path.display()))
+        .and_then(|listener| {
+            fs::set_permissions(&private_path, fs::Permissions::from_mode(0o600))?;
+            // replaces a stale socket left by an agent that didn't exit cleanly
+            fs::rename(&private_path, path)
+                .with_context(|| format!("Could not move control socket to {}", path.display()))?;
+            Ok(listener)
+        });
+    if let Err(e) = fs::remove_dir_all(&private_dir) {
+        warn!("Could not remove {} : {}", private_dir.display(), e);
+    }
+    let listener = bound?;
+    info!("Control socket listening on {}", path.display());
+    Ok(listener)
+}
+
+async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    request: &ControlRequest,
+) -> anyhow::Result<ControlResponse> {
+    let mut stream = BufReader::new(stream);
+    let mut line = serde_json::to_vec(request)?;
+    line.push(b'\n');
+    stream.get_mut().write_all(&line).await?;
+
+    let mut response = String::new();
+    stream.read_line(&mut response).await?;
+    if response.is_empty() {
+        bail!("The agent closed the connection without answering");
+    }
+    Ok(serde_json::from_str(&response)?)
+}
+
+/// Sends `request` to the agent running on this machine.
+pub async fn control_request(
+    path: &str,
+    request: &ControlRequest,
+) -> anyhow::Result<ControlResponse> {
+    #[cfg(unix)]
+    let stream = tokio::net::UnixStream::connect(path).await;
+    #[cfg(windows)]
+    let stream = tokio::net::windows::named_pipe::ClientOptions::new().open(path);
+
+    let stream = stream.with_context(|| {
+        format!(
+            "Could not reach the agent on {}, is it running with the control socket enabled ?",
+            path
+        )
+    })?;
+    tokio::time::timeout(REQUEST_TIMEOUT, exchange(stream, request))
+        .await
+        .map_err(|_| {
+            anyhow!(
+                "The agent did not answer within {}",
+                humantime::format_duration(REQUEST_TIMEOUT)
+            )
+        })?
+}
@@ -0,0 +1,347 @@
+use anyhow::{anyhow, bail, Context};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use log::{debug, error, info, warn};
+use pkcs8::der::pem::LineEnding;
+use rcgen::{CertificateParams, DistinguishedName, DnType, KeyPair, SanType};
+use reqwest::{Client, StatusCode};
+use rustls::{crypto::CryptoProvider, pki_types::CertificateDer, SignatureScheme};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    auth::{
+        build_pkcs12, encrypt_private_key_pem, is_pkcs12_file, load_cert_and_key, load_certs,
+        load_pkcs12, write_file_atomically,
+        x509::{extract_machine_id, parse_x509, MACHINE_ID_URI_PREFIX},
+        PrivateKeyOptions,
+    },
+    conf::server_config::{EnrollmentConfig, ServerAuthConfig},
+};
+
+use super::cert_resolver::ReloadableCertResolver;
+
+/// Obtains and renews the server agent certificate from an enrollment endpoint.
+///
+/// Enrollment sends a CSR along with a one-time enrollment token to `{enrollment_url}/enroll`.
+/// Renewal sends a CSR for a fresh key to `{enrollment_url}/renew`, along with the current
+/// certificate and a signature of the CSR made with the current private key.
+pub struct Enroller {
+    pub enrollment_url: String,
+    pub cert_file: String,
+    pub private_key_file: String,
+    pub key_options: PrivateKeyOptions,
+    pub timeout: Duration,
+}
+
+#[derive(Serialize)]
+struct EnrollRequest<'a> {
+    token: &'a str,
+    csr: &'a str,
+}
+
+#[derive(Serialize)]
+struct RenewRequest<'a> {
+    csr: &'a str,
+    certificate: &'a str,
+    signature: String,
+    signature_scheme: String,
+}
+
+#[derive(Deserialize)]
+struct EnrollResponse {
+    certificate: String,
+}
+
+impl Enroller {
+    pub fn new(enrollment_config: &EnrollmentConfig, auth_config: &ServerAuthConfig) -> Self {
+        Self {
+            enrollment_url: enrollment_config.enrollment_url.clone(),
+            cert_file: auth_config.cert_file.clone(),
+            private_key_file: auth_config.private_key_file.clone(),
+            key_options: auth_config.private_key_options(),
+            timeout: Duration::from_secs(enrollment_config.timeout_secs),
+        }
+    }
+
+    /// Generates a key pair, has a certificate issued for `machine_id` and stores both.
+    pub async fn enroll(&self, machine_id: &str, token: &str) -> anyhow::Result<()> {
+        info!(
+            "Enrolling machine {} at {}",
+            machine_id, self.enrollment_url
+        );
+        let (key_pair, csr) = generate_csr(machine_id)?;
+
+        let request = EnrollRequest {
+            token: token.trim(),
+            csr: &csr,
+        };
+        let certificate = self.submit("enroll", &request).await?;
+        self.store(machine_id, &key_pair, &certificate)?;
+
+        info!("Machine {} enrolled successfully", machine_id);
+        Ok(())
+    }
+
+    /// Has a certificate issued for a fresh key pair, proving possession of the current one.
+    pub async fn renew(&self) -> anyhow::Result<()> {
+        let current_certs = self.current_certs()?;
+        let current_pem = current_certs
+            .iter()
+            .map(|c| {
+                pkcs8::der::pem::encode_string("CERTIFICATE", LineEnding::LF, c.as_ref())
+                    .map_err(|e| anyhow!("Could not encode current certificate : {}", e))
+            })
+            .collect::<anyhow::Result<String>>()?;
+        let machine_id = self.current_machine_id()?;
+        info!(
+            "Renewing certificate of machine {} at {}",
+            machine_id, self.enrollment_url
+        );
+
+        let (key_pair, csr) = generate_csr(&machine_id)?;
+        let (signature_scheme, signature) = self.sign_with_current_key(csr.as_bytes())?;
+
+        let request = RenewRequest {
+            csr: &csr,
+            certificate: &current_pem,
+            signature: STANDARD.encode(signature),
+            signature_scheme: format!("{:?}", signature_scheme),
+        };
+        let certificate = self.submit("renew", &request).await?;
+        self.store(&machine_id, &key_pair, &certificate)?;
+
+        info!("Certificate of machine {} renewed successfully", machine_id);
+        Ok(())
+    }
+
+    /// Time left before the current certificate expires.
+    pub fn remaining_validity(&self) -> anyhow::Result<Duration> {
+        let certs = self.current_certs()?;
+        let Some(cert) = certs.first() else {
+            bail!("Certificate file {} is empty", self.cert_file);
+        };
+        let not_after = parse_x509(cert)?.validity().not_after.timestamp();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        Ok(Duration::from_secs((not_after - now).max(0) as u64))
+    }
+
+    /// Renews the certificate once it is about to expire, then loads it into `cert_resolver`.
+    pub async fn watch_expiry(
+        self,
+        cert_resolver: Arc<ReloadableCertResolver>,
+        renew_before: Duration,
+        check_interval: Duration,
+    ) {
+        loop {
+            match self.remaining_validity() {
+                Ok(remaining) if remaining <= renew_before => {
+                    info!(
+                        "Certificate expires in {}, renewing it",
+                        humantime::format_duration(remaining)
+                    );
+                    match self.renew().await {
+                        Ok(()) => {
+                            if let Err(e) = cert_resolver.reload() {
+                                error!("Could not load renewed certificate : {}", e);
+                            }
+                        }
+                        Err(e) => error!("Could not renew certificate : {}", e),
+                    }
+                }
+                Ok(remaining) => debug!(
+                    "Certificate expires in {}, no renewal needed",
+                    humantime::format_duration(remaining)
+                ),
+                Err(e) => error!("Could not check certificate expiry : {}", e),
+            }
+            tokio::time::sleep(check_interval).await;
+        }
+    }
+
+    fn current_certs(&self) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+        if is_pkcs12_file(&self.cert_file) {
+            Ok(load_pkcs12(&self.cert_file, &self.key_options)?.0)
+        } else {
+            load_certs(&self.cert_file)
+        }
+    }
+
+    fn current_machine_id(&self) -> anyhow::Result<String> {
+        let certs = self.current_certs()?;
+        let Some(cert) = certs.first() else {
+            bail!("Certificate file {} is empty", self.cert_file);
+        };
+        extract_machine_id(&parse_x509(cert)?)
+    }
+
+    fn sign_with_current_key(&self, message: &[u8]) -> anyhow::Result<(SignatureScheme, Vec<u8>)> {
+        let (_, key) =
+            load_cert_and_key(&self.cert_file, &self.private_key_file, &self.key_options)?;
+        let provider = CryptoProvider::get_default()
+            .cloned()
+            .unwrap_or_else(|| Arc::new(rustls::crypto::aws_lc_rs::default_provider()));
+        let signing_key = match provider.key_provider.load_private_key(key) {
+            Ok(v) => v,
+            Err(e) => {
+                error!(
+                    "Unsupported private key in {} : {}",
+                    self.private_key_file, e
+                );
+                bail!("Unsupported private key in {}", self.private_key_file);
+            }
+        };
+
+        let Some(signer) = signing_key.choose_scheme(&[
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            SignatureScheme::RSA_PKCS1_SHA256,
+        ]) else {
+            bail!("Current private key can't sign with ECDSA P-256 or RSA PKCS#1 SHA-256");
+        };
+
+        match signer.sign(message) {
+            Ok(v) => Ok((signer.scheme(), v)),
+            Err(e) => {
+                error!("Could not sign renewal request : {}", e);
+                Err(anyhow!("Could not sign renewal request"))
+            }
+        }
+    }
+
+    async fn submit<T: Serialize>(&self, endpoint: &str, request: &T) -> anyhow::Result<String> {
+        let url = format!("{}/{}", self.enrollment_url.trim_end_matches('/'), endpoint);
+
+        let client = Client::builder().timeout(self.timeout).build()?;
+        let res = match client.post(&url).json(request).send().await {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Could not contact enrollment endpoint {} : {}", url, e);
+                bail!("Could not contact enrollment endpoint {}", url);
+            }
+        };
+
+        if res.status() != StatusCode::OK {
+            let status = res.status();
+            let body = res.text().await.unwrap_or_default();
+            error!(
+                "Enrollment endpoint {} refused the request : received status code {} : {}",
+                url, status, body
+            );
+            bail!("Enrollment endpoint refused the request ({})", status);
+        }
+
+        match res.json::<EnrollResponse>().await {
+            Ok(v) => Ok(v.certificate),
+            Err(e) => {
+                error!("Could not decode enrollment endpoint response : {}", e);
+                Err(anyhow!("Invalid response from enrollment endpoint"))
+            }
+        }
+    }
+
+    /// Checks the issued certificate, then replaces the key and certificate files.
+    /// The key is written first so that a reload never pairs the new certificate with the old key
+    /// for longer than the time between the two renames.
+    fn store(&self, machine_id: &str, key_pair: &KeyPair, certificate: &str) -> anyhow::Result<()> {
+        let mut reader = certificate.as_bytes();
+        let certs = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+        let Some(cert) = certs.first() else {
+            bail!("Enrollment endpoint didn't return any certificate");
+        };
+        let issued_id = extract_machine_id(&parse_x509(cert)?)?;
+        if issued_id != machine_id {
+            error!(
+                "Enrollment endpoint issued a certificate for machine '{}' instead of '{}'",
+                issued_id, machine_id
+            );
+            bail!("Issued certificate doesn't match the machine id");
+        }
+        if parse_x509(cert)?.public_key().raw != key_pair.public_key_der() {
+            bail!("Issued certificate doesn't match the generated key");
+        }
+
+        let key_der = key_pair.serialize_der();
+        if is_pkcs12_file(&self.cert_file) {
+            let passphrase = self.storage_passphrase(&self.cert_file)?;
+            let bundle = build_pkcs12(&certs, &key_der, &passphrase)?;
+            write_file_atomically(Path::new(&self.cert_file), &bundle, true)?;
+        } else {
+            let passphrase = self.storage_passphrase(&self.private_key_file)?;
+            let key_pem = encrypt_private_key_pem(&key_der, &passphrase)?;
+            write_file_atomically(Path::new(&self.private_key_file), key_pem.as_bytes(), true)?;
+            write_file_atomically(Path::new(&self.cert_file), certificate.as_bytes(), false)?;
+        }
+        debug!(
+            "Stored certificate ({}) and private key ({})",
+            self.cert_file, self.private_key_file
+        );
+        Ok(())
+    }
+
+    /// Passphrase to encrypt a newly issued key with. Issued keys are never stored
+    /// unencrypted.
+    fn storage_passphrase(&self, filename: &str) -> anyhow::Result<String> {
+        match self.key_options.passphrase(filename)? {
+            Some(v) if !v.is_empty() => Ok(v),
+            _ => {
+                error!(
+                    "No passphrase available to encrypt the private key in {}",
+                    filename
+                );
+                bail!("No passphrase is available to encrypt the issued private key")
+            }
+        }
+    }
+}
+
+fn generate_csr(machine_id: &str) -> anyhow::Result<(KeyPair, String)> {
+    let key_pair = KeyPair::generate().context("Could not generate key pair")?;
+
+    let mut params = CertificateParams::default();
+    let mut distinguished_name = DistinguishedName::new();
+    distinguished_name.push(DnType::CommonName, machine_id);
+    params.distinguished_name = distinguished_name;
+    params.subject_alt_names = vec![SanType::URI(
+        format!("{}{}", MACHINE_ID_URI_PREFIX, machine_id)
+            .try_into()
+            .context("Machine id can't be used in a URI")?,
+    )];
+
+    let csr = params
+        .serialize_request(&key_pair)
+        .and_then(|csr| csr.pem())
+        .context("Could not build certificate signing request")?;
+    Ok((key_pair, csr))
+}
+
+/// Reads a one-time enrollment token, from the command line or from a file.
+pub fn read_enrollment_token(
+    token: Option<String>,
+    token_file: Option<&Path>,
+) -> anyhow::Result<String> {
+    if let Some(t) = token {
+        return Ok(t);
+    }
+    let Some(token_file) = token_file else {
+        bail!("No enrollment token provided");
+    };
+    match fs::read_to_string(token_file) {
+        Ok(v) if !v.trim().is_empty() => Ok(v.trim().to_owned()),
+        Ok(_) => bail!("Enrollment token file {} is empty", token_file.display()),
+        Err(e) => {
+            warn!(
+                "Could not read enrollment token file {} : {}",
+                token_file.display(),
+                e
+            );
+            bail!(
+                "Could not read enrollment token file {}",
+                token_file.display()
+            )
+        }
+    }
+}
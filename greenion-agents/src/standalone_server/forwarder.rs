@@ -1,9 +1,38 @@
 use log::{error, info};
+use prost::Message;
+use std::{
+    io,
+    sync::{atomic::AtomicU64, Arc},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    sync::watch,
+};
 
 use super::StandaloneServerForwarder;
+use crate::{
+    metrics::{metrics, CountingStream},
+    proto::{
+        common::{write_session_frame, SESSION_DATA_FRAME, SESSION_NOTICE_FRAME},
+        messages::SessionNotice,
+    },
+};
+
+/// Sanzu data read at once, sent in a single frame
+const DATA_FRAME_SIZE: usize = 16 * 1024;
+
+/// Bytes forwarded so far in each direction. Kept up to date while forwarding, so the count
+/// is known even when the session is cut short.
+#[derive(Debug, Default)]
+pub struct Traffic {
+    pub from_client: AtomicU64,
+    pub to_client: AtomicU64,
+}
 
 impl StandaloneServerForwarder {
-    pub async fn forward(mut self) -> anyhow::Result<()> {
+    pub async fn forward(mut self, traffic: Arc<Traffic>) -> anyhow::Result<()> {
         match self.sanzu_stream.set_nodelay(true) {
             Ok(_) => {}
             Err(e) => {
@@ -11,18 +40,103 @@ impl StandaloneServerForwarder {
             }
         };
 
-        let res =
-            tokio::io::copy_bidirectional(&mut self.outbound_tls_stream, &mut self.sanzu_stream)
-                .await;
+        let metrics = metrics();
+        let mut client_stream = CountingStream {
+            inner: &mut self.outbound_tls_stream,
+            read: &[&traffic.from_client, &metrics.upstream_bytes],
+            written: &[&traffic.to_client, &metrics.downstream_bytes],
+        };
+        let res = match self.shutdown_deadline {
+            Some(shutdown_deadline) => {
+                forward_framed(
+                    &mut client_stream,
+                    &mut self.sanzu_stream,
+                    shutdown_deadline,
+                )
+                .await
+            }
+            None => tokio::io::copy_bidirectional(&mut client_stream, &mut self.sanzu_stream).await,
+        };
 
         match res {
             Ok((v1, v2)) => {
                 info!("Server forwarder exited : wrote {} and {} bytes", v1, v2);
+                Ok(())
             }
             Err(e) => {
                 error!("Server forwarder exited with error {}", e);
+                Err(e.into())
+            }
+        }
+    }
+}
+
+/// Forwards the client stream as is to the sanzu server, and the sanzu stream to the client
+/// in data frames, with a notice frame whenever the shutdown deadline is set.
+async fn forward_framed<S: AsyncRead + AsyncWrite + Unpin>(
+    client_stream: S,
+    sanzu_stream: &mut TcpStream,
+    mut shutdown_deadline: watch::Receiver<Option<SystemTime>>,
+) -> io::Result<(u64, u64)> {
+    let (mut client_read, mut client_write) = tokio::io::split(client_stream);
+    let (mut sanzu_read, mut sanzu_write) = sanzu_stream.split();
+
+    let upstream = async {
+        let written = tokio::io::copy(&mut client_read, &mut sanzu_write).await?;
+        sanzu_write.shutdown().await?;
+        Ok::<_, io::Error>(written)
+    };
+    let downstream = async {
+        // the shutdown may have begun before the session started
+        shutdown_deadline.mark_changed();
+        let mut watching = true;
+        let mut buffer = vec![0u8; DATA_FRAME_SIZE];
+        let mut written = 0;
+        loop {
+            tokio::select! {
+                read = sanzu_read.read(&mut buffer) => {
+                    let read = read?;
+                    if read == 0 {
+                        break;
+                    }
+                    write_session_frame(&mut client_write, SESSION_DATA_FRAME, &buffer[..read])
+                        .await?;
+                    client_write.flush().await?;
+                    written += read as u64;
+                }
+                changed = shutdown_deadline.changed(), if watching => {
+                    if changed.is_err() {
+                        watching = false;
+                        continue;
+                    }
+                    let Some(deadline) = *shutdown_deadline.borrow_and_update() else {
+                        continue;
+                    };
+                    write_session_frame(
+                        &mut client_write,
+                        SESSION_NOTICE_FRAME,
+                        &shutdown_notice(deadline).encode_to_vec(),
+                    )
+                    .await?;
+                    client_write.flush().await?;
+                }
             }
         }
-        Ok(())
+        client_write.shutdown().await?;
+        Ok(written)
+    };
+    tokio::try_join!(upstream, downstream)
+}
+
+fn shutdown_notice(deadline: SystemTime) -> SessionNotice {
+    SessionNotice {
+        shutdown_deadline: deadline
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        shutdown_in_secs: deadline
+            .duration_since(SystemTime::now())
+            .unwrap_or_default()
+            .as_secs(),
     }
 }
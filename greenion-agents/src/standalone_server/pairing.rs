@@ -0,0 +1,395 @@
+use anyhow::{anyhow, bail, Context};
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use log::{debug, error, info, warn};
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fs,
+    io::{Cursor, ErrorKind},
+    net::SocketAddr,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsStream;
+
+use crate::{
+    auth::{
+        pake::{channel_binding, PakeSide, Spake2},
+        random_bytes, write_file_atomically,
+    },
+    conf::server_config::PairingConfig,
+    proto::{
+        common::{recv_msg_async, send_msg_async},
+        messages::{
+            ClientHello, ClientPairingConfirmation, PairingResult, PairingStatus, ServerPairing,
+        },
+    },
+    standalone_server::authenticator::CredentialRejected,
+};
+
+const MAX_DEVICE_NAME_LEN: usize = 64;
+
+/// Devices paired with this server, and the pending pairing code.
+///
+/// The code is written by `greenion-server pair` and consumed by the running agent on the
+/// first pairing attempt, whether it succeeds or not. Both files are read on every use, so
+/// pairing or removing a device doesn't need a restart.
+#[derive(Debug, Clone)]
+pub struct PairingStore {
+    devices_file: String,
+    code_file: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PairedDevice {
+    pub id: String,
+    pub name: String,
+    pub paired_at: u64,
+    secret_sha256: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PendingCode {
+    code: String,
+    expires_at: u64,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn secret_hash(secret: &[u8]) -> String {
+    STANDARD.encode(Sha256::digest(secret))
+}
+
+impl PairingStore {
+    pub fn new(config: &PairingConfig) -> Self {
+        Self {
+            devices_file: config.devices_file.clone(),
+            code_file: config.pairing_code_file.clone(),
+        }
+    }
+
+    /// Generates a new one-time pairing code, replacing any pending one.
+    pub fn create_code(&self, validity: Duration) -> anyhow::Result<String> {
+        let mut random = [0u8; 8];
+        random_bytes(&mut random)?;
+        let digits = u64::from_le_bytes(random) % 100_000_000;
+        let code = format!("{:04}-{:04}", digits / 10_000, digits % 10_000);
+
+        let pending = PendingCode {
+            code: code.clone(),
+            expires_at: now_secs() + validity.as_secs(),
+        };
+        write_file_atomically(
+            Path::new(&self.code_file),
+            &serde_json::to_vec(&pending)?,
+            true,
+        )?;
+        Ok(code)
+    }
+
+    /// Returns the pending pairing code, if any, and removes it.
+    fn take_code(&self) -> anyhow::Result<Option<String>> {
+        let content = match fs::read(&self.code_file) {
+            Ok(v) => v,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                error!(
+                    "Could not read pairing code file {} : {}",
+                    self.code_file, e
+                );
+                bail!("Could not read pairing code file {}", self.code_file);
+            }
+        };
+        if let Err(e) = fs::remove_file(&self.code_file) {
+            error!(
+                "Could not remove pairing code file {} : {}",
+                self.code_file, e
+            );
+            bail!("Could not consume the pairing code");
+        }
+
+        let pending = serde_json::from_slice::<PendingCode>(&content)
+            .with_context(|| format!("Pairing code file {} is invalid", self.code_file))?;
+        if pending.expires_at < now_secs() {
+            warn!("Pairing code has expired");
+            return Ok(None);
+        }
+        Ok(Some(pending.code))
+    }
+
+    pub fn devices(&self) -> anyhow::Result<Vec<PairedDevice>> {
+        match fs::read(&self.devices_file) {
+            Ok(v) => serde_json::from_slice(&v)
+                .with_context(|| format!("Paired devices file {} is invalid", self.devices_file)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(vec![]),
+            Err(e) => {
+                error!(
+                    "Could not read paired devices file {} : {}",
+                    self.devices_file, e
+                );
+                bail!("Could not read paired devices file {}", self.devices_file);
+            }
+        }
+    }
+
+    fn save_devices(&self, devices: &[PairedDevice]) -> anyhow::Result<()> {
+        write_file_atomically(
+            Path::new(&self.devices_file),
+            &serde_json::to_vec_pretty(devices)?,
+            true,
+        )
+    }
+
+    /// Records a new device and returns it with the secret it must present from now on.
+    fn add_device(&self, name: &str) -> anyhow::Result<(PairedDevice, Vec<u8>)> {
+        let mut id = [0u8; 16];
+        let mut secret = vec![0u8; 32];
+        random_bytes(&mut id)?;
+        random_bytes(&mut secret)?;
+
+        let device = PairedDevice {
+            id: URL_SAFE_NO_PAD.encode(id),
+            name: name.to_owned(),
+            paired_at: now_secs(),
+            secret_sha256: secret_hash(&secret),
+        };
+        let mut devices = self.devices()?;
+        devices.push(device.clone());
+        self.save_devices(&devices)?;
+        Ok((device, secret))
+    }
+
+    /// Removes the devices whose name or id is `device`. Returns how many were removed.
+    pub fn remove_device(&self, device: &str) -> anyhow::Result<usize> {
+        let mut devices = self.devices()?;
+        let before = devices.len();
+        devices.retain(|d| d.id != device && d.name != device);
+        let removed = before - devices.len();
+        if removed > 0 {
+            self.save_devices(&devices)?;
+        }
+        Ok(removed)
+    }
+
+    /// Returns the paired device matching this credential, if any.
+    pub fn authenticate_device(
+        &self,
+        device_id: &str,
+        secret: &[u8],
+    ) -> anyhow::Result<Option<PairedDevice>> {
+        let hash = secret_hash(secret);
+        Ok(self
+            .devices()?
+            .into_iter()
+            .find(|d| d.id == device_id && d.secret_sha256 == hash))
+    }
+}
+
+/// Runs the server side of a pairing request : a SPAKE2 handshake keyed by the pending pairing
+/// code and bound to the TLS session, followed by key confirmation. On success a new device
+/// credential is sent to the client, and the new device is returned.
+pub async fn pair_device(
+    store: &PairingStore,
+    stream: &mut TlsStream<TcpStream>,
+    client_addr: SocketAddr,
+    hello: &ClientHello,
+    machine_id: &str,
+    timeout: Duration,
+) -> anyhow::Result<PairedDevice> {
+    let code = match store.take_code() {
+        Ok(Some(v)) => v,
+        Ok(None) => {
+            let unavailable = ServerPairing {
+                status: PairingStatus::PairingUnavailable.into(),
+                ..Default::default()
+            };
+            send_msg_async(stream, unavailable, Some(timeout)).await?;
+            bail!(
+                "{} tried to pair but no pairing code is pending. Run `greenion-server pair` first",
+                client_addr
+            );
+        }
+        Err(e) => {
+            let failed = ServerPairing {
+                status: PairingStatus::PairingFailed.into(),
+                ..Default::default()
+            };
+            send_msg_async(stream, failed, Some(timeout)).await?;
+            return Err(e);
+        }
+    };
+
+    let spake = Spake2::start(PakeSide::Server, &code)?;
+    let server_message = spake.message().to_vec();
+    let key = match channel_binding(stream)
+        .and_then(|binding| spake.finish(&hello.pairing_message, &binding))
+    {
+        Ok(v) => v,
+        Err(e) => {
+            let failed = ServerPairing {
+                status: PairingStatus::PairingFailed.into(),
+                ..Default::default()
+            };
+            send_msg_async(stream, failed, Some(timeout)).await?;
+            return Err(
+                CredentialRejected(format!("Pairing with {} failed : {}", client_addr, e)).into(),
+            );
+        }
+    };
+
+    let server_pairing = ServerPairing {
+        status: PairingStatus::PairingContinue.into(),
+        pairing_message: server_message,
+        confirmation: key.confirmation(PakeSide::Server),
+    };
+    send_msg_async(stream, server_pairing, Some(timeout)).await?;
+
+    let confirmation = recv_msg_async(stream, Some(timeout))
+        .await
+        .and_then(|msg| Ok(ClientPairingConfirmation::decode(&mut Cursor::new(msg))?))
+        .map_err(|e| {
+            anyhow!(
+                "Could not receive pairing confirmation from {} : {}",
+                client_addr,
+                e
+            )
+        })?;
+    if !key.verify_confirmation(PakeSide::Client, &confirmation.confirmation) {
+        let failed = PairingResult {
+            status: PairingStatus::PairingFailed.into(),
+            ..Default::default()
+        };
+        send_msg_async(stream, failed, Some(timeout)).await?;
+        return Err(CredentialRejected(format!(
+            "Pairing with {} failed : wrong pairing code, or the connection was intercepted",
+            client_addr
+        ))
+        .into());
+    }
+    debug!("Pairing code confirmed by {}", client_addr);
+
+    let name = match hello.device_name.trim() {
+        "" => client_addr.ip().to_string(),
+        name => name.chars().take(MAX_DEVICE_NAME_LEN).collect(),
+    };
+    let (device, secret) = store.add_device(&name)?;
+    let result = PairingResult {
+        status: PairingStatus::Paired.into(),
+        machine_id: machine_id.to_owned(),
+        device_id: device.id.clone(),
+        device_secret: secret,
+    };
+    send_msg_async(stream, result, Some(timeout)).await?;
+    info!(
+        "Paired device '{}' (id {}) from {}",
+        device.name, device.id, client_addr
+    );
+    Ok(device)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn store(dir: &TempDir) -> PairingStore {
+        PairingStore {
+            devices_file: dir.path().join("devices.json").display().to_string(),
+            code_file: dir.path().join("code.json").display().to_string(),
+        }
+    }
+
+    #[test]
+    fn code_is_used_once() {
+        let dir = TempDir::new().unwrap();
+        let store = store(&dir);
+        assert_eq!(store.take_code().unwrap(), None);
+
+        let code = store.create_code(Duration::from_secs(60)).unwrap();
+        assert_eq!(code.len(), 9);
+        assert!(code.chars().enumerate().all(|(i, c)| if i == 4 {
+            c == '-'
+        } else {
+            c.is_ascii_digit()
+        }));
+        assert_eq!(store.take_code().unwrap(), Some(code));
+        assert_eq!(store.take_code().unwrap(), None);
+    }
+
+    #[test]
+    fn new_code_replaces_pending_one() {
+        let dir = TempDir::new().unwrap();
+        let store = store(&dir);
+        store.create_code(Duration::from_secs(60)).unwrap();
+        let code = store.create_code(Duration::from_secs(60)).unwrap();
+        assert_eq!(store.take_code().unwrap(), Some(code));
+    }
+
+    #[test]
+    fn expired_code() {
+        let dir = TempDir::new().unwrap();
+        let store = store(&dir);
+        let pending = PendingCode {
+            code: "1234-5678".to_owned(),
+            expires_at: now_secs() - 1,
+        };
+        fs::write(&store.code_file, serde_json::to_vec(&pending).unwrap()).unwrap();
+        assert_eq!(store.take_code().unwrap(), None);
+        assert!(!Path::new(&store.code_file).exists());
+    }
+
+    #[test]
+    fn devices() {
+        let dir = TempDir::new().unwrap();
+        let store = store(&dir);
+        assert!(store.devices().unwrap().is_empty());
+
+        let (laptop, laptop_secret) = store.add_device("laptop").unwrap();
+        let (phone, phone_secret) = store.add_device("phone").unwrap();
+        assert_ne!(laptop.id, phone.id);
+        assert_ne!(laptop_secret, phone_secret);
+
+        let found = store
+            .authenticate_device(&laptop.id, &laptop_secret)
+            .unwrap();
+        assert_eq!(found.map(|d| d.name), Some("laptop".to_owned()));
+        assert!(store
+            .authenticate_device(&laptop.id, &phone_secret)
+            .unwrap()
+            .is_none());
+        assert!(store
+            .authenticate_device("unknown", &laptop_secret)
+            .unwrap()
+            .is_none());
+
+        // by name or by id
+        assert_eq!(store.remove_device("laptop").unwrap(), 1);
+        assert_eq!(store.remove_device(&phone.id).unwrap(), 1);
+        assert_eq!(store.remove_device("phone").unwrap(), 0);
+        assert!(store
+            .authenticate_device(&phone.id, &phone_secret)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn secrets_are_not_stored() {
+        let dir = TempDir::new().unwrap();
+        let store = store(&dir);
+        let (_, secret) = store.add_device("laptop").unwrap();
+        let content = fs::read_to_string(&store.devices_file).unwrap();
+        assert!(!content.contains(&STANDARD.encode(&secret)));
+        assert!(!content.contains(&URL_SAFE_NO_PAD.encode(&secret)));
+    }
+}
@@ -0,0 +1,531 @@
+use anyhow::bail;
+use chrono::{Datelike, Local, NaiveTime, Weekday};
+use ipnet::IpNet;
+use log::{debug, error, info};
+use serde::Deserialize;
+use std::{
+    fs,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    auth::jwt::Claims, proto::messages::PolicyDenyReason, standalone_server::utils::canonical_ip,
+};
+
+/// Access policy of the server agent, read from `server_auth_config.policy_file`.
+///
+/// Rules are evaluated in order : the first rule that matches the user decides. An allow
+/// rule only applies if the connection also comes from one of its `source_cidrs` and
+/// happens within one of its `time_windows`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessPolicy {
+    /// Decision when no rule matches the user
+    #[serde(default = "default_action")]
+    pub default_action: PolicyAction,
+    /// Claim holding the groups of the user
+    #[serde(default = "default_groups_claim")]
+    pub groups_claim: String,
+    /// Claim holding the roles of the user
+    #[serde(default = "default_roles_claim")]
+    pub roles_claim: String,
+    /// Sources every connection must come from. Empty allows any source
+    #[serde(default)]
+    pub source_cidrs: Vec<IpNet>,
+    /// When every connection must happen. Empty allows any time
+    #[serde(default)]
+    pub time_windows: Vec<TimeWindow>,
+    /// Maximum duration of every session. 0 means no limit
+    #[serde(default)]
+    pub max_session_duration_secs: u64,
+    #[serde(default)]
+    pub rules: Vec<PolicyRule>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PolicyAction {
+    Allow,
+    Deny,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyRule {
+    pub action: PolicyAction,
+    /// `sub` claims the rule applies to
+    #[serde(default)]
+    pub users: Vec<String>,
+    #[serde(default)]
+    pub groups: Vec<String>,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    #[serde(default)]
+    pub source_cidrs: Vec<IpNet>,
+    #[serde(default)]
+    pub time_windows: Vec<TimeWindow>,
+    #[serde(default)]
+    pub max_session_duration_secs: u64,
+}
+
+/// Days and hours (local time of the server) during which connections are allowed.
+/// A window whose `end` is before its `start` runs past midnight.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimeWindow {
+    #[serde(default = "all_days", deserialize_with = "deserialize_days")]
+    pub days: Vec<Weekday>,
+    #[serde(deserialize_with = "deserialize_time")]
+    pub start: NaiveTime,
+    #[serde(deserialize_with = "deserialize_time")]
+    pub end: NaiveTime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyDecision {
+    Allowed {
+        max_session_duration: Option<Duration>,
+    },
+    Denied(PolicyDenyReason),
+}
+
+fn default_action() -> PolicyAction {
+    PolicyAction::Deny
+}
+fn default_groups_claim() -> String {
+    "groups".to_string()
+}
+fn default_roles_claim() -> String {
+    "roles".to_string()
+}
+fn all_days() -> Vec<Weekday> {
+    vec![
+        Weekday::Mon,
+        Weekday::Tue,
+        Weekday::Wed,
+        Weekday::Thu,
+        Weekday::Fri,
+        Weekday::Sat,
+        Weekday::Sun,
+    ]
+}
+
+fn deserialize_days<'de, D>(deserializer: D) -> Result<Vec<Weekday>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|d| {
+            d.parse::<Weekday>()
+                .map_err(|_| serde::de::Error::custom(format!("unknown day '{}'", d)))
+        })
+        .collect()
+}
+
+fn deserialize_time<'de, D>(deserializer: D) -> Result<NaiveTime, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    NaiveTime::parse_from_str(&s, "%H:%M")
+        .map_err(|_| serde::de::Error::custom(format!("invalid time '{}', expected HH:MM", s)))
+}
+
+impl TimeWindow {
+    fn contains(&self, day: Weekday, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.days.contains(&day) && self.start <= time && time < self.end
+        } else {
+            // past midnight : the part after midnight belongs to the day the window started
+            (self.days.contains(&day) && time >= self.start)
+                || (self.days.contains(&day.pred()) && time < self.end)
+        }
+    }
+}
+
+fn in_time_windows(windows: &[TimeWindow], day: Weekday, time: NaiveTime) -> bool {
+    windows.is_empty() || windows.iter().any(|w| w.contains(day, time))
+}
+
+fn in_sources(cidrs: &[IpNet], addr: IpAddr) -> bool {
+    let addr = canonical_ip(addr);
+    cidrs.is_empty() || cidrs.iter().any(|c| c.contains(&addr))
+}
+
+/// Values of `claim`, which may be a string or a list of strings.
+fn claim_values<'a>(claims: &'a Claims, claim: &str) -> Vec<&'a str> {
+    match claims.extra.get(claim) {
+        Some(serde_json::Value::String(s)) => vec![s.as_str()],
+        Some(serde_json::Value::Array(values)) => {
+            values.iter().filter_map(|v| v.as_str()).collect()
+        }
+        _ => vec![],
+    }
+}
+
+fn max_duration(secs: &[u64]) -> Option<Duration> {
+    secs.iter()
+        .filter(|s| **s > 0)
+        .min()
+        .map(|s| Duration::from_secs(*s))
+}
+
+impl AccessPolicy {
+    pub fn from_file(filename: &str) -> anyhow::Result<Self> {
+        let contents = match fs::read_to_string(filename) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Could not read policy file {} : {}", filename, e);
+                bail!("Could not read policy file {}", filename);
+            }
+        };
+        match toml::from_str::<AccessPolicy>(&contents) {
+            Ok(v) => Ok(v),
+            Err(e) => {
+                error!("Could not parse policy file {} : {}", filename, e);
+                bail!("Policy file {} is invalid : {}", filename, e);
+            }
+        }
+    }
+
+    pub fn evaluate(&self, claims: &Claims, source: IpAddr) -> PolicyDecision {
+        let now = Local::now();
+        self.evaluate_at(claims, source, now.weekday(), now.time())
+    }
+
+    fn evaluate_at(
+        &self,
+        claims: &Claims,
+        source: IpAddr,
+        day: Weekday,
+        time: NaiveTime,
+    ) -> PolicyDecision {
+        if !in_sources(&self.source_cidrs, source) {
+            return PolicyDecision::Denied(PolicyDenyReason::SourceNotAllowed);
+        }
+        if !in_time_windows(&self.time_windows, day, time) {
+            return PolicyDecision::Denied(PolicyDenyReason::OutsideAllowedHours);
+        }
+
+        let groups = claim_values(claims, &self.groups_claim);
+        let roles = claim_values(claims, &self.roles_claim);
+
+        // reason of the first allow rule that matched the user but not the connection
+        let mut unmet = None;
+        for rule in self.rules.iter() {
+            let matches_user = rule.users.contains(&claims.user_id)
+                || rule.groups.iter().any(|g| groups.contains(&g.as_str()))
+                || rule.roles.iter().any(|r| roles.contains(&r.as_str()))
+                || (rule.users.is_empty() && rule.groups.is_empty() && rule.roles.is_empty());
+            if !matches_user {
+                continue;
+            }
+
+            if rule.action == PolicyAction::Deny {
+                return PolicyDecision::Denied(PolicyDenyReason::UserNotAllowed);
+            }
+            if !in_sources(&rule.source_cidrs, source) {
+                unmet.get_or_insert(PolicyDenyReason::SourceNotAllowed);
+                continue;
+            }
+            if !in_time_windows(&rule.time_windows, day, time) {
+                unmet.get_or_insert(PolicyDenyReason::OutsideAllowedHours);
+                continue;
+            }
+            return PolicyDecision::Allowed {
+                max_session_duration: max_duration(&[
+                    self.max_session_duration_secs,
+                    rule.max_session_duration_secs,
+                ]),
+            };
+        }
+
+        match (unmet, self.default_action) {
+            (Some(reason), _) => PolicyDecision::Denied(reason),
+            (None, PolicyAction::Allow) => PolicyDecision::Allowed {
+                max_session_duration: max_duration(&[self.max_session_duration_secs]),
+            },
+            (None, PolicyAction::Deny) => PolicyDecision::Denied(PolicyDenyReason::UserNotAllowed),
+        }
+    }
+}
+
+/// Serves the access policy, read again whenever the policy file changes. If the new
+/// file is invalid, the current policy is kept.
+#[derive(Debug)]
+pub struct PolicyProvider {
+    filename: String,
+    current: Mutex<(Option<SystemTime>, Arc<AccessPolicy>)>,
+}
+
+impl PolicyProvider {
+    pub fn new(filename: &str) -> anyhow::Result<Self> {
+        let modified = modified_at(filename);
+        let policy = AccessPolicy::from_file(filename)?;
+        debug!(
+            "Loaded access policy {} : {} rule(s)",
+            filename,
+            policy.rules.len()
+        );
+        Ok(Self {
+            filename: filename.to_owned(),
+            current: Mutex::new((modified, Arc::new(policy))),
+        })
+    }
+
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    pub fn current(&self) -> Arc<AccessPolicy> {
+        let modified = modified_at(&self.filename);
+        let mut current = self.current.lock().unwrap_or_else(|e| e.into_inner());
+        if current.0 != modified {
+            // remember the date even on failure, so a broken file is only reported once
+            current.0 = modified;
+            match AccessPolicy::from_file(&self.filename) {
+                Ok(policy) => {
+                    info!("Reloaded access policy {}", self.filename);
+                    current.1 = Arc::new(policy);
+                }
+                Err(e) => {
+                    error!(
+                        "Could not reload access policy, keeping the current one : {}",
+                        e
+                    );
+                }
+            }
+        }
+        Arc::clone(&current.1)
+    }
+}
+
+fn modified_at(filename: &str) -> Option<SystemTime> {
+    fs::metadata(filename).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn policy(toml: &str) -> AccessPolicy {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn claims(user_id: &str, extra: serde_json::Value) -> Claims {
+        Claims {
+            exp: 0,
+            user_id: user_id.to_owned(),
+            session_id: 1,
+            machine_id: "1234".to_owned(),
+            machine_ip: "127.0.0.1".to_owned(),
+            machine_port: 5000,
+            extra: serde_json::from_value::<HashMap<_, _>>(extra).unwrap(),
+        }
+    }
+
+    fn at(h: u32, m: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(h, m, 0).unwrap()
+    }
+
+    fn evaluate(
+        policy: &AccessPolicy,
+        claims: &Claims,
+        source: &str,
+        day: Weekday,
+        time: NaiveTime,
+    ) -> PolicyDecision {
+        policy.evaluate_at(claims, source.parse().unwrap(), day, time)
+    }
+
+    fn denied(reason: PolicyDenyReason) -> PolicyDecision {
+        PolicyDecision::Denied(reason)
+    }
+
+    fn allowed(secs: Option<u64>) -> PolicyDecision {
+        PolicyDecision::Allowed {
+            max_session_duration: secs.map(Duration::from_secs),
+        }
+    }
+
+    #[test]
+    fn default_action() {
+        let alice = claims("alice", serde_json::json!({}));
+        let deny = policy("");
+        assert_eq!(
+            evaluate(&deny, &alice, "10.0.0.1", Weekday::Mon, at(12, 0)),
+            denied(PolicyDenyReason::UserNotAllowed)
+        );
+        let allow = policy("default_action = \"allow\"\nmax_session_duration_secs = 60");
+        assert_eq!(
+            evaluate(&allow, &alice, "10.0.0.1", Weekday::Mon, at(12, 0)),
+            allowed(Some(60))
+        );
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let policy = policy(
+            r#"
+            [[rules]]
+            action = "deny"
+            users = ["mallory"]
+            [[rules]]
+            action = "allow"
+            groups = ["admins"]
+            [[rules]]
+            action = "allow"
+            roles = ["support"]
+            [[rules]]
+            action = "deny"
+            "#,
+        );
+        let decide =
+            |claims: &Claims| evaluate(&policy, claims, "10.0.0.1", Weekday::Mon, at(12, 0));
+
+        let admin = claims(
+            "alice",
+            serde_json::json!({ "groups": ["staff", "admins"] }),
+        );
+        assert_eq!(decide(&admin), allowed(None));
+        let support = claims("bob", serde_json::json!({ "roles": "support" }));
+        assert_eq!(decide(&support), allowed(None));
+        let mallory = claims("mallory", serde_json::json!({ "groups": ["admins"] }));
+        assert_eq!(decide(&mallory), denied(PolicyDenyReason::UserNotAllowed));
+        let other = claims("carol", serde_json::json!({ "groups": ["staff"] }));
+        assert_eq!(decide(&other), denied(PolicyDenyReason::UserNotAllowed));
+    }
+
+    #[test]
+    fn custom_claims() {
+        let policy = policy(
+            r#"
+            groups_claim = "teams"
+            [[rules]]
+            action = "allow"
+            groups = ["ops"]
+            "#,
+        );
+        let alice = claims("alice", serde_json::json!({ "teams": ["ops"] }));
+        assert_eq!(
+            evaluate(&policy, &alice, "10.0.0.1", Weekday::Mon, at(12, 0)),
+            allowed(None)
+        );
+        let bob = claims("bob", serde_json::json!({ "groups": ["ops"] }));
+        assert_eq!(
+            evaluate(&policy, &bob, "10.0.0.1", Weekday::Mon, at(12, 0)),
+            denied(PolicyDenyReason::UserNotAllowed)
+        );
+    }
+
+    #[test]
+    fn sources() {
+        let policy = policy(
+            r#"
+            source_cidrs = ["10.0.0.0/8"]
+            [[rules]]
+            action = "allow"
+            users = ["alice"]
+            source_cidrs = ["10.1.0.0/16"]
+            "#,
+        );
+        let alice = claims("alice", serde_json::json!({}));
+        let decide = |source| evaluate(&policy, &alice, source, Weekday::Mon, at(12, 0));
+        assert_eq!(decide("10.1.2.3"), allowed(None));
+        // IPv4-mapped IPv6 addresses of dual stack listeners
+        assert_eq!(decide("::ffff:10.1.2.3"), allowed(None));
+        assert_eq!(
+            decide("10.2.0.1"),
+            denied(PolicyDenyReason::SourceNotAllowed)
+        );
+        assert_eq!(
+            decide("192.168.0.1"),
+            denied(PolicyDenyReason::SourceNotAllowed)
+        );
+    }
+
+    #[test]
+    fn time_windows() {
+        let policy = policy(
+            r#"
+            [[rules]]
+            action = "allow"
+            [[rules.time_windows]]
+            days = ["mon", "tue", "wed", "thu", "fri"]
+            start = "08:00"
+            end = "18:00"
+            "#,
+        );
+        let alice = claims("alice", serde_json::json!({}));
+        let decide = |day, time| evaluate(&policy, &alice, "10.0.0.1", day, time);
+        assert_eq!(decide(Weekday::Mon, at(8, 0)), allowed(None));
+        assert_eq!(
+            decide(Weekday::Mon, at(18, 0)),
+            denied(PolicyDenyReason::OutsideAllowedHours)
+        );
+        assert_eq!(
+            decide(Weekday::Sat, at(12, 0)),
+            denied(PolicyDenyReason::OutsideAllowedHours)
+        );
+    }
+
+    #[test]
+    fn time_window_past_midnight() {
+        let window = TimeWindow {
+            days: vec![Weekday::Fri],
+            start: at(22, 0),
+            end: at(2, 0),
+        };
+        assert!(window.contains(Weekday::Fri, at(23, 0)));
+        assert!(window.contains(Weekday::Sat, at(1, 59)));
+        assert!(!window.contains(Weekday::Fri, at(1, 0)));
+        assert!(!window.contains(Weekday::Sat, at(2, 0)));
+        assert!(!window.contains(Weekday::Sat, at(23, 0)));
+    }
+
+    #[test]
+    fn shortest_session_duration() {
+        let policy = policy(
+            r#"
+            max_session_duration_secs = 3600
+            [[rules]]
+            action = "allow"
+            users = ["alice"]
+            max_session_duration_secs = 600
+            [[rules]]
+            action = "allow"
+            users = ["bob"]
+            "#,
+        );
+        let decide = |user| {
+            evaluate(
+                &policy,
+                &claims(user, serde_json::json!({})),
+                "10.0.0.1",
+                Weekday::Mon,
+                at(12, 0),
+            )
+        };
+        assert_eq!(decide("alice"), allowed(Some(600)));
+        assert_eq!(decide("bob"), allowed(Some(3600)));
+    }
+
+    #[test]
+    fn invalid_policy() {
+        assert!(toml::from_str::<AccessPolicy>("default_action = \"maybe\"").is_err());
+        assert!(toml::from_str::<AccessPolicy>("unknown = 1").is_err());
+        assert!(toml::from_str::<AccessPolicy>(
+            "[[time_windows]]\nstart = \"8h\"\nend = \"18:00\""
+        )
+        .is_err());
+        assert!(toml::from_str::<AccessPolicy>(
+            "[[time_windows]]\ndays = [\"someday\"]\nstart = \"08:00\"\nend = \"18:00\""
+        )
+        .is_err());
+    }
+}
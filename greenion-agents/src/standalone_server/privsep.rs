@@ -0,0 +1,1207 @@
+//! Privilege separation of the server agent on Linux.
+//!
+//! The agent started as root becomes a small supervisor : it loads the private key, keeps
+//! the certificate up to date and starts the sanzu servers. Everything facing the network
+//! (TLS, token validation, pairing, forwarding) runs in a network process started as an
+//! unprivileged user. The two talk over a socket pair with single lines of JSON, and the
+//! supervisor only answers these requests :
+//! - the current certificate chain, and its generation
+//! - a TLS 1.3 server signature with the private key, which never leaves the supervisor
+//! - a sanzu server for a user on one of the configured sanzu ports, how it exited, and
+//!   stopping it when its session ends
+//! - appending a record to the audit log, whose key never leaves the supervisor either
+use anyhow::{anyhow, bail, Context};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use log::{error, info, warn};
+use rustls::{
+    pki_types::CertificateDer,
+    sign::{CertifiedKey, Signer, SigningKey},
+    SignatureAlgorithm, SignatureScheme,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Read, Write},
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::net::UnixStream,
+    },
+    path::{Component, Path},
+    process::ExitStatus,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt},
+    sync::watch,
+    task::JoinHandle,
+};
+
+use super::{
+    audit::{AuditEntry, AuditLog},
+    cert_resolver::ReloadableCertResolver,
+    control::bind_control_socket,
+    sanzu_launch::SanzuLaunchVars,
+    shutdown::ShutdownSignals,
+    SanzuExit, SanzuServerWrapper,
+};
+use crate::conf::server_config::{PrivsepConfig, SanzuServerLaunchConfig, ServerConfig};
+
+/// Requests and responses are single lines of JSON
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+/// Starting a sanzu server takes the longest, about a second
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Signing takes a few milliseconds, a handshake doesn't wait longer than this for it
+const SIGN_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long the sanzu servers have to stop once the network process is gone
+const SANZU_STOP_TIMEOUT: Duration = Duration::from_secs(5);
+/// Every TLS 1.3 server signature starts with 64 spaces then this context (RFC 8446 4.4.3)
+const TLS13_SERVER_CONTEXT: &[u8] = b"TLS 1.3, server CertificateVerify\0";
+/// Context and the largest transcript hash, SHA-512
+const MAX_SIGNED_SIZE: usize = 64 + TLS13_SERVER_CONTEXT.len() + 64;
+const TLS13_SCHEMES: &[SignatureScheme] = &[
+    SignatureScheme::ECDSA_NISTP256_SHA256,
+    SignatureScheme::ECDSA_NISTP384_SHA384,
+    SignatureScheme::ECDSA_NISTP521_SHA512,
+    SignatureScheme::ED25519,
+    SignatureScheme::ED448,
+    SignatureScheme::RSA_PSS_SHA256,
+    SignatureScheme::RSA_PSS_SHA384,
+    SignatureScheme::RSA_PSS_SHA512,
+];
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "request", rename_all = "snake_case")]
+enum SupervisorRequest {
+    /// Generation of the certificate the supervisor serves, it changes on every reload
+    Generation,
+    Certificate,
+    Sign {
+        generation: u64,
+        scheme: u16,
+        /// Base64
+        message: String,
+    },
+    StartSanzu {
+        vars: SanzuLaunchVars,
+    },
+    /// Answered once the last sanzu server started on the port exited
+    WaitSanzu {
+        port: u16,
+    },
+    StopSanzu {
+        port: u16,
+    },
+    /// Not answered, the supervisor appends it to the audit log
+    Audit {
+        entry: AuditEntry,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "response", rename_all = "snake_case")]
+enum SupervisorResponse {
+    Generation {
+        generation: u64,
+    },
+    Certificate {
+        generation: u64,
+        /// Base64 DER certificates, leaf first
+        chain: Vec<String>,
+        algorithm: u8,
+        schemes: Vec<u16>,
+    },
+    Signature {
+        /// Base64
+        signature: String,
+    },
+    SanzuStarted,
+    SanzuExited {
+        exit: SanzuExit,
+    },
+    SanzuStopping,
+    Error {
+        message: String,
+    },
+}
+
+/// Requests and responses carry an id, so that several can be in flight.
+#[derive(Debug, Serialize, Deserialize)]
+struct Envelope<T> {
+    id: u64,
+    #[serde(flatten)]
+    body: T,
+}
+
+/// Network process end of the socket pair. Calls block until the supervisor answers.
+#[derive(Debug)]
+pub struct SupervisorClient {
+    writer: Mutex<UnixStream>,
+    pending: Arc<Mutex<HashMap<u64, mpsc::Sender<SupervisorResponse>>>>,
+    next_id: AtomicU64,
+    /// Generation of the last certificate fetched
+    generation: AtomicU64,
+}
+
+impl SupervisorClient {
+    /// Answers are read by a thread of their own, started here.
+    pub fn new(stream: UnixStream) -> anyhow::Result<Self> {
+        let pending = Arc::new(Mutex::new(HashMap::new()));
+        let reader = stream.try_clone()?;
+        let dispatch = Arc::clone(&pending);
+        std::thread::Builder::new()
+            .name("supervisor".to_owned())
+            .spawn(move || read_responses(reader, dispatch))?;
+        Ok(Self {
+            writer: Mutex::new(stream),
+            pending,
+            next_id: AtomicU64::new(0),
+            generation: AtomicU64::new(0),
+        })
+    }
+
+    fn send(&self, id: u64, request: SupervisorRequest) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(&Envelope { id, body: request })?;
+        line.push(b'\n');
+        self.writer
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .write_all(&line)
+            .map_err(|e| anyhow!("Could not reach the supervisor : {}", e))
+    }
+
+    fn request(&self, request: SupervisorRequest) -> anyhow::Result<SupervisorResponse> {
+        self.request_within(request, Some(REQUEST_TIMEOUT))
+    }
+
+    /// Sends `request` and waits for its answer, for up to `timeout` if there is one.
+    fn request_within(
+        &self,
+        request: SupervisorRequest,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<SupervisorResponse> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = mpsc::channel();
+        self.pending
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(id, tx);
+        let forget = || {
+            self.pending
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .remove(&id)
+        };
+
+        if let Err(e) = self.send(id, request) {
+            forget();
+            return Err(e);
+        }
+
+        let response = match timeout {
+            Some(timeout) => rx.recv_timeout(timeout).ok(),
+            None => rx.recv().ok(),
+        };
+        match response {
+            Some(SupervisorResponse::Error { message }) => bail!("{}", message),
+            Some(response) => Ok(response),
+            None => {
+                forget();
+                bail!("The supervisor did not answer")
+            }
+        }
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Relaxed)
+    }
+
+    /// Generation of the certificate the supervisor serves now.
+    pub fn current_generation(&self) -> anyhow::Result<u64> {
+        match self.request(SupervisorRequest::Generation)? {
+            SupervisorResponse::Generation { generation } => Ok(generation),
+            other => bail!("Unexpected answer from the supervisor : {:?}", other),
+        }
+    }
+
+    /// Certificate the supervisor serves, with a key signing through the supervisor.
+    pub fn certified_key(self: &Arc<Self>) -> anyhow::Result<CertifiedKey> {
+        let SupervisorResponse::Certificate {
+            generation,
+            chain,
+            algorithm,
+            schemes,
+        } = self.request(SupervisorRequest::Certificate)?
+        else {
+            bail!("Unexpected answer from the supervisor");
+        };
+        let chain = chain
+            .iter()
+            .map(|c| BASE64_STANDARD.decode(c).map(CertificateDer::from))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.generation.store(generation, Ordering::Relaxed);
+
+        let key = RemoteSigningKey {
+            client: Arc::clone(self),
+            generation,
+            algorithm: SignatureAlgorithm::from(algorithm),
+            schemes: schemes.into_iter().map(SignatureScheme::from).collect(),
+        };
+        Ok(CertifiedKey::new(chain, Arc::new(key)))
+    }
+
+    pub fn start_sanzu(&self, vars: &SanzuLaunchVars) -> anyhow::Result<()> {
+        match self.request(SupervisorRequest::StartSanzu { vars: vars.clone() })? {
+            SupervisorResponse::SanzuStarted => Ok(()),
+            other => bail!("Unexpected answer from the supervisor : {:?}", other),
+        }
+    }
+
+    /// Blocks until the sanzu server on `port` exited.
+    pub fn wait_sanzu(&self, port: u16) -> anyhow::Result<SanzuExit> {
+        match self.request_within(SupervisorRequest::WaitSanzu { port }, None)? {
+            SupervisorResponse::SanzuExited { exit } => Ok(exit),
+            other => bail!("Unexpected answer from the supervisor : {:?}", other),
+        }
+    }
+
+    /// Hands a record of the audit log to the supervisor, which holds its key.
+    pub fn audit(&self, entry: AuditEntry) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        if let Err(e) = self.send(id, SupervisorRequest::Audit { entry }) {
+            error!("Could not write to the audit log : {}", e);
+        }
+    }
+
+    /// Asks the supervisor to stop the sanzu server on `port`, without waiting for it.
+    pub fn stop_sanzu(&self, port: u16) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        if let Err(e) = self.send(id, SupervisorRequest::StopSanzu { port }) {
+            error!("Could not stop the sanzu server on port {} : {}", port, e);
+        }
+    }
+}
+
+/// Hands every answer to the request waiting for it. The network process can't do anything
+/// without its supervisor, so it exits when the supervisor goes away.
+fn read_responses(
+    stream: UnixStream,
+    pending: Arc<Mutex<HashMap<u64, mpsc::Sender<SupervisorResponse>>>>,
+) {
+    let mut reader = BufReader::new(stream);
+    loop {
+        let mut line = Vec::new();
+        match (&mut reader)
+            .take(MAX_MESSAGE_SIZE as u64 + 1)
+            .read_until(b'\n', &mut line)
+        {
+            Ok(0) => error!("The supervisor closed the connection, stopping"),
+            Ok(n) if n > MAX_MESSAGE_SIZE => error!("The supervisor sent an oversized answer"),
+            Ok(_) => match serde_json::from_slice::<Envelope<SupervisorResponse>>(&line) {
+                Ok(response) => {
+                    let waiting = pending
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .remove(&response.id);
+                    if let Some(tx) = waiting {
+                        let _ = tx.send(response.body);
+                    }
+                    continue;
+                }
+                Err(e) => error!("The supervisor sent an invalid answer : {}", e),
+            },
+            Err(e) => error!("Lost the connection to the supervisor : {}", e),
+        }
+        std::process::exit(1);
+    }
+}
+
+/// Private key held by the supervisor.
+#[derive(Debug)]
+struct RemoteSigningKey {
+    client: Arc<SupervisorClient>,
+    generation: u64,
+    algorithm: SignatureAlgorithm,
+    schemes: Vec<SignatureScheme>,
+}
+
+impl SigningKey for RemoteSigningKey {
+    fn choose_scheme(&self, offered: &[SignatureScheme]) -> Option<Box<dyn Signer>> {
+        let scheme = self.schemes.iter().find(|s| offered.contains(s))?;
+        Some(Box::new(RemoteSigner {
+            client: Arc::clone(&self.client),
+            generation: self.generation,
+            scheme: *scheme,
+        }))
+    }
+
+    fn algorithm(&self) -> SignatureAlgorithm {
+        self.algorithm
+    }
+}
+
+#[derive(Debug)]
+struct RemoteSigner {
+    client: Arc<SupervisorClient>,
+    generation: u64,
+    scheme: SignatureScheme,
+}
+
+impl Signer for RemoteSigner {
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, rustls::Error> {
+        let request = SupervisorRequest::Sign {
+            generation: self.generation,
+            scheme: self.scheme.into(),
+            message: BASE64_STANDARD.encode(message),
+        };
+        // handshakes run on the tokio workers, which must not be blocked while waiting for the
+        // supervisor
+        let wait = || self.client.request_within(request, Some(SIGN_TIMEOUT));
+        let response = match tokio::runtime::Handle::try_current() {
+            Ok(handle) if handle.runtime_flavor() == tokio::runtime::RuntimeFlavor::MultiThread => {
+                tokio::task::block_in_place(wait)
+            }
+            _ => wait(),
+        };
+        let signature = match response {
+            Ok(SupervisorResponse::Signature { signature }) => signature,
+            Ok(other) => {
+                error!("Unexpected answer from the supervisor : {:?}", other);
+                return Err(rustls::Error::General("signature failed".to_owned()));
+            }
+            Err(e) => {
+                error!("The supervisor did not sign the handshake : {}", e);
+                return Err(rustls::Error::General("signature failed".to_owned()));
+            }
+        };
+        BASE64_STANDARD
+            .decode(signature)
+            .map_err(|e| rustls::Error::General(e.to_string()))
+    }
+
+    fn scheme(&self) -> SignatureScheme {
+        self.scheme
+    }
+}
+
+/// Certificates handed to the network process. The previous one stays usable for the
+/// handshakes that started before a reload.
+struct ServedKeys {
+    generation: u64,
+    current: Arc<CertifiedKey>,
+    previous: Option<Arc<CertifiedKey>>,
+}
+
+/// Sanzu server started on a port. It is kept once it exited, to tell how.
+struct StartedSanzu {
+    stop: Arc<AtomicBool>,
+    exit: watch::Receiver<Option<SanzuExit>>,
+}
+
+impl StartedSanzu {
+    fn is_running(&self) -> bool {
+        self.exit.borrow().is_none()
+    }
+}
+
+/// Privileged end of the socket pair.
+struct Supervisor {
+    cert_resolver: Arc<ReloadableCertResolver>,
+    keys: Mutex<ServedKeys>,
+    sanzu_config: SanzuServerLaunchConfig,
+    max_sessions: usize,
+    /// Last sanzu server started on each port
+    sanzu_ports: Mutex<HashMap<u16, StartedSanzu>>,
+    stop_sanzu_servers: Arc<AtomicBool>,
+    sanzu_servers: Mutex<Vec<JoinHandle<()>>>,
+    audit: AuditLog,
+}
+
+impl Supervisor {
+    /// Current certificate, and its generation.
+    fn served_key(&self) -> (u64, Arc<CertifiedKey>) {
+        let latest = self.cert_resolver.current();
+        let mut keys = self.keys.lock().unwrap_or_else(|e| e.into_inner());
+        if !Arc::ptr_eq(&latest, &keys.current) {
+            keys.previous = Some(std::mem::replace(&mut keys.current, latest));
+            keys.generation += 1;
+        }
+        (keys.generation, Arc::clone(&keys.current))
+    }
+
+    fn key_of_generation(&self, generation: u64) -> Option<Arc<CertifiedKey>> {
+        let (current_generation, current) = self.served_key();
+        if generation == current_generation {
+            return Some(current);
+        }
+        let keys = self.keys.lock().unwrap_or_else(|e| e.into_inner());
+        if generation + 1 == current_generation {
+            keys.previous.clone()
+        } else {
+            None
+        }
+    }
+
+    async fn handle(
+        self: &Arc<Self>,
+        request: SupervisorRequest,
+    ) -> anyhow::Result<SupervisorResponse> {
+        match request {
+            SupervisorRequest::Generation => Ok(SupervisorResponse::Generation {
+                generation: self.served_key().0,
+            }),
+            SupervisorRequest::Certificate => {
+                let (generation, key) = self.served_key();
+                Ok(SupervisorResponse::Certificate {
+                    generation,
+                    chain: key.cert.iter().map(|c| BASE64_STANDARD.encode(c)).collect(),
+                    algorithm: key.key.algorithm().into(),
+                    schemes: TLS13_SCHEMES
+                        .iter()
+                        .filter(|s| key.key.choose_scheme(&[**s]).is_some())
+                        .map(|s| (*s).into())
+                        .collect(),
+                })
+            }
+            SupervisorRequest::Sign {
+                generation,
+                scheme,
+                message,
+            } => {
+                let message = BASE64_STANDARD.decode(message)?;
+                if !is_tls13_server_signature(&message) {
+                    bail!("Only TLS 1.3 server handshakes are signed");
+                }
+                let Some(key) = self.key_of_generation(generation) else {
+                    bail!("Certificate generation {} is no longer served", generation);
+                };
+                let Some(signer) = key.key.choose_scheme(&[SignatureScheme::from(scheme)]) else {
+                    bail!("The private key can't sign with scheme {:#06x}", scheme);
+                };
+                let signature = signer.sign(&message)?;
+                Ok(SupervisorResponse::Signature {
+                    signature: BASE64_STANDARD.encode(signature),
+                })
+            }
+            SupervisorRequest::StartSanzu { vars } => {
+                self.start_sanzu(&vars).await?;
+                Ok(SupervisorResponse::SanzuStarted)
+            }
+            SupervisorRequest::WaitSanzu { port } => {
+                let Some(mut exit) = self
+                    .sanzu_ports
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .get(&port)
+                    .map(|s| s.exit.clone())
+                else {
+                    bail!("No sanzu server was started on port {}", port);
+                };
+                let exit = exit
+                    .wait_for(Option::is_some)
+                    .await
+                    .map_err(|_| anyhow!("Lost track of the sanzu server on port {}", port))?
+                    .clone()
+                    .unwrap_or_default();
+                Ok(SupervisorResponse::SanzuExited { exit })
+            }
+            SupervisorRequest::StopSanzu { port } => {
+                let sanzu_ports = self.sanzu_ports.lock().unwrap_or_else(|e| e.into_inner());
+                let Some(started) = sanzu_ports.get(&port).filter(|s| s.is_running()) else {
+                    bail!("No sanzu server runs on port {}", port);
+                };
+                info!("Stopping the sanzu server on port {}", port);
+                started.stop.store(true, Ordering::Relaxed);
+                Ok(SupervisorResponse::SanzuStopping)
+            }
+            SupervisorRequest::Audit { .. } => bail!("Audit records are not answered"),
+        }
+    }
+
+    async fn start_sanzu(self: &Arc<Self>, vars: &SanzuLaunchVars) -> anyhow::Result<()> {
+        let port = vars.port;
+        if self.sanzu_config.sanzu_server_external_startup {
+            bail!("Sanzu servers are started externally");
+        }
+        let first_port = self.sanzu_config.sanzu_server_port;
+        if port < first_port || (port - first_port) as usize >= self.max_sessions {
+            bail!("Port {} is not a sanzu port", port);
+        }
+        vars.validate_for(&self.sanzu_config)?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let (exit_tx, exit) = watch::channel(None);
+        {
+            let mut sanzu_ports = self.sanzu_ports.lock().unwrap_or_else(|e| e.into_inner());
+            if sanzu_ports.get(&port).is_some_and(StartedSanzu::is_running) {
+                bail!("A sanzu server already runs on port {}", port);
+            }
+            sanzu_ports.insert(
+                port,
+                StartedSanzu {
+                    stop: Arc::clone(&stop),
+                    exit,
+                },
+            );
+        }
+        info!(
+            "Starting a sanzu server for user '{}' on port {}",
+            vars.user_id, port
+        );
+
+        let mut sss = match SanzuServerWrapper::from_config(&self.sanzu_config, vars) {
+            Ok(sss) => sss,
+            Err(e) => {
+                self.sanzu_ports
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .remove(&port);
+                return Err(e);
+            }
+        };
+        let (started, sss) = tokio::task::spawn_blocking(move || (sss.start(), sss)).await?;
+        if let Err(e) = started {
+            self.sanzu_ports
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .remove(&port);
+            return Err(e);
+        }
+
+        let stop_all = Arc::clone(&self.stop_sanzu_servers);
+        let waiting = tokio::task::spawn_blocking(move || {
+            let exit =
+                sss.wait(|| stop_all.load(Ordering::Relaxed) || stop.load(Ordering::Relaxed));
+            // the port is free again once the sanzu server exited
+            let _ = exit_tx.send(Some(exit));
+        });
+        let mut sanzu_servers = self.sanzu_servers.lock().unwrap_or_else(|e| e.into_inner());
+        sanzu_servers.retain(|h| !h.is_finished());
+        sanzu_servers.push(waiting);
+        Ok(())
+    }
+}
+
+/// Whether `message` is the content a TLS 1.3 server signs in its CertificateVerify.
+fn is_tls13_server_signature(message: &[u8]) -> bool {
+    message.len() <= MAX_SIGNED_SIZE
+        && message.len() > 64 + TLS13_SERVER_CONTEXT.len()
+        && message[..64].iter().all(|b| *b == b' ')
+        && message[64..].starts_with(TLS13_SERVER_CONTEXT)
+}
+
+/// Answers the network process until it closes its end, or sends something invalid.
+async fn serve_network_process(supervisor: Arc<Supervisor>, stream: tokio::net::UnixStream) {
+    let (reader, writer) = stream.into_split();
+    let writer = Arc::new(tokio::sync::Mutex::new(writer));
+    let mut reader = tokio::io::BufReader::new(reader);
+    loop {
+        let mut line = Vec::new();
+        match (&mut reader)
+            .take(MAX_MESSAGE_SIZE as u64 + 1)
+            .read_until(b'\n', &mut line)
+            .await
+        {
+            Ok(0) => return,
+            Ok(n) if n > MAX_MESSAGE_SIZE => {
+                error!("The network process sent an oversized request");
+                return;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                error!("Lost the connection to the network process : {}", e);
+                return;
+            }
+        }
+        let request = match serde_json::from_slice::<Envelope<SupervisorRequest>>(&line) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("The network process sent an invalid request : {}", e);
+                return;
+            }
+        };
+
+        // kept in order, and never answered
+        if let SupervisorRequest::Audit { entry } = request.body {
+            supervisor.audit.write(entry);
+            continue;
+        }
+
+        let supervisor = Arc::clone(&supervisor);
+        let writer = Arc::clone(&writer);
+        tokio::spawn(async move {
+            let body = match supervisor.handle(request.body).await {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("Refused a request of the network process : {}", e);
+                    SupervisorResponse::Error {
+                        message: e.to_string(),
+                    }
+                }
+            };
+            let Ok(mut line) = serde_json::to_vec(&Envelope {
+                id: request.id,
+                body,
+            }) else {
+                return;
+            };
+            line.push(b'\n');
+            if let Err(e) = writer.lock().await.write_all(&line).await {
+                error!("Could not answer the network process : {}", e);
+            }
+        });
+    }
+}
+
+/// Uid and primary gid of `name`, which must not be root.
+fn lookup_user(name: &str) -> anyhow::Result<(u32, u32)> {
+    let c_name = CString::new(name)?;
+    let mut buf = vec![0 as libc::c_char; 16 * 1024];
+    // SAFETY: passwd is plain data, filled by getpwnam_r
+    let mut pwd = unsafe { std::mem::zeroed::<libc::passwd>() };
+    let mut result = std::ptr::null_mut();
+    // SAFETY: every pointer is valid for the duration of the call, buf.len() is its size
+    let rc = unsafe {
+        libc::getpwnam_r(
+            c_name.as_ptr(),
+            &mut pwd,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if rc != 0 {
+        bail!(
+            "Could not look user '{}' up : {}",
+            name,
+            std::io::Error::from_raw_os_error(rc)
+        );
+    }
+    if result.is_null() {
+        bail!("No user named '{}'", name);
+    }
+    if pwd.pw_uid == 0 {
+        bail!("The network process can't run as '{}', it is root", name);
+    }
+    Ok((pwd.pw_uid, pwd.pw_gid))
+}
+
+fn running_as_root() -> bool {
+    // SAFETY: geteuid can't fail
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Gives `path` to the user of the network process, when privilege separation is enabled and
+/// we are root. Files the network process writes are created by root-run commands such as
+/// `greenion-server pair`.
+pub fn hand_over(config: &PrivsepConfig, path: &Path) -> anyhow::Result<()> {
+    if !config.enabled || !running_as_root() {
+        return Ok(());
+    }
+    let (uid, gid) = lookup_user(&config.user)?;
+    std::os::unix::fs::chown(path, Some(uid), Some(gid))
+        .with_context(|| format!("Could not give {} to '{}'", path.display(), config.user))
+}
+
+/// Prepares the state folder before a root-run command such as `greenion-server pair` writes
+/// to it, when privilege separation is enabled and we are root.
+pub fn prepare_state(config: &ServerConfig) -> anyhow::Result<()> {
+    if !config.privsep_config.enabled || !running_as_root() {
+        return Ok(());
+    }
+    let (uid, gid) = lookup_user(&config.privsep_config.user)?;
+    hand_over_state(config, uid, gid)
+}
+
+/// Lets the network process write the pairing files. They must be in `state_dir`, the only
+/// folder given to the network process.
+fn hand_over_state(config: &ServerConfig, uid: u32, gid: u32) -> anyhow::Result<()> {
+    let state_dir = Path::new(&config.privsep_config.state_dir);
+    if !state_dir.is_absolute()
+        || state_dir
+            .components()
+            .any(|c| matches!(c, Component::ParentDir | Component::CurDir))
+    {
+        bail!(
+            "The state folder must be an absolute path, not {}",
+            state_dir.display()
+        );
+    }
+    let files = state_files(config);
+    if let Some(outside) = files.iter().find(|file| file.parent() != Some(state_dir)) {
+        bail!(
+            "{} must be in the state folder {} with privilege separation",
+            outside.display(),
+            state_dir.display()
+        );
+    }
+
+    create_state_dir(state_dir, &files, uid)?;
+    let chown = |path: &Path| {
+        std::os::unix::fs::chown(path, Some(uid), Some(gid))
+            .with_context(|| format!("Could not give {} to the network process", path.display()))
+    };
+    chown(state_dir)?;
+    for file in files {
+        match fs::symlink_metadata(file) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                bail!("{} is a symbolic link", file.display())
+            }
+            Ok(_) => chown(file)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).context(format!("Could not read {}", file.display())),
+        }
+    }
+    Ok(())
+}
+
+/// Creates the state folder, only readable by its owner. An existing folder is only taken
+/// when it already belongs to the network process or holds nothing but the state files, so
+/// that a folder holding anything else is never given away.
+fn create_state_dir(state_dir: &Path, files: &[&Path], uid: u32) -> anyhow::Result<()> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+
+    // the state files, and the files written next to them such as temporary files
+    let stems = files
+        .iter()
+        .filter_map(|f| f.file_stem())
+        .map(|stem| stem.to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+    let is_state_entry = |name: &str| {
+        let name = name.trim_start_matches('.');
+        stems.iter().any(|stem| name.starts_with(stem.as_str()))
+    };
+
+    match fs::symlink_metadata(state_dir) {
+        Ok(metadata) => {
+            if !metadata.is_dir() {
+                bail!("The state folder {} is not a folder", state_dir.display());
+            }
+            if metadata.uid() != uid {
+                for entry in fs::read_dir(state_dir)? {
+                    let name = entry?.file_name();
+                    if !is_state_entry(&name.to_string_lossy()) {
+                        bail!(
+                            "The state folder {} holds {:?}, which the agent did not write : use a folder dedicated to the agent",
+                            state_dir.display(),
+                            name
+                        );
+                    }
+                }
+            }
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            if let Some(parent) = state_dir.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::DirBuilder::new()
+                .mode(0o700)
+                .create(state_dir)
+                .with_context(|| format!("Could not create {}", state_dir.display()))?;
+        }
+        Err(e) => return Err(e).context(format!("Could not read {}", state_dir.display())),
+    }
+    fs::set_permissions(state_dir, fs::Permissions::from_mode(0o700))?;
+    Ok(())
+}
+
+/// Files the network process writes.
+pub fn state_files(config: &ServerConfig) -> Vec<&Path> {
+    let mut files = vec![];
+    if config.pairing_config.enabled {
+        files.push(Path::new(&config.pairing_config.devices_file));
+        files.push(Path::new(&config.pairing_config.pairing_code_file));
+    }
+    files
+}
+
+/// Runs the agent as the supervisor of an unprivileged network process, until that process
+/// exits. SIGTERM and SIGINT are passed on to it, and the sanzu servers are stopped once it
+/// is gone.
+pub async fn supervise(
+    config: &ServerConfig,
+    config_file: &Path,
+    log_file: &Path,
+    cert_resolver: Arc<ReloadableCertResolver>,
+    listener: std::net::TcpListener,
+) -> anyhow::Result<()> {
+    if !running_as_root() {
+        bail!("Privilege separation needs the agent to be started as root");
+    }
+    let user = &config.privsep_config.user;
+    let (uid, gid) = lookup_user(user)?;
+    hand_over_state(config, uid, gid)?;
+    let audit_config = &config.audit_config;
+    let audit = if audit_config.enabled {
+        let audit = AuditLog::open(
+            Path::new(&audit_config.audit_file),
+            Path::new(&audit_config.key_file),
+        )
+        .with_context(|| format!("Failed to open audit log at {}", &audit_config.audit_file))?;
+        info!("Writing audit log to {}", &audit_config.audit_file);
+        audit
+    } else {
+        AuditLog::disabled()
+    };
+
+    let (ours, theirs) = UnixStream::pair()?;
+    let control = if config.control_config.enabled {
+        Some(
+            bind_control_socket(&config.control_config.socket)
+                .await?
+                .into_std()?,
+        )
+    } else {
+        None
+    };
+    let config_fd = match File::open(config_file) {
+        Ok(f) => Some(f),
+        Err(e) => {
+            warn!(
+                "Could not open config {}, the network process uses the default configuration : {}",
+                config_file.display(),
+                e
+            );
+            None
+        }
+    };
+    let log_fd = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(log_file)
+        .map_err(|e| warn!("The network process only logs to stdout : {}", e))
+        .ok();
+
+    let mut command = tokio::process::Command::new(std::env::current_exe()?);
+    command
+        .arg("privsep-network")
+        .args(["--listener-fd", &listener.as_raw_fd().to_string()])
+        .args(["--supervisor-fd", &theirs.as_raw_fd().to_string()]);
+    let mut inherited = vec![listener.as_raw_fd(), theirs.as_raw_fd()];
+    for (arg, fd) in [
+        ("--config-fd", config_fd.as_ref().map(|f| f.as_raw_fd())),
+        ("--log-fd", log_fd.as_ref().map(|f| f.as_raw_fd())),
+        ("--control-fd", control.as_ref().map(|l| l.as_raw_fd())),
+    ] {
+        if let Some(fd) = fd {
+            command.args([arg, &fd.to_string()]);
+            inherited.push(fd);
+        }
+    }
+    command
+        // socket activation and the watchdog were meant for us, the network process gets the
+        // listener from us and pings the watchdog itself
+        .env_remove("LISTEN_FDS")
+        .env_remove("LISTEN_PID")
+        .env_remove("LISTEN_FDNAMES")
+        .env_remove("WATCHDOG_PID")
+        .uid(uid)
+        .gid(gid)
+        .kill_on_drop(true);
+    // SAFETY: only async-signal-safe calls between fork and exec
+    unsafe {
+        command.pre_exec(move || {
+            for fd in &inherited {
+                if libc::fcntl(*fd, libc::F_SETFD, 0) == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut child = command
+        .spawn()
+        .context("Could not start the network process")?;
+    drop((theirs, listener, control, config_fd, log_fd));
+    let pid = child.id().unwrap_or_default();
+    info!(
+        "Started the network process (pid {}) as user '{}'",
+        pid, user
+    );
+
+    let supervisor = Arc::new(Supervisor {
+        keys: Mutex::new(ServedKeys {
+            generation: 0,
+            current: cert_resolver.current(),
+            previous: None,
+        }),
+        cert_resolver,
+        sanzu_config: config.sanzu_server_launch_config.clone(),
+        max_sessions: config.session_config.max_sessions,
+        sanzu_ports: Mutex::new(HashMap::new()),
+        stop_sanzu_servers: Arc::new(AtomicBool::new(false)),
+        sanzu_servers: Mutex::new(vec![]),
+        audit,
+    });
+    ours.set_nonblocking(true)?;
+    let mut serving = tokio::spawn(serve_network_process(
+        Arc::clone(&supervisor),
+        tokio::net::UnixStream::from_std(ours)?,
+    ));
+
+    let mut signals = ShutdownSignals::new();
+    let mut answering = true;
+    let status: ExitStatus = loop {
+        tokio::select! {
+            status = child.wait() => break status?,
+            signal = signals.recv() => {
+                info!("Received {}, stopping the network process", signal);
+                // SAFETY: pid is our child, which we haven't reaped yet
+                unsafe { libc::kill(pid as i32, libc::SIGTERM) };
+            }
+            // the network process closed its end, or sent something invalid
+            _ = &mut serving, if answering => {
+                answering = false;
+                // SAFETY: pid is our child, which we haven't reaped yet
+                unsafe { libc::kill(pid as i32, libc::SIGKILL) };
+            }
+        }
+    };
+
+    info!("Stopping the sanzu servers started by the agent");
+    supervisor.stop_sanzu_servers.store(true, Ordering::Relaxed);
+    let sanzu_servers = std::mem::take(
+        &mut *supervisor
+            .sanzu_servers
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()),
+    );
+    let stopped = tokio::time::timeout(SANZU_STOP_TIMEOUT, async {
+        for handle in sanzu_servers {
+            let _ = handle.await;
+        }
+    })
+    .await;
+    if stopped.is_err() {
+        warn!("Some sanzu servers did not stop in time");
+    }
+    let audit = supervisor.audit.clone();
+    tokio::task::spawn_blocking(move || audit.flush()).await?;
+
+    if !status.success() {
+        bail!("The network process exited with {}", status);
+    }
+    info!("The network process exited");
+    Ok(())
+}
+
+/// Takes ownership of a descriptor the supervisor passed on, and keeps it from being
+/// inherited any further.
+pub fn inherited_fd(fd: RawFd) -> anyhow::Result<OwnedFd> {
+    // SAFETY: fcntl on any number is harmless, it fails unless the descriptor is open
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+        return Err(anyhow!(
+            "Descriptor {} was not passed on : {}",
+            fd,
+            std::io::Error::last_os_error()
+        ));
+    }
+    // SAFETY: the supervisor handed this open descriptor to us, nothing else owns it
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Fails when the network process was started with privileges.
+pub fn check_unprivileged() -> anyhow::Result<()> {
+    if running_as_root() {
+        bail!("The network process must not run as root");
+    }
+    Ok(())
+}
+
+/// Configuration passed on by the supervisor, the default one when there is none.
+pub fn read_config(config_fd: Option<OwnedFd>) -> anyhow::Result<ServerConfig> {
+    let Some(fd) = config_fd else {
+        return Ok(ServerConfig::default());
+    };
+    let mut content = String::new();
+    File::from(fd).read_to_string(&mut content)?;
+    Ok(toml::from_str(&content)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use rcgen::{CertificateParams, DistinguishedName, DnType, KeyPair};
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::{auth::PrivateKeyOptions, standalone_server::sanzu_launch::DisplayGeometry};
+
+    const FIRST_PORT: u16 = 21000;
+
+    fn tls13_message(hash_len: usize) -> Vec<u8> {
+        let mut message = vec![b' '; 64];
+        message.extend_from_slice(TLS13_SERVER_CONTEXT);
+        message.resize(message.len() + hash_len, 0xab);
+        message
+    }
+
+    fn supervisor(dir: &TempDir) -> Arc<Supervisor> {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::default();
+        params.distinguished_name = DistinguishedName::new();
+        params.distinguished_name.push(DnType::CommonName, "1234");
+        let cert = params.self_signed(&key).unwrap();
+        let cert_file = dir.path().join("cert.pem");
+        let key_file = dir.path().join("key.pem");
+        fs::write(&cert_file, cert.pem()).unwrap();
+        fs::write(&key_file, key.serialize_pem()).unwrap();
+        let cert_resolver = Arc::new(
+            ReloadableCertResolver::new(
+                cert_file.to_str().unwrap(),
+                key_file.to_str().unwrap(),
+                PrivateKeyOptions::new(None, None, Some(true)),
+            )
+            .unwrap(),
+        );
+
+        let sanzu_config = SanzuServerLaunchConfig {
+            sanzu_server_port: FIRST_PORT,
+            ..Default::default()
+        };
+        Arc::new(Supervisor {
+            keys: Mutex::new(ServedKeys {
+                generation: 0,
+                current: cert_resolver.current(),
+                previous: None,
+            }),
+            cert_resolver,
+            sanzu_config,
+            max_sessions: 2,
+            sanzu_ports: Mutex::new(HashMap::new()),
+            stop_sanzu_servers: Arc::new(AtomicBool::new(false)),
+            sanzu_servers: Mutex::new(vec![]),
+            audit: AuditLog::disabled(),
+        })
+    }
+
+    fn sign(message: &[u8], generation: u64) -> SupervisorRequest {
+        SupervisorRequest::Sign {
+            generation,
+            scheme: SignatureScheme::ECDSA_NISTP256_SHA256.into(),
+            message: BASE64_STANDARD.encode(message),
+        }
+    }
+
+    fn start_sanzu(port: u16, user_id: &str) -> SupervisorRequest {
+        SupervisorRequest::StartSanzu {
+            vars: SanzuLaunchVars::new(&SanzuServerLaunchConfig::default(), port, user_id),
+        }
+    }
+
+    async fn refusal(supervisor: &Arc<Supervisor>, request: SupervisorRequest) -> String {
+        supervisor.handle(request).await.unwrap_err().to_string()
+    }
+
+    #[test]
+    fn tls13_server_signatures() {
+        assert!(is_tls13_server_signature(&tls13_message(32)));
+        assert!(is_tls13_server_signature(&tls13_message(64)));
+        assert!(!is_tls13_server_signature(&tls13_message(0)));
+        assert!(!is_tls13_server_signature(&tls13_message(65)));
+
+        let mut client = vec![b' '; 64];
+        client.extend_from_slice(b"TLS 1.3, client CertificateVerify\0");
+        client.extend_from_slice(&[0xab; 32]);
+        assert!(!is_tls13_server_signature(&client));
+        let mut no_padding = tls13_message(32);
+        no_padding[0] = 0;
+        assert!(!is_tls13_server_signature(&no_padding));
+    }
+
+    #[tokio::test]
+    async fn signs_tls13_server_handshakes_only() {
+        let dir = TempDir::new().unwrap();
+        let supervisor = supervisor(&dir);
+        let response = supervisor.handle(sign(&tls13_message(32), 0)).await;
+        assert!(matches!(response, Ok(SupervisorResponse::Signature { .. })));
+
+        assert!(refusal(&supervisor, sign(b"anything else", 0))
+            .await
+            .contains("Only TLS 1.3"));
+        assert!(refusal(&supervisor, sign(&tls13_message(32), 1))
+            .await
+            .contains("no longer served"));
+        let ed25519 = SupervisorRequest::Sign {
+            generation: 0,
+            scheme: SignatureScheme::ED25519.into(),
+            message: BASE64_STANDARD.encode(tls13_message(32)),
+        };
+        assert!(refusal(&supervisor, ed25519).await.contains("can't sign"));
+    }
+
+    #[tokio::test]
+    async fn starts_sanzu_servers_on_sanzu_ports_only() {
+        let dir = TempDir::new().unwrap();
+        let supervisor = supervisor(&dir);
+        for port in [FIRST_PORT - 1, FIRST_PORT + 2, 22] {
+            assert!(refusal(&supervisor, start_sanzu(port, "alice"))
+                .await
+                .contains("is not a sanzu port"));
+        }
+        assert!(refusal(
+            &supervisor,
+            start_sanzu(FIRST_PORT, "alice --config /etc/shadow")
+        )
+        .await
+        .contains("Invalid user id"));
+        // values a compromised network process could forge
+        let forged = |vars: SanzuLaunchVars| SupervisorRequest::StartSanzu { vars };
+        let vars = SanzuLaunchVars::new(&SanzuServerLaunchConfig::default(), FIRST_PORT, "alice");
+        let traversal = SanzuLaunchVars {
+            codec: "..".to_owned(),
+            ..vars.clone()
+        };
+        assert!(refusal(&supervisor, forged(traversal))
+            .await
+            .contains("not a configured encoder"));
+        let unconfigured = SanzuLaunchVars {
+            codec: "libx265".to_owned(),
+            ..vars.clone()
+        };
+        assert!(refusal(&supervisor, forged(unconfigured))
+            .await
+            .contains("not a configured encoder"));
+        let huge = SanzuLaunchVars {
+            display: Some(DisplayGeometry {
+                width: 1 << 20,
+                height: 1080,
+                scale: 1.0,
+            }),
+            ..vars
+        };
+        assert!(refusal(&supervisor, forged(huge))
+            .await
+            .contains("out of bounds"));
+        assert!(refusal(
+            &supervisor,
+            SupervisorRequest::StopSanzu { port: FIRST_PORT }
+        )
+        .await
+        .contains("No sanzu server"));
+        assert!(supervisor.sanzu_ports.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn requests_on_the_wire() {
+        let request = serde_json::from_str::<Envelope<SupervisorRequest>>(
+            r#"{"id": 3, "request": "stop_sanzu", "port": 1122}"#,
+        )
+        .unwrap();
+        assert_eq!(request.id, 3);
+        assert!(matches!(
+            request.body,
+            SupervisorRequest::StopSanzu { port: 1122 }
+        ));
+        assert!(serde_json::from_str::<Envelope<SupervisorRequest>>(
+            r#"{"id": 3, "request": "run_command", "command": "sh"}"#
+        )
+        .is_err());
+    }
+}
@@ -1,62 +1,524 @@
 use anyhow::{anyhow, bail};
-use log::{error, info};
-use std::{net::SocketAddr, sync::Arc, time::Duration};
+use chrono::Utc;
+use log::{error, info, log, warn, Level};
+use prost::Message;
+use std::{
+    io::{self, Cursor, ErrorKind},
+    net::SocketAddr,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
 use tokio::{
+    io::AsyncReadExt,
     net::TcpStream,
-    sync::{Semaphore, TryAcquireError},
-    time::timeout,
+    time::{sleep, sleep_until, timeout, timeout_at, Instant},
 };
-use tokio_rustls::TlsAcceptor;
+use tokio_rustls::{TlsAcceptor, TlsStream};
 
 use crate::{
     close_session,
-    conf::server_config::ServerConfig,
+    conf::server_config::{ServerConfig, SessionConfig, TakeoverPolicy},
+    metrics::metrics,
     proto::{
-        common::send_msg_async,
-        messages::{ServerStartProxy, StartProxyStatus},
+        common::{recv_msg_async, send_msg_async},
+        messages::{ClientTakeoverAnswer, ServerStartProxy, StartProxyStatus},
+    },
+    standalone_server::{
+        audit::{audit_time, token_sha256, AuditDetails, AuditEvent},
+        authenticator::counts_toward_ban,
+        sanzu_launch::{ClientOffer, SanzuLaunchVars},
+        sessions::{ActiveSession, SessionManager, SessionSlot, SessionTermination, StartRefused},
+        Authenticator, SanzuExit, SanzuProcess, SanzuServers, StandaloneServerForwarder,
     },
-    standalone_server::{Authenticator, SanzuServerWrapper, StandaloneServerForwarder},
 };
 
+/// How long a taken over session has to release its slot
+const TAKEOVER_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often a queued connection checks whether it can start
+const QUEUE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How long the sanzu server may take to be seen exiting once the forwarding stopped, for
+/// the session to end because of it
+const SANZU_EXIT_GRACE: Duration = Duration::from_secs(1);
+
+/// Ends the `existing` session of a user connecting again, as allowed by the takeover
+/// policy. A client reconnecting to the same web session always takes it over, and clients
+/// that can't answer the takeover question are refused instead of asked. Returns why the new
+/// connection is refused, if it is.
+async fn take_over_user_session(
+    stream: &mut TlsStream<TcpStream>,
+    client_addr: SocketAddr,
+    existing: &ActiveSession,
+    web_session_id: Option<u32>,
+    sessions: &SessionManager,
+    config: &SessionConfig,
+    client_offer: &ClientOffer,
+) -> anyhow::Result<Option<String>> {
+    let same_web_session = web_session_id.is_some() && existing.web_session_id == web_session_id;
+    let policy = match config.takeover_policy {
+        _ if same_web_session => TakeoverPolicy::Takeover,
+        TakeoverPolicy::Ask if !client_offer.knows_session_statuses() => TakeoverPolicy::Deny,
+        policy => policy,
+    };
+
+    match policy {
+        TakeoverPolicy::Takeover => {}
+        TakeoverPolicy::Deny => {
+            send_msg_async(
+                stream,
+                ServerStartProxy {
+                    result: StartProxyStatus::SessionAlreadyActive.into(),
+                    ..Default::default()
+                },
+                None,
+            )
+            .await?;
+            return Ok(Some(format!(
+                "user already has a session from {}",
+                existing.client_addr
+            )));
+        }
+        TakeoverPolicy::Ask => {
+            send_msg_async(
+                stream,
+                ServerStartProxy {
+                    result: StartProxyStatus::TakeoverConfirmation.into(),
+                    ..Default::default()
+                },
+                None,
+            )
+            .await?;
+            let answer = recv_msg_async(
+                stream,
+                Some(Duration::from_secs(config.takeover_question_timeout_secs)),
+            )
+            .await
+            .and_then(|msg| Ok(ClientTakeoverAnswer::decode(&mut Cursor::new(msg))?));
+            match answer {
+                Ok(answer) if answer.takeover => {}
+                Ok(_) => return Ok(Some("takeover declined by the client".to_owned())),
+                Err(e) => return Ok(Some(format!("no answer to the takeover question : {}", e))),
+            }
+        }
+    }
+
+    info!(
+        "Session {} of user '{}' from {} is taken over by {}",
+        existing.id, existing.user_id, existing.client_addr, client_addr
+    );
+    sessions.terminate(
+        existing.id,
+        SessionTermination {
+            reason: format!("taken over by a new connection from {}", client_addr),
+            keep_web_session: same_web_session,
+        },
+    );
+    if !sessions.wait_released(existing.id, TAKEOVER_TIMEOUT).await {
+        warn!(
+            "Session {} did not end within {} after being taken over",
+            existing.id,
+            humantime::format_duration(TAKEOVER_TIMEOUT)
+        );
+    }
+    Ok(None)
+}
+
+/// Keeps a connection waiting until a slot frees up, telling the client its position in the
+/// queue along the way. `start_within` bounds the time taken to start the session once out
+/// of the queue, before the client gets its next status. Returns why it stopped waiting, if
+/// it did.
+async fn wait_in_queue(
+    stream: &mut TlsStream<TcpStream>,
+    client_addr: SocketAddr,
+    user_id: &str,
+    web_session_id: Option<u32>,
+    sessions: &Arc<SessionManager>,
+    config: &SessionConfig,
+    start_within: Duration,
+) -> anyhow::Result<Result<SessionSlot, String>> {
+    let Some(ticket) = sessions.enqueue(config.max_queue_size, user_id, client_addr) else {
+        return Ok(Err(format!(
+            "the queue is full, {} connection(s) already waiting",
+            config.max_queue_size
+        )));
+    };
+    let max_wait = Duration::from_secs(config.max_queue_wait_secs);
+    let deadline = Instant::now() + max_wait;
+    let update_interval = Duration::from_secs(config.queue_update_interval_secs);
+    let mut last_update: Option<(usize, Instant)> = None;
+    let mut unused = [0u8; 1];
+
+    loop {
+        let released = sessions.released();
+        match sessions.try_start(user_id, web_session_id, client_addr, Some(&ticket)) {
+            Ok(slot) => {
+                info!("{} leaves the queue, a slot is free", client_addr);
+                return Ok(Ok(slot));
+            }
+            // another connection of the user started a session meanwhile
+            Err(StartRefused::UserSession(existing)) => {
+                return Ok(Err(format!(
+                    "user already has a session from {}",
+                    existing.client_addr
+                )));
+            }
+            Err(StartRefused::Unavailable) => {}
+        }
+        if sessions.is_shutting_down() {
+            return Ok(Err("the server is shutting down".to_owned()));
+        }
+        if sessions.is_draining() {
+            return Ok(Err("the server is draining".to_owned()));
+        }
+
+        let position = sessions.queue_position(&ticket);
+        let moved = last_update.is_none_or(|(p, _)| p != position);
+        if moved || last_update.is_some_and(|(_, t)| t.elapsed() >= update_interval) {
+            let estimated_wait = sessions.estimated_wait(position);
+            log!(
+                if moved { Level::Info } else { Level::Debug },
+                "{} waits in the queue at position {} (estimated wait : {})",
+                client_addr,
+                position,
+                estimated_wait
+                    .map(
+                        |d| humantime::format_duration(Duration::from_secs(d.as_secs()))
+                            .to_string()
+                    )
+                    .unwrap_or_else(|| "unknown".to_owned())
+            );
+            send_msg_async(
+                stream,
+                ServerStartProxy {
+                    result: StartProxyStatus::Queued.into(),
+                    queue_position: position as u32,
+                    estimated_wait_secs: estimated_wait.map(|d| d.as_secs()).unwrap_or(0),
+                    // checked on each poll
+                    update_interval_secs: (update_interval.max(start_within) + QUEUE_POLL_INTERVAL)
+                        .as_secs(),
+                    ..Default::default()
+                },
+                None,
+            )
+            .await?;
+            last_update = Some((position, Instant::now()));
+        }
+
+        tokio::select! {
+            _ = released => {}
+            // slots freed while the sanzu server still holds its port are only noticed here
+            _ = sleep(QUEUE_POLL_INTERVAL) => {}
+            _ = sleep_until(deadline) => {
+                return Ok(Err(format!(
+                    "waited {} in the queue",
+                    humantime::format_duration(max_wait)
+                )));
+            }
+            // the client sends nothing while queued : reading only ends when it leaves
+            _ = stream.read(&mut unused) => {
+                return Ok(Err("the client left the queue".to_owned()));
+            }
+        }
+    }
+}
+
+/// Why the sanzu server of a session is unreachable, with how it exited if it did.
+async fn sanzu_failure(sanzu: Option<&SanzuProcess>, reason: String) -> String {
+    match sanzu {
+        Some(sanzu) => match sanzu.exited_within(Duration::ZERO).await {
+            Some(exit) => format!("{}, the sanzu server exited : {}", reason, exit),
+            None => reason,
+        },
+        None => reason,
+    }
+}
+
+/// Why a session ended with its sanzu server.
+fn sanzu_exit_reason(exit: &SanzuExit) -> String {
+    if exit.stopped {
+        format!("sanzu server stopped ({})", exit.status)
+    } else if exit.crashed() {
+        format!("sanzu server crashed : {}", exit)
+    } else {
+        format!("sanzu server exited : {}", exit)
+    }
+}
+
+/// Status telling a client no session can start for it now.
+fn unavailable_status(sessions: &SessionManager, client_offer: &ClientOffer) -> StartProxyStatus {
+    if sessions.is_shutting_down() {
+        client_offer.status(StartProxyStatus::ServerShuttingDown)
+    } else {
+        StartProxyStatus::ServerBusy
+    }
+}
+
 pub async fn process_client_connection(
     stream: tokio::net::TcpStream,
     client_addr: SocketAddr,
     acceptor: TlsAcceptor,
-    machine_id: &str,
+    mut authenticator: Authenticator,
     server_agent_config: ServerConfig,
-    sem_connected_clients: Arc<Semaphore>,
+    sessions: Arc<SessionManager>,
+    sanzu_servers: SanzuServers,
 ) -> anyhow::Result<()> {
-    let outbound_tls_stream = acceptor.accept(stream).await?;
+    let audit = authenticator.audit.clone();
+    let rate_limiter = Arc::clone(&authenticator.rate_limiter);
+    let handshake_timeout = Duration::from_secs(
+        server_agent_config
+            .server_network_config
+            .handshake_timeout_secs as u64,
+    );
+    let accepted_at = Instant::now();
+    let deadline = accepted_at + handshake_timeout;
+
+    let outbound_tls_stream = match timeout_at(deadline, acceptor.accept(stream))
+        .await
+        .unwrap_or_else(|_| {
+            Err(io::Error::new(
+                ErrorKind::TimedOut,
+                "TLS handshake timed out",
+            ))
+        }) {
+        Ok(v) => v,
+        Err(e) => {
+            // scanners and clients that don't trust our certificate don't get banned
+            metrics().handshakes.inc("tls_failed");
+            metrics().handshake_duration.observe(accepted_at.elapsed());
+            audit.record(
+                AuditEvent::TlsFailed,
+                AuditDetails {
+                    source: Some(client_addr.to_string()),
+                    reason: Some(e.to_string()),
+                    ..Default::default()
+                },
+            );
+            return Err(e.into());
+        }
+    };
+    audit.record(
+        AuditEvent::TlsAccepted,
+        AuditDetails {
+            source: Some(client_addr.to_string()),
+            ..Default::default()
+        },
+    );
     let mut outbound_tls_stream = tokio_rustls::TlsStream::Server(outbound_tls_stream);
 
-    let (client_id, client_jwt_str, client_claims) = Authenticator {
-        local_machine_id: machine_id.to_string(),
-        jwks_url: server_agent_config.server_auth_config.jwks_url.clone(),
-        timeout: Duration::from_secs(3),
+    let authenticated = match timeout_at(
+        deadline,
+        authenticator.authenticate(&mut outbound_tls_stream, client_addr),
+    )
+    .await
+    {
+        Ok(Ok(v)) => v,
+        Ok(Err(e)) => {
+            if counts_toward_ban(&e) {
+                rate_limiter.record_failure(client_addr.ip());
+            }
+            metrics().handshake_duration.observe(accepted_at.elapsed());
+            return Err(e);
+        }
+        Err(_) => {
+            metrics().handshakes.inc("timeout");
+            metrics().handshake_duration.observe(accepted_at.elapsed());
+            audit.record(
+                AuditEvent::AuthFailed,
+                AuditDetails {
+                    source: Some(client_addr.to_string()),
+                    reason: Some("handshake timed out".to_owned()),
+                    ..Default::default()
+                },
+            );
+            bail!(
+                "{} did not authenticate within {}",
+                client_addr,
+                humantime::format_duration(handshake_timeout)
+            );
+        }
+    };
+    rate_limiter.record_success(client_addr.ip());
+    metrics().handshake_duration.observe(accepted_at.elapsed());
+    let Some((client_id, client_jwt_str, client_claims, max_session_duration, client_offer)) =
+        authenticated
+    else {
+        // the client only came to pair
+        return Ok(());
+    };
+    // paired devices have no session in the web application
+    let web_session_id = (!client_jwt_str.is_empty()).then_some(client_claims.session_id);
+    let session_details = AuditDetails {
+        source: Some(client_addr.to_string()),
+        method: Some(
+            if client_jwt_str.is_empty() {
+                "device"
+            } else {
+                "jwt"
+            }
+            .to_owned(),
+        ),
+        user_id: Some(client_claims.user_id.clone()),
+        session_id: web_session_id,
+        token_sha256: (!client_jwt_str.is_empty()).then(|| token_sha256(&client_jwt_str)),
+        ..Default::default()
+    };
+    let refuse = |reason: &str| {
+        audit.record(
+            AuditEvent::SessionRefused,
+            AuditDetails {
+                reason: Some(reason.to_owned()),
+                ..session_details.clone()
+            },
+        )
+    };
+
+    if sessions.is_draining() {
+        refuse(if sessions.is_shutting_down() {
+            "server shutting down"
+        } else {
+            "server draining"
+        });
+        send_msg_async(
+            &mut outbound_tls_stream,
+            ServerStartProxy {
+                result: unavailable_status(&sessions, &client_offer).into(),
+                ..Default::default()
+            },
+            None,
+        )
+        .await?;
+        bail!(
+            "The server is draining, no new session starts. Refusing connection of {}",
+            client_addr
+        );
     }
-    .authenticate(&mut outbound_tls_stream, client_addr)
-    .await?;
 
-    let _permit = match Arc::clone(&sem_connected_clients).try_acquire_owned() {
-        Ok(permit) => permit,
-        Err(TryAcquireError::NoPermits) => {
+    let launch_config = &server_agent_config.sanzu_server_launch_config;
+    // sanzu servers started externally already run with the first codec
+    let session_notices = client_offer.reads_session_notices();
+    let mut server_codecs = launch_config.codecs();
+    if launch_config.sanzu_server_external_startup {
+        server_codecs.truncate(1);
+    }
+    let Some(codec) = client_offer.negotiate_codec(&server_codecs) else {
+        refuse("no codec in common");
+        send_msg_async(
+            &mut outbound_tls_stream,
+            ServerStartProxy {
+                result: client_offer.status(StartProxyStatus::NoCommonCodec).into(),
+                server_codecs: server_codecs.clone(),
+                ..Default::default()
+            },
+            None,
+        )
+        .await?;
+        bail!(
+            "{} decodes none of the codecs of the server ({}), it decodes {}",
+            client_addr,
+            server_codecs.join(", "),
+            client_offer.codecs.join(", ")
+        );
+    };
+    let encoder = launch_config.encoder(&codec).unwrap_or_default();
+    info!(
+        "[{}@{}] Streaming with codec {} ({})",
+        client_id, client_addr, codec, encoder
+    );
+
+    let mut taken_over = None;
+    let started = loop {
+        let started = sessions.try_start(&client_claims.user_id, web_session_id, client_addr, None);
+        let existing = match started {
+            Ok(slot) => break Some(slot),
+            Err(StartRefused::UserSession(existing)) => existing,
+            Err(StartRefused::Unavailable) => break None,
+        };
+        // the user is only asked once : a session that didn't end, or another one started
+        // right after the takeover, refuses the connection
+        let reason = if let Some(taken_over) = taken_over {
             send_msg_async(
                 &mut outbound_tls_stream,
                 ServerStartProxy {
-                    result: StartProxyStatus::ServerBusy.into(),
+                    result: StartProxyStatus::SessionAlreadyActive.into(),
+                    ..Default::default()
                 },
                 None,
             )
             .await?;
-            return Err(anyhow::anyhow!(
-                "A second client tried to connect. Refusing his connection"
-            ));
+            Some(if existing.id == taken_over {
+                format!("session {} taken over did not end", taken_over)
+            } else {
+                format!("user already has a session from {}", existing.client_addr)
+            })
+        } else {
+            take_over_user_session(
+                &mut outbound_tls_stream,
+                client_addr,
+                &existing,
+                web_session_id,
+                &sessions,
+                &server_agent_config.session_config,
+                &client_offer,
+            )
+            .await?
+        };
+        if let Some(reason) = reason {
+            refuse(&reason);
+            bail!("Refusing connection of {} : {}", client_addr, reason);
         }
-        Err(TryAcquireError::Closed) => unreachable!(),
+        taken_over = Some(existing.id);
     };
-    info!("[{}@{}] Connection authenticated", client_id, client_addr);
+    let slot = match started {
+        Some(slot) => slot,
+        None => {
+            // older clients can't wait in the queue
+            let queued = if server_agent_config.session_config.queue_enabled
+                && client_offer.knows_session_statuses()
+            {
+                wait_in_queue(
+                    &mut outbound_tls_stream,
+                    client_addr,
+                    &client_claims.user_id,
+                    web_session_id,
+                    &sessions,
+                    &server_agent_config.session_config,
+                    // waiting for the sanzu server, then connecting to it
+                    2 * Duration::from_secs(launch_config.sanzu_server_startup_timeout),
+                )
+                .await?
+            } else {
+                Err("server busy".to_owned())
+            };
+            match queued {
+                Ok(slot) => slot,
+                Err(reason) => {
+                    refuse(&reason);
+                    send_msg_async(
+                        &mut outbound_tls_stream,
+                        ServerStartProxy {
+                            result: unavailable_status(&sessions, &client_offer).into(),
+                            ..Default::default()
+                        },
+                        None,
+                    )
+                    .await?;
+                    return Err(anyhow::anyhow!(
+                        "All {} session slot(s) are in use ({}). Refusing connection of {}",
+                        sessions.max_sessions(),
+                        reason,
+                        client_addr
+                    ));
+                }
+            }
+        }
+    };
+    let sanzu_port = slot.sanzu_port;
+    info!(
+        "[{}@{}] Connection authenticated, session {} uses sanzu port {}",
+        client_id, client_addr, slot.id, sanzu_port
+    );
 
-    if server_agent_config
+    // stopped when the session ends
+    let sanzu = if server_agent_config
         .sanzu_server_launch_config
         .sanzu_server_external_startup
     {
@@ -64,46 +526,49 @@ pub async fn process_client_connection(
             "[{}@{}] Config says that sanzu server startup is externally managed.",
             client_id, client_addr
         );
+        None
     } else {
         info!(
             "[{}@{}] Config says that sanzu server startup is internally managed.",
             client_id, client_addr
         );
 
-        let mut sss = SanzuServerWrapper::new(
-            server_agent_config
-                .sanzu_server_launch_config
-                .sanzu_server_path
-                .as_str(),
-            server_agent_config
-                .sanzu_server_launch_config
-                .sanzu_server_config_path
-                .as_str(),
-            server_agent_config
-                .sanzu_server_launch_config
-                .sanzu_server_codec
-                .as_str(),
-            Some(
-                server_agent_config
-                    .sanzu_server_launch_config
-                    .sanzu_log_file
-                    .clone(),
-            ),
-            Duration::from_secs(1),
-        );
-
-        let _ = sss.start();
-        tokio::task::spawn_blocking(move || sss.wait());
-        // task::spawn(move || sss.wait());
-    }
+        let vars = SanzuLaunchVars {
+            session_id: web_session_id,
+            codec: encoder,
+            display: client_offer.display,
+            ..SanzuLaunchVars::new(launch_config, sanzu_port, &client_claims.user_id)
+        };
+        match sanzu_servers
+            .start_for(&slot, launch_config, &vars, sessions.sanzu_stop_signal())
+            .await
+        {
+            Ok(sanzu) => Some(sanzu),
+            Err(e) => {
+                error!(
+                    "[{}@{}] Could not start the sanzu server : {}",
+                    client_id, client_addr, e
+                );
+                refuse(&format!("could not start sanzu server : {}", e));
+                metrics().sanzu_start_failures.inc();
+                send_msg_async(
+                    &mut outbound_tls_stream,
+                    ServerStartProxy {
+                        result: StartProxyStatus::SanzuStartError.into(),
+                        error: e.to_string(),
+                        ..Default::default()
+                    },
+                    None,
+                )
+                .await?;
+                return Err(anyhow!("Could not start the sanzu server : {}", e));
+            }
+        }
+    };
 
     info!(
         "[{}@{}] Connecting to 127.0.0.1:{}",
-        client_id,
-        client_addr,
-        &server_agent_config
-            .sanzu_server_launch_config
-            .sanzu_server_port
+        client_id, client_addr, sanzu_port
     );
 
     let sanzu_stream_res = {
@@ -111,13 +576,7 @@ pub async fn process_client_connection(
             .sanzu_server_launch_config
             .sanzu_server_external_startup
         {
-            TcpStream::connect(format!(
-                "127.0.0.1:{}",
-                &server_agent_config
-                    .sanzu_server_launch_config
-                    .sanzu_server_port
-            ))
-            .await
+            TcpStream::connect(format!("127.0.0.1:{}", sanzu_port)).await
         } else {
             match timeout(
                 Duration::from_secs(
@@ -125,12 +584,7 @@ pub async fn process_client_connection(
                         .sanzu_server_launch_config
                         .sanzu_server_startup_timeout,
                 ),
-                TcpStream::connect(format!(
-                    "127.0.0.1:{}",
-                    &server_agent_config
-                        .sanzu_server_launch_config
-                        .sanzu_server_port
-                )),
+                TcpStream::connect(format!("127.0.0.1:{}", sanzu_port)),
             )
             .await
             {
@@ -142,10 +596,19 @@ pub async fn process_client_connection(
                             .sanzu_server_launch_config
                             .sanzu_server_startup_timeout
                     );
+                    let reason = sanzu_failure(
+                        sanzu.as_ref(),
+                        "timed out while connecting to sanzu server".to_owned(),
+                    )
+                    .await;
+                    refuse(&reason);
+                    metrics().sanzu_start_failures.inc();
                     send_msg_async(
                         &mut outbound_tls_stream,
                         ServerStartProxy {
                             result: StartProxyStatus::SanzuStartError.into(),
+                            error: reason,
+                            ..Default::default()
                         },
                         None,
                     )
@@ -169,10 +632,19 @@ pub async fn process_client_connection(
                 "[{}@{}] Failed to connect to local sanzu server : {}",
                 client_id, client_addr, e
             );
+            let reason = sanzu_failure(
+                sanzu.as_ref(),
+                format!("could not connect to sanzu server : {}", e),
+            )
+            .await;
+            refuse(&reason);
+            metrics().sanzu_start_failures.inc();
             send_msg_async(
                 &mut outbound_tls_stream,
                 ServerStartProxy {
                     result: StartProxyStatus::SanzuStartError.into(),
+                    error: reason,
+                    ..Default::default()
                 },
                 None,
             )
@@ -185,12 +657,24 @@ pub async fn process_client_connection(
         &mut outbound_tls_stream,
         ServerStartProxy {
             result: StartProxyStatus::StartProxy.into(),
+            codec,
+            session_notices,
+            ..Default::default()
         },
         None,
     )
     .await?;
 
-    let _forward = StandaloneServerForwarder {
+    let started_at = Utc::now();
+    audit.record(
+        AuditEvent::SessionStarted,
+        AuditDetails {
+            started_at: Some(audit_time(started_at)),
+            ..session_details.clone()
+        },
+    );
+    let traffic = Arc::clone(&slot.traffic);
+    let forward = StandaloneServerForwarder {
         outbound_tls_stream,
         sanzu_stream,
         client_id: client_id.clone(),
@@ -200,16 +684,106 @@ pub async fn process_client_connection(
                 .sanzu_server_launch_config
                 .sanzu_server_startup_timeout,
         ),
+        shutdown_deadline: session_notices.then(|| sessions.shutdown_deadline()),
     }
-    .forward()
-    .await;
+    .forward(Arc::clone(&traffic));
+    let max_duration_reached = async {
+        match max_session_duration {
+            Some(max) => sleep(max).await,
+            None => std::future::pending().await,
+        }
+    };
+    let sanzu_exited = async {
+        match &sanzu {
+            Some(sanzu) => sanzu.exited().await,
+            None => std::future::pending().await,
+        }
+    };
+    let mut keep_web_session = false;
+    let forward_res = tokio::select! {
+        res = forward => match res {
+            // the client closed the connection, its sanzu server may well exit along
+            Ok(()) => Ok(()),
+            Err(e) => {
+                // the forwarding also fails when the sanzu server crashes
+                let exit = match &sanzu {
+                    Some(sanzu) => sanzu.exited_within(SANZU_EXIT_GRACE).await,
+                    None => None,
+                };
+                match exit {
+                    Some(exit) => {
+                        if exit.crashed() {
+                            metrics().sanzu_crashes.inc();
+                        }
+                        Err(anyhow!(sanzu_exit_reason(&exit)))
+                    }
+                    None => Err(anyhow!("connection lost : {}", e)),
+                }
+            }
+        },
+        exit = sanzu_exited => {
+            info!(
+                "[{}@{}] The sanzu server of session {} exited : {}",
+                client_id, client_addr, slot.id, exit
+            );
+            if exit.crashed() {
+                metrics().sanzu_crashes.inc();
+            }
+            Err(anyhow!(sanzu_exit_reason(&exit)))
+        }
+        termination = slot.terminated() => {
+            info!(
+                "[{}@{}] Session {} ends : {}",
+                client_id, client_addr, slot.id, termination.reason
+            );
+            keep_web_session = termination.keep_web_session;
+            Err(anyhow!(termination.reason))
+        }
+        _ = max_duration_reached => {
+            info!(
+                "[{}@{}] Session reached the maximum duration allowed by the access policy ({}), closing it",
+                client_id,
+                client_addr,
+                humantime::format_duration(max_session_duration.unwrap_or_default())
+            );
+            Err(anyhow!("maximum session duration reached"))
+        }
+    };
     info!("Connection with {}@{} ended", client_id, client_addr);
+    let ended_at = Utc::now();
+    audit.record(
+        AuditEvent::SessionEnded,
+        AuditDetails {
+            reason: Some(match forward_res {
+                Ok(()) => "connection closed".to_owned(),
+                Err(e) => e.to_string(),
+            }),
+            started_at: Some(audit_time(started_at)),
+            ended_at: Some(audit_time(ended_at)),
+            duration_secs: Some((ended_at - started_at).num_seconds() as u64),
+            bytes_from_client: Some(traffic.from_client.load(Ordering::Relaxed)),
+            bytes_to_client: Some(traffic.to_client.load(Ordering::Relaxed)),
+            ..session_details
+        },
+    );
 
     info!(
-        "Dropping client count semaphore as session with {}@{} just ended",
-        client_id, client_addr
+        "Releasing session slot {} as session with {}@{} just ended",
+        slot.id, client_id, client_addr
     );
-    drop(_permit);
+    drop(sanzu);
+    drop(slot);
+
+    if web_session_id.is_none() {
+        return Ok(());
+    }
+    if keep_web_session {
+        info!(
+            "Session id {} goes on with another connection, not closing it in the web application",
+            client_claims.session_id
+        );
+        return Ok(());
+    }
     info!(
         "Informing web application that session id {} with {} just ended",
         client_claims.session_id, client_addr
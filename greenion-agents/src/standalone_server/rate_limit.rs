@@ -0,0 +1,333 @@
+use log::{debug, info, warn};
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    conf::server_config::RateLimitConfig, metrics::metrics, standalone_server::utils::canonical_ip,
+};
+
+const CONNECTION_WINDOW: Duration = Duration::from_secs(60);
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Limits connections and failed authentications per source address, and temporarily bans
+/// the sources that fail too often. Sources of `allow_list` are never limited.
+#[derive(Debug)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    sources: Mutex<HashMap<IpAddr, SourceState>>,
+    rejected_connections: AtomicU64,
+    bans: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct SourceState {
+    connections: VecDeque<Instant>,
+    failures: VecDeque<Instant>,
+    banned_until: Option<Instant>,
+    /// Number of bans so far, each one lasting twice as long as the previous
+    ban_count: u32,
+    last_ban_end: Option<Instant>,
+    /// Whether the connection limit was already reported for the current minute
+    throttled: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RateLimitStats {
+    pub active_bans: usize,
+    pub total_bans: u64,
+    pub rejected_connections: u64,
+}
+
+/// Exports the number of sources banned at `now`.
+fn export_banned_sources(sources: &HashMap<IpAddr, SourceState>, now: Instant) {
+    let banned = sources
+        .values()
+        .filter(|s| s.banned_until.is_some_and(|until| until > now))
+        .count();
+    metrics().banned_sources.set(banned as i64);
+}
+
+fn prune(times: &mut VecDeque<Instant>, window: Duration, now: Instant) {
+    while times
+        .front()
+        .is_some_and(|t| now.duration_since(*t) >= window)
+    {
+        times.pop_front();
+    }
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            config: config.clone(),
+            sources: Mutex::new(HashMap::new()),
+            rejected_connections: AtomicU64::new(0),
+            bans: AtomicU64::new(0),
+        }
+    }
+
+    fn exempt(&self, addr: IpAddr) -> bool {
+        !self.config.enabled || self.config.allow_list.iter().any(|n| n.contains(&addr))
+    }
+
+    fn failure_window(&self) -> Duration {
+        Duration::from_secs(self.config.failed_auth_window_secs)
+    }
+
+    /// Called for every accepted TCP connection, before any TLS work. Returns false if the
+    /// connection must be dropped.
+    pub fn allow_connection(&self, addr: IpAddr) -> bool {
+        let addr = canonical_ip(addr);
+        if self.exempt(addr) {
+            return true;
+        }
+        let now = Instant::now();
+        let mut sources = self.sources.lock().unwrap_or_else(|e| e.into_inner());
+        let source = sources.entry(addr).or_default();
+
+        if let Some(until) = source.banned_until {
+            if until > now {
+                debug!("Dropping connection from banned source {}", addr);
+                self.rejected_connections.fetch_add(1, Ordering::Relaxed);
+                metrics().refused_connections.inc("banned");
+                return false;
+            }
+            info!("Ban of {} has expired", addr);
+            source.banned_until = None;
+            source.last_ban_end = Some(until);
+            export_banned_sources(&sources, now);
+        }
+        let source = sources.entry(addr).or_default();
+
+        prune(&mut source.connections, CONNECTION_WINDOW, now);
+        if source.connections.len() >= self.config.max_connections_per_minute as usize {
+            if !source.throttled {
+                warn!(
+                    "{} exceeded {} connections per minute, dropping its connections",
+                    addr, self.config.max_connections_per_minute
+                );
+                source.throttled = true;
+            }
+            self.rejected_connections.fetch_add(1, Ordering::Relaxed);
+            metrics().refused_connections.inc("rate_limited");
+            return false;
+        }
+        source.throttled = false;
+        source.connections.push_back(now);
+        true
+    }
+
+    /// Called when a source failed authentication. Bans it once it
+    /// failed `max_failed_auth` times within `failed_auth_window_secs`.
+    pub fn record_failure(&self, addr: IpAddr) {
+        let addr = canonical_ip(addr);
+        if self.exempt(addr) {
+            return;
+        }
+        let now = Instant::now();
+        let window = self.failure_window();
+        let mut sources = self.sources.lock().unwrap_or_else(|e| e.into_inner());
+        let source = sources.entry(addr).or_default();
+        if source.banned_until.is_some() {
+            return;
+        }
+
+        prune(&mut source.failures, window, now);
+        source.failures.push_back(now);
+        if source.failures.len() < self.config.max_failed_auth as usize {
+            return;
+        }
+
+        // a source that stayed quiet for a whole ban duration starts over
+        let base = Duration::from_secs(self.config.ban_duration_secs);
+        if source
+            .last_ban_end
+            .is_some_and(|end| now.duration_since(end) > base)
+        {
+            source.ban_count = 0;
+        }
+        let ban = base
+            .saturating_mul(2u32.saturating_pow(source.ban_count))
+            .min(Duration::from_secs(self.config.max_ban_duration_secs));
+        source.ban_count = source.ban_count.saturating_add(1);
+        source.banned_until = Some(now + ban);
+        source.failures.clear();
+        self.bans.fetch_add(1, Ordering::Relaxed);
+        metrics().source_bans.inc();
+        export_banned_sources(&sources, now);
+        warn!(
+            "Banning {} for {} after {} failed attempts within {}",
+            addr,
+            humantime::format_duration(ban),
+            self.config.max_failed_auth,
+            humantime::format_duration(window)
+        );
+    }
+
+    /// Called when a source authenticated : its previous failures are forgotten.
+    pub fn record_success(&self, addr: IpAddr) {
+        let addr = canonical_ip(addr);
+        let mut sources = self.sources.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(source) = sources.get_mut(&addr) {
+            source.failures.clear();
+        }
+    }
+
+    /// Sources currently banned, with the time left on their ban.
+    pub fn banned_sources(&self) -> Vec<(IpAddr, Duration)> {
+        let now = Instant::now();
+        let sources = self.sources.lock().unwrap_or_else(|e| e.into_inner());
+        sources
+            .iter()
+            .filter_map(|(addr, s)| {
+                s.banned_until
+                    .filter(|until| *until > now)
+                    .map(|until| (*addr, until - now))
+            })
+            .collect()
+    }
+
+    pub fn stats(&self) -> RateLimitStats {
+        RateLimitStats {
+            active_bans: self.banned_sources().len(),
+            total_bans: self.bans.load(Ordering::Relaxed),
+            rejected_connections: self.rejected_connections.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Forgets the sources that have nothing left to remember, so that the table doesn't
+    /// grow with every address that ever connected.
+    pub async fn prune_periodically(self: Arc<Self>) {
+        loop {
+            tokio::time::sleep(PRUNE_INTERVAL).await;
+            let now = Instant::now();
+            let window = self.failure_window();
+            let max_ban = Duration::from_secs(self.config.max_ban_duration_secs);
+            let mut sources = self.sources.lock().unwrap_or_else(|e| e.into_inner());
+            sources.retain(|_, s| {
+                prune(&mut s.connections, CONNECTION_WINDOW, now);
+                prune(&mut s.failures, window, now);
+                let banned = s.banned_until.is_some_and(|until| until > now);
+                // remembered for a while after a ban, so that repeated bans get longer
+                let recently_banned = s
+                    .last_ban_end
+                    .or(s.banned_until)
+                    .is_some_and(|end| end + max_ban > now);
+                banned || recently_banned || !s.connections.is_empty() || !s.failures.is_empty()
+            });
+            // bans of sources that don't come back end without anyone noticing
+            export_banned_sources(&sources, now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(toml: &str) -> RateLimiter {
+        RateLimiter::new(&toml::from_str(toml).unwrap())
+    }
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    fn ban_left(limiter: &RateLimiter, addr: &str) -> Option<Duration> {
+        limiter
+            .banned_sources()
+            .into_iter()
+            .find(|(a, _)| *a == ip(addr))
+            .map(|(_, left)| left)
+    }
+
+    /// Ends the ban of `addr`, as if its duration had elapsed.
+    fn expire_ban(limiter: &RateLimiter, addr: &str) {
+        if let Some(source) = limiter.sources.lock().unwrap().get_mut(&ip(addr)) {
+            source.banned_until = Some(Instant::now() - Duration::from_millis(1));
+        }
+        assert!(limiter.allow_connection(ip(addr)));
+    }
+
+    #[test]
+    fn connections_per_minute() {
+        let limiter = limiter("max_connections_per_minute = 3");
+        for _ in 0..3 {
+            assert!(limiter.allow_connection(ip("10.0.0.1")));
+        }
+        assert!(!limiter.allow_connection(ip("10.0.0.1")));
+        assert!(limiter.allow_connection(ip("10.0.0.2")));
+        assert_eq!(limiter.stats().rejected_connections, 1);
+    }
+
+    #[test]
+    fn ban_after_failures() {
+        let limiter = limiter("max_failed_auth = 3\nban_duration_secs = 60");
+        limiter.record_failure(ip("10.0.0.1"));
+        limiter.record_failure(ip("10.0.0.1"));
+        assert!(limiter.allow_connection(ip("10.0.0.1")));
+        limiter.record_failure(ip("10.0.0.1"));
+
+        let left = ban_left(&limiter, "10.0.0.1").unwrap();
+        assert!(left > Duration::from_secs(59) && left <= Duration::from_secs(60));
+        assert!(!limiter.allow_connection(ip("10.0.0.1")));
+        assert!(limiter.allow_connection(ip("10.0.0.2")));
+        let stats = limiter.stats();
+        assert_eq!((stats.active_bans, stats.total_bans), (1, 1));
+    }
+
+    #[test]
+    fn success_forgets_failures() {
+        let limiter = limiter("max_failed_auth = 2");
+        limiter.record_failure(ip("10.0.0.1"));
+        limiter.record_success(ip("10.0.0.1"));
+        limiter.record_failure(ip("10.0.0.1"));
+        assert!(ban_left(&limiter, "10.0.0.1").is_none());
+    }
+
+    #[test]
+    fn repeated_bans_get_longer() {
+        let limiter =
+            limiter("max_failed_auth = 1\nban_duration_secs = 60\nmax_ban_duration_secs = 200");
+        let mut bans = vec![];
+        for _ in 0..3 {
+            limiter.record_failure(ip("10.0.0.1"));
+            bans.push(
+                ban_left(&limiter, "10.0.0.1")
+                    .unwrap()
+                    .as_secs_f64()
+                    .round() as u64,
+            );
+            expire_ban(&limiter, "10.0.0.1");
+        }
+        assert_eq!(bans, vec![60, 120, 200]);
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses() {
+        let limiter = limiter("max_failed_auth = 1");
+        limiter.record_failure(ip("::ffff:10.0.0.1"));
+        assert!(!limiter.allow_connection(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn exempt_sources() {
+        let limiter = limiter(
+            "max_failed_auth = 1\nmax_connections_per_minute = 1\nallow_list = [\"10.0.0.0/24\"]",
+        );
+        limiter.record_failure(ip("10.0.0.1"));
+        assert!(limiter.allow_connection(ip("10.0.0.1")));
+        assert!(limiter.allow_connection(ip("10.0.0.1")));
+
+        let disabled = self::limiter("enabled = false\nmax_failed_auth = 1");
+        disabled.record_failure(ip("10.0.0.1"));
+        assert!(disabled.banned_sources().is_empty());
+    }
+}
@@ -0,0 +1,349 @@
+//! Confinement of the network process, see `privsep`. Landlock limits the files it can reach
+//! and a seccomp filter the system calls it can make. Both apply to the calling thread and the
+//! threads it starts afterwards, so `confine` runs before the async runtime starts.
+use anyhow::bail;
+use log::{debug, info, warn};
+use std::{
+    fs::OpenOptions,
+    io,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::fs::OpenOptionsExt,
+    },
+    path::{Path, PathBuf},
+};
+
+use crate::conf::server_config::ServerConfig;
+
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1 << 0;
+const LANDLOCK_RULE_PATH_BENEATH: u32 = 1;
+
+const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+const ACCESS_FS_READ: u64 = ACCESS_FS_EXECUTE | ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+/// Rights that apply to files, the others only apply to directories
+const ACCESS_FS_FILE: u64 =
+    ACCESS_FS_EXECUTE | ACCESS_FS_WRITE_FILE | ACCESS_FS_READ_FILE | ACCESS_FS_TRUNCATE;
+
+/// System locations read by the libraries : resolver, CA certificates, cgroup limits...
+const READABLE_SYSTEM_PATHS: &[&str] = &[
+    "/etc",
+    "/usr",
+    "/lib",
+    "/lib64",
+    "/proc",
+    "/sys",
+    "/dev",
+    "/run/systemd/resolve",
+];
+
+#[repr(C)]
+struct LandlockRulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct LandlockPathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xC000_003E;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xC000_00B7;
+
+/// Offsets in `struct seccomp_data`
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+mod seccomp_data {
+    pub const NR: u32 = 0;
+    pub const ARCH: u32 = 4;
+    pub const ARG0: u32 = 16;
+}
+
+/// Nothing the network process does needs these : running programs, inspecting other
+/// processes, changing credentials or namespaces, or administering the system.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const DENIED_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_execve,
+    libc::SYS_execveat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_fork,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_vfork,
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_setuid,
+    libc::SYS_setgid,
+    libc::SYS_setreuid,
+    libc::SYS_setregid,
+    libc::SYS_setresuid,
+    libc::SYS_setresgid,
+    libc::SYS_setgroups,
+    libc::SYS_setfsuid,
+    libc::SYS_setfsgid,
+    libc::SYS_capset,
+    libc::SYS_unshare,
+    libc::SYS_setns,
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_chroot,
+    libc::SYS_bpf,
+    libc::SYS_perf_event_open,
+    libc::SYS_userfaultfd,
+    libc::SYS_io_uring_setup,
+    libc::SYS_io_uring_enter,
+    libc::SYS_io_uring_register,
+    libc::SYS_keyctl,
+    libc::SYS_add_key,
+    libc::SYS_request_key,
+    libc::SYS_kexec_load,
+    libc::SYS_kexec_file_load,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_reboot,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_acct,
+    libc::SYS_quotactl,
+    libc::SYS_syslog,
+    libc::SYS_personality,
+    libc::SYS_name_to_handle_at,
+    libc::SYS_open_by_handle_at,
+    libc::SYS_settimeofday,
+    libc::SYS_clock_settime,
+    libc::SYS_adjtimex,
+    libc::SYS_sethostname,
+    libc::SYS_setdomainname,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_iopl,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_ioperm,
+];
+
+/// `clone` flags creating namespaces
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const CLONE_NEW_NAMESPACES: libc::c_int = libc::CLONE_NEWNS
+    | libc::CLONE_NEWCGROUP
+    | libc::CLONE_NEWUTS
+    | libc::CLONE_NEWIPC
+    | libc::CLONE_NEWUSER
+    | libc::CLONE_NEWPID
+    | libc::CLONE_NEWNET;
+
+/// Confines the calling thread, and the threads it starts from now on. Only the folders the
+/// configuration says the agent writes to stay writable.
+pub fn confine(config: &ServerConfig) -> anyhow::Result<()> {
+    // SAFETY: prctl with integer arguments only
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } == -1 {
+        bail!(
+            "Could not set no_new_privs : {}",
+            io::Error::last_os_error()
+        );
+    }
+
+    let auth = &config.server_auth_config;
+    let mut readable = READABLE_SYSTEM_PATHS
+        .iter()
+        .map(PathBuf::from)
+        .collect::<Vec<_>>();
+    // files that may be replaced rather than rewritten are reached through their folder
+    readable.extend(
+        auth.jwks_file
+            .iter()
+            .chain(&auth.jwt_public_key_files)
+            .chain(&auth.policy_file)
+            .filter_map(|f| Path::new(f).parent().map(Path::to_owned)),
+    );
+
+    // the supervisor checked the state files are all in it
+    let writable = vec![PathBuf::from(&config.privsep_config.state_dir)];
+
+    restrict_filesystem(&readable, &writable)?;
+    filter_syscalls()
+}
+
+fn restrict_filesystem(readable: &[PathBuf], writable: &[PathBuf]) -> anyhow::Result<()> {
+    // SAFETY: asking for the ABI version takes no pointer
+    let abi = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<LandlockRulesetAttr>(),
+            0usize,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+    if abi < 1 {
+        warn!(
+            "Landlock is not available on this system, the network process can reach every file its user can : {}",
+            io::Error::last_os_error()
+        );
+        return Ok(());
+    }
+    // 1 : file access, 2 : REFER, 3 : TRUNCATE. The later ones restrict what we don't use.
+    let handled = match abi {
+        1 => (1 << 13) - 1,
+        2 => (1 << 14) - 1,
+        _ => (1 << 15) - 1,
+    };
+
+    let attr = LandlockRulesetAttr {
+        handled_access_fs: handled,
+    };
+    // SAFETY: attr outlives the call and its size is passed along
+    let ruleset = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            &attr as *const LandlockRulesetAttr,
+            std::mem::size_of::<LandlockRulesetAttr>(),
+            0u32,
+        )
+    };
+    if ruleset < 0 {
+        bail!(
+            "Could not create the landlock ruleset : {}",
+            io::Error::last_os_error()
+        );
+    }
+    // SAFETY: the kernel just returned this descriptor to us
+    let ruleset = unsafe { OwnedFd::from_raw_fd(ruleset as i32) };
+
+    let rules = readable
+        .iter()
+        .map(|p| (p, ACCESS_FS_READ))
+        .chain(writable.iter().map(|p| (p, handled)));
+    for (path, access) in rules {
+        let file = match OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_PATH | libc::O_CLOEXEC)
+            .open(path)
+        {
+            Ok(v) => v,
+            Err(e) => {
+                debug!("Not allowing {} : {}", path.display(), e);
+                continue;
+            }
+        };
+        let access = if file.metadata()?.is_dir() {
+            access
+        } else {
+            access & ACCESS_FS_FILE
+        };
+        let rule = LandlockPathBeneathAttr {
+            allowed_access: access & handled,
+            parent_fd: file.as_raw_fd(),
+        };
+        // SAFETY: rule outlives the call, both descriptors are open
+        let rc = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset.as_raw_fd(),
+                LANDLOCK_RULE_PATH_BENEATH,
+                &rule as *const LandlockPathBeneathAttr,
+                0u32,
+            )
+        };
+        if rc != 0 {
+            bail!(
+                "Could not allow {} : {}",
+                path.display(),
+                io::Error::last_os_error()
+            );
+        }
+        debug!(
+            "Landlock allows {} ({})",
+            path.display(),
+            if access & ACCESS_FS_WRITE_FILE != 0 {
+                "read-write"
+            } else {
+                "read-only"
+            }
+        );
+    }
+
+    // SAFETY: the ruleset descriptor is open
+    if unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0u32) } != 0 {
+        bail!(
+            "Could not enforce the landlock ruleset : {}",
+            io::Error::last_os_error()
+        );
+    }
+    info!("Landlock confines the network process (ABI {})", abi);
+    Ok(())
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn filter_syscalls() -> anyhow::Result<()> {
+    use libc::{
+        sock_filter, BPF_ABS, BPF_JEQ, BPF_JMP, BPF_JSET, BPF_K, BPF_LD, BPF_RET, BPF_W,
+        SECCOMP_RET_ALLOW, SECCOMP_RET_ERRNO, SECCOMP_RET_KILL_PROCESS,
+    };
+    let instruction = |code: u32, k: u32, jt: u8, jf: u8| sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    };
+    let load = |offset: u32| instruction(BPF_LD | BPF_W | BPF_ABS, offset, 0, 0);
+    let ret = |action: u32| instruction(BPF_RET | BPF_K, action, 0, 0);
+    let jump_if = |op: u32, k: u32, jt: u8, jf: u8| instruction(BPF_JMP | op | BPF_K, k, jt, jf);
+    let deny = ret(SECCOMP_RET_ERRNO | libc::EPERM as u32);
+
+    let mut filter = vec![
+        // system call numbers are only meaningful for our architecture
+        load(seccomp_data::ARCH),
+        jump_if(BPF_JEQ, AUDIT_ARCH, 1, 0),
+        ret(SECCOMP_RET_KILL_PROCESS),
+        load(seccomp_data::NR),
+        // its arguments can't be inspected, the C library falls back to clone
+        jump_if(BPF_JEQ, libc::SYS_clone3 as u32, 0, 1),
+        ret(SECCOMP_RET_ERRNO | libc::ENOSYS as u32),
+        // threads may be started, namespaces may not
+        jump_if(BPF_JEQ, libc::SYS_clone as u32, 0, 3),
+        load(seccomp_data::ARG0),
+        jump_if(BPF_JSET, CLONE_NEW_NAMESPACES as u32, 0, 1),
+        deny,
+        load(seccomp_data::NR),
+    ];
+    for syscall in DENIED_SYSCALLS {
+        filter.push(jump_if(BPF_JEQ, *syscall as u32, 0, 1));
+        filter.push(deny);
+    }
+    filter.push(ret(SECCOMP_RET_ALLOW));
+
+    let program = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_mut_ptr(),
+    };
+    // SAFETY: program points to filter, which outlives the call. no_new_privs is set.
+    if unsafe {
+        libc::prctl(
+            libc::PR_SET_SECCOMP,
+            libc::SECCOMP_MODE_FILTER,
+            &program as *const libc::sock_fprog,
+        )
+    } != 0
+    {
+        bail!(
+            "Could not install the seccomp filter : {}",
+            io::Error::last_os_error()
+        );
+    }
+    info!(
+        "seccomp filter installed, {} system calls are denied",
+        DENIED_SYSCALLS.len()
+    );
+    Ok(())
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn filter_syscalls() -> anyhow::Result<()> {
+    warn!("No seccomp filter for this architecture, the network process can make any system call");
+    Ok(())
+}
@@ -0,0 +1,584 @@
+use anyhow::{bail, Context};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::{fs, path::PathBuf};
+
+use crate::{
+    conf::server_config::SanzuServerLaunchConfig,
+    proto::{
+        common::{version_at_least, SESSION_NOTICES_VERSION, SESSION_STATUSES_VERSION},
+        messages::{self, StartProxyStatus},
+    },
+};
+
+/// Displays larger than this are not believed
+const MAX_DISPLAY_SIZE: u32 = 16384;
+const MAX_DISPLAY_SCALE: f32 = 10.0;
+
+/// Variables whose value changes from a session to another
+const SESSION_VARIABLES: [&str; 5] = ["{user}", "{session_id}", "{width}", "{height}", "{scale}"];
+
+/// Display of the client, as it advertised it.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct DisplayGeometry {
+    pub width: u32,
+    pub height: u32,
+    pub scale: f32,
+}
+
+impl DisplayGeometry {
+    /// None for displays that can't be real ones.
+    fn advertised(display: &messages::Display) -> Option<Self> {
+        Some(Self {
+            width: display.width,
+            height: display.height,
+            scale: display.scale,
+        })
+        .filter(Self::is_plausible)
+    }
+
+    fn is_plausible(&self) -> bool {
+        (1..=MAX_DISPLAY_SIZE).contains(&self.width)
+            && (1..=MAX_DISPLAY_SIZE).contains(&self.height)
+            && self.scale.is_finite()
+            && self.scale > 0.0
+            && self.scale <= MAX_DISPLAY_SCALE
+    }
+}
+
+/// What the client asked for in its hello, to launch its sanzu server.
+#[derive(Debug, Clone, Default)]
+pub struct ClientOffer {
+    /// Protocol version of the client
+    pub version: String,
+    /// Codec families its sanzu client decodes, empty for clients that don't tell
+    pub codecs: Vec<String>,
+    pub preferred_codec: Option<String>,
+    pub display: Option<DisplayGeometry>,
+}
+
+impl ClientOffer {
+    pub fn from_hello(hello: &messages::ClientHello) -> Self {
+        let display = hello.display.as_ref().and_then(|display| {
+            let advertised = DisplayGeometry::advertised(display);
+            if advertised.is_none() {
+                warn!(
+                    "Ignoring the display advertised by the client : {:?}",
+                    display
+                );
+            }
+            advertised
+        });
+        Self {
+            version: hello.version.clone(),
+            codecs: hello.codecs.clone(),
+            preferred_codec: (!hello.preferred_codec.is_empty())
+                .then(|| hello.preferred_codec.clone()),
+            display,
+        }
+    }
+
+    /// Whether the client knows the start statuses that ask it to confirm a takeover, keep it
+    /// in the queue, tell the server is shutting down or that no codec is in common.
+    pub fn knows_session_statuses(&self) -> bool {
+        version_at_least(&self.version, SESSION_STATUSES_VERSION)
+    }
+
+    /// `status`, or the closest one known by clients that don't know the session statuses.
+    pub fn status(&self, status: StartProxyStatus) -> StartProxyStatus {
+        if self.knows_session_statuses() {
+            return status;
+        }
+        match status {
+            StartProxyStatus::TakeoverConfirmation => StartProxyStatus::SessionAlreadyActive,
+            StartProxyStatus::Queued | StartProxyStatus::ServerShuttingDown => {
+                StartProxyStatus::ServerBusy
+            }
+            StartProxyStatus::NoCommonCodec => StartProxyStatus::InternalServerError,
+            status => status,
+        }
+    }
+
+    /// Whether the client reads the frames of the session stream, with the notices sent
+    /// during the session.
+    pub fn reads_session_notices(&self) -> bool {
+        version_at_least(&self.version, SESSION_NOTICES_VERSION)
+    }
+
+    /// Codec family of the session : the preferred one of the client if the server allows
+    /// it, else the first family of the server the client decodes. Clients that don't tell
+    /// their codecs get the first family of the server. None when they have none in common.
+    pub fn negotiate_codec(&self, server_codecs: &[String]) -> Option<String> {
+        if self.codecs.is_empty() {
+            return server_codecs.first().cloned();
+        }
+        if let Some(preferred) = &self.preferred_codec {
+            if server_codecs.contains(preferred) {
+                return Some(preferred.clone());
+            }
+        }
+        server_codecs
+            .iter()
+            .find(|codec| self.codecs.contains(codec))
+            .cloned()
+    }
+}
+
+/// Values of a session substituted in the sanzu server launch templates.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SanzuLaunchVars {
+    pub port: u16,
+    pub user_id: String,
+    /// Session of the web application, none for paired devices
+    pub session_id: Option<u32>,
+    /// FFmpeg encoder of the negotiated codec family
+    pub codec: String,
+    /// None when the client did not advertise its display
+    pub display: Option<DisplayGeometry>,
+}
+
+impl SanzuLaunchVars {
+    /// Variables of a session of `user_id` on `port`, with the preferred codec of the
+    /// configuration.
+    pub fn new(config: &SanzuServerLaunchConfig, port: u16, user_id: &str) -> Self {
+        Self {
+            port,
+            user_id: user_id.to_owned(),
+            codec: config.preferred_encoder(),
+            ..Default::default()
+        }
+    }
+
+    /// Values come from the client : they must not be able to change the meaning of the
+    /// arguments or of the config file they end up in.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !is_valid_user_id(&self.user_id) {
+            bail!("Invalid user id '{}'", self.user_id);
+        }
+        if self.codec.is_empty()
+            || !self
+                .codec
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+        {
+            bail!("Invalid codec '{}'", self.codec);
+        }
+        if let Some(display) = &self.display {
+            if !display.scale.is_finite() || display.scale <= 0.0 {
+                bail!("Invalid display scale {}", display.scale);
+            }
+        }
+        Ok(())
+    }
+
+    /// Like `validate`, for values that may not have gone through the negotiation with the
+    /// client, e.g. sent by the network process to the supervisor : the codec must also be
+    /// an encoder of `config`, and the display one a client could advertise.
+    pub fn validate_for(&self, config: &SanzuServerLaunchConfig) -> anyhow::Result<()> {
+        self.validate()?;
+        if !config.encoders().contains(&self.codec) {
+            bail!("Codec '{}' is not a configured encoder", self.codec);
+        }
+        if let Some(display) = self.display.filter(|d| !d.is_plausible()) {
+            bail!(
+                "Display {}x{} at scale {} is out of bounds",
+                display.width,
+                display.height,
+                display.scale
+            );
+        }
+        Ok(())
+    }
+
+    /// `template` with `{port}`, `{user}`, `{session_id}`, `{codec}`, `{width}`, `{height}`,
+    /// `{scale}` and `{config}` replaced. Values the session doesn't have are left empty.
+    pub fn render(&self, template: &str, config_path: &str) -> String {
+        let (width, height, scale) = match &self.display {
+            Some(d) => (
+                d.width.to_string(),
+                d.height.to_string(),
+                d.scale.to_string(),
+            ),
+            None => Default::default(),
+        };
+        template
+            .replace("{port}", &self.port.to_string())
+            .replace("{user}", &self.user_id)
+            .replace(
+                "{session_id}",
+                &self.session_id.map(|id| id.to_string()).unwrap_or_default(),
+            )
+            .replace("{codec}", &self.codec)
+            .replace("{width}", &width)
+            .replace("{height}", &height)
+            .replace("{scale}", &scale)
+            .replace("{config}", config_path)
+    }
+}
+
+/// User ids are substituted in the sanzu server arguments and config files, and may end up in
+/// its path : they can't start like an option, nor be `.`, `..` or a hidden file name. Paired
+/// devices are `device:<device id>`.
+fn is_valid_user_id(user_id: &str) -> bool {
+    !user_id.is_empty()
+        && user_id.len() <= 128
+        && !user_id.starts_with(['-', '.'])
+        && user_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-' | '@' | '+' | ':'))
+}
+
+/// Config file generated for a sanzu server, removed once dropped.
+pub struct GeneratedConfig(PathBuf);
+
+impl Drop for GeneratedConfig {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.0) {
+            debug!(
+                "Could not remove the sanzu server config file {} : {}",
+                self.0.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Command of a sanzu server, rendered from the launch templates for a session.
+pub struct SanzuLaunch {
+    pub path: String,
+    pub args: Vec<String>,
+    pub env: Vec<(String, String)>,
+    pub generated_config: Option<GeneratedConfig>,
+}
+
+impl SanzuLaunch {
+    /// Renders the templates of `config` with `vars`, writing the config file of the sanzu
+    /// server when `sanzu_server_config_template` is set.
+    pub fn render(
+        config: &SanzuServerLaunchConfig,
+        vars: &SanzuLaunchVars,
+    ) -> anyhow::Result<Self> {
+        vars.validate_for(config)?;
+        let generated_config = match &config.sanzu_server_config_template {
+            Some(template_path) => Some(generate_config(config, template_path, vars)?),
+            None => None,
+        };
+        let config_path = match &generated_config {
+            Some(generated) => generated.0.to_string_lossy().to_string(),
+            None => config.sanzu_server_config_path.clone(),
+        };
+        Ok(Self {
+            path: vars.render(&config.sanzu_server_path, &config_path),
+            args: config
+                .sanzu_server_args
+                .iter()
+                .map(|arg| vars.render(arg, &config_path))
+                .collect(),
+            env: config
+                .sanzu_server_env
+                .iter()
+                .map(|(name, value)| (name.clone(), vars.render(value, &config_path)))
+                .collect(),
+            generated_config,
+        })
+    }
+}
+
+/// Writes the config file of the sanzu server on `vars.port`, rendered from `template_path`.
+fn generate_config(
+    config: &SanzuServerLaunchConfig,
+    template_path: &str,
+    vars: &SanzuLaunchVars,
+) -> anyhow::Result<GeneratedConfig> {
+    let template = fs::read_to_string(template_path).with_context(|| {
+        format!(
+            "Could not read the sanzu server config template {}",
+            template_path
+        )
+    })?;
+    let dir = PathBuf::from(&config.sanzu_server_generated_config_dir);
+    fs::create_dir_all(&dir).with_context(|| format!("Could not create {}", dir.display()))?;
+    let file_name = PathBuf::from(template_path)
+        .file_name()
+        .map(|name| {
+            name.to_string_lossy()
+                .trim_end_matches(".template")
+                .to_owned()
+        })
+        .unwrap_or_else(|| "sanzu_server_config.toml".to_owned());
+    let path = dir.join(format!("{}-{}", vars.port, file_name));
+    let content = vars.render(&template, &path.to_string_lossy());
+    fs::write(&path, content).with_context(|| {
+        format!(
+            "Could not write the sanzu server config file {}",
+            path.display()
+        )
+    })?;
+    info!("Generated the sanzu server config file {}", path.display());
+    Ok(GeneratedConfig(path))
+}
+
+/// Whether the sanzu servers started with `config` differ from a session to another, so
+/// can't be started before knowing the session.
+pub fn depends_on_session(config: &SanzuServerLaunchConfig) -> bool {
+    let config_template = config
+        .sanzu_server_config_template
+        .as_ref()
+        .and_then(|path| fs::read_to_string(path).ok());
+    std::iter::once(&config.sanzu_server_path)
+        .chain(config.sanzu_server_args.iter())
+        .chain(config.sanzu_server_env.values())
+        .chain(config_template.iter())
+        .any(|template| SESSION_VARIABLES.iter().any(|v| template.contains(v)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn vars(user_id: &str) -> SanzuLaunchVars {
+        SanzuLaunchVars {
+            port: 1122,
+            user_id: user_id.to_owned(),
+            session_id: Some(7),
+            codec: "libx264".to_owned(),
+            display: Some(DisplayGeometry {
+                width: 1920,
+                height: 1080,
+                scale: 1.5,
+            }),
+        }
+    }
+
+    fn offer(version: &str, codecs: &[&str], preferred_codec: Option<&str>) -> ClientOffer {
+        ClientOffer {
+            version: version.to_owned(),
+            codecs: codecs.iter().map(|c| c.to_string()).collect(),
+            preferred_codec: preferred_codec.map(str::to_owned),
+            display: None,
+        }
+    }
+
+    fn codecs(codecs: &[&str]) -> Vec<String> {
+        codecs.iter().map(|c| c.to_string()).collect()
+    }
+
+    #[test]
+    fn render_variables() {
+        let vars = vars("alice@example.com");
+        assert_eq!(
+            vars.render(
+                "-p {port} -u {user} -s {session_id} -e {codec} -g {width}x{height}@{scale} -f {config}",
+                "/etc/sanzu.toml"
+            ),
+            "-p 1122 -u alice@example.com -s 7 -e libx264 -g 1920x1080@1.5 -f /etc/sanzu.toml"
+        );
+        let unknown = SanzuLaunchVars {
+            session_id: None,
+            display: None,
+            ..vars
+        };
+        assert_eq!(
+            unknown.render("{session_id}|{width}|{height}|{scale}|{other}", ""),
+            "||||{other}"
+        );
+    }
+
+    #[test]
+    fn validate_values_from_the_client() {
+        for user_id in [
+            "alice",
+            "alice@example.com",
+            "device:wzAE2R8uZyn0yTdWCLNlYg",
+            "a.b_c-d+e",
+        ] {
+            assert!(vars(user_id).validate().is_ok(), "{}", user_id);
+        }
+        for user_id in [
+            "",
+            "-alice",
+            "alice bob",
+            "../alice",
+            ".",
+            "..",
+            "...",
+            ".alice",
+            "alice;rm",
+            "alice\n",
+            &"a".repeat(129),
+        ] {
+            assert!(vars(user_id).validate().is_err(), "{:?}", user_id);
+        }
+
+        let mut codec = vars("alice");
+        codec.codec = "libx264 -f".to_owned();
+        assert!(codec.validate().is_err());
+        let mut scale = vars("alice");
+        scale.display = scale.display.map(|d| DisplayGeometry {
+            scale: f32::NAN,
+            ..d
+        });
+        assert!(scale.validate().is_err());
+    }
+
+    #[test]
+    fn render_launch() {
+        let dir = TempDir::new().unwrap();
+        let template = dir.path().join("sanzu.toml.template");
+        fs::write(&template, "port = {port}\nuser = \"{user}\"\n").unwrap();
+        let config = SanzuServerLaunchConfig {
+            sanzu_server_path: "/opt/sanzu/{codec}/sanzu_server".to_owned(),
+            sanzu_server_args: vec![
+                "-p".to_owned(),
+                "{port}".to_owned(),
+                "-f".to_owned(),
+                "{config}".to_owned(),
+            ],
+            sanzu_server_env: BTreeMap::from([("SANZU_USER".to_owned(), "{user}".to_owned())]),
+            sanzu_server_config_template: Some(template.display().to_string()),
+            sanzu_server_generated_config_dir: dir.path().join("generated").display().to_string(),
+            ..Default::default()
+        };
+
+        let launch = SanzuLaunch::render(&config, &vars("alice")).unwrap();
+        let generated = dir.path().join("generated").join("1122-sanzu.toml");
+        assert_eq!(launch.path, "/opt/sanzu/libx264/sanzu_server");
+        assert_eq!(
+            launch.args,
+            vec!["-p", "1122", "-f", &generated.display().to_string()]
+        );
+        assert_eq!(
+            launch.env,
+            vec![("SANZU_USER".to_owned(), "alice".to_owned())]
+        );
+        assert_eq!(
+            fs::read_to_string(&generated).unwrap(),
+            "port = 1122\nuser = \"alice\"\n"
+        );
+        drop(launch);
+        assert!(!generated.exists());
+
+        assert!(SanzuLaunch::render(&config, &vars("alice bob")).is_err());
+        assert!(!generated.exists());
+    }
+
+    #[test]
+    fn session_dependent_launch() {
+        let dir = TempDir::new().unwrap();
+        let mut config = SanzuServerLaunchConfig {
+            sanzu_server_args: vec![
+                "-p".to_owned(),
+                "{port}".to_owned(),
+                "-e".to_owned(),
+                "{codec}".to_owned(),
+            ],
+            ..Default::default()
+        };
+        assert!(!depends_on_session(&config));
+
+        config.sanzu_server_env = BTreeMap::from([("WIDTH".to_owned(), "{width}".to_owned())]);
+        assert!(depends_on_session(&config));
+
+        config.sanzu_server_env.clear();
+        let template = dir.path().join("sanzu.toml.template");
+        fs::write(&template, "user = \"{user}\"\n").unwrap();
+        config.sanzu_server_config_template = Some(template.display().to_string());
+        assert!(depends_on_session(&config));
+    }
+
+    #[test]
+    fn negotiate_codec() {
+        let server = codecs(&["h265", "h264"]);
+        // clients that don't tell their codecs
+        assert_eq!(
+            offer("v0.0.1", &[], None)
+                .negotiate_codec(&server)
+                .as_deref(),
+            Some("h265")
+        );
+        assert_eq!(
+            offer("v0.0.2", &["h264", "h265"], Some("h264"))
+                .negotiate_codec(&server)
+                .as_deref(),
+            Some("h264")
+        );
+        // the preferred codec of the client isn't allowed by the server
+        assert_eq!(
+            offer("v0.0.2", &["av1", "h264"], Some("av1"))
+                .negotiate_codec(&server)
+                .as_deref(),
+            Some("h264")
+        );
+        assert_eq!(
+            offer("v0.0.2", &["h264", "h265"], None)
+                .negotiate_codec(&server)
+                .as_deref(),
+            Some("h265")
+        );
+        assert_eq!(
+            offer("v0.0.2", &["av1"], None).negotiate_codec(&server),
+            None
+        );
+    }
+
+    #[test]
+    fn statuses_of_legacy_clients() {
+        let legacy = offer("v0.0.1", &[], None);
+        let current = offer("v0.0.2", &[], None);
+        for (status, legacy_status) in [
+            (
+                StartProxyStatus::TakeoverConfirmation,
+                StartProxyStatus::SessionAlreadyActive,
+            ),
+            (StartProxyStatus::Queued, StartProxyStatus::ServerBusy),
+            (
+                StartProxyStatus::ServerShuttingDown,
+                StartProxyStatus::ServerBusy,
+            ),
+            (
+                StartProxyStatus::NoCommonCodec,
+                StartProxyStatus::InternalServerError,
+            ),
+            (StartProxyStatus::StartProxy, StartProxyStatus::StartProxy),
+        ] {
+            assert_eq!(legacy.status(status), legacy_status);
+            assert_eq!(current.status(status), status);
+        }
+        assert!(!legacy.reads_session_notices());
+        assert!(current.reads_session_notices());
+    }
+
+    #[test]
+    fn advertised_display() {
+        let hello = |width, height, scale| messages::ClientHello {
+            display: Some(messages::Display {
+                width,
+                height,
+                scale,
+            }),
+            ..Default::default()
+        };
+        assert_eq!(
+            ClientOffer::from_hello(&hello(2560, 1440, 2.0)).display,
+            Some(DisplayGeometry {
+                width: 2560,
+                height: 1440,
+                scale: 2.0
+            })
+        );
+        for (width, height, scale) in [
+            (0, 1080, 1.0),
+            (1920, 100_000, 1.0),
+            (1920, 1080, f32::INFINITY),
+            (1920, 1080, -1.0),
+        ] {
+            assert_eq!(
+                ClientOffer::from_hello(&hello(width, height, scale)).display,
+                None
+            );
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use std::time::{Duration, Instant};
+
+/// First wait between two probes, doubled after each one
+const FIRST_PROBE_DELAY: Duration = Duration::from_millis(10);
+const MAX_PROBE_DELAY: Duration = Duration::from_millis(250);
+
+/// Tells when a sanzu server being started listens on its port, probing it more and more
+/// rarely until the startup timeout.
+pub struct ReadinessProbe {
+    port: u16,
+    deadline: Instant,
+    delay: Duration,
+}
+
+impl ReadinessProbe {
+    pub fn new(port: u16, timeout: Duration) -> Self {
+        Self {
+            port,
+            deadline: Instant::now() + timeout,
+            delay: FIRST_PROBE_DELAY,
+        }
+    }
+
+    /// How long to wait before probing again, None once the startup timeout is reached.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        let left = self.deadline.checked_duration_since(Instant::now())?;
+        if left.is_zero() {
+            return None;
+        }
+        let delay = self.delay.min(left);
+        self.delay = (self.delay * 2).min(MAX_PROBE_DELAY);
+        Some(delay)
+    }
+
+    /// Whether a process of `process_group`, the sanzu server or one it started, listens on
+    /// the port. The sockets of the system are looked up, so the sanzu server never sees a
+    /// connection that isn't from a client, and matched with the open files of the group, so
+    /// that another process listening on the port isn't taken for the sanzu server.
+    #[cfg(target_os = "linux")]
+    pub fn port_listening(&self, process_group: u32) -> bool {
+        let sockets = ["/proc/net/tcp", "/proc/net/tcp6"]
+            .iter()
+            .filter_map(|table| std::fs::read_to_string(table).ok())
+            .flat_map(|table| listening_sockets(&table, self.port))
+            .collect::<Vec<_>>();
+        !sockets.is_empty()
+            && group_processes(process_group)
+                .into_iter()
+                .any(|pid| has_open_socket(pid, &sockets))
+    }
+
+    /// Whether a socket listens on the port, found by connecting to it.
+    #[cfg(not(target_os = "linux"))]
+    pub fn port_listening(&self) -> bool {
+        std::net::TcpStream::connect_timeout(
+            &std::net::SocketAddr::from(([127, 0, 0, 1], self.port)),
+            MAX_PROBE_DELAY,
+        )
+        .is_ok()
+    }
+}
+
+/// Inodes of the sockets of a `/proc/net/tcp` table listening on `port`.
+#[cfg(target_os = "linux")]
+fn listening_sockets(sockets: &str, port: u16) -> Vec<u64> {
+    // TCP_LISTEN
+    const LISTEN: &str = "0A";
+    sockets
+        .lines()
+        .skip(1)
+        .filter_map(|socket| {
+            let mut fields = socket.split_whitespace().skip(1);
+            let local_port = fields
+                .next()
+                .and_then(|local| local.rsplit_once(':'))
+                .and_then(|(_, p)| u16::from_str_radix(p, 16).ok());
+            let state = fields.nth(1);
+            // after tx_queue:rx_queue, tr:tm->when, retrnsmt, uid and timeout
+            let inode = fields.nth(5).and_then(|inode| inode.parse().ok());
+            (local_port == Some(port) && state == Some(LISTEN))
+                .then_some(inode)
+                .flatten()
+        })
+        .collect()
+}
+
+/// Processes of `process_group`.
+#[cfg(target_os = "linux")]
+fn group_processes(process_group: u32) -> Vec<u32> {
+    let Ok(processes) = std::fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    processes
+        .filter_map(|process| process.ok()?.file_name().to_str()?.parse::<u32>().ok())
+        .filter(|pid| {
+            std::fs::read_to_string(format!("/proc/{}/stat", pid))
+                .is_ok_and(|stat| stat_process_group(&stat) == Some(process_group))
+        })
+        .collect()
+}
+
+/// Process group in a `/proc/<pid>/stat` line, after the command name, the state and the
+/// parent pid. The command name may hold spaces and parentheses.
+#[cfg(target_os = "linux")]
+fn stat_process_group(stat: &str) -> Option<u32> {
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(2)?.parse().ok()
+}
+
+/// Whether process `pid` has one of `sockets` open.
+#[cfg(target_os = "linux")]
+fn has_open_socket(pid: u32, sockets: &[u64]) -> bool {
+    let Ok(files) = std::fs::read_dir(format!("/proc/{}/fd", pid)) else {
+        return false;
+    };
+    files.filter_map(Result::ok).any(|file| {
+        std::fs::read_link(file.path()).is_ok_and(|target| {
+            target
+                .to_str()
+                .and_then(|t| t.strip_prefix("socket:[")?.strip_suffix(']'))
+                .and_then(|inode| inode.parse::<u64>().ok())
+                .is_some_and(|inode| sockets.contains(&inode))
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probe_delays() {
+        let mut probe = ReadinessProbe::new(1122, Duration::from_secs(1));
+        let delays = (0..7)
+            .map(|_| probe.next_delay().unwrap().as_millis())
+            .collect::<Vec<_>>();
+        assert_eq!(delays, vec![10, 20, 40, 80, 160, 250, 250]);
+        let mut expired = ReadinessProbe::new(1122, Duration::ZERO);
+        assert_eq!(expired.next_delay(), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn listening_sockets_of_a_port() {
+        let table = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 0100007F:0462 00000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 41234 1 0000000000000000 100 0 0 10 0
+   1: 0100007F:0462 0100007F:C350 01 00000000:00000000 00:00000000 00000000  1000        0 41235 1 0000000000000000 20 4 30 10 -1
+   2: 00000000:0016 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 1789 1 0000000000000000 100 0 0 10 0
+   3: 00000000000000000000000000000000:0462 00000000000000000000000000000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 41240 1 0000000000000000 100 0 0 10 0
+";
+        // the established connection to the port is not listening
+        assert_eq!(listening_sockets(table, 1122), vec![41234, 41240]);
+        assert_eq!(listening_sockets(table, 22), vec![1789]);
+        assert!(listening_sockets(table, 5000).is_empty());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn process_group_of_stat() {
+        assert_eq!(
+            stat_process_group("4242 (sanzu_server) S 1 4242 4242 0 -1 4194560"),
+            Some(4242)
+        );
+        assert_eq!(
+            stat_process_group("4243 (a) b (c) R 4242 4200 4200 0 -1"),
+            Some(4200)
+        );
+        assert_eq!(stat_process_group("4244 truncated"), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn listening_process_group() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let probe = ReadinessProbe::new(port, Duration::from_secs(1));
+        // SAFETY: getpgrp has no preconditions and can't fail
+        let process_group = unsafe { libc::getpgrp() } as u32;
+        assert!(probe.port_listening(process_group));
+        assert!(!probe.port_listening(process_group + 1));
+        drop(listener);
+        assert!(!probe.port_listening(process_group));
+    }
+}
@@ -1,11 +1,105 @@
-use anyhow::anyhow;
+use anyhow::{anyhow, bail};
 use log::{debug, error, info, warn};
+use std::collections::VecDeque;
 use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::process::CommandExt;
 use std::path::Path;
-use std::process::Command;
+use std::process::{ChildStderr, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread::sleep;
+use std::time::{Duration, Instant};
 
-use super::{SanzuServerLinuxHandles, SanzuServerWrapper};
+use super::sanzu_readiness::ReadinessProbe;
+use super::{SanzuExit, SanzuServerLinuxHandles, SanzuServerWrapper};
+
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// How long the sanzu server has to exit after SIGTERM, before it is killed
+const STOP_TIMEOUT: Duration = Duration::from_secs(3);
+/// Lines of the standard error kept to explain why the sanzu server exited
+const STDERR_TAIL_LINES: usize = 10;
+const STDERR_TAIL_LINE_LENGTH: usize = 200;
+/// How long the last lines of the standard error may take to come after an exit
+const STDERR_DRAIN_TIMEOUT: Duration = Duration::from_millis(500);
+
+type StderrTail = Arc<Mutex<VecDeque<String>>>;
+
+/// Ready marker of the sanzu server, and whether it wrote it yet
+type ReadyMarker = (String, Arc<AtomicBool>);
+
+/// Copies the standard error of the sanzu server to its log file, or to ours, keeping its
+/// last lines and looking for its ready marker.
+fn copy_stderr(
+    stderr: ChildStderr,
+    mut logfile: Option<File>,
+    tail: StderrTail,
+    ready: Option<ReadyMarker>,
+) {
+    let mut reader = BufReader::new(stderr);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) => return,
+            Ok(_) => {}
+            Err(e) => {
+                debug!("Stopped reading the sanzu server errors : {}", e);
+                return;
+            }
+        }
+        let written = match &mut logfile {
+            Some(logfile) => logfile.write_all(&line),
+            None => io::stderr().write_all(&line),
+        };
+        if let Err(e) = written {
+            debug!("Could not save sanzu server errors : {}", e);
+        }
+
+        let text = String::from_utf8_lossy(&line);
+        let text = text.trim_end();
+        if let Some((marker, seen)) = &ready {
+            if text.contains(marker.as_str()) {
+                seen.store(true, Ordering::Relaxed);
+            }
+        }
+        if text.is_empty() {
+            continue;
+        }
+        let mut tail = tail.lock().unwrap_or_else(|e| e.into_inner());
+        if tail.len() == STDERR_TAIL_LINES {
+            tail.pop_front();
+        }
+        tail.push_back(text.chars().take(STDERR_TAIL_LINE_LENGTH).collect());
+    }
+}
+
+/// Sends `signal` to every process of the group of the sanzu server.
+fn signal_group(pgid: u32, signal: libc::c_int) {
+    // SAFETY: kill only sends a signal, the group is the one the sanzu server leads
+    if unsafe { libc::kill(-(pgid as libc::pid_t), signal) } == -1 {
+        let e = io::Error::last_os_error();
+        // nothing left in the group
+        if e.raw_os_error() != Some(libc::ESRCH) {
+            error!("Could not signal the sanzu server processes : {}", e);
+        }
+    }
+}
+
+impl SanzuServerLinuxHandles {
+    /// Last lines of the standard error, once the sanzu server exited.
+    fn stderr_tail(&self) -> Vec<String> {
+        let deadline = Instant::now() + STDERR_DRAIN_TIMEOUT;
+        while !self.sanzu_server_stderr_copier.is_finished() && Instant::now() < deadline {
+            sleep(Duration::from_millis(50));
+        }
+        let tail = self
+            .sanzu_server_stderr_tail
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        tail.iter().cloned().collect()
+    }
+}
 
 impl SanzuServerWrapper {
     pub fn start(&mut self) -> anyhow::Result<()> {
@@ -55,12 +149,13 @@ impl SanzuServerWrapper {
         info!("Starting sanzu server at '{}'...", &self.sanzu_server_path);
 
         let mut _sanzu_cmd = Command::new(sanzu_server_path);
-        _sanzu_cmd.args(["-f", &self.sanzu_server_config]);
-        _sanzu_cmd.args(["-x", "-e", &self.sanzu_server_codec]);
+        _sanzu_cmd.args(&self.sanzu_server_args);
+        _sanzu_cmd.envs(self.sanzu_server_env.iter().map(|(k, v)| (k, v)));
+        debug!("Sanzu server arguments : {:?}", &self.sanzu_server_args);
 
-        if let Some(logfile) = logfile {
-            _sanzu_cmd.stderr(logfile);
-        }
+        // its own process group, so that the processes it starts are stopped with it
+        _sanzu_cmd.process_group(0);
+        _sanzu_cmd.stderr(Stdio::piped());
         //.env("RUST_LOG", "debug")
         let mut _sanzu_process = match _sanzu_cmd.spawn() {
             Ok(v) => {
@@ -69,23 +164,147 @@ impl SanzuServerWrapper {
             }
             Err(e) => {
                 error!("Could not start sanzu server : {}", e);
-                return Err(anyhow!("Could not start sanzu server"));
+                return Err(anyhow!("Could not start sanzu server : {}", e));
             }
         };
 
-        self.handles = Some(SanzuServerLinuxHandles {
+        let started_at = Instant::now();
+        let tail = StderrTail::default();
+        let stderr = _sanzu_process
+            .stderr
+            .take()
+            .ok_or_else(|| anyhow!("The sanzu server standard error is not piped"))?;
+        let ready = self
+            .sanzu_ready_marker
+            .clone()
+            .map(|marker| (marker, Arc::new(AtomicBool::new(false))));
+        let copier = {
+            let tail = Arc::clone(&tail);
+            let ready = ready.clone();
+            std::thread::spawn(move || copy_stderr(stderr, logfile, tail, ready))
+        };
+
+        let mut handles = SanzuServerLinuxHandles {
             sanzu_server_process_child: _sanzu_process,
-        });
+            sanzu_server_stderr_tail: tail,
+            sanzu_server_stderr_copier: copier,
+        };
 
-        sleep(self.sanzu_wait_time);
+        let mut probe = ReadinessProbe::new(self.sanzu_server_port, self.sanzu_startup_timeout);
+        loop {
+            if let Ok(Some(status)) = handles.sanzu_server_process_child.try_wait() {
+                signal_group(handles.sanzu_server_process_child.id(), libc::SIGKILL);
+                let exit = SanzuExit {
+                    status: status.to_string(),
+                    stopped: false,
+                    failed: !status.success(),
+                    stderr_tail: handles.stderr_tail(),
+                };
+                error!("Sanzu server exited upon startup : {}", exit);
+                return Err(anyhow!("sanzu server exited upon startup : {}", exit));
+            }
+
+            let listening = probe.port_listening(handles.sanzu_server_process_child.id());
+            let marker_seen = ready
+                .as_ref()
+                .is_none_or(|(_, seen)| seen.load(Ordering::Relaxed));
+            if listening && marker_seen {
+                break;
+            }
+            match probe.next_delay() {
+                Some(delay) => sleep(delay),
+                None => {
+                    let pid = handles.sanzu_server_process_child.id();
+                    signal_group(pid, libc::SIGKILL);
+                    let _ = handles.sanzu_server_process_child.wait();
+                    let waited_for = if listening {
+                        format!(
+                            "did not write '{}'",
+                            self.sanzu_ready_marker.as_deref().unwrap_or_default()
+                        )
+                    } else {
+                        format!("does not listen on port {}", self.sanzu_server_port)
+                    };
+                    error!(
+                        "Sanzu server (pid {}) {} after {}, killed it",
+                        pid,
+                        waited_for,
+                        humantime::format_duration(self.sanzu_startup_timeout)
+                    );
+                    bail!(
+                        "sanzu server {} after {}",
+                        waited_for,
+                        humantime::format_duration(self.sanzu_startup_timeout)
+                    );
+                }
+            }
+        }
+        info!(
+            "Sanzu server (pid {}) ready on port {} after {} ms",
+            handles.sanzu_server_process_child.id(),
+            self.sanzu_server_port,
+            started_at.elapsed().as_millis()
+        );
+        self.handles = Some(handles);
 
         Ok(())
     }
 
-    pub fn wait(self) -> anyhow::Result<()> {
-        if let Some(mut handles) = self.handles {
-            let _ = handles.sanzu_server_process_child.wait();
+    /// Waits for the sanzu server to exit, stopping it once `stop` returns true. Processes
+    /// it started and left behind are killed once it exited.
+    pub fn wait(self, stop: impl Fn() -> bool) -> SanzuExit {
+        let Some(mut handles) = self.handles else {
+            return SanzuExit {
+                status: "not started".to_owned(),
+                ..Default::default()
+            };
+        };
+        let child = &mut handles.sanzu_server_process_child;
+        let pid = child.id();
+        let mut stop_deadline: Option<Instant> = None;
+        let (status, failed) = loop {
+            match child.try_wait() {
+                Ok(Some(status)) => break (status.to_string(), !status.success()),
+                Ok(None) => {}
+                Err(e) => {
+                    error!("Could not wait for the sanzu server (pid {}) : {}", pid, e);
+                    break (format!("unknown, {}", e), false);
+                }
+            }
+            match stop_deadline {
+                None if stop() => {
+                    info!("Stopping sanzu server (pid {})", pid);
+                    signal_group(pid, libc::SIGTERM);
+                    stop_deadline = Some(Instant::now() + STOP_TIMEOUT);
+                }
+                Some(deadline) if Instant::now() >= deadline => {
+                    warn!(
+                        "Sanzu server (pid {}) still runs {} after being stopped, killing it",
+                        pid,
+                        humantime::format_duration(STOP_TIMEOUT)
+                    );
+                    signal_group(pid, libc::SIGKILL);
+                    if let Err(e) = child.wait() {
+                        error!("Could not wait for the sanzu server (pid {}) : {}", pid, e);
+                    }
+                }
+                _ => {}
+            }
+            sleep(WAIT_POLL_INTERVAL);
+        };
+        signal_group(pid, libc::SIGKILL);
+
+        let exit = SanzuExit {
+            status,
+            stopped: stop_deadline.is_some(),
+            failed,
+            stderr_tail: handles.stderr_tail(),
+        };
+        if exit.stopped {
+            info!("Sanzu server (pid {}) stopped : {}", pid, exit.status);
+        } else {
+            warn!("Sanzu server (pid {}) exited : {}", pid, exit);
         }
-        Ok(())
+        exit
     }
 }
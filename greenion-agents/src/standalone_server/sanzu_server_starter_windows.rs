@@ -1,14 +1,18 @@
-use super::SanzuServerWrapper;
+use super::sanzu_readiness::ReadinessProbe;
+use super::{SanzuExit, SanzuServerWrapper};
 use crate::standalone_server::SanzuServerWindowsHandles;
 use anyhow::anyhow;
 use anyhow::Context;
 use core::time::Duration;
 use log::{debug, error, info, warn};
+use std::collections::BTreeMap;
 use std::ffi::c_void;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
 use std::mem::size_of;
 use std::ops::BitOr;
-use std::path::Path;
-use std::thread::sleep;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 use windows::Win32::Foundation::{BOOL, HANDLE, INVALID_HANDLE_VALUE};
 use windows::Win32::Security::SECURITY_ATTRIBUTES;
 use windows::Win32::Storage::FileSystem::*;
@@ -17,6 +21,41 @@ use windows::{Win32::Foundation::*, Win32::Security::*, Win32::System::RemoteDes
 use windows_core::Free;
 use windows_strings::*;
 
+const WAIT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Looks for the ready marker in what the sanzu server writes to its log file. The file is
+/// shared, so only what is written once the sanzu server starts is looked at.
+struct LogMarker {
+    marker: String,
+    path: PathBuf,
+    offset: u64,
+    seen: bool,
+}
+
+impl LogMarker {
+    fn new(marker: &str, path: PathBuf) -> Self {
+        let offset = std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+        Self {
+            marker: marker.to_owned(),
+            path,
+            offset,
+            seen: false,
+        }
+    }
+
+    fn seen(&mut self) -> bool {
+        if !self.seen {
+            let mut written = Vec::new();
+            let read = File::open(&self.path).and_then(|mut f| {
+                f.seek(SeekFrom::Start(self.offset))?;
+                f.read_to_end(&mut written)
+            });
+            self.seen = read.is_ok() && String::from_utf8_lossy(&written).contains(&self.marker);
+        }
+        self.seen
+    }
+}
+
 impl SanzuServerWrapper {
     pub fn start(&mut self) -> anyhow::Result<()> {
         let mut startup_info = STARTUPINFOW {
@@ -42,6 +81,16 @@ impl SanzuServerWrapper {
                 }
             };
         }
+        let mut ready_marker = match (&self.sanzu_ready_marker, &self.sanzu_log_file) {
+            (Some(marker), Some(logfile)) => {
+                Some(LogMarker::new(marker, std::env::temp_dir().join(logfile)))
+            }
+            (Some(_), None) => {
+                warn!("The sanzu server ready marker is looked for in its log file, which it doesn't have. Only waiting for its port");
+                None
+            }
+            _ => None,
+        };
         let mut console_token = Self::make_token_with_console_access()
             .context("error when retrieving token with session access")?;
 
@@ -98,14 +147,16 @@ impl SanzuServerWrapper {
 
         info!("Starting sanzu server at '{}'...", &self.sanzu_server_path);
 
-        let commandline_str: String = format!(
-            "{:?} -x -f {:?} -e {:?} \0",
-            self.sanzu_server_path, self.sanzu_server_config, self.sanzu_server_codec,
-        );
+        let mut commandline_str: String = format!("{:?}", self.sanzu_server_path);
+        for arg in self.sanzu_server_args.iter() {
+            commandline_str.push_str(&format!(" {:?}", arg));
+        }
+        commandline_str.push_str(" \0");
 
         info!("Starting sanzu server with command : '{}'", commandline_str);
 
         let mut command_line: Vec<u16> = commandline_str.encode_utf16().collect();
+        let environment = self.environment_block();
 
         let _sanzu_process = unsafe {
             CreateProcessAsUserW(
@@ -118,7 +169,9 @@ impl SanzuServerWrapper {
                 HIGH_PRIORITY_CLASS
                     .bitor(CREATE_UNICODE_ENVIRONMENT)
                     .bitor(CREATE_NO_WINDOW),
-                None,
+                environment
+                    .as_ref()
+                    .map(|block| block.as_ptr() as *const c_void),
                 PCWSTR::null(),
                 &startup_info as *const STARTUPINFOW,
                 &mut sanzu_process_info as *mut PROCESS_INFORMATION,
@@ -129,25 +182,62 @@ impl SanzuServerWrapper {
         };
 
         debug!(
-            "Started sanzu server, sleeping for {} seconds...",
-            self.sanzu_wait_time.as_secs()
+            "Started sanzu server, waiting for it to listen on port {}",
+            self.sanzu_server_port
         );
-        sleep(self.sanzu_wait_time);
-        debug!("Done, let's see if sanzu server is still up");
+        let started_at = Instant::now();
+        let mut probe = ReadinessProbe::new(self.sanzu_server_port, self.sanzu_startup_timeout);
+        let mut ready = false;
+        let mut wait_rest = WAIT_TIMEOUT;
+        while let Some(delay) = probe.next_delay() {
+            wait_rest = unsafe {
+                WaitForSingleObject(sanzu_process_info.hProcess, delay.as_millis() as u32)
+            };
+            if wait_rest != WAIT_TIMEOUT {
+                break;
+            }
+            if probe.port_listening() && ready_marker.as_mut().is_none_or(LogMarker::seen) {
+                ready = true;
+                break;
+            }
+        }
 
-        let wait_rest = unsafe { WaitForSingleObject(sanzu_process_info.hProcess, 0) };
         if wait_rest != WAIT_TIMEOUT {
-            error!("Sanzu server crashed");
+            let (status, _) = Self::exit_status(sanzu_process_info.hProcess);
+            error!("Sanzu server crashed : {}", status);
+            unsafe {
+                sanzu_process_info.hProcess.free();
+                sanzu_process_info.hThread.free();
+                if let Some(mut h) = logfile_handle {
+                    h.free();
+                }
+            }
+            Err(anyhow!("sanzu server crashed upon startup : {}", status))
+        } else if !ready {
+            error!(
+                "Sanzu server not ready after {}, terminating it",
+                humantime::format_duration(self.sanzu_startup_timeout)
+            );
             unsafe {
+                if let Err(e) = TerminateProcess(sanzu_process_info.hProcess, 1) {
+                    error!("Could not stop sanzu server : {}", e);
+                }
                 sanzu_process_info.hProcess.free();
                 sanzu_process_info.hThread.free();
                 if let Some(mut h) = logfile_handle {
                     h.free();
                 }
             }
-            Err(anyhow!("sanzu server crashed upon startup"))
+            Err(anyhow!(
+                "sanzu server not ready after {}",
+                humantime::format_duration(self.sanzu_startup_timeout)
+            ))
         } else {
-            info!("Sanzu server seems to have started up correctly");
+            info!(
+                "Sanzu server ready on port {} after {} ms",
+                self.sanzu_server_port,
+                started_at.elapsed().as_millis()
+            );
             self.handles = Some(SanzuServerWindowsHandles {
                 sanzu_server_process_handle: sanzu_process_info.hProcess,
                 sanzu_server_thread_handle: sanzu_process_info.hThread,
@@ -157,13 +247,58 @@ impl SanzuServerWrapper {
         }
     }
 
-    pub fn wait(self) {
+    /// Environment of the agent with the variables of the configuration, None when there are
+    /// none : the sanzu server then inherits the environment of the agent.
+    fn environment_block(&self) -> Option<Vec<u16>> {
+        if self.sanzu_server_env.is_empty() {
+            return None;
+        }
+        let mut variables: BTreeMap<String, String> = std::env::vars().collect();
+        variables.extend(self.sanzu_server_env.iter().cloned());
+        let mut block: Vec<u16> = variables
+            .iter()
+            .flat_map(|(name, value)| {
+                format!("{}={}\0", name, value)
+                    .encode_utf16()
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        block.push(0);
+        Some(block)
+    }
+
+    /// Exit status of the process, and whether it is non-zero.
+    fn exit_status(process: HANDLE) -> (String, bool) {
+        let mut exit_code = 0u32;
+        match unsafe { GetExitCodeProcess(process, &mut exit_code) } {
+            Ok(_) => (format!("exit code {}", exit_code), exit_code != 0),
+            Err(e) => (format!("unknown, {}", e), false),
+        }
+    }
+
+    /// Waits for the sanzu server to exit, terminating it once `stop` returns true.
+    pub fn wait(self, stop: impl Fn() -> bool) -> SanzuExit {
+        let mut stopped = false;
         if let Some(mut handles) = self.handles {
             loop {
-                let wait_rest =
-                    unsafe { WaitForSingleObject(handles.sanzu_server_process_handle, 0) };
+                if stop() {
+                    stopped = true;
+                    info!("Stopping sanzu server");
+                    if let Err(e) =
+                        unsafe { TerminateProcess(handles.sanzu_server_process_handle, 1) }
+                    {
+                        error!("Could not stop sanzu server : {}", e);
+                    }
+                }
+                let wait_rest = unsafe {
+                    WaitForSingleObject(
+                        handles.sanzu_server_process_handle,
+                        WAIT_POLL_INTERVAL.as_millis() as u32,
+                    )
+                };
                 if wait_rest != WAIT_TIMEOUT {
                     error!("Waiting for sanzu server : sanzu server is not alive anymore. Cleaning remaining handles");
+                    let (status, failed) = Self::exit_status(handles.sanzu_server_process_handle);
                     unsafe {
                         handles.sanzu_server_process_handle.free();
                         handles.sanzu_server_thread_handle.free();
@@ -171,12 +306,20 @@ impl SanzuServerWrapper {
                             h.free();
                         }
                         error!("Reseting default process sleep settings");
-                        return;
                     }
+                    return SanzuExit {
+                        status,
+                        stopped,
+                        failed,
+                        stderr_tail: Vec::new(),
+                    };
                 }
-                sleep(Duration::from_secs(5));
             }
         }
+        SanzuExit {
+            status: "not started".to_owned(),
+            ..Default::default()
+        }
     }
 
     fn open_sanzu_server_log_file_handle(logfile: &str) -> anyhow::Result<HANDLE> {
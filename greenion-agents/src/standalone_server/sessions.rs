@@ -0,0 +1,677 @@
+use log::{debug, info};
+use std::{
+    collections::{HashMap, VecDeque},
+    net::{SocketAddr, TcpListener},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+use tokio::sync::{futures::Notified, watch, Notify};
+
+use super::forwarder::Traffic;
+use crate::metrics::metrics;
+
+const PORT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Number of ended sessions used to estimate queue waits
+const DURATION_HISTORY: usize = 20;
+
+/// Sessions running on this server, each with its own sanzu server port.
+///
+/// Ports are taken from `first_port` up to `first_port + max_sessions - 1`. When the agent
+/// starts sanzu servers itself, ports already bound by another process are skipped.
+#[derive(Debug)]
+pub struct SessionManager {
+    max_sessions: usize,
+    first_port: u16,
+    check_ports_free: bool,
+    sessions: Mutex<HashMap<u64, ActiveSession>>,
+    next_id: AtomicU64,
+    /// Notified whenever a slot is released, or a connection leaves the queue
+    released: Notify,
+    /// Connections waiting for a slot, first in line first
+    queue: Mutex<VecDeque<QueuedConnection>>,
+    /// No new session starts while draining
+    draining: AtomicBool,
+    /// Draining for good : the agent stops once the running sessions are closed
+    shutting_down: AtomicBool,
+    /// Set when shutting down : running sessions are ended at this time at the latest
+    shutdown_deadline: watch::Sender<Option<SystemTime>>,
+    /// Set once the sanzu servers started by the agent must be stopped
+    stop_sanzu_servers: Arc<AtomicBool>,
+    /// Ports of the sanzu servers of the warm pool, given first to new sessions. False while
+    /// the sanzu server is being stopped : the port is then given to no session.
+    warm_ports: Mutex<HashMap<u16, bool>>,
+    /// Durations of the last sessions that ended
+    durations: Mutex<VecDeque<Duration>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ActiveSession {
+    pub id: u64,
+    pub user_id: String,
+    /// `sessionId` of the connection token, None for paired devices
+    pub web_session_id: Option<u32>,
+    pub client_addr: SocketAddr,
+    pub sanzu_port: u16,
+    pub started_at: SystemTime,
+    pub traffic: Arc<Traffic>,
+    control: Arc<SessionControl>,
+}
+
+/// Connection waiting in the queue for a slot.
+#[derive(Debug, Clone)]
+pub struct QueuedConnection {
+    id: u64,
+    pub user_id: String,
+    pub client_addr: SocketAddr,
+    pub queued_at: SystemTime,
+}
+
+/// Why a session was ended from the outside.
+#[derive(Debug, Clone)]
+pub struct SessionTermination {
+    pub reason: String,
+    /// The session lives on in another connection : it must not be closed in the web
+    /// application
+    pub keep_web_session: bool,
+}
+
+#[derive(Debug, Default)]
+struct SessionControl {
+    termination: Mutex<Option<SessionTermination>>,
+    notify: Notify,
+}
+
+/// Why `SessionManager::try_start` gave no slot.
+#[derive(Debug)]
+pub enum StartRefused {
+    /// The user already has this session : a user has one session at a time
+    UserSession(ActiveSession),
+    /// Draining, every slot is in use, no port is available, or others are first in line
+    Unavailable,
+}
+
+/// Slot of a running session, released when dropped.
+#[derive(Debug)]
+pub struct SessionSlot {
+    manager: Arc<SessionManager>,
+    pub id: u64,
+    pub sanzu_port: u16,
+    /// The warm pool started a sanzu server on the port for this session
+    pub warm: bool,
+    pub traffic: Arc<Traffic>,
+    control: Arc<SessionControl>,
+}
+
+impl SessionSlot {
+    /// Resolves when the session is asked to end, by a takeover or an administrator.
+    pub async fn terminated(&self) -> SessionTermination {
+        loop {
+            let notified = self.control.notify.notified();
+            if let Some(termination) = self
+                .control
+                .termination
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .clone()
+            {
+                return termination;
+            }
+            notified.await;
+        }
+    }
+}
+
+impl Drop for SessionSlot {
+    fn drop(&mut self) {
+        let mut sessions = self
+            .manager
+            .sessions
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if let Some(session) = sessions.remove(&self.id) {
+            let duration = session.started_at.elapsed().unwrap_or_default();
+            metrics().active_sessions.dec();
+            metrics().session_duration.observe(duration);
+            let mut durations = self
+                .manager
+                .durations
+                .lock()
+                .unwrap_or_else(|e| e.into_inner());
+            if durations.len() == DURATION_HISTORY {
+                durations.pop_front();
+            }
+            durations.push_back(duration);
+        }
+        self.manager.released.notify_waiters();
+        debug!(
+            "Released session slot {} (sanzu port {}), {} session(s) left",
+            self.id,
+            self.sanzu_port,
+            sessions.len()
+        );
+    }
+}
+
+/// Place of a connection in the queue, given up when dropped.
+#[derive(Debug)]
+pub struct QueueTicket {
+    manager: Arc<SessionManager>,
+    id: u64,
+}
+
+impl Drop for QueueTicket {
+    fn drop(&mut self) {
+        self.manager
+            .queue
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|q| q.id != self.id);
+        // the next connection in line may be able to start now
+        self.manager.released.notify_waiters();
+    }
+}
+
+fn port_is_free(port: u16) -> bool {
+    TcpListener::bind(("127.0.0.1", port)).is_ok()
+}
+
+impl SessionManager {
+    pub fn new(max_sessions: usize, first_port: u16, check_ports_free: bool) -> Self {
+        Self {
+            max_sessions: max_sessions.max(1),
+            first_port,
+            check_ports_free,
+            sessions: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(1),
+            released: Notify::new(),
+            queue: Mutex::new(VecDeque::new()),
+            draining: AtomicBool::new(false),
+            shutting_down: AtomicBool::new(false),
+            shutdown_deadline: watch::channel(None).0,
+            stop_sanzu_servers: Arc::new(AtomicBool::new(false)),
+            warm_ports: Mutex::new(HashMap::new()),
+            durations: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn max_sessions(&self) -> usize {
+        self.max_sessions
+    }
+
+    /// Reserves a slot and a sanzu server port for a new session of `user_id`, a port of the
+    /// warm pool if there is one. Refused when the user already has a session, checked along
+    /// with the reservation so that two connections of a user can't both start one. Also
+    /// refused when draining, every slot is in use, no port of the range is available, or
+    /// connections are waiting in the queue and `ticket` isn't the first one.
+    pub fn try_start(
+        self: &Arc<Self>,
+        user_id: &str,
+        web_session_id: Option<u32>,
+        client_addr: SocketAddr,
+        ticket: Option<&QueueTicket>,
+    ) -> Result<SessionSlot, StartRefused> {
+        let mut sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        if self.is_draining() {
+            return Err(StartRefused::Unavailable);
+        }
+        if let Some(existing) = sessions.values().find(|s| s.user_id == user_id) {
+            return Err(StartRefused::UserSession(existing.clone()));
+        }
+        if sessions.len() >= self.max_sessions {
+            return Err(StartRefused::Unavailable);
+        }
+        let mut queue = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+        if queue
+            .front()
+            .is_some_and(|q| Some(q.id) != ticket.map(|t| t.id))
+        {
+            return Err(StartRefused::Unavailable);
+        }
+
+        let used_ports = sessions.values().map(|s| s.sanzu_port).collect::<Vec<_>>();
+        let mut warm_ports = self.warm_ports.lock().unwrap_or_else(|e| e.into_inner());
+        let warm_port = warm_ports
+            .iter()
+            .filter(|(p, offered)| **offered && !used_ports.contains(p))
+            .map(|(p, _)| *p)
+            .min();
+        let sanzu_port = warm_port.or_else(|| {
+            self.ports()
+                .filter(|p| !used_ports.contains(p) && !warm_ports.contains_key(p))
+                .find(|p| !self.check_ports_free || port_is_free(*p))
+        });
+        let Some(sanzu_port) = sanzu_port else {
+            info!(
+                "No free sanzu server port from {} for a new session",
+                self.first_port
+            );
+            return Err(StartRefused::Unavailable);
+        };
+
+        if ticket.is_some() {
+            queue.pop_front();
+        }
+        drop(queue);
+        if let Some(port) = warm_port {
+            warm_ports.remove(&port);
+        }
+        drop(warm_ports);
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let traffic = Arc::new(Traffic::default());
+        let control = Arc::new(SessionControl::default());
+        sessions.insert(
+            id,
+            ActiveSession {
+                id,
+                user_id: user_id.to_owned(),
+                web_session_id,
+                client_addr,
+                sanzu_port,
+                started_at: SystemTime::now(),
+                traffic: Arc::clone(&traffic),
+                control: Arc::clone(&control),
+            },
+        );
+        metrics().active_sessions.inc();
+        Ok(SessionSlot {
+            manager: Arc::clone(self),
+            id,
+            sanzu_port,
+            warm: warm_port.is_some(),
+            traffic,
+            control,
+        })
+    }
+
+    /// Sanzu server ports, from `first_port`.
+    fn ports(&self) -> impl Iterator<Item = u16> + '_ {
+        (0..self.max_sessions).filter_map(|i| self.first_port.checked_add(i as u16))
+    }
+
+    /// Reserves a free port for a sanzu server of the warm pool, until a session is given it
+    /// or it is released. Returns None when draining, or when no port is free.
+    pub fn reserve_warm_port(&self) -> Option<u16> {
+        let sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        if self.is_draining() {
+            return None;
+        }
+        let mut warm_ports = self.warm_ports.lock().unwrap_or_else(|e| e.into_inner());
+        let used_ports = sessions.values().map(|s| s.sanzu_port).collect::<Vec<_>>();
+        let port = self
+            .ports()
+            .filter(|p| !used_ports.contains(p) && !warm_ports.contains_key(p))
+            .find(|p| !self.check_ports_free || port_is_free(*p))?;
+        warm_ports.insert(port, true);
+        Some(port)
+    }
+
+    /// Stops giving a port of the warm pool to new sessions, until it is released once its
+    /// sanzu server exited. False if a session was already given it.
+    pub fn retire_warm_port(&self, port: u16) -> bool {
+        let mut warm_ports = self.warm_ports.lock().unwrap_or_else(|e| e.into_inner());
+        match warm_ports.get_mut(&port) {
+            Some(offered) => {
+                *offered = false;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Gives back a port of the warm pool that no session was given.
+    pub fn release_warm_port(&self, port: u16) {
+        let released = self
+            .warm_ports
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&port)
+            .is_some();
+        if released {
+            self.released.notify_waiters();
+        }
+    }
+
+    /// Puts a connection at the end of the queue. Returns None when `max_size` connections
+    /// are already waiting.
+    pub fn enqueue(
+        self: &Arc<Self>,
+        max_size: usize,
+        user_id: &str,
+        client_addr: SocketAddr,
+    ) -> Option<QueueTicket> {
+        let mut queue = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+        if queue.len() >= max_size {
+            return None;
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        queue.push_back(QueuedConnection {
+            id,
+            user_id: user_id.to_owned(),
+            client_addr,
+            queued_at: SystemTime::now(),
+        });
+        Some(QueueTicket {
+            manager: Arc::clone(self),
+            id,
+        })
+    }
+
+    /// Position of `ticket` in the queue, 1 for the first one in line.
+    pub fn queue_position(&self, ticket: &QueueTicket) -> usize {
+        let queue = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+        queue
+            .iter()
+            .position(|q| q.id == ticket.id)
+            .map(|p| p + 1)
+            .unwrap_or(0)
+    }
+
+    /// Rough wait for the connection at `position` in the queue, from the average duration of
+    /// the last sessions. None until a session has ended.
+    pub fn estimated_wait(&self, position: usize) -> Option<Duration> {
+        let average = {
+            let durations = self.durations.lock().unwrap_or_else(|e| e.into_inner());
+            if durations.is_empty() {
+                return None;
+            }
+            durations.iter().sum::<Duration>() / durations.len() as u32
+        };
+        // time left to each running session if it lasts as long as the average one
+        let mut left = self
+            .sessions()
+            .iter()
+            .map(|s| average.saturating_sub(s.started_at.elapsed().unwrap_or_default()))
+            .collect::<Vec<_>>();
+        left.sort();
+        left.resize(self.max_sessions, Duration::ZERO);
+        let rounds = (position.saturating_sub(1) / self.max_sessions) as u32;
+        Some(left[position.saturating_sub(1) % self.max_sessions] + average * rounds)
+    }
+
+    /// Resolves when a slot is released or a connection leaves the queue. Must be created
+    /// before checking whether a session can start, so that no release is missed.
+    pub fn released(&self) -> Notified<'_> {
+        self.released.notified()
+    }
+
+    /// Connections waiting in the queue, first in line first.
+    pub fn queued(&self) -> Vec<QueuedConnection> {
+        let queue = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+        queue.iter().cloned().collect()
+    }
+
+    /// Stops or resumes starting new sessions. Running sessions are left alone, and queued
+    /// connections are woken up to notice.
+    pub fn set_draining(&self, draining: bool) {
+        self.draining.store(draining, Ordering::Relaxed);
+        self.released.notify_waiters();
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Drains for good, before the agent stops. Running sessions are told they end within
+    /// `drain`.
+    pub fn begin_shutdown(&self, drain: Duration) {
+        self.shutting_down.store(true, Ordering::Relaxed);
+        self.shutdown_deadline
+            .send_replace(Some(SystemTime::now() + drain));
+        self.set_draining(true);
+    }
+
+    /// Time at which running sessions are ended, once shutting down.
+    pub fn shutdown_deadline(&self) -> watch::Receiver<Option<SystemTime>> {
+        self.shutdown_deadline.subscribe()
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::Relaxed)
+    }
+
+    /// Flag the waiters of the sanzu servers started by the agent watch, to stop them.
+    pub fn sanzu_stop_signal(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.stop_sanzu_servers)
+    }
+
+    pub fn stop_sanzu_servers(&self) {
+        self.stop_sanzu_servers.store(true, Ordering::Relaxed);
+    }
+
+    /// Asks session `id` to end. Returns false if there is no such session.
+    pub fn terminate(&self, id: u64, termination: SessionTermination) -> bool {
+        let sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(session) = sessions.get(&id) else {
+            return false;
+        };
+        *session
+            .control
+            .termination
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = Some(termination);
+        session.control.notify.notify_waiters();
+        true
+    }
+
+    /// Waits until session `id` released its slot, and its sanzu server released its port
+    /// when the agent starts them. Returns false on timeout.
+    pub async fn wait_released(&self, id: u64, timeout: Duration) -> bool {
+        let Some(sanzu_port) = self
+            .sessions
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&id)
+            .map(|s| s.sanzu_port)
+        else {
+            return true;
+        };
+        let wait = async {
+            loop {
+                let released = self.released.notified();
+                if !self
+                    .sessions
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .contains_key(&id)
+                {
+                    break;
+                }
+                released.await;
+            }
+            while self.check_ports_free && !port_is_free(sanzu_port) {
+                tokio::time::sleep(PORT_POLL_INTERVAL).await;
+            }
+        };
+        tokio::time::timeout(timeout, wait).await.is_ok()
+    }
+
+    /// Sessions running now.
+    pub fn sessions(&self) -> Vec<ActiveSession> {
+        let sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        let mut sessions = sessions.values().cloned().collect::<Vec<_>>();
+        sessions.sort_by_key(|s| s.id);
+        sessions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIRST_PORT: u16 = 20000;
+
+    fn manager(max_sessions: usize) -> Arc<SessionManager> {
+        Arc::new(SessionManager::new(max_sessions, FIRST_PORT, false))
+    }
+
+    fn addr() -> SocketAddr {
+        "192.0.2.1:40000".parse().unwrap()
+    }
+
+    fn start(
+        manager: &Arc<SessionManager>,
+        user_id: &str,
+        ticket: Option<&QueueTicket>,
+    ) -> Result<SessionSlot, StartRefused> {
+        manager.try_start(user_id, Some(1), addr(), ticket)
+    }
+
+    #[test]
+    fn slots_and_ports() {
+        let manager = manager(2);
+        let alice = start(&manager, "alice", None).unwrap();
+        let bob = start(&manager, "bob", None).unwrap();
+        assert_eq!(
+            (alice.sanzu_port, bob.sanzu_port),
+            (FIRST_PORT, FIRST_PORT + 1)
+        );
+        assert!(matches!(
+            start(&manager, "carol", None),
+            Err(StartRefused::Unavailable)
+        ));
+
+        drop(alice);
+        let carol = start(&manager, "carol", None).unwrap();
+        assert_eq!(carol.sanzu_port, FIRST_PORT);
+        let users = manager
+            .sessions()
+            .into_iter()
+            .map(|s| s.user_id)
+            .collect::<Vec<_>>();
+        assert_eq!(users, vec!["bob", "carol"]);
+    }
+
+    #[test]
+    fn one_session_per_user() {
+        let manager = manager(2);
+        let first = start(&manager, "alice", None).unwrap();
+        match start(&manager, "alice", None) {
+            Err(StartRefused::UserSession(existing)) => assert_eq!(existing.id, first.id),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn queue_order() {
+        let manager = manager(1);
+        let alice = start(&manager, "alice", None).unwrap();
+        let bob = manager.enqueue(2, "bob", addr()).unwrap();
+        let carol = manager.enqueue(2, "carol", addr()).unwrap();
+        assert!(manager.enqueue(2, "dave", addr()).is_none());
+        assert_eq!(
+            (manager.queue_position(&bob), manager.queue_position(&carol)),
+            (1, 2)
+        );
+
+        drop(alice);
+        // the slot goes to the first in line
+        assert!(matches!(
+            start(&manager, "eve", None),
+            Err(StartRefused::Unavailable)
+        ));
+        assert!(matches!(
+            start(&manager, "carol", Some(&carol)),
+            Err(StartRefused::Unavailable)
+        ));
+        let bob_slot = start(&manager, "bob", Some(&bob)).unwrap();
+        assert_eq!(manager.queue_position(&carol), 1);
+
+        // leaving the queue lets the next one in line through
+        let dave = manager.enqueue(2, "dave", addr()).unwrap();
+        drop(carol);
+        drop(bob_slot);
+        assert_eq!(manager.queue_position(&dave), 1);
+        assert!(start(&manager, "dave", Some(&dave)).is_ok());
+    }
+
+    #[test]
+    fn estimated_wait() {
+        let manager = manager(2);
+        assert_eq!(manager.estimated_wait(1), None);
+        manager
+            .durations
+            .lock()
+            .unwrap()
+            .extend([Duration::from_secs(100), Duration::from_secs(300)]);
+        // no session running : the first two in line start right away
+        assert_eq!(manager.estimated_wait(2), Some(Duration::ZERO));
+        assert_eq!(manager.estimated_wait(3), Some(Duration::from_secs(200)));
+    }
+
+    #[test]
+    fn draining() {
+        let manager = manager(2);
+        manager.set_draining(true);
+        assert!(matches!(
+            start(&manager, "alice", None),
+            Err(StartRefused::Unavailable)
+        ));
+        manager.set_draining(false);
+        assert!(start(&manager, "alice", None).is_ok());
+    }
+
+    #[test]
+    fn shutdown() {
+        let manager = manager(2);
+        assert!(manager.shutdown_deadline().borrow().is_none());
+        manager.begin_shutdown(Duration::from_secs(30));
+        assert!(manager.is_draining() && manager.is_shutting_down());
+        assert!(manager.shutdown_deadline().borrow().is_some());
+        assert!(matches!(
+            start(&manager, "alice", None),
+            Err(StartRefused::Unavailable)
+        ));
+    }
+
+    #[tokio::test]
+    async fn takeover() {
+        let manager = manager(1);
+        let slot = start(&manager, "alice", None).unwrap();
+        let termination = SessionTermination {
+            reason: "taken over".to_owned(),
+            keep_web_session: true,
+        };
+        assert!(!manager.terminate(slot.id + 1, termination.clone()));
+
+        let id = slot.id;
+        let session = tokio::spawn(async move {
+            let termination = slot.terminated().await;
+            drop(slot);
+            termination
+        });
+        assert!(manager.terminate(id, termination));
+        assert!(manager.wait_released(id, Duration::from_secs(5)).await);
+        let ended = session.await.unwrap();
+        assert_eq!(ended.reason, "taken over");
+        assert!(ended.keep_web_session);
+        assert!(start(&manager, "alice", None).is_ok());
+    }
+
+    #[test]
+    fn warm_ports() {
+        let manager = manager(3);
+        let warm = manager.reserve_warm_port().unwrap();
+        assert_eq!(warm, FIRST_PORT);
+        let alice = start(&manager, "alice", None).unwrap();
+        assert!(alice.warm && alice.sanzu_port == warm);
+
+        // a warm port being stopped is given to no session
+        let stopping = manager.reserve_warm_port().unwrap();
+        assert!(manager.retire_warm_port(stopping));
+        let bob = start(&manager, "bob", None).unwrap();
+        assert!(!bob.warm && bob.sanzu_port != stopping);
+        manager.release_warm_port(stopping);
+        drop(bob);
+        let bob = start(&manager, "bob", None).unwrap();
+        assert_eq!(bob.sanzu_port, stopping);
+
+        // already given to a session
+        assert!(!manager.retire_warm_port(warm));
+        manager.set_draining(true);
+        assert!(manager.reserve_warm_port().is_none());
+    }
+}
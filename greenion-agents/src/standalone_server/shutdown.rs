@@ -0,0 +1,173 @@
+use log::{info, warn};
+use std::time::Duration;
+use tokio::{task::JoinSet, time::Instant};
+
+use super::{
+    sessions::{SessionManager, SessionTermination},
+    systemd::SystemdNotifier,
+};
+
+/// How long the connections have to close their sessions in the web application once
+/// they were told to end
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Signals asking the agent to stop : SIGTERM and SIGINT on unix systems, Ctrl-C elsewhere.
+pub struct ShutdownSignals {
+    #[cfg(unix)]
+    terminate: Option<tokio::signal::unix::Signal>,
+    #[cfg(unix)]
+    interrupt: Option<tokio::signal::unix::Signal>,
+}
+
+impl ShutdownSignals {
+    /// Listens for the signals from now on : they no longer kill the agent right away.
+    pub fn new() -> Self {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            let listen = |kind: SignalKind, name: &str| match signal(kind) {
+                Ok(v) => Some(v),
+                Err(e) => {
+                    warn!(
+                        "Could not listen for {}, it will stop the agent abruptly : {}",
+                        name, e
+                    );
+                    None
+                }
+            };
+            Self {
+                terminate: listen(SignalKind::terminate(), "SIGTERM"),
+                interrupt: listen(SignalKind::interrupt(), "SIGINT"),
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            Self {}
+        }
+    }
+
+    /// Resolves with the name of the next signal received.
+    pub async fn recv(&mut self) -> &'static str {
+        #[cfg(unix)]
+        {
+            let terminate = async {
+                match self.terminate.as_mut() {
+                    Some(s) => s.recv().await,
+                    None => std::future::pending().await,
+                }
+            };
+            let interrupt = async {
+                match self.interrupt.as_mut() {
+                    Some(s) => s.recv().await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = terminate => "SIGTERM",
+                _ = interrupt => "SIGINT",
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            match tokio::signal::ctrl_c().await {
+                Ok(()) => "Ctrl-C",
+                Err(e) => {
+                    warn!("Could not listen for Ctrl-C : {}", e);
+                    std::future::pending().await
+                }
+            }
+        }
+    }
+}
+
+impl Default for ShutdownSignals {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Stops the agent once the listener is closed : no new session starts and connections still
+/// waiting are told the server is shutting down. Running sessions are told they end within
+/// `drain`, and may go on for that long, or until another signal, then they are ended and
+/// closed in the web application. The sanzu servers started by the agent are stopped last.
+pub async fn shut_down(
+    sessions: &SessionManager,
+    connections: &mut JoinSet<()>,
+    signals: &mut ShutdownSignals,
+    notifier: &SystemdNotifier,
+    drain: Duration,
+) {
+    sessions.begin_shutdown(drain);
+
+    let running = sessions.sessions().len();
+    if running > 0 {
+        info!(
+            "Waiting up to {} for {} running session(s) to end",
+            humantime::format_duration(drain),
+            running
+        );
+        let deadline = Instant::now() + drain;
+        let mut keep_alive = notifier.keep_alive_interval().map(tokio::time::interval);
+        loop {
+            let released = sessions.released();
+            let running = sessions.sessions().len();
+            if running == 0 {
+                info!("Every session has ended");
+                break;
+            }
+            let keep_alive_tick = async {
+                match keep_alive.as_mut() {
+                    Some(i) => i.tick().await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = released => {}
+                _ = keep_alive_tick => {
+                    notifier.keep_alive(&format!(
+                        "Shutting down, waiting for {} session(s) to end",
+                        running
+                    ));
+                }
+                _ = tokio::time::sleep_until(deadline) => {
+                    info!("Drain period is over");
+                    break;
+                }
+                signal = signals.recv() => {
+                    info!("Received {} again, not waiting for the sessions to end", signal);
+                    break;
+                }
+            }
+        }
+    }
+
+    for session in sessions.sessions() {
+        info!(
+            "Ending session {} of user '{}' from {}",
+            session.id, session.user_id, session.client_addr
+        );
+        sessions.terminate(
+            session.id,
+            SessionTermination {
+                reason: "the server is shutting down".to_owned(),
+                keep_web_session: false,
+            },
+        );
+    }
+
+    let closed = tokio::time::timeout(CLOSE_TIMEOUT, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+    if closed.is_err() {
+        warn!(
+            "{} connection(s) did not end within {}, aborting them",
+            connections.len(),
+            humantime::format_duration(CLOSE_TIMEOUT)
+        );
+        connections.abort_all();
+    }
+
+    info!("Stopping the sanzu servers started by the agent");
+    sessions.stop_sanzu_servers();
+}
@@ -0,0 +1,148 @@
+use ipnet::IpNet;
+use log::info;
+use std::{
+    net::IpAddr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::{
+    auth::jwt::Claims, conf::server_config::ServerNetworkConfig, metrics::metrics,
+    standalone_server::utils::canonical_ip,
+};
+
+/// Source addresses allowed to reach the listener, from `allowed_source_cidrs` and
+/// `denied_source_cidrs`. A denied network wins over an allowed one, and an empty allow list
+/// allows every source that isn't denied.
+#[derive(Debug)]
+pub struct SourceAcl {
+    allowed: Vec<IpNet>,
+    denied: Vec<IpNet>,
+    refused_connections: AtomicU64,
+}
+
+impl SourceAcl {
+    pub fn new(config: &ServerNetworkConfig) -> Self {
+        Self {
+            allowed: config.allowed_source_cidrs.clone(),
+            denied: config.denied_source_cidrs.clone(),
+            refused_connections: AtomicU64::new(0),
+        }
+    }
+
+    /// Called for every accepted TCP connection, before any TLS work. Returns false if the
+    /// connection must be dropped.
+    pub fn allows(&self, addr: IpAddr) -> bool {
+        let addr = canonical_ip(addr);
+        let refused_by = if let Some(net) = self.denied.iter().find(|n| n.contains(&addr)) {
+            format!("denied network {}", net)
+        } else if !self.allowed.is_empty() && !self.allowed.iter().any(|n| n.contains(&addr)) {
+            "no allowed network".to_owned()
+        } else {
+            return true;
+        };
+        self.refused_connections.fetch_add(1, Ordering::Relaxed);
+        metrics().refused_connections.inc("source_acl");
+        info!(
+            "Refusing connection from {} : source address matches {}",
+            addr, refused_by
+        );
+        false
+    }
+
+    /// Number of connections refused because of their source address.
+    pub fn refused_connections(&self) -> u64 {
+        self.refused_connections.load(Ordering::Relaxed)
+    }
+}
+
+/// Whether `addr` is one of the addresses or networks held by `claim`, which may be a string
+/// or a list of strings. A missing claim matches nothing.
+pub fn source_matches_claim(claims: &Claims, claim: &str, addr: IpAddr) -> bool {
+    let addr = canonical_ip(addr);
+    let values = match claims.extra.get(claim) {
+        Some(serde_json::Value::String(s)) => vec![s.as_str()],
+        Some(serde_json::Value::Array(values)) => {
+            values.iter().filter_map(|v| v.as_str()).collect()
+        }
+        _ => vec![],
+    };
+    values.iter().any(|v| {
+        if let Ok(net) = v.parse::<IpNet>() {
+            net.contains(&addr)
+        } else if let Ok(ip) = v.parse::<IpAddr>() {
+            canonical_ip(ip) == addr
+        } else {
+            false
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn acl(allowed: &[&str], denied: &[&str]) -> SourceAcl {
+        let nets = |nets: &[&str]| nets.iter().map(|n| n.parse().unwrap()).collect();
+        SourceAcl {
+            allowed: nets(allowed),
+            denied: nets(denied),
+            refused_connections: AtomicU64::new(0),
+        }
+    }
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    fn claims(extra: serde_json::Value) -> Claims {
+        Claims {
+            exp: 0,
+            user_id: "alice".to_owned(),
+            session_id: 1,
+            machine_id: "1234".to_owned(),
+            machine_ip: "127.0.0.1".to_owned(),
+            machine_port: 5000,
+            extra: serde_json::from_value::<HashMap<_, _>>(extra).unwrap(),
+        }
+    }
+
+    #[test]
+    fn empty_lists_allow_everything() {
+        let acl = acl(&[], &[]);
+        assert!(acl.allows(ip("192.0.2.1")));
+        assert!(acl.allows(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn denied_wins_over_allowed() {
+        let acl = acl(&["10.0.0.0/8"], &["10.1.0.0/16"]);
+        assert!(acl.allows(ip("10.2.0.1")));
+        assert!(!acl.allows(ip("10.1.0.1")));
+        assert!(!acl.allows(ip("192.0.2.1")));
+        assert_eq!(acl.refused_connections(), 2);
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses() {
+        let acl = acl(&["10.0.0.0/8"], &[]);
+        assert!(acl.allows(ip("::ffff:10.0.0.1")));
+        let acl = self::acl(&[], &["10.0.0.0/8"]);
+        assert!(!acl.allows(ip("::ffff:10.0.0.1")));
+    }
+
+    #[test]
+    fn claim_addresses_and_networks() {
+        let claims = claims(serde_json::json!({
+            "src": ["192.0.2.7", "10.0.0.0/24", "2001:db8::/32", "not an address"],
+            "single": "192.0.2.8",
+        }));
+        assert!(source_matches_claim(&claims, "src", ip("192.0.2.7")));
+        assert!(source_matches_claim(&claims, "src", ip("::ffff:10.0.0.9")));
+        assert!(source_matches_claim(&claims, "src", ip("2001:db8::1")));
+        assert!(!source_matches_claim(&claims, "src", ip("192.0.2.8")));
+        assert!(source_matches_claim(&claims, "single", ip("192.0.2.8")));
+        assert!(!source_matches_claim(&claims, "missing", ip("192.0.2.7")));
+    }
+}
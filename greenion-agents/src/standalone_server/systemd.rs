@@ -0,0 +1,213 @@
+use log::{debug, info, warn};
+use std::time::Duration;
+
+use super::sessions::SessionManager;
+
+/// First file descriptor passed by socket activation
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: i32 = 3;
+/// Status updates are sent this often when the watchdog is disabled
+const STATUS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Notifies systemd of the state of the agent when it runs as a `Type=notify` unit. Does
+/// nothing when `NOTIFY_SOCKET` isn't set, and on other systems.
+#[derive(Debug)]
+pub struct SystemdNotifier {
+    #[cfg(unix)]
+    socket: Option<std::os::unix::net::UnixDatagram>,
+    #[cfg(unix)]
+    address: Option<std::os::unix::net::SocketAddr>,
+    watchdog: Option<Duration>,
+}
+
+impl SystemdNotifier {
+    pub fn from_env() -> Self {
+        #[cfg(unix)]
+        {
+            let (socket, address) = match std::env::var("NOTIFY_SOCKET") {
+                Ok(path) => match notify_socket(&path) {
+                    Ok((socket, address)) => {
+                        debug!("Notifying systemd through {}", path);
+                        (Some(socket), Some(address))
+                    }
+                    Err(e) => {
+                        warn!("Could not use the systemd notify socket {} : {}", path, e);
+                        (None, None)
+                    }
+                },
+                Err(_) => (None, None),
+            };
+            let watchdog = socket.as_ref().and_then(|_| watchdog_timeout());
+            if let Some(timeout) = watchdog {
+                info!(
+                    "systemd watchdog is enabled, pinging it every {}",
+                    humantime::format_duration(timeout / 2)
+                );
+            }
+            Self {
+                socket,
+                address,
+                watchdog,
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            Self { watchdog: None }
+        }
+    }
+
+    fn listening(&self) -> bool {
+        #[cfg(unix)]
+        {
+            self.socket.is_some()
+        }
+        #[cfg(not(unix))]
+        {
+            false
+        }
+    }
+
+    fn notify(&self, state: &str) {
+        #[cfg(unix)]
+        {
+            if let (Some(socket), Some(address)) = (&self.socket, &self.address) {
+                if let Err(e) = socket.send_to_addr(state.as_bytes(), address) {
+                    warn!("Could not notify systemd : {}", e);
+                }
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = state;
+        }
+    }
+
+    /// The agent accepts connections.
+    pub fn ready(&self, status: &str) {
+        self.notify(&format!("READY=1\nSTATUS={}", status));
+    }
+
+    pub fn status(&self, status: &str) {
+        self.notify(&format!("STATUS={}", status));
+    }
+
+    /// Also pings the watchdog when it is enabled.
+    pub fn keep_alive(&self, status: &str) {
+        if self.watchdog.is_some() {
+            self.notify(&format!("WATCHDOG=1\nSTATUS={}", status));
+        } else {
+            self.status(status);
+        }
+    }
+
+    pub fn stopping(&self, status: &str) {
+        self.notify(&format!("STOPPING=1\nSTATUS={}", status));
+    }
+
+    /// How often `keep_alive` must be called, None when systemd isn't listening.
+    pub fn keep_alive_interval(&self) -> Option<Duration> {
+        self.listening()
+            .then(|| self.watchdog.map(|t| t / 2).unwrap_or(STATUS_INTERVAL))
+    }
+}
+
+#[cfg(unix)]
+fn notify_socket(
+    path: &str,
+) -> std::io::Result<(
+    std::os::unix::net::UnixDatagram,
+    std::os::unix::net::SocketAddr,
+)> {
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+    let address = match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            SocketAddr::from_abstract_name(name)?
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "abstract sockets are only available on Linux",
+            ))
+        }
+        None => SocketAddr::from_pathname(path)?,
+    };
+    Ok((UnixDatagram::unbound()?, address))
+}
+
+/// Watchdog timeout set by the unit, if it is meant for this process.
+#[cfg(unix)]
+fn watchdog_timeout() -> Option<Duration> {
+    let usec = std::env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok()? != std::process::id() {
+            return None;
+        }
+    }
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
+
+/// Listening socket passed by systemd socket activation (`LISTEN_FDS`), if any. Only the
+/// first socket is used.
+#[cfg(unix)]
+pub fn activated_listener() -> anyhow::Result<Option<std::net::TcpListener>> {
+    use std::os::fd::{FromRawFd, OwnedFd};
+
+    let Ok(fds) = std::env::var("LISTEN_FDS") else {
+        return Ok(None);
+    };
+    // the variables are inherited by the sanzu servers, which ignore them as they hold
+    // another pid
+    let for_us = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        == Some(std::process::id());
+    let fds = fds.parse::<i32>().unwrap_or(0);
+    if !for_us || fds < 1 {
+        return Ok(None);
+    }
+    if fds > 1 {
+        warn!(
+            "systemd passed {} sockets, only listening on the first one",
+            fds
+        );
+    }
+
+    // SAFETY: systemd hands the descriptors from SD_LISTEN_FDS_START over to this process,
+    // nothing else owns them
+    let fd = unsafe { OwnedFd::from_raw_fd(SD_LISTEN_FDS_START) };
+    // duplicated so that the socket is closed on exec, and not inherited by sanzu servers
+    let listener = std::net::TcpListener::from(fd.try_clone()?);
+    drop(fd);
+    // fails unless the descriptor is a socket
+    let local_addr = listener.local_addr()?;
+    listener.set_nonblocking(true)?;
+    info!(
+        "Using the socket passed by systemd, bound to {}",
+        local_addr
+    );
+    Ok(Some(listener))
+}
+
+#[cfg(not(unix))]
+pub fn activated_listener() -> anyhow::Result<Option<std::net::TcpListener>> {
+    Ok(None)
+}
+
+/// Status line of the agent shown by `systemctl status`.
+pub fn sessions_status(sessions: &SessionManager) -> String {
+    let status = format!(
+        "{} / {} session(s), {} queued",
+        sessions.sessions().len(),
+        sessions.max_sessions(),
+        sessions.queued().len()
+    );
+    if sessions.is_draining() {
+        format!("Draining, {}", status)
+    } else {
+        status
+    }
+}
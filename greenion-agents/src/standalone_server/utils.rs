@@ -3,6 +3,7 @@ use log::error;
 use std::{
     env::{self, args},
     fs::create_dir_all,
+    net::IpAddr,
     panic,
     path::PathBuf,
     str::FromStr,
@@ -77,3 +78,12 @@ pub fn setup_server_panic_hook() {
         error!("Backtrace: {}", backtrace);
     }));
 }
+
+/// Source address as compared against networks : IPv4-mapped IPv6 addresses, as seen on
+/// dual-stack listeners, are turned back into IPv4.
+pub fn canonical_ip(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(addr),
+        v4 => v4,
+    }
+}
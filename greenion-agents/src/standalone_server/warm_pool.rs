@@ -0,0 +1,242 @@
+use log::{info, warn};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{Mutex, Notify},
+    task::JoinHandle,
+};
+
+use super::{
+    sanzu_launch::{self, SanzuLaunchVars},
+    sessions::SessionManager,
+    SanzuLauncher, SanzuProcess,
+};
+use crate::conf::server_config::{SanzuServerLaunchConfig, WarmPoolConfig};
+
+/// User the sanzu servers of the pool are started for. Their launch can't depend on it
+const WARM_POOL_USER: &str = "warm-pool";
+/// How often the pool checks its sanzu servers, and starts the missing ones
+const CHECK_INTERVAL: Duration = Duration::from_millis(500);
+/// How long the pool waits after a sanzu server failed to start, before starting another
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+
+enum WarmServer {
+    Starting(JoinHandle<anyhow::Result<SanzuProcess>>),
+    Ready {
+        process: SanzuProcess,
+        since: Instant,
+    },
+    /// Replaced or no longer needed : its port is reserved until it exited
+    Stopping(JoinHandle<()>),
+}
+
+impl WarmServer {
+    fn stopping(process: SanzuProcess) -> Self {
+        Self::Stopping(tokio::spawn(async move {
+            process.stop().await;
+        }))
+    }
+}
+
+/// Sanzu servers started while the agent is idle, each handed to the next session that
+/// starts. The sessions manager reserves their ports, and gives them first to new sessions.
+pub struct WarmPool {
+    size: usize,
+    idle_recycle: Option<Duration>,
+    sanzu_config: SanzuServerLaunchConfig,
+    /// Codec the sanzu servers of the pool are started with
+    codec: String,
+    launcher: SanzuLauncher,
+    sessions: Arc<SessionManager>,
+    servers: Mutex<HashMap<u16, WarmServer>>,
+    /// Notified when a session was given a sanzu server of the pool
+    taken: Notify,
+}
+
+impl WarmPool {
+    /// None when the pool is disabled, or can't be used with this configuration.
+    pub fn new(
+        config: &WarmPoolConfig,
+        sanzu_config: &SanzuServerLaunchConfig,
+        max_sessions: usize,
+        launcher: SanzuLauncher,
+        sessions: Arc<SessionManager>,
+    ) -> Option<Self> {
+        if config.size == 0 {
+            return None;
+        }
+        if sanzu_config.sanzu_server_external_startup {
+            warn!("Sanzu servers are started externally, the warm pool is disabled");
+            return None;
+        }
+        if sanzu_launch::depends_on_session(sanzu_config) {
+            warn!("The sanzu server launch depends on the session, the warm pool is disabled");
+            return None;
+        }
+        let size = config.size.min(max_sessions.max(1));
+        info!(
+            "Keeping {} sanzu server(s) started ahead of the sessions",
+            size
+        );
+        Some(Self {
+            size,
+            idle_recycle: (config.idle_recycle_secs > 0)
+                .then(|| Duration::from_secs(config.idle_recycle_secs)),
+            sanzu_config: sanzu_config.clone(),
+            codec: sanzu_config.preferred_encoder(),
+            launcher,
+            sessions,
+            servers: Mutex::new(HashMap::new()),
+            taken: Notify::new(),
+        })
+    }
+
+    /// Keeps the pool full, until the agent stops.
+    pub async fn run(self: Arc<Self>) {
+        let mut retry_at = None;
+        loop {
+            let released = self.sessions.released();
+            let taken = self.taken.notified();
+            self.maintain(&mut retry_at).await;
+            tokio::select! {
+                _ = released => {}
+                _ = taken => {}
+                _ = tokio::time::sleep(CHECK_INTERVAL) => {}
+            }
+        }
+    }
+
+    /// Forgets the sanzu servers that exited or stayed unused for too long, and starts new
+    /// ones in their place. Stops them all while draining. The port of a sanzu server being
+    /// stopped is given to no session until it exited, and a sanzu server whose port was
+    /// already given to a session is left for it.
+    async fn maintain(&self, retry_at: &mut Option<Instant>) {
+        let draining = self.sessions.is_draining();
+        let mut servers = self.servers.lock().await;
+        let ports = servers.keys().copied().collect::<Vec<_>>();
+        for port in ports {
+            let Some(server) = servers.remove(&port) else {
+                continue;
+            };
+            let kept = match server {
+                WarmServer::Starting(handle) if !handle.is_finished() => {
+                    Some(WarmServer::Starting(handle))
+                }
+                WarmServer::Starting(handle) => {
+                    match handle.await.map_err(anyhow::Error::from).and_then(|r| r) {
+                        Ok(process) => {
+                            info!("Sanzu server of the warm pool ready on port {}", port);
+                            Some(WarmServer::Ready {
+                                process,
+                                since: Instant::now(),
+                            })
+                        }
+                        Err(e) => {
+                            warn!(
+                                "Could not start a sanzu server for the warm pool on port {} : {}",
+                                port, e
+                            );
+                            *retry_at = Some(Instant::now() + RETRY_DELAY);
+                            None
+                        }
+                    }
+                }
+                WarmServer::Ready { process, since } => {
+                    if let Some(exit) = process.exit() {
+                        warn!(
+                            "Sanzu server of the warm pool on port {} exited : {}",
+                            port, exit
+                        );
+                        None
+                    } else if let Some(idle) = self
+                        .idle_recycle
+                        .filter(|idle| since.elapsed() >= *idle)
+                        // a session given the port takes the sanzu server instead
+                        .filter(|_| self.sessions.retire_warm_port(port))
+                    {
+                        info!(
+                            "Sanzu server of the warm pool on port {} unused for {}, replacing it",
+                            port,
+                            humantime::format_duration(idle)
+                        );
+                        Some(WarmServer::stopping(process))
+                    } else {
+                        Some(WarmServer::Ready { process, since })
+                    }
+                }
+                WarmServer::Stopping(handle) if !handle.is_finished() => {
+                    Some(WarmServer::Stopping(handle))
+                }
+                WarmServer::Stopping(_) => None,
+            };
+            let kept = match kept {
+                // a sanzu server being started is stopped once it is ready
+                Some(WarmServer::Ready { process, .. })
+                    if draining && self.sessions.retire_warm_port(port) =>
+                {
+                    Some(WarmServer::stopping(process))
+                }
+                kept => kept,
+            };
+            match kept {
+                Some(server) => {
+                    servers.insert(port, server);
+                }
+                None => self.sessions.release_warm_port(port),
+            }
+        }
+
+        if draining || retry_at.is_some_and(|at| Instant::now() < at) {
+            return;
+        }
+        while servers
+            .values()
+            .filter(|server| !matches!(server, WarmServer::Stopping(_)))
+            .count()
+            < self.size
+        {
+            let Some(port) = self.sessions.reserve_warm_port() else {
+                break;
+            };
+            info!("Starting a sanzu server for the warm pool on port {}", port);
+            let launcher = self.launcher.clone();
+            let sanzu_config = self.sanzu_config.clone();
+            let vars = SanzuLaunchVars::new(&sanzu_config, port, WARM_POOL_USER);
+            let stop = self.sessions.sanzu_stop_signal();
+            let starting =
+                tokio::spawn(async move { launcher.launch(&sanzu_config, &vars, stop).await });
+            servers.insert(port, WarmServer::Starting(starting));
+        }
+    }
+
+    /// Sanzu server of the pool on `port`, for the session given this port. Waits for it if
+    /// it is still starting. None if it failed to start, exited, is being stopped or doesn't
+    /// use `codec` : the session then starts its own, once the port is free again.
+    pub async fn take(&self, port: u16, codec: &str) -> Option<SanzuProcess> {
+        let server = self.servers.lock().await.remove(&port)?;
+        self.taken.notify_one();
+        let process = match server {
+            WarmServer::Starting(handle) => handle.await.ok()?.ok()?,
+            WarmServer::Ready { process, .. } => process,
+            WarmServer::Stopping(handle) => {
+                let _ = handle.await;
+                return None;
+            }
+        };
+        if process.exit().is_some() {
+            return None;
+        }
+        if codec != self.codec {
+            info!(
+                "The session on port {} streams with {}, stopping the sanzu server of the warm pool using {}",
+                port, codec, self.codec
+            );
+            process.stop().await;
+            return None;
+        }
+        Some(process)
+    }
+}
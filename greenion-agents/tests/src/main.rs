@@ -376,6 +376,7 @@ sanzu_client_external_startup = true
         r#"
 [server_auth_config]
 jwks_url = "http://localhost:8080/.well-known/jwks.json"
+allow_unencrypted_private_key = true
 
 [server_network_config]
 server_port = 9447
@@ -391,6 +392,7 @@ sanzu_server_port = 1122
 jwks_url = "http://localhost:8080/.well-known/jwks.json"
 cert_file = "certs\\cert.pem"
 private_key_file = "certs\\key.pem"
+allow_unencrypted_private_key = true
 
 [server_network_config]
 server_port = 9447